@@ -23,7 +23,7 @@ use crate::tls;
 
 #[cfg(feature = "websocket")]
 use {
-    crate::websockets::{split_url, UrlError},
+    crate::websockets::{split_url, split_url_with_auth, upgrade_url, UrlError},
     async_tungstenite::tungstenite::client::IntoClientRequest,
     ws_stream_tungstenite::WsStream,
 };
@@ -381,7 +381,7 @@ async fn network_connect(
         Transport::Unix => unreachable!(),
         #[cfg(feature = "websocket")]
         Transport::Ws => {
-            let mut request = options.broker_addr.as_str().into_client_request()?;
+            let mut request = upgrade_url(&options.broker_addr)?.into_client_request()?;
             request
                 .headers_mut()
                 .insert("Sec-WebSocket-Protocol", "mqtt".parse().unwrap());
@@ -392,7 +392,7 @@ async fn network_connect(
         }
         #[cfg(all(feature = "use-rustls", feature = "websocket"))]
         Transport::Wss(tls_config) => {
-            let mut request = options.broker_addr.as_str().into_client_request()?;
+            let mut request = upgrade_url(&options.broker_addr)?.into_client_request()?;
             request
                 .headers_mut()
                 .insert("Sec-WebSocket-Protocol", "mqtt".parse().unwrap());
@@ -426,7 +426,12 @@ async fn mqtt_connect(
     connect.clean_session = clean_session;
     connect.last_will = last_will;
 
-    if let Some((username, password)) = options.credentials() {
+    #[cfg(feature = "websocket")]
+    let credentials = credentials(options)?;
+    #[cfg(not(feature = "websocket"))]
+    let credentials = options.credentials();
+
+    if let Some((username, password)) = credentials {
         let login = Login::new(username, password);
         connect.login = Some(login);
     }
@@ -443,3 +448,44 @@ async fn mqtt_connect(
         packet => Err(ConnectionError::NotConnAck(packet)),
     }
 }
+
+/// Credentials set in the options, or else those in the userinfo of a
+/// websocket broker url
+#[cfg(feature = "websocket")]
+fn credentials(options: &MqttOptions) -> Result<Option<(String, String)>, UrlError> {
+    if let Some(credentials) = options.credentials() {
+        return Ok(Some(credentials));
+    }
+
+    let credentials = match options.transport() {
+        Transport::Ws => split_url_with_auth(&options.broker_addr)?.2,
+        #[cfg(feature = "use-rustls")]
+        Transport::Wss(_) => split_url_with_auth(&options.broker_addr)?.2,
+        _ => None,
+    };
+
+    Ok(credentials)
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn websocket_url_credentials_are_used_unless_set() {
+        let mut options = MqttOptions::new("test", "wss://user:p%40ss@h/mqtt", 443);
+        assert_eq!(credentials(&options).unwrap(), None);
+
+        options.set_transport(Transport::Ws);
+        assert_eq!(
+            credentials(&options).unwrap(),
+            Some(("user".to_owned(), "p@ss".to_owned()))
+        );
+
+        options.set_credentials("other", "secret");
+        assert_eq!(
+            credentials(&options).unwrap(),
+            Some(("other".to_owned(), "secret".to_owned()))
+        );
+    }
+}
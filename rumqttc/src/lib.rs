@@ -138,6 +138,9 @@ use tokio_rustls::rustls::{Certificate, ClientConfig, RootCertStore};
 #[cfg(feature = "proxy")]
 pub use proxy::{Proxy, ProxyAuth, ProxyType};
 
+#[cfg(feature = "websocket")]
+pub use websockets::UrlError;
+
 pub type Incoming = Packet;
 
 /// Current outgoing activity on the eventloop
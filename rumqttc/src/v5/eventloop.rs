@@ -24,7 +24,7 @@ use {std::path::Path, tokio::net::UnixStream};
 
 #[cfg(feature = "websocket")]
 use {
-    crate::websockets::{split_url, UrlError},
+    crate::websockets::{split_url, split_url_with_auth, upgrade_url, UrlError},
     async_tungstenite::tungstenite::client::IntoClientRequest,
     ws_stream_tungstenite::WsStream,
 };
@@ -327,7 +327,7 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
         Transport::Unix => unreachable!(),
         #[cfg(feature = "websocket")]
         Transport::Ws => {
-            let mut request = options.broker_addr.as_str().into_client_request()?;
+            let mut request = upgrade_url(&options.broker_addr)?.into_client_request()?;
             request
                 .headers_mut()
                 .insert("Sec-WebSocket-Protocol", "mqtt".parse().unwrap());
@@ -338,7 +338,7 @@ async fn network_connect(options: &MqttOptions) -> Result<Network, ConnectionErr
         }
         #[cfg(all(feature = "use-rustls", feature = "websocket"))]
         Transport::Wss(tls_config) => {
-            let mut request = options.broker_addr.as_str().into_client_request()?;
+            let mut request = upgrade_url(&options.broker_addr)?.into_client_request()?;
             request
                 .headers_mut()
                 .insert("Sec-WebSocket-Protocol", "mqtt".parse().unwrap());
@@ -376,7 +376,11 @@ async fn mqtt_connect(
     };
 
     // send mqtt connect packet
-    network.connect(connect, options).await?;
+    #[cfg(feature = "websocket")]
+    let credentials = credentials(options)?;
+    #[cfg(not(feature = "websocket"))]
+    let credentials = options.credentials();
+    network.connect(connect, options, credentials).await?;
 
     // validate connack
     match network.read().await? {
@@ -393,3 +397,21 @@ async fn mqtt_connect(
         packet => Err(ConnectionError::NotConnAck(Box::new(packet))),
     }
 }
+
+/// Credentials set in the options, or else those in the userinfo of a
+/// websocket broker url
+#[cfg(feature = "websocket")]
+fn credentials(options: &MqttOptions) -> Result<Option<(String, String)>, UrlError> {
+    if let Some(credentials) = options.credentials() {
+        return Ok(Some(credentials));
+    }
+
+    let credentials = match options.transport() {
+        Transport::Ws => split_url_with_auth(&options.broker_addr)?.2,
+        #[cfg(feature = "use-rustls")]
+        Transport::Wss(_) => split_url_with_auth(&options.broker_addr)?.2,
+        _ => None,
+    };
+
+    Ok(credentials)
+}
@@ -100,10 +100,15 @@ impl Network {
         }
     }
 
-    pub async fn connect(&mut self, connect: Connect, options: &MqttOptions) -> io::Result<usize> {
+    pub async fn connect(
+        &mut self,
+        connect: Connect,
+        options: &MqttOptions,
+        credentials: Option<(String, String)>,
+    ) -> io::Result<usize> {
         let mut write = BytesMut::new();
         let last_will = options.last_will();
-        let login = options.credentials().map(|l| Login {
+        let login = credentials.map(|l| Login {
             username: l.0,
             password: l.1,
         });
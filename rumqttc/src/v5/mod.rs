@@ -23,6 +23,9 @@ pub use crate::tls::Error as TlsError;
 #[cfg(feature = "proxy")]
 pub use crate::proxy::{Proxy, ProxyAuth, ProxyType};
 
+#[cfg(feature = "websocket")]
+pub use crate::websockets::UrlError;
+
 pub type Incoming = Packet;
 
 /// Requests by the client to mqtt event loop. Request are
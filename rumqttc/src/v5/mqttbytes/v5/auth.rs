@@ -0,0 +1,306 @@
+use super::*;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Return code in Auth
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AuthReason {
+    Success,
+    ContinueAuthentication,
+    ReAuthenticate,
+}
+
+/// Exchange of enhanced authentication data between client and server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Auth {
+    pub reason: AuthReason,
+    pub properties: Option<AuthProperties>,
+}
+
+impl Auth {
+    pub fn new(reason: AuthReason, properties: Option<AuthProperties>) -> Self {
+        Self { reason, properties }
+    }
+
+    pub fn size(&self) -> usize {
+        let len = self.len();
+        let remaining_len_size = len_len(len);
+
+        1 + remaining_len_size + len
+    }
+
+    fn len(&self) -> usize {
+        // The Reason Code and Property Length can be omitted if the Reason Code is 0x00 (Success)
+        // and there are no Properties. In this case the AUTH has a Remaining Length of 0.
+        // <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901220>
+        if self.reason == AuthReason::Success && self.properties.is_none() {
+            return 0;
+        }
+
+        let mut len = 1; // reason
+
+        if let Some(p) = &self.properties {
+            let properties_len = p.len();
+            let properties_len_len = len_len(properties_len);
+            len += properties_len_len + properties_len;
+        } else {
+            len += 1;
+        }
+
+        len
+    }
+
+    pub fn read(fixed_header: FixedHeader, mut bytes: Bytes) -> Result<Auth, Error> {
+        let variable_header_index = fixed_header.fixed_header_len;
+        bytes.advance(variable_header_index);
+
+        if fixed_header.remaining_len == 0 {
+            return Ok(Auth {
+                reason: AuthReason::Success,
+                properties: None,
+            });
+        }
+
+        let auth_reason = read_u8(&mut bytes)?;
+        if fixed_header.remaining_len < 2 {
+            return Ok(Auth {
+                reason: reason(auth_reason)?,
+                properties: None,
+            });
+        }
+
+        let properties = AuthProperties::read(&mut bytes)?;
+        let auth = Auth {
+            reason: reason(auth_reason)?,
+            properties,
+        };
+
+        Ok(auth)
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) -> Result<usize, Error> {
+        let len = self.len();
+        buffer.put_u8(0xF0);
+        let count = write_remaining_length(buffer, len)?;
+
+        // If there are no properties during success, sending reason code is optional
+        if self.reason == AuthReason::Success && self.properties.is_none() {
+            return Ok(2);
+        }
+
+        buffer.put_u8(code(self.reason));
+
+        if let Some(p) = &self.properties {
+            p.write(buffer)?;
+        } else {
+            write_remaining_length(buffer, 0)?;
+        }
+
+        Ok(1 + count + len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuthProperties {
+    pub method: Option<String>,
+    pub data: Option<Bytes>,
+    pub reason_string: Option<String>,
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl AuthProperties {
+    fn len(&self) -> usize {
+        let mut len = 0;
+
+        if let Some(method) = &self.method {
+            len += 1 + 2 + method.len();
+        }
+
+        if let Some(data) = &self.data {
+            len += 1 + 2 + data.len();
+        }
+
+        if let Some(reason) = &self.reason_string {
+            len += 1 + 2 + reason.len();
+        }
+
+        for (key, value) in self.user_properties.iter() {
+            len += 1 + 2 + key.len() + 2 + value.len();
+        }
+
+        len
+    }
+
+    pub fn read(bytes: &mut Bytes) -> Result<Option<AuthProperties>, Error> {
+        let mut method = None;
+        let mut data = None;
+        let mut reason_string = None;
+        let mut user_properties = Vec::new();
+
+        let (properties_len_len, properties_len) = length(bytes.iter())?;
+        bytes.advance(properties_len_len);
+        if properties_len == 0 {
+            return Ok(None);
+        }
+
+        let mut cursor = 0;
+        // read until cursor reaches property length. properties_len = 0 will skip this loop
+        while cursor < properties_len {
+            let prop = read_u8(bytes)?;
+            cursor += 1;
+
+            match property(prop)? {
+                PropertyType::AuthenticationMethod => {
+                    let auth_method = read_mqtt_string(bytes)?;
+                    cursor += 2 + auth_method.len();
+                    method = Some(auth_method);
+                }
+                PropertyType::AuthenticationData => {
+                    let auth_data = read_mqtt_bytes(bytes)?;
+                    cursor += 2 + auth_data.len();
+                    data = Some(auth_data);
+                }
+                PropertyType::ReasonString => {
+                    let reason = read_mqtt_string(bytes)?;
+                    cursor += 2 + reason.len();
+                    reason_string = Some(reason);
+                }
+                PropertyType::UserProperty => {
+                    let key = read_mqtt_string(bytes)?;
+                    let value = read_mqtt_string(bytes)?;
+                    cursor += 2 + key.len() + 2 + value.len();
+                    user_properties.push((key, value));
+                }
+                _ => return Err(Error::InvalidPropertyType(prop)),
+            }
+        }
+
+        Ok(Some(AuthProperties {
+            method,
+            data,
+            reason_string,
+            user_properties,
+        }))
+    }
+
+    pub fn write(&self, buffer: &mut BytesMut) -> Result<(), Error> {
+        let len = self.len();
+        write_remaining_length(buffer, len)?;
+
+        if let Some(method) = &self.method {
+            buffer.put_u8(PropertyType::AuthenticationMethod as u8);
+            write_mqtt_string(buffer, method);
+        }
+
+        if let Some(data) = &self.data {
+            buffer.put_u8(PropertyType::AuthenticationData as u8);
+            write_mqtt_bytes(buffer, data);
+        }
+
+        if let Some(reason) = &self.reason_string {
+            buffer.put_u8(PropertyType::ReasonString as u8);
+            write_mqtt_string(buffer, reason);
+        }
+
+        for (key, value) in self.user_properties.iter() {
+            buffer.put_u8(PropertyType::UserProperty as u8);
+            write_mqtt_string(buffer, key);
+            write_mqtt_string(buffer, value);
+        }
+
+        Ok(())
+    }
+}
+
+/// Connection return code type
+fn reason(num: u8) -> Result<AuthReason, Error> {
+    let code = match num {
+        0x00 => AuthReason::Success,
+        0x18 => AuthReason::ContinueAuthentication,
+        0x19 => AuthReason::ReAuthenticate,
+        num => return Err(Error::InvalidConnectReturnCode(num)),
+    };
+
+    Ok(code)
+}
+
+fn code(reason: AuthReason) -> u8 {
+    match reason {
+        AuthReason::Success => 0x00,
+        AuthReason::ContinueAuthentication => 0x18,
+        AuthReason::ReAuthenticate => 0x19,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::test::{USER_PROP_KEY, USER_PROP_VAL};
+    use super::*;
+    use bytes::BytesMut;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn length_calculation() {
+        let mut dummy_bytes = BytesMut::new();
+        // Use user_properties to pad the size to exceed ~128 bytes to make the
+        // remaining_length field in the packet be 2 bytes long.
+        let auth_props = AuthProperties {
+            method: None,
+            data: None,
+            reason_string: None,
+            user_properties: vec![(USER_PROP_KEY.into(), USER_PROP_VAL.into())],
+        };
+
+        let auth_pkt = Auth::new(AuthReason::ContinueAuthentication, Some(auth_props));
+
+        let size_from_size = auth_pkt.size();
+        let size_from_write = auth_pkt.write(&mut dummy_bytes).unwrap();
+        let size_from_bytes = dummy_bytes.len();
+
+        assert_eq!(size_from_write, size_from_bytes);
+        assert_eq!(size_from_size, size_from_bytes);
+    }
+
+    #[test]
+    fn short_form_round_trip() {
+        let auth = Auth::new(AuthReason::Success, None);
+        let mut stream = BytesMut::new();
+        let written = auth.write(&mut stream).unwrap();
+        assert_eq!(&stream[..], &[0xF0, 0x00]);
+        assert_eq!(written, auth.size());
+
+        let packet = Packet::read(&mut stream, None).unwrap();
+        assert_eq!(packet, Packet::Auth(auth));
+
+        // Reason code without properties
+        let auth = Auth::new(AuthReason::ReAuthenticate, None);
+        let mut stream = BytesMut::new();
+        auth.write(&mut stream).unwrap();
+        assert_eq!(&stream[..], &[0xF0, 0x02, 0x19, 0x00]);
+
+        let packet = Packet::read(&mut stream, None).unwrap();
+        assert_eq!(packet, Packet::Auth(auth));
+    }
+
+    #[test]
+    fn full_properties_round_trip() {
+        let auth = Auth::new(
+            AuthReason::ContinueAuthentication,
+            Some(AuthProperties {
+                method: Some("SCRAM-SHA-256".into()),
+                data: Some(Bytes::from_static(&[1, 2, 3, 4])),
+                reason_string: Some("challenge".into()),
+                user_properties: vec![(USER_PROP_KEY.into(), USER_PROP_VAL.into())],
+            }),
+        );
+
+        let mut stream = BytesMut::new();
+        let written = auth.write(&mut stream).unwrap();
+        assert_eq!(written, stream.len());
+        assert_eq!(written, auth.size());
+
+        let packet = Packet::read(&mut stream, None).unwrap();
+        assert_eq!(packet, Packet::Auth(auth));
+        assert!(stream.is_empty());
+    }
+}
@@ -312,9 +312,14 @@ impl Disconnect {
 
         let reason_code = read_u8(&mut bytes)?;
 
+        let properties = match fixed_header.remaining_len {
+            1 => None,
+            _ => DisconnectProperties::extract(&mut bytes)?,
+        };
+
         let disconnect = Self {
             reason_code: reason_code.try_into()?,
-            properties: DisconnectProperties::extract(&mut bytes)?,
+            properties,
         };
 
         Ok(disconnect)
@@ -334,10 +339,9 @@ impl Disconnect {
 
         buffer.put_u8(self.reason_code as u8);
 
+        // Property length is omitted when there are no properties
         if let Some(properties) = &self.properties {
             properties.write(buffer)?;
-        } else {
-            write_remaining_length(buffer, 0)?;
         }
 
         Ok(1 + len_len + length)
@@ -467,4 +471,21 @@ mod test {
         assert_eq!(size_from_write, size_from_bytes);
         assert_eq!(size_from_size, size_from_bytes);
     }
+
+    #[test]
+    fn reason_without_properties_round_trip() {
+        let mut buffer = BytesMut::new();
+        let disconnect = Disconnect::new(DisconnectReasonCode::ServerShuttingDown);
+
+        let written = disconnect.write(&mut buffer).unwrap();
+        assert_eq!(&buffer[..], &[0xE0, 0x01, 0x8B]);
+        assert_eq!(written, disconnect.size());
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let disconnect_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        assert_eq!(
+            Disconnect::read(fixed_header, disconnect_bytes).unwrap(),
+            disconnect
+        );
+    }
 }
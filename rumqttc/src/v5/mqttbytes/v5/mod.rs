@@ -1,6 +1,7 @@
 use std::slice::Iter;
 
 pub use self::{
+    auth::{Auth, AuthProperties, AuthReason},
     connack::{ConnAck, ConnAckProperties, ConnectReturnCode},
     connect::{Connect, ConnectProperties, LastWill, LastWillProperties, Login},
     disconnect::{Disconnect, DisconnectReasonCode},
@@ -19,6 +20,7 @@ pub use self::{
 use super::*;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 
+mod auth;
 mod connack;
 mod connect;
 mod disconnect;
@@ -49,6 +51,7 @@ pub enum Packet {
     Unsubscribe(Unsubscribe),
     UnsubAck(UnsubAck),
     Disconnect(Disconnect),
+    Auth(Auth),
 }
 
 impl Packet {
@@ -65,6 +68,7 @@ impl Packet {
             return match packet_type {
                 PacketType::PingReq => Ok(Packet::PingReq(PingReq)),
                 PacketType::PingResp => Ok(Packet::PingResp(PingResp)),
+                PacketType::Auth => Ok(Packet::Auth(Auth::read(fixed_header, packet.freeze())?)),
                 _ => Err(Error::PayloadRequired),
             };
         }
@@ -121,6 +125,10 @@ impl Packet {
                 let disconnect = Disconnect::read(fixed_header, packet)?;
                 Packet::Disconnect(disconnect)
             }
+            PacketType::Auth => {
+                let auth = Auth::read(fixed_header, packet)?;
+                Packet::Auth(auth)
+            }
         };
 
         Ok(packet)
@@ -142,6 +150,7 @@ impl Packet {
             Self::PingReq(_) => PingReq::write(write),
             Self::PingResp(_) => PingResp::write(write),
             Self::Disconnect(disconnect) => disconnect.write(write),
+            Self::Auth(auth) => auth.write(write),
         }
     }
 }
@@ -164,6 +173,7 @@ pub enum PacketType {
     PingReq,
     PingResp,
     Disconnect,
+    Auth,
 }
 
 #[repr(u8)]
@@ -250,6 +260,7 @@ impl FixedHeader {
             12 => Ok(PacketType::PingReq),
             13 => Ok(PacketType::PingResp),
             14 => Ok(PacketType::Disconnect),
+            15 => Ok(PacketType::Auth),
             _ => Err(Error::InvalidPacketType(num)),
         }
     }
@@ -110,6 +110,26 @@ impl PubComp {
 
         Ok(1 + count + len)
     }
+
+    /// Writes the packet without exceeding `max_size` bytes, the Maximum Packet
+    /// Size negotiated with the receiver. User Properties and then the Reason
+    /// String are left out if the full packet doesn't fit
+    pub fn write_within(&self, buffer: &mut BytesMut, max_size: usize) -> Result<usize, Error> {
+        if self.size() <= max_size {
+            return self.write(buffer);
+        }
+
+        let mut pubcomp = self.clone();
+        if let Some(properties) = &mut pubcomp.properties {
+            properties.user_properties.clear();
+            if properties.reason_string.is_some() && pubcomp.size() <= max_size {
+                return pubcomp.write(buffer);
+            }
+        }
+
+        pubcomp.properties = None;
+        pubcomp.write(buffer)
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -188,6 +208,22 @@ impl PubCompProperties {
 
         Ok(())
     }
+
+    /// Same as `write` but with user properties sorted by (key, value), so that
+    /// properties with the same pairs in different order produce identical bytes.
+    /// Only meant for byte level comparisons, `write` preserves the order in which
+    /// user properties were added as the spec requires
+    pub fn write_canonical(&self, buffer: &mut BytesMut) -> Result<(), Error> {
+        let mut user_properties = self.user_properties.clone();
+        user_properties.sort();
+
+        let properties = PubCompProperties {
+            reason_string: self.reason_string.clone(),
+            user_properties,
+        };
+
+        properties.write(buffer)
+    }
 }
 
 /// Connection return code type
@@ -234,4 +270,102 @@ mod test {
         assert_eq!(size_from_write, size_from_bytes);
         assert_eq!(size_from_size, size_from_bytes);
     }
+
+    #[test]
+    fn oversized_properties_are_left_out_to_fit() {
+        let properties = PubCompProperties {
+            reason_string: Some("packet identifier not found".into()),
+            user_properties: vec![(USER_PROP_KEY.into(), USER_PROP_VAL.into()); 10],
+        };
+        let pubcomp = PubComp {
+            pkid: 42,
+            reason: PubCompReason::PacketIdentifierNotFound,
+            properties: Some(properties),
+        };
+        assert!(pubcomp.size() > 100);
+
+        // Reason String alone fits
+        let mut buffer = BytesMut::new();
+        let written = pubcomp.write_within(&mut buffer, 100).unwrap();
+        assert_eq!(written, buffer.len());
+        assert!(written <= 100);
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let read = PubComp::read(fixed_header, buffer.freeze()).unwrap();
+        let properties = read.properties.unwrap();
+        assert_eq!(
+            properties.reason_string.as_deref(),
+            Some("packet identifier not found")
+        );
+        assert!(properties.user_properties.is_empty());
+
+        // Only pkid and reason code fit
+        let mut buffer = BytesMut::new();
+        let written = pubcomp.write_within(&mut buffer, 10).unwrap();
+        assert_eq!(written, buffer.len());
+        assert!(written <= 10);
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let read = PubComp::read(fixed_header, buffer.freeze()).unwrap();
+        assert_eq!(read.pkid, 42);
+        assert_eq!(read.reason, PubCompReason::PacketIdentifierNotFound);
+        assert_eq!(read.properties, None);
+
+        // Packets which fit are written as they are
+        let mut buffer = BytesMut::new();
+        pubcomp.write_within(&mut buffer, pubcomp.size()).unwrap();
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        assert_eq!(
+            PubComp::read(fixed_header, buffer.freeze()).unwrap(),
+            pubcomp
+        );
+    }
+
+    #[test]
+    fn canonical_write_is_independent_of_user_property_order() {
+        let props_a = PubCompProperties {
+            reason_string: Some("test".into()),
+            user_properties: vec![
+                ("b".into(), "1".into()),
+                ("a".into(), "2".into()),
+                ("a".into(), "1".into()),
+            ],
+        };
+        let props_b = PubCompProperties {
+            reason_string: Some("test".into()),
+            user_properties: vec![
+                ("a".into(), "1".into()),
+                ("b".into(), "1".into()),
+                ("a".into(), "2".into()),
+            ],
+        };
+
+        let mut canonical_a = BytesMut::new();
+        let mut canonical_b = BytesMut::new();
+        props_a.write_canonical(&mut canonical_a).unwrap();
+        props_b.write_canonical(&mut canonical_b).unwrap();
+        assert_eq!(canonical_a, canonical_b);
+
+        // Default write keeps insertion order
+        let mut bytes_a = BytesMut::new();
+        let mut bytes_b = BytesMut::new();
+        props_a.write(&mut bytes_a).unwrap();
+        props_b.write(&mut bytes_b).unwrap();
+        assert_ne!(bytes_a, bytes_b);
+
+        // Canonical bytes are what the default write gives for sorted pairs
+        let sorted = PubCompProperties {
+            reason_string: Some("test".into()),
+            user_properties: vec![
+                ("a".into(), "1".into()),
+                ("a".into(), "2".into()),
+                ("b".into(), "1".into()),
+            ],
+        };
+        let mut expected = BytesMut::new();
+        sorted.write(&mut expected).unwrap();
+        assert_eq!(canonical_a, expected);
+
+        let mut bytes = canonical_a.freeze();
+        let read = PubCompProperties::read(&mut bytes).unwrap().unwrap();
+        assert_eq!(read, sorted);
+    }
 }
@@ -15,7 +15,7 @@ fn main() {
 
     dbg!(&config);
 
-    let mut broker = Broker::new(config);
+    let mut broker = Broker::new(config).unwrap();
     let meters = broker.meters().unwrap();
 
     let (mut link_tx, mut link_rx) = broker.link("consumer").unwrap();
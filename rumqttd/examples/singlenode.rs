@@ -25,7 +25,7 @@ fn main() {
 
     dbg!(&config);
 
-    let mut broker = Broker::new(config);
+    let mut broker = Broker::new(config).unwrap();
     let (mut link_tx, mut link_rx) = broker.link("singlenode").unwrap();
     thread::spawn(move || {
         broker.start().unwrap();
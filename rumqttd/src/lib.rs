@@ -21,22 +21,23 @@ pub mod protocol;
 mod router;
 mod segments;
 mod server;
+mod topic;
 
 pub type ConnectionId = usize;
 pub type RouterId = usize;
 pub type NodeId = usize;
-pub type Topic = String;
-pub type Filter = String;
 pub type TopicId = usize;
 pub type Offset = (u64, u64);
 pub type Cursor = (u64, u64);
 
 pub use link::alerts;
+pub use link::auth;
 pub use link::local;
 pub use link::meters;
 
-pub use router::{Alert, IncomingMeter, Meter, Notification, OutgoingMeter};
-pub use server::Broker;
+pub use router::{Alert, ConfigError, IncomingMeter, Meter, Notification, OutgoingMeter};
+pub use server::{AdminError, Broker};
+pub use topic::{Filter, Topic};
 
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -126,6 +127,9 @@ pub struct ConnectionSettings {
     pub auth: Option<HashMap<String, String>>,
     #[serde(default)]
     pub dynamic_filters: bool,
+    /// Hook consulted for every CONNECT before the connection is handed to the router
+    #[serde(skip)]
+    pub auth_hook: Option<auth::AuthHandler>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -146,6 +150,249 @@ pub struct RouterConfig {
     pub max_read_len: u64,
     pub max_connections: usize,
     pub initialized_filters: Option<Vec<Filter>>,
+    /// Reject topics and filters containing control characters other than
+    /// U+0000, which is always rejected
+    #[serde(default)]
+    pub reject_control_chars: bool,
+    /// Maximum number of messages a subscriber can fall behind the head of
+    /// a filter before it is disconnected
+    #[serde(default)]
+    pub max_consumer_lag: Option<u64>,
+    /// Report filters on which more than this many connections were parked
+    /// at once as `saturated_filters` in router meters
+    #[serde(default)]
+    pub saturated_waiters: Option<usize>,
+    /// Pause delivery to all the subscribers once unacknowledged publishes of all the
+    /// connections add up to this many bytes. Delivery resumes as acks free up space
+    #[serde(default)]
+    pub max_total_inflight_bytes: Option<usize>,
+    /// Filters which keep only the latest publish of every topic instead of the
+    /// full history. New subscriptions to these get the current state of all topics
+    #[serde(default)]
+    pub compacted_filters: Vec<Filter>,
+    /// Most topics a compacted filter keeps. Topics written longest ago are
+    /// dropped to make room for new ones. Defaults to 10000
+    #[serde(default)]
+    pub max_compacted_keys: Option<usize>,
+    /// Filters which deliver only the latest of the publishes on a topic that
+    /// arrived since the subscriber last read. Meant for state updates where
+    /// older values are of no use
+    #[serde(default)]
+    pub conflated_filters: Vec<Filter>,
+    /// Clear retained messages matching a filter once its last subscriber
+    /// unsubscribes. Useful for ephemeral namespaces
+    #[serde(default)]
+    pub clear_retained_on_unsubscribe: bool,
+    /// Number of messages a QoS 0 subscriber reads at once to begin with. Grown
+    /// towards `max_read_len` for subscribers which keep up with full reads and
+    /// shrunk for those which don't. Reads are always `max_read_len` long when unset
+    #[serde(default)]
+    pub initial_read_len: Option<u64>,
+    /// Drop QoS 0 publishes instead of appending them to filters on which every
+    /// subscriber lags more than this many messages behind. QoS 1 and 2 publishes
+    /// are always appended
+    #[serde(default)]
+    pub shed_qos0_lag: Option<u64>,
+    /// Lag `shed_qos0_lag` assumes for filters without connected subscribers
+    #[serde(default)]
+    pub idle_filter_lag: IdleFilterLag,
+    /// Topic on which `{"filter": .., "subscribers": ..}` is published every time a
+    /// filter gains or loses a subscriber, e.g. `$SYS/subscriptions`. Events are
+    /// only delivered to subscriptions on exactly this topic
+    #[serde(default)]
+    pub subscription_events_topic: Option<String>,
+    /// Drop publishes carrying the id of a recent publish on the same filter
+    #[serde(default)]
+    pub dedup: Option<DedupSettings>,
+    /// Send QoS 1 and 2 publishes again with DUP set when a MQTT 3.1.1
+    /// subscriber hasn't acked them within this many milliseconds. MQTT 5
+    /// subscribers only get them again on reconnect
+    #[serde(default)]
+    pub retransmit_interval_ms: Option<u64>,
+    /// Highest topic alias clients may set, advertised in CONNACK. Defaults
+    /// to 4096
+    #[serde(default)]
+    pub topic_alias_maximum: Option<u16>,
+    /// Largest packet clients may send, advertised in CONNACK. Publishes which
+    /// don't fit disconnect the client
+    #[serde(default)]
+    pub server_max_packet_size: Option<u32>,
+    /// Number of QoS 1 and 2 publishes a client may send before waiting for
+    /// their acks, advertised in CONNACK
+    #[serde(default)]
+    pub receive_maximum: Option<u16>,
+    /// Number of QoS 0 publishes forwarded to a subscriber per tick. Larger
+    /// backlogs are spread over several ticks instead of being delivered at once
+    #[serde(default)]
+    pub qos0_messages_per_tick: Option<u64>,
+    /// Length of a `qos0_messages_per_tick` tick in milliseconds. Defaults to 10
+    #[serde(default)]
+    pub qos0_tick_ms: Option<u64>,
+    /// What to do with the oldest segment of a filter which reached
+    /// `max_segment_count` while a subscriber hasn't read all of it yet
+    #[serde(default)]
+    pub segment_overflow: SegmentOverflow,
+    /// Largest retained message, topic and payload included, kept per topic.
+    /// Larger publishes with RETAIN are still delivered to current
+    /// subscribers, they just don't replace the retained message
+    #[serde(default)]
+    pub max_retained_message_size: Option<usize>,
+    /// Refuse subscriptions to filters with more levels than this
+    #[serde(default)]
+    pub max_filter_levels: Option<usize>,
+    /// Refuse subscriptions to filters with more `+` and `#` wildcards than this
+    #[serde(default)]
+    pub max_wildcards_per_filter: Option<usize>,
+    /// Rewrites of published topics, applied before they are routed. The
+    /// first rule matching a topic is used
+    #[serde(default)]
+    pub topic_rewrites: Vec<TopicRewrite>,
+    /// Topic of the marker publish delivered to a subscription once it has
+    /// caught up with its filter, retained messages included. Subscriptions
+    /// ask for the marker with a `live-marker: true` user property. Its
+    /// payload is the filter of the subscription
+    #[serde(default)]
+    pub live_marker_topic: Option<String>,
+    /// Retain publishes with an empty payload instead of clearing the retained
+    /// message of their topic, as the spec has it
+    #[serde(default)]
+    pub retain_empty_payloads: bool,
+    /// Refuse publish topics and subscription filters longer than this many
+    /// bytes, which would otherwise bloat the topic caches and retained messages
+    #[serde(default)]
+    pub max_topic_length: Option<usize>,
+    /// Route publishes to topics containing `+` or `#` with the wildcards
+    /// taken literally instead of disconnecting the publisher
+    #[serde(default)]
+    pub allow_publish_wildcards: bool,
+    /// Segment limits of filters which need to keep more or less data than
+    /// the rest. The first entry listing a filter is used, filters not listed
+    /// use `max_segment_size` and `max_segment_count`
+    #[serde(default)]
+    pub filter_storage: Vec<FilterStorage>,
+    /// Match published topics against the filters again once their cached
+    /// matches are this many milliseconds old. Expired matches are dropped
+    /// even when the topic isn't published to again. Cached matches are only
+    /// updated as filters are added and removed when unset
+    #[serde(default)]
+    pub match_cache_ttl_ms: Option<u64>,
+    /// What to do with publishes to topics no filter matches. Their retained
+    /// message is stored either way
+    #[serde(default)]
+    pub no_match_policy: NoMatchPolicy,
+    /// Write at most what fits in the outgoing buffer of a connection per
+    /// flush, keeping runs of consecutive PUBACKs in the same flush. Acks
+    /// which don't fit wait for the connection to take the buffer
+    #[serde(default)]
+    pub coalesce_pubacks: bool,
+    /// Time how long acks are queued before being written out, reported in
+    /// router meters
+    #[serde(default)]
+    pub track_ack_latency: bool,
+    /// Meter deliveries of every filter per subscriber, reported in router
+    /// meters
+    #[serde(default)]
+    pub meter_subscribers: bool,
+    /// Publishes to `$delayed/..` of a client held at once. Defaults to 1000,
+    /// further delayed publishes disconnect the client with `QuotaExceeded`
+    #[serde(default)]
+    pub max_delayed_publishes: Option<usize>,
+    /// Longest delay of a publish to `$delayed/..` in seconds, at most
+    /// `u32::MAX`. Defaults to a day
+    #[serde(default)]
+    pub max_publish_delay_secs: Option<u64>,
+}
+
+/// Eviction of segments beyond `max_segment_count`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentOverflow {
+    /// Always evict the oldest segment, subscribers which haven't read it
+    /// skip ahead
+    #[default]
+    DropOldest,
+    /// Keep segments which subscribers, their unacked publishes or saved
+    /// sessions have yet to read. Filters grow beyond `max_segment_count`
+    /// until they catch up, so this is only bounded along with
+    /// `max_consumer_lag`
+    KeepUnread,
+}
+
+/// Lag of a filter once its last connected subscriber leaves
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdleFilterLag {
+    /// The filter is caught up, so its QoS 0 publishes are never shed. They're
+    /// kept for persistent sessions which resume later
+    #[default]
+    CaughtUp,
+    /// The filter keeps the cursor of its last subscriber and lags further
+    /// behind with every publish, until a subscriber connects again
+    Hold,
+}
+
+/// Handling of publishes to topics no filter matches
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NoMatchPolicy {
+    /// Drop the publish
+    #[default]
+    Drop,
+    /// Route QoS 1 and 2 publishes to this topic instead, with their original
+    /// topic in an `original-topic` user property. QoS 0 publishes are dropped
+    DeadLetter(String),
+}
+
+/// Deduplication of publishes by an id set by producers in a user property
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct DedupSettings {
+    /// Key of the user property holding the id
+    pub property: String,
+    /// Number of most recent ids remembered per filter, at least 1
+    pub window: usize,
+    /// Filters which are deduplicated
+    pub filters: Vec<Filter>,
+}
+
+/// Segment limits of a set of filters, e.g. to keep more of durable device
+/// data than of short-lived actions
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FilterStorage {
+    pub max_segment_size: usize,
+    pub max_segment_count: usize,
+    /// Filters which use these limits
+    pub filters: Vec<Filter>,
+}
+
+/// Replaces `prefix` at the start of a published topic with `replacement`, e.g.
+/// to move the topics of a tenant under its own namespace
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct TopicRewrite {
+    pub prefix: String,
+    pub replacement: String,
+}
+
+impl TopicRewrite {
+    /// Rewritten `topic`, if it starts with `prefix`
+    pub fn apply(&self, topic: &str) -> Option<String> {
+        let rest = topic.strip_prefix(&self.prefix)?;
+        Some(self.replacement.clone() + rest)
+    }
+}
+
+/// Changes to apply to the [`RouterConfig`] of a running router. Fields left as
+/// `None` are kept as they are. `Some(None)` removes an optional limit
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RouterConfigPatch {
+    pub max_read_len: Option<u64>,
+    pub initial_read_len: Option<Option<u64>>,
+    pub max_consumer_lag: Option<Option<u64>>,
+    pub max_total_inflight_bytes: Option<Option<usize>>,
+    pub shed_qos0_lag: Option<Option<u64>>,
+    /// Can't be changed at runtime as existing segments are sized with it
+    pub max_segment_size: Option<usize>,
+    /// Can't be changed at runtime as existing logs retain segments with it
+    pub max_segment_count: Option<usize>,
 }
 
 type ReloadHandle = Handle<EnvFilter, Layered<Layer<Registry, Pretty, Format<Pretty>>, Registry>>;
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::protocol::{ConnectReturnCode, Login, MqttVersion};
+
+/// Credentials presented by a client while connecting
+#[derive(Debug, Clone)]
+pub struct AuthRequest {
+    pub client_id: String,
+    pub login: Option<Login>,
+    /// MQTT 5 enhanced authentication method
+    pub authentication_method: Option<String>,
+    /// MQTT 5 enhanced authentication data
+    pub authentication_data: Option<Bytes>,
+}
+
+/// Decision of an [`AuthHook`] for a connecting client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthResult {
+    /// Let the connection through to the router
+    Accept,
+    /// Reject the connection with given reason
+    Refuse(ConnectReturnCode),
+}
+
+/// Authenticates clients before the router acknowledges their connection
+pub trait AuthHook: Send + Sync {
+    fn authenticate(&self, connect: &AuthRequest) -> AuthResult;
+}
+
+/// Shareable handle to an [`AuthHook`] which can be set on `ConnectionSettings`
+#[derive(Clone)]
+pub struct AuthHandler(pub Arc<dyn AuthHook>);
+
+impl AuthHandler {
+    pub fn new(hook: impl AuthHook + 'static) -> AuthHandler {
+        AuthHandler(Arc::new(hook))
+    }
+}
+
+impl fmt::Debug for AuthHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("AuthHandler")
+    }
+}
+
+/// Asks `hook` whether the connection is let through. Hooks are called on
+/// the blocking thread pool, so they are free to do slow I/O
+pub fn authenticate(hook: &dyn AuthHook, request: &AuthRequest) -> Result<(), ConnectReturnCode> {
+    match hook.authenticate(request) {
+        AuthResult::Accept => Ok(()),
+        AuthResult::Refuse(code) => Err(code),
+    }
+}
+
+/// Maps a refusal to a code the CONNACK of given protocol version can carry
+pub fn refusal_code(code: ConnectReturnCode, version: MqttVersion) -> ConnectReturnCode {
+    use ConnectReturnCode::*;
+
+    match (version, code) {
+        (MqttVersion::V4, BadUserNamePassword | NotAuthorized | ServiceUnavailable) => code,
+        (MqttVersion::V4, BadClientId | ClientIdentifierNotValid) => BadClientId,
+        (MqttVersion::V4, ServerUnavailable | ServerBusy) => ServiceUnavailable,
+        (MqttVersion::V4, _) => NotAuthorized,
+        (MqttVersion::V5, BadClientId) => ClientIdentifierNotValid,
+        (MqttVersion::V5, ServiceUnavailable) => ServerUnavailable,
+        (MqttVersion::V5, Success | RefusedProtocolVersion) => NotAuthorized,
+        (MqttVersion::V5, _) => code,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{authenticate, refusal_code, AuthHook, AuthRequest, AuthResult};
+    use crate::protocol::{ConnectReturnCode, Login, MqttVersion};
+
+    struct Passwords;
+
+    impl AuthHook for Passwords {
+        fn authenticate(&self, connect: &AuthRequest) -> AuthResult {
+            match &connect.login {
+                Some(login) if login.password == "secret" => AuthResult::Accept,
+                _ => AuthResult::Refuse(ConnectReturnCode::BadUserNamePassword),
+            }
+        }
+    }
+
+    fn request(password: Option<&str>) -> AuthRequest {
+        AuthRequest {
+            client_id: "client".to_owned(),
+            login: password.map(|password| Login {
+                username: "user".to_owned(),
+                password: password.to_owned(),
+            }),
+            authentication_method: None,
+            authentication_data: None,
+        }
+    }
+
+    #[test]
+    fn valid_credentials_are_accepted() {
+        let result = authenticate(&Passwords, &request(Some("secret")));
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn refusal_carries_hook_reason() {
+        let result = authenticate(&Passwords, &request(Some("wrong")));
+        assert_eq!(result, Err(ConnectReturnCode::BadUserNamePassword));
+
+        let result = authenticate(&Passwords, &request(None));
+        assert_eq!(result, Err(ConnectReturnCode::BadUserNamePassword));
+    }
+
+    #[test]
+    fn refusal_codes_fit_the_protocol_version() {
+        let code = refusal_code(ConnectReturnCode::Banned, MqttVersion::V4);
+        assert_eq!(code, ConnectReturnCode::NotAuthorized);
+
+        let code = refusal_code(ConnectReturnCode::Banned, MqttVersion::V5);
+        assert_eq!(code, ConnectReturnCode::Banned);
+
+        let code = refusal_code(ConnectReturnCode::ServiceUnavailable, MqttVersion::V5);
+        assert_eq!(code, ConnectReturnCode::ServerUnavailable);
+
+        let code = refusal_code(ConnectReturnCode::Success, MqttVersion::V4);
+        assert_eq!(code, ConnectReturnCode::NotAuthorized);
+    }
+}
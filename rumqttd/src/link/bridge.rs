@@ -30,7 +30,9 @@ use crate::{
         local::{Link, LinkError},
         network::Network,
     },
-    protocol::{self, Connect, Packet, PingReq, Protocol, QoS, RetainForwardRule, Subscribe},
+    protocol::{
+        self, Connect, MqttVersion, Packet, PingReq, Protocol, QoS, RetainForwardRule, Subscribe,
+    },
     router::Event,
     BridgeConfig, ConnectionId, Notification, Transport,
 };
@@ -59,7 +61,17 @@ where
         "Starting bridge with subscription on filter \"{}\"",
         &config.sub_path,
     );
-    let (mut tx, mut rx, _ack) = Link::new(None, &config.name, router_tx, true, None, true, None)?;
+    let (mut tx, mut rx, _ack) = Link::new(
+        None,
+        &config.name,
+        router_tx,
+        true,
+        None,
+        None,
+        true,
+        None,
+        MqttVersion::V4,
+    )?;
 
     'outer: loop {
         let mut network = match network_connect(&config, &config.addr, protocol.clone()).await {
@@ -282,7 +294,7 @@ async fn network_init<P: Protocol>(
     };
 
     let filters = vec![protocol::Filter {
-        path: config.sub_path.clone(),
+        path: config.sub_path.to_string(),
         qos,
         nolocal: false,
         preserve_retain: false,
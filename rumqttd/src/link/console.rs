@@ -1,4 +1,5 @@
 use crate::link::local::{Link, LinkRx};
+use crate::protocol::MqttVersion;
 use crate::router::{Event, Print};
 use crate::{ConnectionId, ConsoleSettings};
 use axum::extract::{Path, State};
@@ -23,8 +24,18 @@ impl ConsoleLink {
     /// Requires the corresponding Router to be running to complete
     pub fn new(config: ConsoleSettings, router_tx: Sender<(ConnectionId, Event)>) -> ConsoleLink {
         let tx = router_tx.clone();
-        let (link_tx, link_rx, _ack) =
-            Link::new(None, "console", tx, true, None, true, None).unwrap();
+        let (link_tx, link_rx, _ack) = Link::new(
+            None,
+            "console",
+            tx,
+            true,
+            None,
+            None,
+            true,
+            None,
+            MqttVersion::V4,
+        )
+        .unwrap();
         let connection_id = link_tx.connection_id;
         ConsoleLink {
             config,
@@ -48,8 +59,19 @@ pub async fn start(console: Arc<ConsoleLink>) {
         .route("/subscriptions/:filter", get(subscriptions_with_filter))
         .route("/waiters/:filter", get(waiters_with_filter))
         .route("/readyqueue", get(readyqueue))
-        .route("/logs", post(logs))
-        .with_state(console);
+        .route("/datalog", get(datalog))
+        .route("/topics", get(topics))
+        .route("/offsets", get(offsets))
+        .route("/commitlog/:filter", get(commitlog_with_filter))
+        .route("/publish/:filter/:segment/:offset", get(publish_at_offset))
+        .route("/tail/:filter/:count", get(tail_with_filter))
+        .route("/connections", get(connections))
+        .route("/logs", post(logs));
+
+    #[cfg(feature = "debug-dump")]
+    let app = app.route("/filterdata/:filter", get(filterdata_with_filter));
+
+    let app = app.with_state(console);
 
     axum::Server::from_tcp(listener)
         .unwrap()
@@ -103,7 +125,9 @@ async fn subscriptions_with_filter(
     Path(filter): Path<String>,
     State(console): State<Arc<ConsoleLink>>,
 ) -> impl IntoResponse {
-    let filter = filter.replace('.', "/");
+    let Ok(filter) = filter.replace('.', "/").parse() else {
+        return Response::builder().status(400).body("".to_owned()).unwrap();
+    };
     let event = Event::PrintStatus(Print::Subscription(filter));
     let message = (console.connection_id, event);
     if console.router_tx.send(message).is_err() {
@@ -117,7 +141,9 @@ async fn waiters_with_filter(
     Path(filter): Path<String>,
     State(console): State<Arc<ConsoleLink>>,
 ) -> impl IntoResponse {
-    let filter = filter.replace('.', "/");
+    let Ok(filter) = filter.replace('.', "/").parse() else {
+        return Response::builder().status(400).body("".to_owned()).unwrap();
+    };
     let event = Event::PrintStatus(Print::Waiters(filter));
     let message = (console.connection_id, event);
     if console.router_tx.send(message).is_err() {
@@ -137,6 +163,111 @@ async fn readyqueue(State(console): State<Arc<ConsoleLink>>) -> impl IntoRespons
     Response::new("OK".to_owned())
 }
 
+async fn datalog(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    let event = Event::PrintStatus(Print::DataLog);
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+async fn topics(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    let event = Event::PrintStatus(Print::Topics);
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+async fn offsets(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    let event = Event::PrintStatus(Print::Offsets);
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+async fn commitlog_with_filter(
+    Path(filter): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> impl IntoResponse {
+    let Ok(filter) = filter.replace('.', "/").parse() else {
+        return Response::builder().status(400).body("".to_owned()).unwrap();
+    };
+    let event = Event::PrintStatus(Print::CommitLog(filter));
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+async fn publish_at_offset(
+    Path((filter, segment, offset)): Path<(String, u64, u64)>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> impl IntoResponse {
+    let Ok(filter) = filter.replace('.', "/").parse() else {
+        return Response::builder().status(400).body("".to_owned()).unwrap();
+    };
+    let event = Event::PrintStatus(Print::Publish(filter, (segment, offset)));
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+async fn tail_with_filter(
+    Path((filter, count)): Path<(String, usize)>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> impl IntoResponse {
+    let Ok(filter) = filter.replace('.', "/").parse() else {
+        return Response::builder().status(400).body("".to_owned()).unwrap();
+    };
+    let event = Event::PrintStatus(Print::Tail(filter, count));
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+async fn connections(State(console): State<Arc<ConsoleLink>>) -> impl IntoResponse {
+    let event = Event::PrintStatus(Print::Connections);
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
+#[cfg(feature = "debug-dump")]
+async fn filterdata_with_filter(
+    Path(filter): Path<String>,
+    State(console): State<Arc<ConsoleLink>>,
+) -> impl IntoResponse {
+    let Ok(filter) = filter.replace('.', "/").parse() else {
+        return Response::builder().status(400).body("".to_owned()).unwrap();
+    };
+    let event = Event::PrintStatus(Print::FilterData(filter));
+    let message = (console.connection_id, event);
+    if console.router_tx.send(message).is_err() {
+        return Response::builder().status(404).body("".to_owned()).unwrap();
+    }
+
+    Response::new("OK".to_owned())
+}
+
 async fn logs(State(console): State<Arc<ConsoleLink>>, data: String) -> impl IntoResponse {
     info!("Reloading tracing filter");
     if let Some(handle) = &console.config.filter_handle {
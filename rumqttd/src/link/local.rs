@@ -1,5 +1,5 @@
 use crate::protocol::{
-    ConnAck, Filter, LastWill, Packet, Publish, QoS, RetainForwardRule, Subscribe,
+    ConnAck, Filter, LastWill, MqttVersion, Packet, Publish, QoS, RetainForwardRule, Subscribe,
 };
 use crate::router::Ack;
 use crate::router::{
@@ -38,14 +38,16 @@ pub enum LinkError {
 pub struct Link;
 
 impl Link {
-    #[allow(clippy::type_complexity)]
+    #[allow(clippy::type_complexity, clippy::too_many_arguments)]
     fn prepare(
         tenant_id: Option<String>,
         client_id: &str,
         clean: bool,
+        session_expiry_interval: Option<u32>,
         last_will: Option<LastWill>,
         dynamic_filters: bool,
         topic_alias_max: u16,
+        version: MqttVersion,
     ) -> (
         Event,
         Arc<Mutex<VecDeque<Packet>>>,
@@ -56,9 +58,11 @@ impl Link {
             tenant_id,
             client_id.to_owned(),
             clean,
+            session_expiry_interval,
             last_will,
             dynamic_filters,
             topic_alias_max,
+            version,
         );
         let incoming = Incoming::new(connection.client_id.to_owned());
         let (outgoing, link_rx) = Outgoing::new(connection.client_id.to_owned());
@@ -74,15 +78,17 @@ impl Link {
         (event, incoming_data_buffer, outgoing_data_buffer, link_rx)
     }
 
-    #[allow(clippy::new_ret_no_self)]
+    #[allow(clippy::new_ret_no_self, clippy::too_many_arguments)]
     pub fn new(
         tenant_id: Option<String>,
         client_id: &str,
         router_tx: Sender<(ConnectionId, Event)>,
         clean: bool,
+        session_expiry_interval: Option<u32>,
         last_will: Option<LastWill>,
         dynamic_filters: bool,
         topic_alias_max: Option<u16>,
+        version: MqttVersion,
     ) -> Result<(LinkTx, LinkRx, Notification), LinkError> {
         // Connect to router
         // Local connections to the router shall have access to all subscriptions
@@ -91,9 +97,11 @@ impl Link {
             tenant_id,
             client_id,
             clean,
+            session_expiry_interval,
             last_will,
             dynamic_filters,
             topic_alias_max.unwrap_or(0),
+            version,
         );
         router_tx.send((0, message))?;
 
@@ -112,14 +120,17 @@ impl Link {
         Ok((tx, rx, notification))
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn init(
         tenant_id: Option<String>,
         client_id: &str,
         router_tx: Sender<(ConnectionId, Event)>,
         clean: bool,
+        session_expiry_interval: Option<u32>,
         last_will: Option<LastWill>,
         dynamic_filters: bool,
         topic_alias_max: Option<u16>,
+        version: MqttVersion,
     ) -> Result<(LinkTx, LinkRx, ConnAck), LinkError> {
         // Connect to router
         // Local connections to the router shall have access to all subscriptions
@@ -128,9 +139,11 @@ impl Link {
             tenant_id,
             client_id,
             clean,
+            session_expiry_interval,
             last_will,
             dynamic_filters,
             topic_alias_max.unwrap_or(0),
+            version,
         );
         router_tx.send_async((0, message)).await?;
 
@@ -266,7 +279,7 @@ impl LinkTx {
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
@@ -282,7 +295,7 @@ impl LinkTx {
             qos: QoS::AtMostOnce,
             nolocal: false,
             preserve_retain: false,
-            retain_forward_rule: RetainForwardRule::Never,
+            retain_forward_rule: RetainForwardRule::OnEverySubscribe,
         }];
 
         let subscribe = Subscribe { pkid: 0, filters };
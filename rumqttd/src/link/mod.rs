@@ -1,4 +1,5 @@
 pub mod alerts;
+pub mod auth;
 pub mod bridge;
 pub mod console;
 pub mod local;
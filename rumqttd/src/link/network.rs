@@ -7,7 +7,7 @@ use std::{
 };
 use tokio::time::{error::Elapsed, Duration};
 
-use crate::protocol::{self, Packet, Protocol};
+use crate::protocol::{self, MqttVersion, Packet, Protocol};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -57,6 +57,10 @@ impl<P: Protocol> Network<P> {
         }
     }
 
+    pub fn version(&self) -> MqttVersion {
+        self.protocol.version()
+    }
+
     pub fn set_keepalive(&mut self, keepalive: u16) {
         let keepalive = Duration::from_secs(keepalive as u64);
         self.keepalive = keepalive + keepalive.mul_f32(0.5);
@@ -120,7 +124,7 @@ impl<P: Protocol> Network<P> {
                     }
                 }
                 Err(protocol::Error::InsufficientBytes(_)) => return Ok(packets.len()),
-                Err(e) => return Err(io::Error::new(ErrorKind::InvalidData, e.to_string()).into()),
+                Err(e) => return Err(e.into()),
             }
         }
     }
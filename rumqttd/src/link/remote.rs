@@ -1,7 +1,10 @@
+use crate::link::auth::{self, AuthRequest};
 use crate::link::local::{Link, LinkError, LinkRx, LinkTx};
 use crate::link::network;
 use crate::link::network::Network;
-use crate::protocol::{Connect, Packet, Protocol};
+use crate::protocol::{
+    ConnAck, Connect, ConnectReturnCode, Disconnect, MqttVersion, Packet, Protocol,
+};
 use crate::router::{Event, Notification};
 use crate::{ConnectionId, ConnectionSettings};
 
@@ -38,6 +41,8 @@ pub enum Error {
     ConnectionAck(String),
     #[error("Authentication error")]
     InvalidAuth,
+    #[error("Authentication refused = {0:?}")]
+    AuthRefused(ConnectReturnCode),
     #[error("Channel try send error")]
     TrySend(#[from] TrySendError<(ConnectionId, Event)>),
     #[error("Link error = {0}")]
@@ -87,7 +92,7 @@ impl<P: Protocol> RemoteLink<P> {
         if let Some(auths) = &config.auth {
             // if authentication is configured and connect packet doesn't have login details return
             // an error
-            if let Some(login) = login {
+            if let Some(login) = &login {
                 let is_authenticated = auths
                     .iter()
                     .any(|(user, pass)| (user, pass) == (&login.username, &login.password));
@@ -100,6 +105,33 @@ impl<P: Protocol> RemoteLink<P> {
             }
         }
 
+        if let Some(hook) = &config.auth_hook {
+            let request = AuthRequest {
+                client_id: connect.client_id.clone(),
+                login,
+                authentication_method: props.as_ref().and_then(|p| p.authentication_method.clone()),
+                authentication_data: props.as_ref().and_then(|p| p.authentication_data.clone()),
+            };
+
+            // Hooks may block on external services, keep them off the runtime's workers
+            let hook = hook.0.clone();
+            let result =
+                tokio::task::spawn_blocking(move || auth::authenticate(hook.as_ref(), &request))
+                    .await
+                    .unwrap_or(Err(ConnectReturnCode::ServiceUnavailable));
+
+            if let Err(code) = result {
+                let code = auth::refusal_code(code, network.version());
+                let ack = ConnAck {
+                    session_present: false,
+                    code,
+                };
+
+                network.write(Packet::ConnAck(ack, None)).await?;
+                return Err(Error::AuthRefused(code));
+            }
+        }
+
         // When keep_alive feature is disabled client can live forever, which is not good in
         // distributed broker context so currenlty we don't allow it.
         if connect.keep_alive == 0 {
@@ -119,6 +151,17 @@ impl<P: Protocol> RemoteLink<P> {
             return Err(Error::InvalidClientId);
         }
 
+        // Session Expiry Interval of MQTT 5 is 0 when absent. MQTT 3.1.1
+        // sessions don't expire
+        let session_expiry_interval = match network.version() {
+            MqttVersion::V5 => Some(
+                props
+                    .as_ref()
+                    .and_then(|p| p.session_expiry_interval)
+                    .unwrap_or(0),
+            ),
+            MqttVersion::V4 => None,
+        };
         let topic_alias_max = props.and_then(|p| p.topic_alias_max);
 
         let (link_tx, link_rx, notification) = Link::new(
@@ -126,9 +169,11 @@ impl<P: Protocol> RemoteLink<P> {
             &client_id,
             router_tx,
             clean_session,
+            session_expiry_interval,
             lastwill,
             dynamic_filters,
             topic_alias_max,
+            network.version(),
         )?;
 
         let id = link_rx.id();
@@ -149,6 +194,25 @@ impl<P: Protocol> RemoteLink<P> {
         })
     }
 
+    /// Tells the client why it's disconnected when its packet couldn't be
+    /// parsed. Other network errors leave nothing to write to
+    async fn disconnect_malformed(&mut self, error: network::Error) -> Error {
+        if let network::Error::Protocol(e) = &error {
+            let disconnect = Disconnect {
+                reason_code: e.disconnect_reason(),
+            };
+            if let Err(e) = self
+                .network
+                .write(Packet::Disconnect(disconnect, None))
+                .await
+            {
+                trace!(error = ?e, "Failed to send disconnect");
+            }
+        }
+
+        error.into()
+    }
+
     pub async fn start(&mut self) -> Result<(), Error> {
         self.network.set_keepalive(self.connect.keep_alive);
 
@@ -157,12 +221,18 @@ impl<P: Protocol> RemoteLink<P> {
         loop {
             select! {
                 o = self.network.read() => {
-                    let packet = o?;
-                    let len = {
+                    let packet = match o {
+                        Ok(packet) => packet,
+                        Err(e) => return Err(self.disconnect_malformed(e).await),
+                    };
+                    let read = {
                         let mut buffer = self.link_tx.buffer();
                         buffer.push_back(packet);
-                        self.network.readv(&mut buffer)?;
-                        buffer.len()
+                        self.network.readv(&mut buffer).map(|_| buffer.len())
+                    };
+                    let len = match read {
+                        Ok(len) => len,
+                        Err(e) => return Err(self.disconnect_malformed(e).await),
                     };
 
                     trace!("Packets read from network, count = {}", len);
@@ -1,5 +1,6 @@
 use crate::link::local::{LinkError, LinkRx, LinkTx};
 use crate::local::Link;
+use crate::protocol::MqttVersion;
 use crate::router::{Event, Notification};
 use crate::{ConnectionId, ConnectionSettings, Filter};
 use bytes::Bytes;
@@ -77,8 +78,10 @@ impl ShadowLink {
             router_tx,
             true,
             None,
+            None,
             config.dynamic_filters,
             None,
+            MqttVersion::V4,
         )?;
         let connection_id = link_rx.id();
 
@@ -158,7 +161,7 @@ impl ShadowLink {
                 }
                 _ = interval.tick() => {
                     for filter in self.subscriptions.iter() {
-                        self.link_tx.shadow(filter)?;
+                        self.link_tx.shadow(filter.as_str())?;
                     }
                 }
                 _ = ping.tick() => {
@@ -181,9 +184,9 @@ impl ShadowLink {
     async fn extract_message(&mut self, message: &str) -> Result<(), Error> {
         match serde_json::from_str(message)? {
             Incoming::Shadow { filter } => match validate_shadow(&self.client_id, &filter) {
-                Ok(_) => {
-                    self.subscriptions.insert(filter.clone());
-                    self.link_tx.try_subscribe(filter)?;
+                Ok(filter) => {
+                    self.link_tx.try_subscribe(filter.as_str())?;
+                    self.subscriptions.insert(filter);
                 }
                 Err(e) => {
                     error!(?e, "validation error");
@@ -208,12 +211,12 @@ impl ShadowLink {
 /// Validates that the fields `tenant_id` and `device_id` are the same as that of device connected
 /// for a topic filter of format "/tenant/tenant_id/device/device_id/..."
 /// Note: Tenant not checked, but could be, in the future
-fn validate_shadow(client_id: &String, filter: &str) -> Result<(), Error> {
+fn validate_shadow(client_id: &String, filter: &str) -> Result<Filter, Error> {
     let tokens: Vec<&str> = filter.split('/').collect();
     let id = tokens.get(4).ok_or(Error::InvalidFilter)?.to_owned();
 
     match id == client_id {
-        true => Ok(()),
+        true => filter.parse().map_err(|_| Error::InvalidFilter),
         false => Err(Error::InvalidClientId),
     }
 }
@@ -247,6 +247,7 @@ impl Publish {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "debug-dump", derive(serde::Serialize))]
 pub struct PublishProperties {
     pub payload_format_indicator: Option<u8>,
     pub message_expiry_interval: Option<u32>,
@@ -620,36 +621,82 @@ pub fn valid_topic(topic: &str) -> bool {
 ///
 /// <https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106>
 pub fn valid_filter(filter: &str) -> bool {
-    if filter.is_empty() {
-        return false;
+    validate_filter(filter).is_ok()
+}
+
+/// Error returned when a topic name or filter is malformed or contains
+/// characters which are not allowed on ingest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum TopicError {
+    #[error("Null character at index {0}")]
+    NullCharacter(usize),
+    #[error("Control character {0:?} at index {1}")]
+    ControlCharacter(char, usize),
+    #[error("Empty topic")]
+    Empty,
+    #[error("Wildcard in topic name at index {0}")]
+    WildcardInTopic(usize),
+    #[error("Wildcard not occupying the whole level {0} of filter")]
+    PartialWildcard(usize),
+    #[error("Multi-level wildcard at level {0} isn't the last level of filter")]
+    MultiLevelWildcardNotLast(usize),
+}
+
+/// Checks that a topic name or filter doesn't contain U+0000, which MQTT
+/// forbids in all UTF-8 encoded strings. When `reject_control_chars` is set,
+/// the other control characters (U+0001..U+001F and U+007F..U+009F), which
+/// the spec says should not be used, are rejected as well.
+///
+/// <https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901010>
+pub fn validate_topic_chars(s: &str, reject_control_chars: bool) -> Result<(), TopicError> {
+    for (index, c) in s.char_indices() {
+        if c == '\0' {
+            return Err(TopicError::NullCharacter(index));
+        }
+
+        if reject_control_chars && c.is_control() {
+            return Err(TopicError::ControlCharacter(c, index));
+        }
     }
 
-    let hirerarchy = filter.split('/').collect::<Vec<&str>>();
-    if let Some((last, remaining)) = hirerarchy.split_last() {
-        for entry in remaining.iter() {
-            // # is not allowed in filter except as a last entry
-            // invalid: sport/tennis#/player
-            // invalid: sport/tennis/#/ranking
-            if entry.contains('#') {
-                return false;
-            }
+    Ok(())
+}
 
-            // + must occupy an entire level of the filter
-            // invalid: sport+
-            if entry.len() > 1 && entry.contains('+') {
-                return false;
-            }
+/// Checks a topic name. See [`crate::Topic`]
+pub fn validate_topic(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    if let Some(index) = topic.find(['+', '#']) {
+        return Err(TopicError::WildcardInTopic(index));
+    }
+
+    validate_topic_chars(topic, false)
+}
+
+/// Checks a topic filter. See [`crate::Filter`]
+///
+/// <https://docs.oasis-open.org/mqtt/mqtt/v3.1.1/os/mqtt-v3.1.1-os.html#_Toc398718106>
+pub fn validate_filter(filter: &str) -> Result<(), TopicError> {
+    if filter.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    let levels = filter.split('/').count();
+    for (level, entry) in filter.split('/').enumerate() {
+        // invalid: sport+, sport/tennis#, sport/++
+        if entry.len() > 1 && (entry.contains('+') || entry.contains('#')) {
+            return Err(TopicError::PartialWildcard(level));
         }
 
-        // only single '#" or '+' is allowed in last entry
-        // invalid: sport/tennis#
-        // invalid: sport/++
-        if last.len() != 1 && (last.contains('#') || last.contains('+')) {
-            return false;
+        // invalid: sport/#/ranking
+        if entry == "#" && level != levels - 1 {
+            return Err(TopicError::MultiLevelWildcardNotLast(level));
         }
     }
 
-    true
+    validate_topic_chars(filter, false)
 }
 
 /// Checks if topic matches a filter. topic and filter validation isn't done here.
@@ -658,7 +705,9 @@ pub fn valid_filter(filter: &str) -> bool {
 /// **NOTE**: make sure a topic is validated during a publish and filter is validated
 /// during a subscribe
 pub fn matches(topic: &str, filter: &str) -> bool {
-    if !topic.is_empty() && topic[..1].contains('$') {
+    // Wildcards at the first level don't match topics starting with '$' (e.g.
+    // $SYS/..), filters spelling out the '$' level do
+    if topic.starts_with('$') && filter.starts_with(['+', '#']) {
         return false;
     }
 
@@ -748,7 +797,200 @@ pub enum Error {
     InsufficientBytes(usize),
 }
 
+impl Error {
+    /// Reason code of the DISCONNECT sent to a client whose packet failed
+    /// with this error
+    pub fn disconnect_reason(&self) -> DisconnectReasonCode {
+        match self {
+            Error::InvalidProtocol
+            | Error::InvalidProtocolLevel(_)
+            | Error::PacketIdZero
+            | Error::EmptySubscription
+            | Error::SubscriptionIdZero
+            | Error::PayloadRequired => DisconnectReasonCode::ProtocolError,
+            Error::PayloadTooLong | Error::PayloadSizeLimitExceeded(_) => {
+                DisconnectReasonCode::PacketTooLarge
+            }
+            Error::InvalidConnectReturnCode(_)
+            | Error::InvalidReason(_)
+            | Error::InvalidRemainingLength(_)
+            | Error::IncorrectPacketFormat
+            | Error::InvalidPacketType(_)
+            | Error::InvalidRetainForwardRule(_)
+            | Error::InvalidQoS(_)
+            | Error::InvalidSubscribeReasonCode(_)
+            | Error::PayloadSizeIncorrect
+            | Error::PayloadNotUtf8(_)
+            | Error::TopicNotUtf8
+            | Error::BoundaryCrossed(_)
+            | Error::MalformedPacket
+            | Error::MalformedRemainingLength
+            | Error::InvalidPropertyType(_)
+            | Error::InsufficientBytes(_) => DisconnectReasonCode::MalformedPacket,
+        }
+    }
+}
+
+/// MQTT version spoken by a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttVersion {
+    V4,
+    V5,
+}
+
 pub trait Protocol {
     fn read_mut(&mut self, stream: &mut BytesMut, max_size: usize) -> Result<Packet, Error>;
     fn write(&self, packet: Packet, write: &mut BytesMut) -> Result<usize, Error>;
+    fn version(&self) -> MqttVersion;
+}
+
+#[cfg(test)]
+mod test {
+    use super::{matches, validate_topic_chars, DisconnectReasonCode, Error, TopicError};
+
+    #[test]
+    fn topic_with_null_character_is_rejected() {
+        assert_eq!(
+            validate_topic_chars("hello/\0/world", false),
+            Err(TopicError::NullCharacter(6))
+        );
+    }
+
+    #[test]
+    fn control_characters_are_rejected_only_when_configured() {
+        assert!(validate_topic_chars("hello/\tworld", false).is_ok());
+        assert_eq!(
+            validate_topic_chars("hello/\tworld", true),
+            Err(TopicError::ControlCharacter('\t', 6))
+        );
+    }
+
+    #[test]
+    fn valid_topics_and_filters_are_accepted() {
+        assert!(validate_topic_chars("hello/world/ünïcödé", true).is_ok());
+        assert!(validate_topic_chars("hello/+/world/#", true).is_ok());
+    }
+
+    #[test]
+    fn wildcards_at_root_match_their_levels() {
+        let cases = [
+            ("+", "a", true),
+            ("+", "a/b", false),
+            ("+", "/", false),
+            ("+/+", "/", true),
+            ("+/#", "a", true),
+            ("+/#", "a/b", true),
+            ("+/#", "a/b/c", true),
+            ("+/#", "/", true),
+            ("#", "a", true),
+            ("#", "a/b/c", true),
+            ("#", "/", true),
+            ("#", "$SYS", false),
+            ("#", "$SYS/uptime", false),
+            ("+", "$SYS", false),
+            ("+/#", "$SYS/uptime", false),
+            ("+/uptime", "$SYS/uptime", false),
+            ("$SYS/#", "$SYS/uptime", true),
+            ("$SYS/+", "$SYS/uptime", true),
+            ("$SYS/uptime", "$SYS/uptime", true),
+            ("$SYS/#", "SYS/uptime", false),
+            ("a/$SYS", "a/$SYS", true),
+            ("a/+", "a/$SYS", true),
+            ("a/#", "a/$SYS", true),
+        ];
+
+        for (filter, topic, expected) in cases {
+            assert_eq!(matches(topic, filter), expected, "{topic:?} vs {filter:?}");
+        }
+    }
+
+    #[test]
+    fn parse_errors_map_to_disconnect_reasons() {
+        let invalid = vec![0xff];
+        let utf8 = std::str::from_utf8(&invalid).unwrap_err();
+        let cases = [
+            (
+                Error::InvalidConnectReturnCode(9),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::InvalidReason(9),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::InvalidRemainingLength(9),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (Error::InvalidProtocol, DisconnectReasonCode::ProtocolError),
+            (
+                Error::InvalidProtocolLevel(9),
+                DisconnectReasonCode::ProtocolError,
+            ),
+            (
+                Error::IncorrectPacketFormat,
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::InvalidPacketType(0),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::InvalidRetainForwardRule(3),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (Error::InvalidQoS(3), DisconnectReasonCode::MalformedPacket),
+            (
+                Error::InvalidSubscribeReasonCode(9),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (Error::PacketIdZero, DisconnectReasonCode::ProtocolError),
+            (
+                Error::EmptySubscription,
+                DisconnectReasonCode::ProtocolError,
+            ),
+            (
+                Error::SubscriptionIdZero,
+                DisconnectReasonCode::ProtocolError,
+            ),
+            (
+                Error::PayloadSizeIncorrect,
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (Error::PayloadTooLong, DisconnectReasonCode::PacketTooLarge),
+            (
+                Error::PayloadSizeLimitExceeded(9),
+                DisconnectReasonCode::PacketTooLarge,
+            ),
+            (Error::PayloadRequired, DisconnectReasonCode::ProtocolError),
+            (
+                Error::PayloadNotUtf8(utf8),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (Error::TopicNotUtf8, DisconnectReasonCode::MalformedPacket),
+            (
+                Error::BoundaryCrossed(9),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::MalformedPacket,
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::MalformedRemainingLength,
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::InvalidPropertyType(0),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+            (
+                Error::InsufficientBytes(9),
+                DisconnectReasonCode::MalformedPacket,
+            ),
+        ];
+
+        for (error, reason) in cases {
+            assert_eq!(error.disconnect_reason(), reason, "{error:?}");
+        }
+    }
 }
@@ -352,7 +352,9 @@ impl Protocol for V4 {
             Packet::Publish(publish, None) => publish::write(&publish, buffer)?,
             Packet::PubAck(puback, None) => puback::write(&puback, buffer)?,
             Packet::Subscribe(subscribe, None) => subscribe::write(&subscribe, buffer)?,
-            Packet::SubAck(suback, None) => suback::write(&suback, buffer)?,
+            // SubAckProperties only carry a reason string for refused filters,
+            // which v4 has no way to express
+            Packet::SubAck(suback, _) => suback::write(&suback, buffer)?,
             Packet::PubRec(pubrec, None) => pubrec::write(&pubrec, buffer)?,
             Packet::PubRel(pubrel, None) => pubrel::write(&pubrel, buffer)?,
             Packet::PubComp(pubcomp, None) => pubcomp::write(&pubcomp, buffer)?,
@@ -367,4 +369,8 @@ impl Protocol for V4 {
         };
         Ok(size)
     }
+
+    fn version(&self) -> MqttVersion {
+        MqttVersion::V4
+    }
 }
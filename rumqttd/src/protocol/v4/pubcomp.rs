@@ -10,6 +10,9 @@ pub fn read(fixed_header: FixedHeader, mut bytes: Bytes) -> Result<PubComp, Erro
     let variable_header_index = fixed_header.fixed_header_len;
     bytes.advance(variable_header_index);
     let pkid = read_u16(&mut bytes)?;
+    if pkid == 0 {
+        return Err(Error::MalformedPacket);
+    }
 
     if fixed_header.remaining_len == 2 {
         return Ok(PubComp {
@@ -61,7 +61,8 @@ mod filter {
                 qos: qos(requested_qos).ok_or(Error::InvalidQoS(requested_qos))?,
                 nolocal: false,
                 preserve_retain: false,
-                retain_forward_rule: RetainForwardRule::Never,
+                // Retained messages are sent on every subscribe in v3.1.1
+                retain_forward_rule: RetainForwardRule::OnEverySubscribe,
             });
         }
 
@@ -55,7 +55,10 @@ pub fn read(
     let disconnect = Disconnect {
         reason_code: reason(reason_code)?,
     };
-    let properties = properties::read(&mut bytes)?;
+    let properties = match fixed_header.remaining_len {
+        1 => None,
+        _ => properties::read(&mut bytes)?,
+    };
 
     Ok((disconnect, properties))
 }
@@ -78,10 +81,9 @@ pub fn write(
 
     buffer.put_u8(code(disconnect.reason_code));
 
+    // Property length is omitted when there are no properties
     if let Some(properties) = &properties {
         properties::write(properties, buffer)?;
-    } else {
-        write_remaining_length(buffer, 0)?;
     }
 
     Ok(1 + len_len + length)
@@ -295,6 +297,25 @@ mod test {
 
         assert_eq!(&buffer[..], &expected);
     }
+
+    #[test]
+    fn reason_without_properties_round_trip() {
+        let mut buffer = BytesMut::new();
+        let disconnect = Disconnect {
+            reason_code: DisconnectReasonCode::ServerShuttingDown,
+        };
+
+        let written = write(&disconnect, &None, &mut buffer).unwrap();
+        assert_eq!(&buffer[..], &[0xE0, 0x01, 0x8B]);
+        assert_eq!(written, buffer.len());
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let disconnect_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        assert_eq!(
+            read(fixed_header, disconnect_bytes).unwrap(),
+            (disconnect, None)
+        );
+    }
 }
 
 fn reason(code: u8) -> Result<DisconnectReasonCode, Error> {
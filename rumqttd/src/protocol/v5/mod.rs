@@ -461,4 +461,8 @@ impl Protocol for V5 {
         };
         Ok(size)
     }
+
+    fn version(&self) -> MqttVersion {
+        MqttVersion::V5
+    }
 }
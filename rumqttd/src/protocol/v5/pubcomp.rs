@@ -29,6 +29,9 @@ pub fn read(
     let variable_header_index = fixed_header.fixed_header_len;
     bytes.advance(variable_header_index);
     let pkid = read_u16(&mut bytes)?;
+    if pkid == 0 {
+        return Err(Error::MalformedPacket);
+    }
 
     if fixed_header.remaining_len == 2 {
         return Ok((
@@ -175,3 +178,28 @@ fn code(reason: PubCompReason) -> u8 {
         PubCompReason::PacketIdentifierNotFound => 146,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BytesMut;
+
+    #[test]
+    fn pubcomp_with_zero_pkid_is_rejected() {
+        let mut buffer = BytesMut::new();
+        buffer.extend_from_slice(&[0x70, 0x02, 0x00, 0x00]);
+
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let pubcomp_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        assert!(matches!(
+            read(fixed_header, pubcomp_bytes),
+            Err(Error::MalformedPacket)
+        ));
+
+        buffer.extend_from_slice(&[0x70, 0x02, 0x00, 0x01]);
+        let fixed_header = parse_fixed_header(buffer.iter()).unwrap();
+        let pubcomp_bytes = buffer.split_to(fixed_header.frame_length()).freeze();
+        let (pubcomp, _) = read(fixed_header, pubcomp_bytes).unwrap();
+        assert_eq!(pubcomp.pkid, 1);
+    }
+}
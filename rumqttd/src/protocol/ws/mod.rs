@@ -58,4 +58,8 @@ impl Protocol for Ws {
     fn write(&self, packet: Packet, write: &mut bytes::BytesMut) -> Result<usize, super::Error> {
         todo!()
     }
+
+    fn version(&self) -> super::MqttVersion {
+        super::MqttVersion::V4
+    }
 }
@@ -1,7 +1,10 @@
 use slab::Slab;
 
 use crate::Filter;
-use crate::{protocol::LastWill, Topic};
+use crate::{
+    protocol::{LastWill, MqttVersion},
+    Topic,
+};
 use std::collections::{HashMap, HashSet};
 
 use super::ConnectionEvents;
@@ -18,6 +21,9 @@ pub struct Connection {
     pub dynamic_filters: bool,
     /// Clean session
     pub clean: bool,
+    /// Seconds the session is kept after disconnection. `None` keeps a
+    /// persistent session until the client connects again
+    pub session_expiry_interval: Option<u32>,
     /// Subscriptions
     pub subscriptions: HashSet<Filter>,
     /// Last will of this connection
@@ -28,17 +34,23 @@ pub struct Connection {
     pub(crate) topic_aliases: HashMap<u16, Topic>,
     /// Topic aliases used by broker
     pub(crate) broker_topic_aliases: Option<BrokerAliases>,
+    /// Limits only MQTT 5 clients are told about in CONNACK are enforced
+    /// only for them
+    pub(crate) version: MqttVersion,
 }
 
 impl Connection {
     /// Create connection state to hold identifying information of connecting device
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         tenant_id: Option<String>,
         client_id: String,
         clean: bool,
+        session_expiry_interval: Option<u32>,
         last_will: Option<LastWill>,
         dynamic_filters: bool,
         topic_alias_max: u16,
+        version: MqttVersion,
     ) -> Connection {
         // Change client id to -> tenant_id.client_id and derive topic path prefix
         // to validate topics
@@ -63,18 +75,20 @@ impl Connection {
             tenant_prefix,
             dynamic_filters,
             clean,
+            session_expiry_interval,
             subscriptions: HashSet::default(),
             last_will,
             events: ConnectionEvents::default(),
             topic_aliases: HashMap::new(),
             broker_topic_aliases,
+            version,
         }
     }
 }
 
 #[derive(Debug)]
 pub(crate) struct BrokerAliases {
-    pub(crate) broker_topic_aliases: HashMap<Filter, u16>,
+    pub(crate) broker_topic_aliases: HashMap<Topic, u16>,
     pub(crate) used_aliases: Slab<()>,
     pub(crate) topic_alias_max: u16,
 }
@@ -120,7 +134,7 @@ impl BrokerAliases {
 
         let alias_to_use = alias_to_use as u16;
         self.broker_topic_aliases
-            .insert(topic.to_owned(), alias_to_use);
+            .insert(Topic::new_unchecked(topic), alias_to_use);
         Some(alias_to_use)
     }
 }
@@ -0,0 +1,33 @@
+use super::{DataRequest, DeliveryOrder, FilterIdx};
+use crate::{Offset, RouterConfig};
+
+/// Router config with small segments, which tests extend with the settings
+/// they exercise
+pub fn config() -> RouterConfig {
+    RouterConfig {
+        instant_ack: true,
+        max_segment_size: 1024,
+        max_connections: 10,
+        max_segment_count: 10,
+        max_read_len: 1024,
+        initialized_filters: None,
+        ..Default::default()
+    }
+}
+
+/// QoS 0 request reading `filter` from `cursor`
+pub fn request(filter: &str, filter_idx: FilterIdx, cursor: Offset) -> DataRequest {
+    DataRequest {
+        filter: filter.parse().unwrap(),
+        filter_idx,
+        qos: 0,
+        preserve_retain: false,
+        cursor,
+        read_ahead: 0,
+        read_count: 0,
+        max_count: 100,
+        delivery_order: DeliveryOrder::OldestFirst,
+        backlog: None,
+        live_marker: false,
+    }
+}
@@ -1,53 +1,132 @@
 use std::collections::{HashMap, HashSet};
+use std::time::Instant;
 
 use super::{
+    logs::AckLogState,
     scheduler::{PauseReason, Tracker},
-    ConnectionEvents,
+    ConnectionEvents, DataRequest, FilterIdx,
 };
+use crate::Filter;
 
 pub struct Graveyard {
     connections: HashMap<String, SavedState>,
+    /// Earliest expiry among saved sessions
+    next_expiry: Option<Instant>,
 }
 
 impl Graveyard {
     pub fn new() -> Graveyard {
         Graveyard {
             connections: HashMap::new(),
+            next_expiry: None,
         }
     }
 
     /// Add a new connection.
     /// Return tracker of previous connection if connection id already exists
+    /// Expired sessions are dropped instead of being returned
     pub fn retrieve(&mut self, id: &str) -> Option<SavedState> {
-        self.connections.remove(id)
+        let saved = self.connections.remove(id)?;
+        match saved.expires_at {
+            Some(expires_at) if expires_at <= Instant::now() => None,
+            _ => Some(saved),
+        }
     }
 
-    /// Save connection tracker
+    /// Save connection tracker. A session with `expires_at` is purged by
+    /// `purge_expired` once the deadline passes
     pub fn save(
         &mut self,
         mut tracker: Tracker,
-        subscriptions: HashSet<String>,
+        subscriptions: HashSet<Filter>,
         metrics: ConnectionEvents,
+        acks: AckLogState,
+        expires_at: Option<Instant>,
     ) {
         tracker.pause(PauseReason::Busy);
         let id = tracker.id.clone();
 
+        if let Some(expires_at) = expires_at {
+            self.next_expiry = Some(self.next_expiry.map_or(expires_at, |t| t.min(expires_at)));
+        }
+
         self.connections.insert(
             id,
             SavedState {
                 tracker,
                 subscriptions,
                 metrics,
+                acks,
+                expires_at,
             },
         );
     }
+
+    /// Earliest expiry among saved sessions
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.next_expiry
+    }
+
+    /// Drops sessions which expired by `now` along with their data requests
+    /// and returns their client ids
+    pub fn purge_expired(&mut self, now: Instant) -> Vec<String> {
+        match self.next_expiry {
+            Some(next_expiry) if next_expiry <= now => {}
+            _ => return Vec::new(),
+        }
+
+        let mut purged = Vec::new();
+        self.connections.retain(|id, saved| match saved.expires_at {
+            Some(expires_at) if expires_at <= now => {
+                purged.push(id.clone());
+                false
+            }
+            _ => true,
+        });
+
+        self.next_expiry = self
+            .connections
+            .values()
+            .filter_map(|saved| saved.expires_at)
+            .min();
+
+        purged
+    }
+
+    /// Whether a saved session is subscribed to `filter`
+    pub fn is_subscribed(&self, filter: &str) -> bool {
+        self.connections
+            .values()
+            .any(|saved| saved.subscriptions.contains(filter))
+    }
+
+    /// Data requests of all the saved sessions
+    pub fn data_requests(&self) -> impl Iterator<Item = &DataRequest> {
+        self.connections
+            .values()
+            .flat_map(|saved| saved.tracker.get_data_requests())
+    }
+
+    /// Points data requests of saved sessions at the indexes their filters were
+    /// moved to by `DataLog::compact_native`
+    pub fn remap_filters(&mut self, remap: &HashMap<FilterIdx, FilterIdx>) {
+        for saved in self.connections.values_mut() {
+            for request in saved.tracker.data_requests.iter_mut() {
+                request.remap_filter(remap);
+            }
+        }
+    }
 }
 
 #[derive(Debug)]
 pub struct SavedState {
     pub tracker: Tracker,
-    pub subscriptions: HashSet<String>,
+    pub subscriptions: HashSet<Filter>,
     pub metrics: ConnectionEvents,
+    /// Unfinished QoS 2 handshakes of the session
+    pub acks: AckLogState,
+    /// Instant after which the session is purged
+    pub expires_at: Option<Instant>,
 }
 
 impl SavedState {
@@ -56,6 +135,8 @@ impl SavedState {
             tracker: Tracker::new(client_id),
             subscriptions: HashSet::new(),
             metrics: ConnectionEvents::default(),
+            acks: AckLogState::default(),
+            expires_at: None,
         }
     }
 }
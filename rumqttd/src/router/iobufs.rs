@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use flume::{Receiver, Sender};
@@ -58,8 +59,15 @@ pub struct Outgoing {
     pub(crate) data_buffer: Arc<Mutex<VecDeque<Notification>>>,
     /// Handle which is given to router to allow router to communicate with this connection
     pub(crate) handle: Sender<()>,
-    /// The buffer to keep track of inflight packets.
-    inflight_buffer: VecDeque<(u16, FilterIdx, Cursor)>,
+    /// The buffer to keep track of inflight packets along with their size.
+    inflight_buffer: VecDeque<(u16, FilterIdx, Cursor, usize)>,
+    /// Total size of inflight packets
+    inflight_bytes: usize,
+    /// Publishes of `inflight_buffer`, in the same order, along with the time
+    /// at which they were last sent. Only kept when retransmission is enabled
+    unacked: VecDeque<(Instant, Forward)>,
+    /// Whether unacked publishes are kept for retransmission
+    retransmit: bool,
     /// Last packet id
     last_pkid: u16,
     /// Metrics of outgoing messages of this connection
@@ -81,6 +89,9 @@ impl Outgoing {
             client_id,
             data_buffer: Arc::new(Mutex::new(data_buffer)),
             inflight_buffer,
+            inflight_bytes: 0,
+            unacked: VecDeque::new(),
+            retransmit: false,
             handle,
             last_pkid: 0,
             meter: Default::default(),
@@ -98,6 +109,24 @@ impl Outgoing {
         MAX_INFLIGHT - self.inflight_buffer.len()
     }
 
+    /// Number of publishes sent to the client which aren't acked yet
+    pub fn inflight(&self) -> usize {
+        self.inflight_buffer.len()
+    }
+
+    pub fn inflight_bytes(&self) -> usize {
+        self.inflight_bytes
+    }
+
+    /// Keep sent publishes until they are acked, so that they can be sent
+    /// again by `due_for_retransmit`
+    pub(crate) fn set_retransmit(&mut self, retransmit: bool) {
+        self.retransmit = retransmit;
+        if retransmit {
+            self.unacked.reserve(MAX_INFLIGHT);
+        }
+    }
+
     pub fn push_notification(&mut self, notification: Notification) -> usize {
         let mut buffer = self.data_buffer.lock();
         buffer.push_back(notification);
@@ -127,13 +156,19 @@ impl Outgoing {
             return (buffer_count, inflight_count);
         }
 
+        let now = Instant::now();
         for mut p in publishes {
             // Index and pkid of current outgoing packet
             self.last_pkid += 1;
             p.publish.pkid = self.last_pkid;
 
+            let size = p.publish.topic.len() + p.publish.payload.len();
             self.inflight_buffer
-                .push_back((self.last_pkid, filter_idx, p.cursor));
+                .push_back((self.last_pkid, filter_idx, p.cursor, size));
+            self.inflight_bytes += size;
+            if self.retransmit {
+                self.unacked.push_back((now, p.clone()));
+            }
 
             // Place max pkid packet at index 0
             if self.last_pkid == MAX_PKID {
@@ -141,7 +176,7 @@ impl Outgoing {
             }
 
             self.meter.publish_count += 1;
-            self.meter.total_size += p.publish.topic.len() + p.publish.payload.len();
+            self.meter.total_size += size;
             buffer.push_back(Notification::Forward(p));
         }
 
@@ -158,13 +193,14 @@ impl Outgoing {
         (buffer_count, inflight_count)
     }
 
-    // Returns (unsolicited, outoforder) flags
-    // Return: Out of order or unsolicited acks
-    pub fn register_ack(&mut self, pkid: u16) -> Option<()> {
-        let (head, _filter_idx, _cursor) = match self.inflight_buffer.pop_front() {
+    // Returns size of the acked publish
+    // Return: None on out of order or unsolicited acks
+    pub fn register_ack(&mut self, pkid: u16) -> Option<usize> {
+        let (head, _filter_idx, _cursor, size) = match self.inflight_buffer.pop_front() {
             Some(v) => v,
             None => return None,
         };
+        self.unacked.pop_front();
 
         // We don't support out of order acks
         if pkid != head {
@@ -172,17 +208,56 @@ impl Outgoing {
             return None;
         }
 
-        Some(())
+        self.inflight_bytes -= size;
+        Some(size)
     }
 
-    // Here we are assuming that the first unique filter_idx we find while iterating will have the
-    // least corresponding cursor because of the way we insert into the inflight_buffer
-    pub fn retransmission_map(&self) -> HashMap<FilterIdx, Cursor> {
-        let mut o = HashMap::new();
-        for (_, filter_idx, cursor) in self.inflight_buffer.iter() {
-            if !o.contains_key(filter_idx) {
-                o.insert(*filter_idx, *cursor);
+    /// Publishes which weren't acked within `interval` of being sent, marked
+    /// as duplicates. Their retransmission is timed from `now`
+    pub fn due_for_retransmit(&mut self, now: Instant, interval: Duration) -> Vec<Forward> {
+        let mut due = Vec::new();
+        for (sent, forward) in self.unacked.iter_mut() {
+            if now.saturating_duration_since(*sent) < interval {
+                continue;
             }
+
+            *sent = now;
+            forward.publish.dup = true;
+            due.push(forward.clone());
+        }
+
+        due
+    }
+
+    /// Lowest cursor among the unacked publishes read from a filter. They
+    /// are read again from there if the connection drops before acking
+    pub fn inflight_cursor(&self, filter_idx: FilterIdx) -> Option<Cursor> {
+        self.inflight_buffer
+            .iter()
+            .filter(|(_, idx, _, _)| *idx == filter_idx)
+            .map(|(_, _, cursor, _)| *cursor)
+            .min()
+    }
+
+    /// Points inflight publishes at the indexes their filters were moved to by
+    /// `DataLog::compact_native`
+    pub fn remap_filters(&mut self, remap: &HashMap<FilterIdx, FilterIdx>) {
+        for (_, filter_idx, _, _) in self.inflight_buffer.iter_mut() {
+            if let Some(&new) = remap.get(filter_idx) {
+                *filter_idx = new;
+            }
+        }
+    }
+
+    /// Lowest cursor of the unacked publishes of every filter. Backlogs
+    /// delivered newest first are inflight with decreasing cursors, so the
+    /// first publish of a filter isn't necessarily the lowest
+    pub fn retransmission_map(&self) -> HashMap<FilterIdx, Cursor> {
+        let mut o: HashMap<FilterIdx, Cursor> = HashMap::new();
+        for &(_, filter_idx, cursor, _) in self.inflight_buffer.iter() {
+            o.entry(filter_idx)
+                .and_modify(|lowest| *lowest = (*lowest).min(cursor))
+                .or_insert(cursor);
         }
 
         o
@@ -192,6 +267,7 @@ impl Outgoing {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::protocol::{Publish, QoS};
 
     #[test]
     fn retransmission_map_is_calculated_accurately() {
@@ -202,25 +278,77 @@ mod test {
         result.insert(1, (0, 1));
         result.insert(2, (1, 1));
         result.insert(3, (1, 0));
+        result.insert(4, (0, 5));
 
         let buf = vec![
-            (1, 0, (0, 8)),
-            (1, 0, (0, 10)),
-            (1, 1, (0, 1)),
-            (3, 1, (0, 4)),
-            (2, 2, (1, 1)),
-            (1, 2, (2, 6)),
-            (1, 2, (2, 1)),
-            (1, 3, (1, 0)),
-            (1, 3, (1, 1)),
-            (1, 3, (1, 3)),
-            (1, 3, (1, 3)),
+            (1, 0, (0, 8), 0),
+            (1, 0, (0, 10), 0),
+            (1, 1, (0, 1), 0),
+            (3, 1, (0, 4), 0),
+            (2, 2, (1, 1), 0),
+            (1, 2, (2, 6), 0),
+            (1, 2, (2, 1), 0),
+            (1, 3, (1, 0), 0),
+            (1, 3, (1, 1), 0),
+            (1, 3, (1, 3), 0),
+            (1, 3, (1, 3), 0),
+            // Newest first backlog
+            (1, 4, (0, 9), 0),
+            (1, 4, (0, 7), 0),
+            (1, 4, (0, 5), 0),
         ];
 
         outgoing.inflight_buffer.extend(buf);
         assert_eq!(outgoing.retransmission_map(), result);
     }
 
+    #[test]
+    fn unacked_publishes_are_due_for_retransmit_after_interval() {
+        let (mut outgoing, _) = Outgoing::new("retransmit-test".to_string());
+        outgoing.set_retransmit(true);
+        let forwards = (0..2).map(|i| {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            Forward {
+                cursor: (0, i),
+                size: 0,
+                publish,
+                properties: None,
+            }
+        });
+
+        outgoing.push_forwards(forwards, 1, 0);
+        let sent = Instant::now();
+        outgoing.register_ack(1);
+
+        let interval = Duration::from_secs(5);
+        assert!(outgoing
+            .due_for_retransmit(sent + Duration::from_secs(4), interval)
+            .is_empty());
+
+        let now = sent + Duration::from_secs(5);
+        let due = outgoing.due_for_retransmit(now, interval);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].publish.pkid, 2);
+        assert!(due[0].publish.dup);
+
+        // Next retransmission is timed from the last one
+        assert!(outgoing
+            .due_for_retransmit(now + Duration::from_secs(4), interval)
+            .is_empty());
+        assert_eq!(
+            outgoing
+                .due_for_retransmit(now + Duration::from_secs(5), interval)
+                .len(),
+            1
+        );
+
+        outgoing.register_ack(2);
+        assert!(outgoing
+            .due_for_retransmit(now + Duration::from_secs(60), interval)
+            .is_empty());
+    }
+
     // use super::{Outgoing, MAX_INFLIGHT};
     // use crate::protocol::{Publish, QoS};
     // use crate::router::Forward;
@@ -1,35 +1,86 @@
 use super::Ack;
+use bytes::Bytes;
 use slab::Slab;
-use tracing::trace;
+use tracing::{trace, warn};
 
 use crate::protocol::{
-    matches, ConnAck, ConnAckProperties, PingResp, PubAck, PubComp, PubRec, PubRel, Publish,
-    PublishProperties, SubAck, UnsubAck,
+    matches, validate_topic, ConnAck, ConnAckProperties, PingResp, PubAck, PubComp, PubRec,
+    PubRecReason, PubRel, Publish, PublishProperties, SubAck, SubAckProperties, TopicError,
+    UnsubAck,
+};
+use crate::router::trie::FilterTrie;
+use crate::router::{
+    DataRequest, FilterIdx, OffsetRange, SubscriberMeter, SubscriptionMeter, Waiters,
+};
+use crate::{
+    ConnectionId, Filter, IdleFilterLag, NoMatchPolicy, Offset, RouterConfig, RouterConfigPatch,
+    Topic,
 };
-use crate::router::{DataRequest, FilterIdx, SubscriptionMeter, Waiters};
-use crate::{ConnectionId, Filter, Offset, RouterConfig, Topic};
 
-use crate::segments::{CommitLog, Position};
+use crate::segments::{AppendError, AppendPosition, CommitLog, CommitLogStats, Position};
+use crate::topic::TopicPool;
 use crate::Storage;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+pub(crate) type PubWithProp = (Publish, Option<PublishProperties>);
+
+const MAX_COMPACTED_KEYS: usize = 10_000;
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ConfigError {
+    #[error("{0} can't be changed while the router is running")]
+    Immutable(&'static str),
+    #[error("{0} must be at least {1}")]
+    TooSmall(&'static str, usize),
+    #[error("{0} can't exceed {1}")]
+    Exceeds(&'static str, &'static str),
+    #[error("{0} must be at most {1}")]
+    TooLarge(&'static str, u64),
+    #[error("{0} is not a valid topic: {1}")]
+    InvalidTopic(&'static str, TopicError),
+}
+
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum RetainError {
+    #[error("Retained message of {0} bytes exceeds max_retained_message_size of {1}")]
+    TooLarge(usize, usize),
+}
 
-type PubWithProp = (Publish, Option<PublishProperties>);
+/// Publish held by the router until its delay elapses, already stripped
+/// of its `$delayed/<seconds>/` prefix
+pub struct DelayedPublish {
+    pub publish: Publish,
+    pub properties: Option<PublishProperties>,
+    pub topic: Topic,
+    /// Client which published it, whose delayed publishes are capped
+    pub client_id: String,
+    /// Dynamic filters of the publishing connection, which may be gone
+    /// by the time the publish is routed
+    pub dynamic_filters: bool,
+}
 
 #[derive(Clone)]
 pub struct PublishData {
+    /// Publish as it is forwarded to subscribers. RETAIN is only set on this
+    /// for deliveries of retained messages
     pub publish: Publish,
     pub properties: Option<PublishProperties>,
     pub timestamp: Instant,
+    /// RETAIN flag set by the publisher, forwarded only to subscriptions with
+    /// Retain As Published option
+    pub published_retain: bool,
 }
 
 impl From<PubWithProp> for PublishData {
-    fn from((publish, properties): PubWithProp) -> Self {
+    fn from((mut publish, properties): PubWithProp) -> Self {
+        let published_retain = std::mem::take(&mut publish.retain);
         PublishData {
             publish,
             properties,
             timestamp: Instant::now(),
+            published_retain,
         }
     }
 }
@@ -58,36 +109,171 @@ pub struct DataLog {
     pub native: Slab<Data<PublishData>>,
     /// Map of subscription filter name to filter index
     filter_indexes: HashMap<Filter, FilterIdx>,
+    /// Filters of `filter_indexes` by level, used to match new topics
+    filter_trie: FilterTrie,
     retained_publishes: HashMap<Topic, PublishData>,
+    /// Publishes to `$delayed/..` held until the instant they are due
+    delayed_publishes: BTreeMap<Instant, Vec<DelayedPublish>>,
+    /// Number of publishes in `delayed_publishes`
+    delayed_count: usize,
+    /// Number of publishes in `delayed_publishes` of every client
+    delayed_by_client: HashMap<String, usize>,
     /// List of filters associated with a topic
-    publish_filters: HashMap<Topic, Vec<FilterIdx>>,
+    pub(crate) publish_filters: HashMap<Topic, FilterMatches>,
+    /// When the entries of `publish_filters` were matched. Only kept with
+    /// `match_cache_ttl_ms`
+    match_cached_at: HashMap<Topic, Instant>,
+    /// Topics of `match_cached_at` in the order they were cached, so that
+    /// `expire_matches` doesn't scan the whole cache
+    match_expiry: VecDeque<(Instant, String)>,
+    /// Lookups of `matches` answered from and missing `publish_filters`
+    match_cache_hits: u64,
+    match_cache_misses: u64,
+    /// Bumped whenever filters are added, removed or moved, so that matches
+    /// computed against older filters aren't cached
+    filters_generation: u64,
+    /// Topics shared by `retained_publishes`, `publish_filters` and the
+    /// publishes in the logs
+    pub(crate) topics: TopicPool,
+}
+
+/// Reads are at least one message long and read ahead starts within `max_read_len`
+fn validate_read_len(max_read_len: u64, initial_read_len: Option<u64>) -> Result<(), ConfigError> {
+    if max_read_len == 0 {
+        return Err(ConfigError::TooSmall("max_read_len", 1));
+    }
+
+    match initial_read_len {
+        Some(0) => Err(ConfigError::TooSmall("initial_read_len", 1)),
+        Some(len) if len > max_read_len => {
+            Err(ConfigError::Exceeds("initial_read_len", "max_read_len"))
+        }
+        _ => Ok(()),
+    }
 }
 
 impl DataLog {
-    pub fn new(config: RouterConfig) -> io::Result<DataLog> {
-        let mut native = Slab::new();
-        let mut filter_indexes = HashMap::new();
-        let retained_publishes = HashMap::new();
-        let publish_filters = HashMap::new();
+    pub fn new(config: RouterConfig) -> Result<DataLog, ConfigError> {
+        // Commitlogs refuse to be built with smaller segments
+        if config.max_segment_size < 1024 {
+            return Err(ConfigError::TooSmall("max_segment_size", 1024));
+        }
+
+        if config.max_segment_count == 0 {
+            return Err(ConfigError::TooSmall("max_segment_count", 1));
+        }
+
+        validate_read_len(config.max_read_len, config.initial_read_len)?;
+
+        // Keeps due instants of delayed publishes representable
+        let max_delay = u32::MAX as u64;
+        if config
+            .max_publish_delay_secs
+            .is_some_and(|secs| secs > max_delay)
+        {
+            return Err(ConfigError::TooLarge("max_publish_delay_secs", max_delay));
+        }
+
+        if config.dedup.as_ref().is_some_and(|dedup| dedup.window == 0) {
+            return Err(ConfigError::TooSmall("dedup.window", 1));
+        }
+
+        if config.max_compacted_keys == Some(0) {
+            return Err(ConfigError::TooSmall("max_compacted_keys", 1));
+        }
+
+        if let NoMatchPolicy::DeadLetter(topic) = &config.no_match_policy {
+            validate_topic(topic).map_err(|e| ConfigError::InvalidTopic("no_match_policy", e))?;
+        }
+
+        for storage in config.filter_storage.iter() {
+            if storage.max_segment_size < 1024 {
+                return Err(ConfigError::TooSmall(
+                    "filter_storage.max_segment_size",
+                    1024,
+                ));
+            }
+
+            if storage.max_segment_count == 0 {
+                return Err(ConfigError::TooSmall("filter_storage.max_segment_count", 1));
+            }
+        }
+
+        let mut datalog = DataLog {
+            config,
+            native: Slab::new(),
+            publish_filters: HashMap::new(),
+            match_cached_at: HashMap::new(),
+            match_expiry: VecDeque::new(),
+            filter_indexes: HashMap::new(),
+            filter_trie: FilterTrie::new(),
+            retained_publishes: HashMap::new(),
+            delayed_publishes: BTreeMap::new(),
+            delayed_count: 0,
+            delayed_by_client: HashMap::new(),
+            match_cache_hits: 0,
+            match_cache_misses: 0,
+            filters_generation: 0,
+            topics: TopicPool::default(),
+        };
+
+        datalog.init_filters();
+        Ok(datalog)
+    }
 
-        if let Some(warmup_filters) = config.initialized_filters.clone() {
+    /// Creates the logs of `initialized_filters`
+    fn init_filters(&mut self) {
+        if let Some(warmup_filters) = self.config.initialized_filters.clone() {
             for filter in warmup_filters {
-                let data = Data::new(&filter, config.max_segment_size, config.max_segment_count);
+                let data = Data::new(&filter, &self.config);
 
                 // Add commitlog to datalog and add datalog index to filter to
                 // datalog index map
-                let idx = native.insert(data);
-                filter_indexes.insert(filter, idx);
+                let idx = self.native.insert(data);
+                self.filter_trie.insert(&filter, idx);
+                self.filter_indexes.insert(filter, idx);
             }
         }
+    }
 
-        Ok(DataLog {
-            config,
-            native,
-            publish_filters,
-            filter_indexes,
-            retained_publishes,
-        })
+    /// Applies limits which are safe to change at runtime. The whole patch is
+    /// rejected if it changes a setting existing logs depend on
+    pub fn update_config(&mut self, patch: RouterConfigPatch) -> Result<(), ConfigError> {
+        let config = &mut self.config;
+        if matches!(patch.max_segment_size, Some(v) if v != config.max_segment_size) {
+            return Err(ConfigError::Immutable("max_segment_size"));
+        }
+
+        if matches!(patch.max_segment_count, Some(v) if v != config.max_segment_count) {
+            return Err(ConfigError::Immutable("max_segment_count"));
+        }
+
+        validate_read_len(
+            patch.max_read_len.unwrap_or(config.max_read_len),
+            patch.initial_read_len.unwrap_or(config.initial_read_len),
+        )?;
+
+        if let Some(v) = patch.max_read_len {
+            config.max_read_len = v;
+        }
+
+        if let Some(v) = patch.initial_read_len {
+            config.initial_read_len = v;
+        }
+
+        if let Some(v) = patch.max_consumer_lag {
+            config.max_consumer_lag = v;
+        }
+
+        if let Some(v) = patch.max_total_inflight_bytes {
+            config.max_total_inflight_bytes = v;
+        }
+
+        if let Some(v) = patch.shed_qos0_lag {
+            config.shed_qos0_lag = v;
+        }
+
+        Ok(())
     }
 
     pub fn meter(&mut self, filter: &str) -> Option<&mut SubscriptionMeter> {
@@ -95,48 +281,176 @@ impl DataLog {
         Some(&mut data.meter)
     }
 
-    pub fn waiters(&self, filter: &Filter) -> Option<&Waiters<DataRequest>> {
+    /// Deliveries of a filter to connection `id`, kept with `meter_subscribers`
+    pub fn subscriber_meter(
+        &self,
+        filter_idx: FilterIdx,
+        id: ConnectionId,
+    ) -> Option<SubscriberMeter> {
+        self.native.get(filter_idx)?.subscribers.get(&id).cloned()
+    }
+
+    /// Meters `publishes` read with `request` for connection `id`
+    pub fn meter_delivery(
+        &mut self,
+        id: ConnectionId,
+        request: &DataRequest,
+        publishes: &[(PubWithProp, Offset)],
+    ) {
+        if !self.config.meter_subscribers {
+            return;
+        }
+
+        let lag = self.consumer_lag(request);
+        let data = match self.native.get_mut(request.filter_idx) {
+            Some(data) => data,
+            None => return,
+        };
+
+        let meter = data.subscribers.entry(id).or_default();
+        meter.count += publishes.len();
+        meter.total_size += publishes
+            .iter()
+            .map(|((publish, _), _)| publish.topic.len() + publish.payload.len())
+            .sum::<usize>();
+        meter.lag = lag;
+    }
+
+    /// Drops the delivery meter, cursor and queued retained messages of
+    /// connection `id` on `filter`
+    pub fn remove_subscriber(&mut self, id: ConnectionId, filter: &str) {
+        if let Some(data) = self
+            .filter_indexes
+            .get(filter)
+            .and_then(|&idx| self.native.get_mut(idx))
+        {
+            data.subscribers.remove(&id);
+            data.resend.remove(&id);
+            data.readers.remove(id);
+        }
+    }
+
+    pub fn filter_idx(&self, filter: &str) -> Option<FilterIdx> {
+        self.filter_indexes.get(filter).copied()
+    }
+
+    /// Offsets at which the next entry of each of `filters` will be written.
+    /// Appends need `&mut self`, so the offsets form a consistent cut to
+    /// checkpoint from. Unknown filters are left out
+    pub fn snapshot_offsets(&self, filters: &[FilterIdx]) -> HashMap<FilterIdx, Offset> {
+        filters
+            .iter()
+            .filter_map(|&idx| Some((idx, self.native.get(idx)?.next_offset())))
+            .collect()
+    }
+
+    /// Fill of the segments of a filter's commitlog
+    pub fn commitlog_stats(&self, filter_idx: FilterIdx) -> Option<CommitLogStats> {
+        Some(self.native.get(filter_idx)?.log.stats())
+    }
+
+    /// Drops the log of `filter` along with its routes. Subscriptions to the
+    /// filter have to be removed by the caller beforehand
+    pub fn remove_filter(&mut self, filter: &str) -> Option<Data<PublishData>> {
+        let filter_idx = self.filter_indexes.remove(filter)?;
+        self.filters_generation += 1;
+        self.filter_trie.remove(filter);
+        self.publish_filters.retain(|_, matches| {
+            *matches = matches
+                .as_slice()
+                .iter()
+                .copied()
+                .filter(|&idx| idx != filter_idx)
+                .collect();
+            !matches.is_empty()
+        });
+        let publish_filters = &self.publish_filters;
+        self.match_cached_at
+            .retain(|topic, _| publish_filters.contains_key(topic));
+        self.topics.prune();
+
+        Some(self.native.remove(filter_idx))
+    }
+
+    /// Moves the logs of all filters to the front of `native` so that slots
+    /// freed by `remove_filter` don't linger. Returns the old to new index of
+    /// every filter. Indexes held by `DataLog` are rewritten here, all other
+    /// holders have to be rewritten by the caller
+    pub fn compact_native(&mut self) -> HashMap<FilterIdx, FilterIdx> {
+        let mut remap = HashMap::with_capacity(self.native.len());
+        let mut native = Slab::with_capacity(self.native.len());
+        for (old, data) in std::mem::take(&mut self.native) {
+            remap.insert(old, native.insert(data));
+        }
+        self.native = native;
+        self.filters_generation += 1;
+
+        for filter_idx in self.filter_indexes.values_mut() {
+            *filter_idx = remap[filter_idx];
+        }
+        self.filter_trie.remap_filters(&remap);
+
+        for matches in self.publish_filters.values_mut() {
+            *matches = matches.as_slice().iter().map(|idx| remap[idx]).collect();
+        }
+
+        for (_, data) in self.native.iter_mut() {
+            for request in data.waiters.requests_mut() {
+                request.remap_filter(&remap);
+            }
+        }
+
+        remap
+    }
+
+    pub fn waiters(&self, filter: &str) -> Option<&Waiters<DataRequest>> {
         self.native
             .get(*self.filter_indexes.get(filter)?)
             .map(|data| &data.waiters)
     }
 
-    pub fn remove_waiters_for_id(
-        &mut self,
-        id: ConnectionId,
-        filter: &Filter,
-    ) -> Option<DataRequest> {
+    pub fn remove_waiters_for_id(&mut self, id: ConnectionId, filter: &str) -> Option<DataRequest> {
         let data = self
             .native
             .get_mut(*self.filter_indexes.get(filter)?)
             .unwrap();
-        let waiters = data.waiters.get_mut();
 
-        waiters
+        data.waiters.remove(id)
+    }
+
+    /// Filters on which more than `threshold` connections were parked at once
+    /// since they were last woken up. A signal to apply backpressure upstream
+    pub fn saturated_filters(&self, threshold: usize) -> Vec<FilterIdx> {
+        self.native
             .iter()
-            .position(|&(conn_id, _)| conn_id == id)
-            .and_then(|index| {
-                waiters
-                    .swap_remove_back(index)
-                    .map(|(_, data_req)| data_req)
-            })
+            .filter(|(_, data)| data.waiters.high_water() > threshold)
+            .map(|(idx, _)| idx)
+            .collect()
     }
 
-    // TODO: Currently returning a Option<Vec> instead of Option<&Vec> due to Rust borrow checker
-    // limitation
-    pub fn matches(&mut self, topic: &str) -> Option<Vec<usize>> {
-        match &self.publish_filters.get(topic) {
-            Some(v) => Some(v.to_vec()),
-            None => {
-                let v: Vec<usize> = self
-                    .filter_indexes
-                    .iter()
-                    .filter(|(filter, _)| matches(topic, filter))
-                    .map(|(_, filter_idx)| *filter_idx)
-                    .collect();
+    // TODO: Currently returning an owned FilterMatches instead of a reference due to Rust borrow
+    // checker limitation. Cloning is free for topics matching at most one filter
+    pub fn matches(&mut self, topic: &str) -> Option<FilterMatches> {
+        let ttl = self.config.match_cache_ttl_ms.map(Duration::from_millis);
+        let expired = ttl.is_some_and(|ttl| {
+            self.match_cached_at
+                .get(topic)
+                .is_some_and(|cached_at| cached_at.elapsed() >= ttl)
+        });
+
+        match self.publish_filters.get(topic) {
+            Some(v) if !expired => {
+                self.match_cache_hits += 1;
+                Some(v.clone())
+            }
+            _ => {
+                self.match_cache_misses += 1;
+                let (v, generation) = self.compute_matches(topic);
 
                 if !v.is_empty() {
-                    self.publish_filters.insert(topic.to_owned(), v.clone());
+                    self.cache_matches(topic, v.clone(), generation);
+                } else if expired {
+                    self.uncache_matches(topic);
                 }
 
                 Some(v)
@@ -144,6 +458,64 @@ impl DataLog {
         }
     }
 
+    /// Filters matching `topic`, along with the generation of the filters
+    /// they were matched against
+    fn compute_matches(&self, topic: &str) -> (FilterMatches, u64) {
+        (self.filter_trie.matches(topic), self.filters_generation)
+    }
+
+    /// Caches matches of `topic` from `compute_matches`, unless filters
+    /// changed since they were computed. Caching the same matches again is
+    /// harmless. Returns whether they were cached
+    fn cache_matches(&mut self, topic: &str, matches: FilterMatches, generation: u64) -> bool {
+        if generation != self.filters_generation {
+            return false;
+        }
+
+        let (interned, _) = self.topics.intern(topic);
+        if self.config.match_cache_ttl_ms.is_some() {
+            let now = Instant::now();
+            self.match_cached_at.insert(interned.clone(), now);
+            self.match_expiry.push_back((now, topic.to_owned()));
+        }
+        self.publish_filters.insert(interned, matches);
+        true
+    }
+
+    /// Drops cached matches older than `match_cache_ttl_ms`, so that topics
+    /// which aren't published to anymore don't stay cached
+    pub fn expire_matches(&mut self, now: Instant) {
+        let Some(ttl) = self.config.match_cache_ttl_ms.map(Duration::from_millis) else {
+            return;
+        };
+
+        while let Some((cached_at, _)) = self.match_expiry.front() {
+            if now.saturating_duration_since(*cached_at) < ttl {
+                break;
+            }
+
+            // Topics cached again since are expired from their later entry
+            let (cached_at, topic) = self.match_expiry.pop_front().unwrap();
+            if self.match_cached_at.get(topic.as_str()) == Some(&cached_at) {
+                self.uncache_matches(&topic);
+            }
+        }
+    }
+
+    /// Drops the cached matches of `topic`, along with its name unless it's
+    /// retained
+    fn uncache_matches(&mut self, topic: &str) {
+        self.match_cached_at.remove(topic);
+        self.publish_filters.remove(topic);
+        self.topics.release(topic);
+    }
+
+    /// Number of `matches` lookups answered from the cache and of those which
+    /// had to match the topic against the filters, as `(hits, misses)`
+    pub fn match_cache_stats(&self) -> (u64, u64) {
+        (self.match_cache_hits, self.match_cache_misses)
+    }
+
     pub fn next_native_offset(&mut self, filter: &str) -> (FilterIdx, Offset) {
         let publish_filters = &mut self.publish_filters;
         let filter_indexes = &mut self.filter_indexes;
@@ -151,16 +523,15 @@ impl DataLog {
         let (filter_idx, data) = match filter_indexes.get(filter) {
             Some(idx) => (*idx, self.native.get(*idx).unwrap()),
             None => {
-                let data = Data::new(
-                    filter,
-                    self.config.max_segment_size,
-                    self.config.max_segment_count,
-                );
+                let data = Data::new(filter, &self.config);
 
                 // Add commitlog to datalog and add datalog index to filter to
                 // datalog index map
                 let idx = self.native.insert(data);
-                self.filter_indexes.insert(filter.to_owned(), idx);
+                self.filter_indexes
+                    .insert(Filter::new_unchecked(filter), idx);
+                self.filter_trie.insert(filter, idx);
+                self.filters_generation += 1;
 
                 // Match new filter to existing topics and add to publish_filters if it matches
                 for (topic, filters) in publish_filters.iter_mut() {
@@ -173,7 +544,7 @@ impl DataLog {
             }
         };
 
-        (filter_idx, data.log.next_offset())
+        (filter_idx, data.start_offset())
     }
 
     pub fn native_readv(
@@ -181,6 +552,7 @@ impl DataLog {
         filter_idx: FilterIdx,
         offset: Offset,
         len: u64,
+        preserve_retain: bool,
     ) -> io::Result<(Position, Vec<(PubWithProp, Offset)>)> {
         // unwrap to get index of `self.native` is fine here, because when a new subscribe packet
         // arrives in `Router::handle_device_payload`, it first calls the function
@@ -192,8 +564,86 @@ impl DataLog {
         // reflect that. Consequently, this method is also infallible.
         // Encoding this information is important so that calling function
         // has more information on how this method behaves.
-        let next = data.log.readv(offset, len, &mut o)?;
+        let next = data.readv(offset, len, &mut o)?;
+
+        // Only the latest of consecutive publishes on a topic is delivered from
+        // conflated filters. The cursor still moves past all of them
+        if data.conflated {
+            o.reverse();
+            o.dedup_by(|(newer, _), (older, _)| newer.publish.topic == older.publish.topic);
+            o.reverse();
+        }
+
+        Ok((next, Self::deliverable(o, preserve_retain)))
+    }
+
+    /// Up to `len` publishes of a filter from offset `from` as JSON objects with
+    /// their `offset`, `topic`, `qos`, `payload_base64` and `properties`. Meant
+    /// for inspecting the contents of a filter, not for delivery
+    #[cfg(feature = "debug-dump")]
+    pub fn dump_filter_json(
+        &self,
+        filter_idx: FilterIdx,
+        from: Offset,
+        len: u64,
+    ) -> Vec<serde_json::Value> {
+        if !self.native.contains(filter_idx) {
+            return Vec::new();
+        }
+
+        let publishes = match self.native_readv(filter_idx, from, len, true) {
+            Ok((_, publishes)) => publishes,
+            Err(_) => return Vec::new(),
+        };
+
+        publishes
+            .into_iter()
+            .map(|((publish, properties), offset)| {
+                serde_json::json!({
+                    "offset": offset,
+                    "topic": String::from_utf8_lossy(&publish.topic),
+                    "qos": publish.qos as u8,
+                    "payload_base64": base64::encode(&publish.payload),
+                    "properties": properties,
+                })
+            })
+            .collect()
+    }
+
+    /// Reads up to `len` publishes of `backlog`, a `[start, end)` range, backwards
+    /// from its end. Returns the part of the backlog which is left to read, `None`
+    /// once all of it was read
+    pub fn native_readv_rev(
+        &self,
+        filter_idx: FilterIdx,
+        backlog: OffsetRange,
+        len: u64,
+        preserve_retain: bool,
+    ) -> (Option<OffsetRange>, Vec<(PubWithProp, Offset)>) {
+        let (start, end) = backlog;
+        let data = self.native.get(filter_idx).unwrap();
+        let mut o = Vec::new();
+        data.readv_rev(start, end, len, &mut o);
+
+        let remaining = match o.last() {
+            Some((_, offset)) if o.len() as u64 == len && *offset > start => Some((start, *offset)),
+            _ => None,
+        };
+
+        // Publishes are read newest first, so the first of consecutive ones on
+        // a topic is the latest
+        if data.conflated {
+            o.dedup_by(|(older, _), (newer, _)| newer.publish.topic == older.publish.topic);
+        }
 
+        (remaining, Self::deliverable(o, preserve_retain))
+    }
+
+    /// Drops expired publishes and prepares the rest to be forwarded
+    fn deliverable(
+        mut o: Vec<(PublishData, Offset)>,
+        preserve_retain: bool,
+    ) -> Vec<(PubWithProp, Offset)> {
         let now = Instant::now();
         o.retain_mut(|(pubdata, _)| {
             // Keep data if no properties exists, which implies no message expiry!
@@ -221,17 +671,92 @@ impl DataLog {
         });
 
         // no need to include timestamp when returning
-        let o = o
-            .into_iter()
-            .map(|(pubdata, offset)| ((pubdata.publish, pubdata.properties), offset))
-            .collect();
+        o.into_iter()
+            .map(|(mut pubdata, offset)| {
+                // Retain As Published: forward RETAIN flag as set by the publisher
+                // ref: https://docs.oasis-open.org/mqtt/mqtt/v5.0/os/mqtt-v5.0-os.html#_Toc3901104
+                if preserve_retain && pubdata.published_retain {
+                    pubdata.publish.retain = true;
+                }
+
+                ((pubdata.publish, pubdata.properties), offset)
+            })
+            .collect()
+    }
 
-        Ok((next, o))
+    /// Publish at `offset` of a filter. See `Data::get`
+    pub fn get_publish(&self, filter_idx: FilterIdx, offset: Offset) -> Option<&PublishData> {
+        self.native.get(filter_idx)?.get(offset)
+    }
+
+    /// Up to `n` latest publishes of a filter, oldest first
+    pub fn tail(&self, filter_idx: FilterIdx, n: usize) -> Vec<(Offset, Publish)> {
+        match self.native.get(filter_idx) {
+            Some(data) => data.tail(n),
+            None => Vec::new(),
+        }
+    }
+
+    /// Records the cursor connection `id` reads a filter from next. Called when
+    /// the cursor of its `DataRequest` is set or advanced
+    pub fn track_reader(&mut self, filter_idx: FilterIdx, id: ConnectionId, cursor: Offset) {
+        if let Some(data) = self.native.get_mut(filter_idx) {
+            data.readers.update(id, cursor);
+        }
+    }
+
+    /// Lag of the connected subscriber closest to the head of a filter.
+    /// `None` when the filter has no connected subscribers
+    pub fn filter_lag(&self, filter_idx: FilterIdx) -> Option<u64> {
+        let data = self.native.get(filter_idx)?;
+        let cursor = data.readers.fastest()?;
+        Some(data.next_offset().1.saturating_sub(cursor.1))
+    }
+
+    /// Lag of the connected subscriber furthest behind the head of a filter.
+    /// `None` when the filter has no connected subscribers
+    pub fn max_delivery_lag(&self, filter_idx: FilterIdx) -> Option<u64> {
+        let data = self.native.get(filter_idx)?;
+        let cursor = data.readers.slowest()?;
+        Some(data.next_offset().1.saturating_sub(cursor.1))
+    }
+
+    /// Lag of a filter as seen by `shed_qos0_lag`. Filters without connected
+    /// subscribers follow `idle_filter_lag`
+    pub fn shedding_lag(&self, filter_idx: FilterIdx) -> u64 {
+        if let Some(lag) = self.filter_lag(filter_idx) {
+            return lag;
+        }
+
+        let Some(data) = self.native.get(filter_idx) else {
+            return 0;
+        };
+        match (self.config.idle_filter_lag, data.readers.held) {
+            (IdleFilterLag::Hold, Some(cursor)) => data.next_offset().1.saturating_sub(cursor.1),
+            _ => 0,
+        }
+    }
+
+    /// Number of messages appended to the filter of this request which the
+    /// subscriber hasn't read yet
+    pub fn consumer_lag(&self, request: &DataRequest) -> u64 {
+        match self.native.get(request.filter_idx) {
+            Some(data) => data.next_offset().1.saturating_sub(request.cursor.1),
+            None => 0,
+        }
+    }
+
+    /// Estimate of resident bytes of all the native logs. See `CommitLog::memory_usage`
+    pub fn estimated_memory(&self) -> usize {
+        self.native
+            .iter()
+            .map(|(_, data)| data.memory_usage())
+            .sum()
     }
 
     pub fn shadow(&mut self, filter: &str) -> Option<PubWithProp> {
         let data = self.native.get_mut(*self.filter_indexes.get(filter)?)?;
-        data.log.last().map(|p| (p.publish, p.properties))
+        data.last().map(|p| (p.publish, p.properties))
     }
 
     /// This method is called when the subscriber has caught up with the commit log. In which case,
@@ -239,210 +764,2211 @@ impl DataLog {
     /// wait and only try reading again when new messages have been added to the commit log. This
     /// methods converts a `DataRequest` (which actively reads the commit log in `Router::consume`)
     /// to a `Waiter` (which only reads when notified).
-    pub fn park(&mut self, id: ConnectionId, request: DataRequest) {
+    ///
+    /// A connection is parked at most once per filter. When it already is,
+    /// the stale request is replaced by `request` and handed back
+    pub fn park(&mut self, id: ConnectionId, request: DataRequest) -> Option<DataRequest> {
         // calling unwrap on index here is fine, because only place this function is called is in
         // `Router::consume` method, when the status after reading from commit log of the same
         // filter as `request` is "done", that is, the subscriber has caught up. In other words,
         // there has been atleast 1 call to `native_readv` for the same filter, which means if
         // `native_readv` hasn't paniced, so this won't panic either.
         let data = self.native.get_mut(request.filter_idx).unwrap();
-        data.waiters.register(id, request);
+        data.waiters.register(id, request)
     }
 
-    /// Cleanup a connection from all the waiters
-    pub fn clean(&mut self, id: ConnectionId) -> Vec<DataRequest> {
-        let mut inflight = Vec::new();
+    /// Cleanup a connection from the waiters of every filter, returning the
+    /// removed requests along with the filter they were parked on
+    pub fn remove_all_waiters(&mut self, id: ConnectionId) -> Vec<(Filter, DataRequest)> {
+        let mut removed = Vec::new();
         for (_, data) in self.native.iter_mut() {
-            inflight.append(&mut data.waiters.remove(id));
+            let requests = data.waiters.remove(id);
+            removed.extend(requests.into_iter().map(|r| (data.filter.clone(), r)));
         }
 
-        inflight
+        removed
     }
 
+    /// Replaces the retained message on `topic`. Messages larger than
+    /// `max_retained_message_size` are refused and leave the current one in place
     pub fn insert_to_retained_publishes(
         &mut self,
         publish: Publish,
         publish_properties: Option<PublishProperties>,
         topic: Topic,
-    ) {
-        let pub_with_props = (publish, publish_properties);
-        self.retained_publishes.insert(topic, pub_with_props.into());
-    }
+    ) -> Result<(), RetainError> {
+        let publish_data: PublishData = (publish, publish_properties).into();
+        if let Some(max) = self.config.max_retained_message_size {
+            let size = publish_data.size();
+            if size > max {
+                return Err(RetainError::TooLarge(size, max));
+            }
+        }
 
-    pub fn remove_from_retained_publishes(&mut self, topic: Topic) {
-        self.retained_publishes.remove(&topic);
+        self.retained_publishes.insert(topic, publish_data);
+        Ok(())
     }
 
-    pub fn handle_retained_messages(
-        &mut self,
-        filter: &str,
-        notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
-    ) {
-        trace!(info = "retain-msg", filter = &filter);
+    /// Number of distinct topics of the publishes held by the logs or
+    /// retained. Counted by visiting all of them on every call
+    pub fn published_topic_count(&self) -> usize {
+        let logged = self
+            .native
+            .iter()
+            .flat_map(|(_, data)| data.iter_from((0, 0)))
+            .map(|(data, _)| &data.publish.topic[..]);
+        let retained = self
+            .retained_topics()
+            .map(|topic| topic.as_str().as_bytes());
 
-        let idx = self.filter_indexes.get(filter).unwrap();
+        logged.chain(retained).collect::<HashSet<_>>().len()
+    }
 
-        let datalog = self.native.get_mut(*idx).unwrap();
+    /// Topics which have a retained message
+    pub fn retained_topics(&self) -> impl Iterator<Item = &Topic> {
+        self.retained_publishes.keys()
+    }
 
-        for (topic, publish) in self.retained_publishes.iter_mut() {
-            if matches(topic, filter) {
-                datalog.append(publish.clone(), notifications);
-            }
+    pub fn retained_count(&self) -> usize {
+        self.retained_publishes.len()
+    }
+
+    /// Topics which were published to or have a retained message, arranged
+    /// by their levels. Built on every call
+    pub fn topic_tree(&self) -> TopicNode {
+        let mut root = TopicNode::default();
+        let topics = self.publish_filters.keys().chain(self.retained_topics());
+
+        for topic in topics {
+            let node = topic.split('/').fold(&mut root, |node, level| {
+                node.children.entry(level.to_owned()).or_default()
+            });
+
+            node.retained = self.retained_publishes.contains_key(topic);
+            node.filters = self
+                .publish_filters
+                .get(topic)
+                .map_or(0, FilterMatches::len);
         }
+
+        root
     }
-}
 
-pub struct Data<T> {
-    filter: Filter,
-    pub log: CommitLog<T>,
-    pub waiters: Waiters<DataRequest>,
-    meter: SubscriptionMeter,
-}
+    /// Snapshot of filters, topic caches, retained topics and parked readers
+    /// for diagnostics. Only topics, offsets and counts are copied, payloads
+    /// are left out
+    pub fn debug_dump(&self) -> DataLogDump {
+        let filters = self
+            .native
+            .iter()
+            .map(|(filter_idx, data)| FilterDump {
+                filter_idx,
+                filter: data.filter.clone(),
+                compacted: data.compaction.is_some(),
+                next_offset: data.next_offset(),
+                count: data.meter.count,
+                total_size: data.meter.total_size,
+                waiters: data
+                    .waiters
+                    .waiters()
+                    .iter()
+                    .map(|(id, request)| WaiterDump {
+                        connection_id: *id,
+                        cursor: request.cursor,
+                    })
+                    .collect(),
+            })
+            .collect();
 
-impl<T> Data<T>
-where
-    T: Storage + Clone,
-{
-    pub fn new(filter: &str, max_segment_size: usize, max_mem_segments: usize) -> Data<T> {
-        let log = CommitLog::new(max_segment_size, max_mem_segments).unwrap();
+        let publish_filters = self
+            .publish_filters
+            .iter()
+            .map(|(topic, matches)| {
+                let mut matches = matches.as_slice().to_vec();
+                matches.sort_unstable();
+                (topic.clone(), matches)
+            })
+            .collect();
 
-        let waiters = Waiters::with_capacity(10);
-        let metrics = SubscriptionMeter::default();
-        Data {
-            filter: filter.to_owned(),
-            log,
-            waiters,
-            meter: metrics,
+        let mut retained_topics: Vec<Topic> = self.retained_topics().cloned().collect();
+        retained_topics.sort();
+
+        DataLogDump {
+            filters,
+            publish_filters,
+            retained_topics,
         }
     }
 
-    /// Writes to all the filters that are mapped to this publish topic
-    /// and wakes up consumers that are matching this topic (if they exist)
-    pub fn append(
-        &mut self,
-        item: T,
-        notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
-    ) -> (Offset, &Filter) {
-        let size = item.size();
-        let offset = self.log.append(item);
-        if let Some(mut parked) = self.waiters.take() {
-            notifications.append(&mut parked);
-        }
+    /// Shared copies of `topic` for storing a publish on it. The topic has to
+    /// be validated beforehand
+    pub fn intern_topic(&mut self, topic: &str) -> (Topic, Bytes) {
+        self.topics.intern(topic)
+    }
 
-        self.meter.count += 1;
-        self.meter.total_size += size;
+    /// Holds a publish until `due`
+    pub fn delay_publish(&mut self, due: Instant, delayed: DelayedPublish) {
+        *self
+            .delayed_by_client
+            .entry(delayed.client_id.clone())
+            .or_default() += 1;
+        self.delayed_publishes.entry(due).or_default().push(delayed);
+        self.delayed_count += 1;
+    }
 
-        (offset, &self.filter)
+    /// Number of publishes held until they are due
+    #[cfg(test)]
+    pub fn delayed_count(&self) -> usize {
+        self.delayed_count
     }
-}
 
-/// Acks log for a subscription
-#[derive(Debug)]
-pub struct AckLog {
-    // Committed acks per connection. First pkid, last pkid, data
-    committed: VecDeque<Ack>,
-    // Recorded qos 2 publishes
-    recorded: VecDeque<Publish>,
-}
+    /// Number of publishes of `client_id` held until they are due
+    pub fn delayed_count_of(&self, client_id: &str) -> usize {
+        self.delayed_by_client.get(client_id).copied().unwrap_or(0)
+    }
 
-impl AckLog {
-    /// New log
-    pub fn new() -> AckLog {
-        AckLog {
-            committed: VecDeque::with_capacity(100),
+    /// When the next delayed publish is due
+    pub fn next_delayed_due(&self) -> Option<Instant> {
+        self.delayed_publishes.keys().next().copied()
+    }
+
+    /// Takes the delayed publishes due by `now`, in the order they fall due
+    pub fn take_due_publishes(&mut self, now: Instant) -> Vec<DelayedPublish> {
+        let mut due = Vec::new();
+        while let Some(entry) = self.delayed_publishes.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+
+            due.extend(entry.remove());
+        }
+
+        for delayed in due.iter() {
+            if let Some(count) = self.delayed_by_client.get_mut(&delayed.client_id) {
+                *count -= 1;
+                if *count == 0 {
+                    self.delayed_by_client.remove(&delayed.client_id);
+                }
+            }
+        }
+
+        self.delayed_count -= due.len();
+        due
+    }
+
+    pub fn remove_from_retained_publishes(&mut self, topic: &str) {
+        self.retained_publishes.remove(topic);
+        self.topics.release(topic);
+    }
+
+    /// Removes retained messages on all the topics matching `filter` and
+    /// returns the number of messages removed
+    pub fn clear_retained_matching(&mut self, filter: &str) -> usize {
+        let count = self.retained_publishes.len();
+        self.retained_publishes
+            .retain(|topic, _| !matches(topic, filter));
+        self.topics.prune();
+
+        count - self.retained_publishes.len()
+    }
+
+    /// Queues retained messages matching `filter` for delivery to connection
+    /// `id` only, which already reads from the filter. Its cursor is left as
+    /// is, the messages are forwarded ahead of its next read. A parked
+    /// connection is moved to `notifications` to be woken up. Returns the
+    /// number of queued messages
+    pub fn resend_retained(
+        &mut self,
+        filter: &str,
+        id: ConnectionId,
+        notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+    ) -> usize {
+        let Some(&idx) = self.filter_indexes.get(filter) else {
+            return 0;
+        };
+
+        let retained: Vec<PublishData> = self
+            .retained_publishes
+            .iter()
+            .filter(|(topic, _)| matches(topic, filter))
+            .map(|(_, publish)| {
+                let mut publish = publish.clone();
+                publish.publish.retain = true;
+                publish
+            })
+            .collect();
+
+        if retained.is_empty() {
+            return 0;
+        }
+
+        let count = retained.len();
+        let data = self.native.get_mut(idx).unwrap();
+        // Re-subscribing again before delivery doesn't duplicate messages
+        data.resend.insert(id, retained);
+        if let Some(request) = data.waiters.remove(id) {
+            notifications.push_back((id, request));
+        }
+
+        count
+    }
+
+    /// Up to `len` retained messages queued for connection `id` by
+    /// `resend_retained`, forwarded at `cursor`. The rest stay queued for the
+    /// next read
+    pub fn take_resend(
+        &mut self,
+        filter_idx: FilterIdx,
+        id: ConnectionId,
+        cursor: Offset,
+        len: u64,
+        preserve_retain: bool,
+    ) -> Vec<(PubWithProp, Offset)> {
+        let Some(data) = self.native.get_mut(filter_idx) else {
+            return Vec::new();
+        };
+        let Some(resend) = data.resend.get_mut(&id) else {
+            return Vec::new();
+        };
+
+        let len = resend.len().min(len as usize);
+        let o = resend
+            .drain(..len)
+            .map(|publish| (publish, cursor))
+            .collect();
+        if resend.is_empty() {
+            data.resend.remove(&id);
+        }
+
+        Self::deliverable(o, preserve_retain)
+    }
+
+    pub fn handle_retained_messages(
+        &mut self,
+        filter: &str,
+        notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+    ) {
+        trace!(info = "retain-msg", filter = &filter);
+
+        let idx = self.filter_indexes.get(filter).unwrap();
+
+        let datalog = self.native.get_mut(*idx).unwrap();
+
+        for (topic, publish) in self.retained_publishes.iter_mut() {
+            if matches(topic, filter) {
+                // Retained messages are always delivered with RETAIN set on a new
+                // subscription so that clients can tell them apart from live ones
+                let mut publish = publish.clone();
+                publish.publish.retain = true;
+                if let Err(e) = datalog.append(publish, notifications) {
+                    warn!(reason = ?e, "Retained message of {topic} not delivered on {filter}");
+                }
+            }
+        }
+    }
+}
+
+/// Routing state of a `DataLog` as returned by `DataLog::debug_dump`.
+/// Serializable with the `debug-dump` feature
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug-dump", derive(serde::Serialize))]
+pub struct DataLogDump {
+    /// Filters in the order of their index
+    pub filters: Vec<FilterDump>,
+    /// Cached filter indices matching every published topic, sorted
+    pub publish_filters: BTreeMap<Topic, Vec<FilterIdx>>,
+    /// Topics with a retained message, sorted
+    pub retained_topics: Vec<Topic>,
+}
+
+/// Level of the topic namespace. The root node stands for the empty prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TopicNode {
+    /// Topic ending at this level has a retained message
+    pub retained: bool,
+    /// Number of filters matching the topic ending at this level
+    pub filters: usize,
+    /// Next levels by name
+    pub children: BTreeMap<String, TopicNode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug-dump", derive(serde::Serialize))]
+pub struct FilterDump {
+    pub filter_idx: FilterIdx,
+    pub filter: Filter,
+    pub compacted: bool,
+    pub next_offset: Offset,
+    /// Number and total size of publishes appended to the filter
+    pub count: usize,
+    pub total_size: usize,
+    /// Connections parked on the filter after catching up
+    pub waiters: Vec<WaiterDump>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "debug-dump", derive(serde::Serialize))]
+pub struct WaiterDump {
+    pub connection_id: ConnectionId,
+    pub cursor: Offset,
+}
+
+/// Indexes of filters matching a publish topic. Topics matching zero or one
+/// filter, which is the common case for concrete subscriptions, are stored
+/// without a heap allocation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum FilterMatches {
+    #[default]
+    Zero,
+    One(FilterIdx),
+    Many(Vec<FilterIdx>),
+}
+
+impl FilterMatches {
+    pub fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        matches!(self, FilterMatches::Zero)
+    }
+
+    pub fn as_slice(&self) -> &[FilterIdx] {
+        match self {
+            FilterMatches::Zero => &[],
+            FilterMatches::One(idx) => std::slice::from_ref(idx),
+            FilterMatches::Many(v) => v,
+        }
+    }
+
+    /// Adds a filter unless it's already matched, so that a single ingest
+    /// never lands on the same filter twice, whatever path led to it
+    pub fn push(&mut self, filter_idx: FilterIdx) {
+        if self.as_slice().contains(&filter_idx) {
+            warn!(filter_idx, "Suppressed repeated route to the same filter");
+            return;
+        }
+
+        match self {
+            FilterMatches::Zero => *self = FilterMatches::One(filter_idx),
+            FilterMatches::One(idx) => *self = FilterMatches::Many(vec![*idx, filter_idx]),
+            FilterMatches::Many(v) => v.push(filter_idx),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn into_vec(self) -> Vec<FilterIdx> {
+        match self {
+            FilterMatches::Zero => Vec::new(),
+            FilterMatches::One(idx) => vec![idx],
+            FilterMatches::Many(v) => v,
+        }
+    }
+}
+
+impl FromIterator<FilterIdx> for FilterMatches {
+    fn from_iter<I: IntoIterator<Item = FilterIdx>>(iter: I) -> Self {
+        let mut o = FilterMatches::Zero;
+        for filter_idx in iter {
+            o.push(filter_idx);
+        }
+
+        o
+    }
+}
+
+pub struct Data<T> {
+    filter: Filter,
+    pub log: CommitLog<T>,
+    pub waiters: Waiters<DataRequest>,
+    pub meter: SubscriptionMeter,
+    /// Deliveries to every connection reading from the filter
+    subscribers: HashMap<ConnectionId, SubscriberMeter>,
+    /// Retained messages to be delivered to a single connection ahead of
+    /// the log, see `DataLog::resend_retained`
+    resend: HashMap<ConnectionId, Vec<PublishData>>,
+    /// Cursors of the connected subscribers, updated as they read
+    readers: Readers,
+    /// Latest entry of every topic, used instead of `log` by compacted filters
+    compaction: Option<Compaction<T>>,
+    /// Recently seen publish ids of deduplicated filters
+    dedup: Option<Dedup>,
+    /// Collapse consecutive publishes on a topic to the latest when read
+    conflated: bool,
+}
+
+/// Cursors of the connections reading from a filter, ordered so that the
+/// slowest and the fastest reader are found without visiting all of them.
+/// Cursors only move forward, see [`DataRequest::update_cursor`]
+#[derive(Debug, Default)]
+struct Readers {
+    cursors: HashMap<ConnectionId, Offset>,
+    /// Number of readers at every cursor
+    order: BTreeMap<Offset, usize>,
+    /// Cursor of the last reader to leave, while there are no readers
+    held: Option<Offset>,
+}
+
+impl Readers {
+    fn update(&mut self, id: ConnectionId, cursor: Offset) {
+        match self.cursors.insert(id, cursor) {
+            Some(previous) if previous == cursor => return,
+            Some(previous) => self.release(previous),
+            None => (),
+        }
+
+        *self.order.entry(cursor).or_default() += 1;
+        self.held = None;
+    }
+
+    fn remove(&mut self, id: ConnectionId) {
+        if let Some(cursor) = self.cursors.remove(&id) {
+            self.release(cursor);
+            if self.cursors.is_empty() {
+                self.held = Some(cursor);
+            }
+        }
+    }
+
+    fn release(&mut self, cursor: Offset) {
+        if let Some(count) = self.order.get_mut(&cursor) {
+            *count -= 1;
+            if *count == 0 {
+                self.order.remove(&cursor);
+            }
+        }
+    }
+
+    fn fastest(&self) -> Option<Offset> {
+        self.order.keys().next_back().copied()
+    }
+
+    fn slowest(&self) -> Option<Offset> {
+        self.order.keys().next().copied()
+    }
+}
+
+/// Ids of the last `window` publishes, oldest first
+struct Dedup {
+    property: String,
+    window: usize,
+    ids: VecDeque<String>,
+    seen: HashSet<String>,
+}
+
+/// Entries of a compacted filter ordered by the offset at which they were
+/// last written. Writing to a topic again moves its entry to a new offset
+struct Compaction<T> {
+    entries: BTreeMap<u64, T>,
+    /// Offset and size of the entry of every topic
+    offsets: HashMap<Bytes, (u64, usize)>,
+    next_offset: u64,
+    /// Size of all the entries
+    total_size: usize,
+    /// Most topics kept, the one written longest ago is dropped beyond this
+    max_keys: usize,
+}
+
+impl Data<PublishData> {
+    pub fn new(filter: &str, config: &RouterConfig) -> Data<PublishData> {
+        let (max_segment_size, max_segment_count) = config
+            .filter_storage
+            .iter()
+            .find(|storage| storage.filters.iter().any(|f| f == filter))
+            .map_or(
+                (config.max_segment_size, config.max_segment_count),
+                |storage| (storage.max_segment_size, storage.max_segment_count),
+            );
+        let log = CommitLog::new(max_segment_size, max_segment_count).unwrap();
+
+        let waiters = Waiters::with_capacity(10);
+        let metrics = SubscriptionMeter::default();
+        let compacted = config.compacted_filters.iter().any(|f| f == filter);
+        let conflated = config.conflated_filters.iter().any(|f| f == filter);
+        let compaction = compacted.then(|| Compaction {
+            entries: BTreeMap::new(),
+            offsets: HashMap::new(),
+            next_offset: 0,
+            total_size: 0,
+            max_keys: config.max_compacted_keys.unwrap_or(MAX_COMPACTED_KEYS),
+        });
+        let dedup = config
+            .dedup
+            .as_ref()
+            .filter(|dedup| dedup.filters.iter().any(|f| f == filter))
+            .map(|dedup| Dedup {
+                property: dedup.property.clone(),
+                window: dedup.window,
+                ids: VecDeque::with_capacity(dedup.window),
+                seen: HashSet::with_capacity(dedup.window),
+            });
+
+        Data {
+            filter: Filter::new_unchecked(filter),
+            log,
+            waiters,
+            meter: metrics,
+            subscribers: HashMap::new(),
+            resend: HashMap::new(),
+            readers: Readers::default(),
+            compaction,
+            dedup,
+            conflated,
+        }
+    }
+
+    pub fn filter(&self) -> &Filter {
+        &self.filter
+    }
+
+    /// Records the id of a publish on a deduplicated filter. Returns true if
+    /// the id was already seen among the last `window` publishes
+    pub fn is_duplicate(&mut self, properties: Option<&PublishProperties>) -> bool {
+        let Some(dedup) = &mut self.dedup else {
+            return false;
+        };
+
+        let id = properties.and_then(|p| {
+            p.user_properties
+                .iter()
+                .find(|(key, _)| key == &dedup.property)
+        });
+
+        // Publishes without an id are never duplicates
+        let Some((_, id)) = id else {
+            return false;
+        };
+
+        if dedup.seen.contains(id) {
+            return true;
+        }
+
+        if dedup.ids.len() >= dedup.window {
+            if let Some(oldest) = dedup.ids.pop_front() {
+                dedup.seen.remove(&oldest);
+            }
+        }
+
+        dedup.ids.push_back(id.clone());
+        dedup.seen.insert(id.clone());
+        false
+    }
+
+    /// Offset at which the next entry will be written
+    pub fn next_offset(&self) -> Offset {
+        match &self.compaction {
+            Some(compaction) => (0, compaction.next_offset),
+            None => self.log.next_offset(),
+        }
+    }
+
+    /// Offset from which new subscriptions start reading. Compacted filters
+    /// are read from the start to get the current state of every topic
+    pub fn start_offset(&self) -> Offset {
+        match &self.compaction {
+            Some(_) => (0, 0),
+            None => self.log.next_offset(),
+        }
+    }
+
+    /// Reads `len` entries from `cursor`. Entries of compacted filters which
+    /// were overwritten since are skipped
+    pub fn readv(
+        &self,
+        cursor: Offset,
+        len: u64,
+        out: &mut Vec<(PublishData, Offset)>,
+    ) -> io::Result<Position> {
+        let Some(compaction) = &self.compaction else {
+            return self.log.readv(cursor, len, out);
+        };
+
+        let entries = compaction.entries.range(cursor.1..).take(len as usize);
+        out.extend(entries.map(|(offset, entry)| (entry.clone(), (0, *offset))));
+
+        let end = match out.last() {
+            Some((_, (_, offset))) if *offset + 1 < compaction.next_offset => (0, *offset + 1),
+            _ => {
+                let end = (0, compaction.next_offset.max(cursor.1));
+                return Ok(Position::Done { start: cursor, end });
+            }
+        };
+
+        Ok(Position::Next { start: cursor, end })
+    }
+
+    /// Reads up to `len` entries in `[start, end)` backwards, newest first
+    pub fn readv_rev(
+        &self,
+        start: Offset,
+        end: Offset,
+        len: u64,
+        out: &mut Vec<(PublishData, Offset)>,
+    ) {
+        let Some(compaction) = &self.compaction else {
+            return self.log.readv_rev(start, end, len, out);
+        };
+
+        let entries = compaction.entries.range(start.1..end.1).rev();
+        let entries = entries.take(len as usize);
+        out.extend(entries.map(|(offset, entry)| (entry.clone(), (0, *offset))));
+    }
+
+    /// Entries from `cursor` onwards, without cloning them
+    pub fn iter_from(
+        &self,
+        cursor: Offset,
+    ) -> Box<dyn Iterator<Item = (&PublishData, Offset)> + '_> {
+        match &self.compaction {
+            Some(compaction) => Box::new(
+                compaction
+                    .entries
+                    .range(cursor.1..)
+                    .map(|(offset, entry)| (entry, (0, *offset))),
+            ),
+            None => Box::new(self.log.iter_from(cursor)),
+        }
+    }
+
+    /// Entry at `offset`, `None` if it was dropped by retention or, for
+    /// compacted filters, overwritten by a newer publish on the same topic
+    pub fn get(&self, offset: Offset) -> Option<&PublishData> {
+        match &self.compaction {
+            Some(compaction) => compaction.entries.get(&offset.1),
+            None => self.log.get(offset),
+        }
+    }
+
+    /// Up to `n` latest entries, oldest first. Only the latest entry of every
+    /// topic is left in compacted filters
+    pub fn tail(&self, n: usize) -> Vec<(Offset, Publish)> {
+        match &self.compaction {
+            Some(compaction) => {
+                let mut o: Vec<_> = compaction
+                    .entries
+                    .iter()
+                    .rev()
+                    .take(n)
+                    .map(|(offset, data)| ((0, *offset), data.publish.clone()))
+                    .collect();
+                o.reverse();
+                o
+            }
+            None => self
+                .log
+                .tail(n)
+                .into_iter()
+                .map(|(data, offset)| (offset, data.publish))
+                .collect(),
+        }
+    }
+
+    pub fn last(&self) -> Option<PublishData> {
+        match &self.compaction {
+            Some(compaction) => compaction.entries.values().next_back().cloned(),
+            None => self.log.last(),
+        }
+    }
+
+    /// Estimate of resident bytes. See `CommitLog::memory_usage`
+    pub fn memory_usage(&self) -> usize {
+        let compacted = self.compaction.as_ref().map_or(0, |compaction| {
+            compaction.entries.len() * std::mem::size_of::<PublishData>() + compaction.total_size
+        });
+
+        self.log.memory_usage() + compacted
+    }
+
+    /// Writes to all the filters that are mapped to this publish topic
+    /// and wakes up consumers that are matching this topic (if they exist)
+    pub fn append(
+        &mut self,
+        item: PublishData,
+        notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+    ) -> Result<(AppendPosition, &Filter), AppendError> {
+        self.append_above(item, notifications, None)
+    }
+
+    /// Whether appending evicts the oldest segment of the log. See
+    /// [`CommitLog::is_full`]
+    pub fn is_full(&self) -> bool {
+        self.compaction.is_none() && self.log.is_full()
+    }
+
+    /// Whether a publish of `size` bytes can be appended. See
+    /// [`CommitLog::fits`]
+    pub fn fits(&self, size: usize) -> bool {
+        self.compaction.is_some() || self.log.fits(size)
+    }
+
+    /// Appends like `append`, but keeps segments holding publishes at or after
+    /// `floor` instead of evicting them
+    pub fn append_above(
+        &mut self,
+        item: PublishData,
+        notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+        floor: Option<Offset>,
+    ) -> Result<(AppendPosition, &Filter), AppendError> {
+        let size = item.size();
+        let position = match &mut self.compaction {
+            Some(compaction) => {
+                // Replace previous entry of this topic
+                let offset = compaction.next_offset;
+                let topic = item.publish.topic.clone();
+                if let Some((previous, previous_size)) =
+                    compaction.offsets.insert(topic, (offset, size))
+                {
+                    compaction.entries.remove(&previous);
+                    compaction.total_size -= previous_size;
+                }
+
+                compaction.entries.insert(offset, item);
+                compaction.total_size += size;
+                compaction.next_offset += 1;
+
+                if compaction.offsets.len() > compaction.max_keys {
+                    if let Some((_, oldest)) = compaction.entries.pop_first() {
+                        let (_, size) = compaction.offsets.remove(&oldest.publish.topic).unwrap();
+                        compaction.total_size -= size;
+                        self.meter.messages_dropped += 1;
+                    }
+                }
+
+                AppendPosition { segment: 0, offset }
+            }
+            None => {
+                let (position, eviction) = self.log.append_bounded(item, size, floor)?;
+                self.meter.segments_evicted += eviction.segments;
+                self.meter.messages_dropped += eviction.items as usize;
+                position
+            }
+        };
+
+        if let Some(mut parked) = self.waiters.take() {
+            notifications.append(&mut parked);
+        }
+
+        self.meter.count += 1;
+        self.meter.total_size += size;
+
+        Ok((position, &self.filter))
+    }
+}
+
+/// Acks log for a subscription
+#[derive(Debug)]
+pub struct AckLog {
+    // Committed acks per connection. First pkid, last pkid, data
+    committed: VecDeque<Ack>,
+    // Recorded qos 2 publishes
+    recorded: VecDeque<Publish>,
+    // Pkids of `recorded` publishes
+    recorded_pkids: HashSet<u16>,
+    // Pkids of publishes released with PUBCOMP, until the client reuses them
+    released_pkids: HashSet<u16>,
+    // Keep runs of consecutive PUBACKs in one drain. See `drain_coalesced`
+    coalesce_pubacks: bool,
+    // When acks in `committed` were queued, if latency is tracked
+    enqueued: Option<VecDeque<Instant>>,
+    latency: AckLatency,
+}
+
+impl AckLog {
+    /// New log
+    pub fn new() -> AckLog {
+        AckLog {
+            committed: VecDeque::with_capacity(100),
             recorded: VecDeque::with_capacity(100),
+            recorded_pkids: HashSet::new(),
+            released_pkids: HashSet::new(),
+            coalesce_pubacks: false,
+            enqueued: None,
+            latency: AckLatency::default(),
+        }
+    }
+
+    fn push(&mut self, ack: Ack) {
+        self.committed.push_back(ack);
+        if let Some(enqueued) = &mut self.enqueued {
+            enqueued.push_back(Instant::now());
+        }
+    }
+
+    pub fn connack(&mut self, id: ConnectionId, ack: ConnAck, props: Option<ConnAckProperties>) {
+        let ack = Ack::ConnAck(id, ack, props);
+        self.push(ack);
+    }
+
+    pub fn suback(&mut self, ack: SubAck, props: Option<SubAckProperties>) {
+        let ack = match props {
+            Some(props) => Ack::SubAckWithProperties(ack, props),
+            None => Ack::SubAck(ack),
+        };
+        self.push(ack);
+    }
+
+    pub fn puback(&mut self, ack: PubAck) {
+        debug_assert_ne!(ack.pkid, 0, "packet identifier can't be 0");
+        let ack = Ack::PubAck(ack);
+        self.push(ack);
+    }
+
+    /// Records a QoS 2 publish and queues its PUBREC. Returns false if a
+    /// publish with the same pkid is already recorded, which happens when the
+    /// client retransmits (DUP) before receiving PUBREC. In that case only
+    /// the PUBREC is sent again so that the publish isn't delivered twice
+    // TODO: Remove this allow once we support QoS::ExactlyOnce
+    #[allow(dead_code)]
+    pub fn pubrec(&mut self, publish: Publish, ack: PubRec) -> bool {
+        debug_assert_ne!(ack.pkid, 0, "packet identifier can't be 0");
+        let duplicate = !self.recorded_pkids.insert(publish.pkid);
+        if duplicate {
+            trace!(
+                pkid = publish.pkid,
+                dup = publish.dup,
+                "Duplicate QoS 2 publish"
+            );
+        } else {
+            self.released_pkids.remove(&publish.pkid);
+            self.recorded.push_back(publish);
+        }
+
+        let ack = Ack::PubRec(ack);
+        self.push(ack);
+        !duplicate
+    }
+
+    pub fn pubrel(&mut self, ack: PubRel) {
+        debug_assert_ne!(ack.pkid, 0, "packet identifier can't be 0");
+        let ack = Ack::PubRel(ack);
+        self.push(ack);
+    }
+
+    /// Releases the recorded QoS 2 publish with pkid of given PUBCOMP. The
+    /// PUBCOMP is queued in any case, so a client which sends PUBREL again
+    /// because it missed the first PUBCOMP gets another one
+    pub fn pubcomp(&mut self, ack: PubComp) -> Released {
+        debug_assert_ne!(ack.pkid, 0, "packet identifier can't be 0");
+        let pkid = ack.pkid;
+        let ack = Ack::PubComp(ack);
+        self.push(ack);
+
+        if self.released_pkids.contains(&pkid) {
+            return Released::Duplicate;
+        }
+
+        if !self.recorded_pkids.remove(&pkid) {
+            return Released::Unknown;
+        }
+
+        let index = self.recorded.iter().position(|p| p.pkid == pkid).unwrap();
+        self.released_pkids.insert(pkid);
+        Released::Publish(self.recorded.remove(index).unwrap())
+    }
+
+    pub fn pingresp(&mut self, ack: PingResp) {
+        let ack = Ack::PingResp(ack);
+        self.push(ack);
+    }
+
+    pub fn unsuback(&mut self, ack: UnsubAck) {
+        let ack = Ack::UnsubAck(ack);
+        self.push(ack);
+    }
+
+    /// Pending acks, oldest first. Acks taken off here aren't timed, use
+    /// `drain` when latency is tracked
+    #[allow(dead_code)]
+    pub fn readv(&mut self) -> &mut VecDeque<Ack> {
+        &mut self.committed
+    }
+
+    /// Keeps runs of consecutive PUBACKs together in `drain_coalesced` so that
+    /// they are flushed to the client at once
+    pub fn set_coalesce_pubacks(&mut self, coalesce: bool) {
+        self.coalesce_pubacks = coalesce;
+    }
+
+    /// Takes up to `max` pending acks, oldest first, to be written out in one
+    /// buffer. With PUBACK coalescing, a drain which would end in the middle of
+    /// a run of PUBACKs stops before the run instead, unless the run alone
+    /// doesn't fit in `max`
+    pub fn drain_coalesced(&mut self, max: usize) -> Vec<Ack> {
+        let is_puback = |ack: &Ack| matches!(ack, Ack::PubAck(_) | Ack::PubAckWithProperties(..));
+
+        let mut len = max.min(self.committed.len());
+        if self.coalesce_pubacks && len < self.committed.len() && is_puback(&self.committed[len]) {
+            let run_start = (0..len)
+                .rev()
+                .take_while(|&i| is_puback(&self.committed[i]))
+                .last();
+
+            if let Some(start) = run_start.filter(|&start| start > 0) {
+                len = start;
+            }
+        }
+
+        self.take(len, Instant::now())
+    }
+
+    /// Takes all the pending acks, oldest first
+    pub fn drain(&mut self) -> Vec<Ack> {
+        self.take(self.committed.len(), Instant::now())
+    }
+
+    fn take(&mut self, len: usize, now: Instant) -> Vec<Ack> {
+        if let Some(enqueued) = &mut self.enqueued {
+            // Acks taken off `readv` weren't timed. They are always the oldest
+            let untimed = enqueued.len().saturating_sub(self.committed.len());
+            enqueued.drain(..untimed);
+
+            for at in enqueued.drain(..len) {
+                self.latency.record(now.saturating_duration_since(at));
+            }
+        }
+
+        self.committed.drain(..len).collect()
+    }
+
+    /// Times how long acks are queued before being drained, see
+    /// `take_ack_latency_stats`. Off by default
+    pub fn set_track_latency(&mut self, track: bool) {
+        self.enqueued = match track {
+            true => Some(self.committed.iter().map(|_| Instant::now()).collect()),
+            false => None,
+        };
+    }
+
+    /// Time acks spent queued before being drained since the last call, if
+    /// latency is tracked
+    pub fn take_ack_latency_stats(&mut self) -> Option<AckLatency> {
+        self.enqueued.as_ref()?;
+        Some(std::mem::take(&mut self.latency))
+    }
+
+    /// No acks are pending delivery and no QoS 2 publishes await release
+    pub fn is_empty(&self) -> bool {
+        self.committed.is_empty() && self.recorded.is_empty()
+    }
+
+    /// QoS 1 and 2 publishes of the client which the broker hasn't finished
+    /// acknowledging: PUBACKs not sent yet and QoS 2 publishes waiting for PUBREL
+    pub fn unacked_publishes(&self) -> usize {
+        let pubacks = self
+            .committed
+            .iter()
+            .filter(|ack| matches!(ack, Ack::PubAck(_) | Ack::PubAckWithProperties(..)))
+            .count();
+
+        pubacks + self.recorded.len()
+    }
+
+    /// Snapshot of unfinished QoS 2 handshakes, saved along with persistent
+    /// sessions and handed to [`AckLog::import_state`] when the client reconnects
+    pub fn export_state(&self) -> AckLogState {
+        let pending_pubrecs = self
+            .committed
+            .iter()
+            .filter_map(|ack| match ack {
+                Ack::PubRec(ack) => Some(ack.pkid),
+                Ack::PubRecWithProperties(ack, _) => Some(ack.pkid),
+                _ => None,
+            })
+            .collect();
+
+        AckLogState {
+            recorded: self.recorded.iter().cloned().collect(),
+            pending_pubrecs,
+        }
+    }
+
+    /// Restores handshakes exported by [`AckLog::export_state`]. PUBRECs which
+    /// weren't delivered before the export are queued again
+    pub fn import_state(&mut self, state: AckLogState) {
+        for publish in state.recorded {
+            if self.recorded_pkids.insert(publish.pkid) {
+                self.released_pkids.remove(&publish.pkid);
+                self.recorded.push_back(publish);
+            }
+        }
+
+        for pkid in state.pending_pubrecs {
+            let ack = PubRec {
+                pkid,
+                reason: PubRecReason::Success,
+            };
+            self.push(Ack::PubRec(ack));
+        }
+    }
+}
+
+/// Time acks of an [`AckLog`] spent queued before being drained
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AckLatency {
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub total: Duration,
+}
+
+impl AckLatency {
+    fn record(&mut self, latency: Duration) {
+        self.min = match self.count {
+            0 => latency,
+            _ => self.min.min(latency),
+        };
+        self.max = self.max.max(latency);
+        self.total += latency;
+        self.count += 1;
+    }
+
+    /// Adds up latencies recorded by another log
+    pub fn merge(&mut self, other: &AckLatency) {
+        if other.count == 0 {
+            return;
+        }
+
+        self.min = match self.count {
+            0 => other.min,
+            _ => self.min.min(other.min),
+        };
+        self.max = self.max.max(other.max);
+        self.total += other.total;
+        self.count += other.count;
+    }
+
+    pub fn avg(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_nanos((self.total.as_nanos() / u128::from(count)) as u64),
+        }
+    }
+}
+
+/// Outcome of releasing a QoS 2 publish with [`AckLog::pubcomp`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Released {
+    /// Publish released for the first time, to be appended to the commitlog
+    Publish(Publish),
+    /// Publish was already released. The client sent PUBREL again
+    Duplicate,
+    /// No publish was recorded with the pkid
+    Unknown,
+}
+
+/// Unfinished QoS 2 handshakes of an [`AckLog`]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AckLogState {
+    /// QoS 2 publishes waiting for PUBREL, in the order they were received
+    pub recorded: Vec<Publish>,
+    /// Pkids of PUBRECs which were not yet sent to the client
+    pub pending_pubrecs: Vec<u16>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        AckLog, ConfigError, DataLog, FilterMatches, Position, Released, RetainError, WaiterDump,
+    };
+    use crate::protocol::{
+        PingResp, PubAck, PubAckReason, PubComp, PubCompReason, PubRec, PubRecReason, PubRel,
+        PubRelReason, Publish, QoS, SubAck, SubscribeReasonCode,
+    };
+    use crate::router::fixtures::{config, request};
+    use crate::router::Ack;
+    use crate::{DedupSettings, FilterStorage, RouterConfig, RouterConfigPatch};
+    use bytes::Bytes;
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn publish_filters_updating_correctly_on_new_topic_subscription() {
+        let mut data = DataLog::new(config()).unwrap();
+        data.next_native_offset("topic/a");
+        data.matches("topic/a");
+
+        data.next_native_offset("topic/+");
+
+        assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn matches_computed_before_a_filter_change_are_not_cached() {
+        let mut data = DataLog::new(config()).unwrap();
+        let (wildcard, _) = data.next_native_offset("topic/+");
+
+        // Filter is added while matches of the topic are being computed
+        let (stale, generation) = data.compute_matches("topic/a");
+        let (exact, _) = data.next_native_offset("topic/a");
+        assert!(!data.cache_matches("topic/a", stale, generation));
+        assert!(!data.publish_filters.contains_key("topic/a"));
+
+        let mut matched = data.matches("topic/a").unwrap().into_vec();
+        matched.sort_unstable();
+        assert_eq!(matched, vec![wildcard, exact]);
+
+        // Computing and caching again leaves the same entry
+        let (fresh, generation) = data.compute_matches("topic/a");
+        assert!(data.cache_matches("topic/a", fresh, generation));
+        let mut cached = data.publish_filters["topic/a"].clone().into_vec();
+        cached.sort_unstable();
+        assert_eq!(cached, matched);
+        assert_eq!(data.publish_filters.len(), 1);
+
+        let (stale, generation) = data.compute_matches("topic/a");
+        data.remove_filter("topic/a");
+        assert!(!data.cache_matches("topic/a", stale, generation));
+        assert_eq!(data.publish_filters["topic/a"].as_slice(), &[wildcard]);
+    }
+
+    #[test]
+    fn single_filter_matches_are_held_inline() {
+        let mut data = DataLog::new(config()).unwrap();
+        let (idx, _) = data.next_native_offset("topic/a");
+
+        // Cached and returned without a heap allocated list of filters
+        for _ in 0..3 {
+            assert_eq!(data.matches("topic/a"), Some(FilterMatches::One(idx)));
+        }
+
+        let (wildcard, _) = data.next_native_offset("topic/+");
+        let mut matches = data.matches("topic/a").unwrap().into_vec();
+        matches.sort();
+        assert_eq!(matches, vec![idx, wildcard]);
+    }
+
+    #[test]
+    fn expired_matches_are_recomputed() {
+        let config = RouterConfig {
+            match_cache_ttl_ms: Some(1000),
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let (idx, _) = data.next_native_offset("topic/+");
+        assert_eq!(data.matches("topic/a"), Some(FilterMatches::One(idx)));
+
+        // Stale route to a filter the topic doesn't match
+        data.publish_filters.get_mut("topic/a").unwrap().push(100);
+        assert_eq!(data.matches("topic/a").unwrap().len(), 2);
+        assert_eq!(data.match_cache_stats(), (1, 1));
+
+        let matched = Instant::now().checked_sub(Duration::from_secs(2)).unwrap();
+        *data.match_cached_at.get_mut("topic/a").unwrap() = matched;
+        assert_eq!(data.matches("topic/a"), Some(FilterMatches::One(idx)));
+        assert_eq!(data.match_cache_stats(), (1, 2));
+        assert!(data.match_cached_at["topic/a"] > matched);
+    }
+
+    #[test]
+    fn expired_matches_are_swept_without_lookups() {
+        let config = RouterConfig {
+            match_cache_ttl_ms: Some(1000),
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        data.next_native_offset("topic/+");
+        data.matches("topic/a");
+        let cached = Instant::now();
+        data.matches("topic/b");
+
+        data.expire_matches(cached + Duration::from_millis(999));
+        assert_eq!(data.publish_filters.len(), 2);
+
+        // topic/a is cached again and expires a second later than topic/b
+        let recached = data.match_cached_at["topic/a"] + Duration::from_secs(1);
+        *data.match_cached_at.get_mut("topic/a").unwrap() = recached;
+        data.match_expiry
+            .push_back((recached, "topic/a".to_owned()));
+        data.expire_matches(cached + Duration::from_millis(1500));
+        assert_eq!(data.publish_filters.len(), 1);
+        assert!(data.publish_filters.contains_key("topic/a"));
+        assert_eq!(data.topics.len(), 1);
+
+        data.expire_matches(recached + Duration::from_secs(1));
+        assert!(data.publish_filters.is_empty());
+        assert!(data.match_expiry.is_empty());
+        assert_eq!(data.topics.len(), 0);
+    }
+
+    #[test]
+    fn publish_filters_updating_correctly_on_new_publish() {
+        let mut data = DataLog::new(config()).unwrap();
+        data.next_native_offset("+/+");
+
+        data.matches("topic/a");
+
+        assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn retained_deliveries_carry_retain_flag_unlike_live_publishes() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = data.next_native_offset("topic/+");
+
+        let retained = Publish::new("topic/a", "retained", true);
+        data.insert_to_retained_publishes(retained, None, "topic/a".parse().unwrap())
+            .unwrap();
+        data.handle_retained_messages("topic/+", &mut notifications);
+
+        let live = Publish::new("topic/b", "live", true);
+        data.native
+            .get_mut(idx)
+            .unwrap()
+            .append((live, None).into(), &mut notifications)
+            .unwrap();
+
+        let (_, o) = data.native_readv(idx, cursor, 10, false).unwrap();
+        let retains: Vec<bool> = o.iter().map(|((p, _), _)| p.retain).collect();
+        assert_eq!(retains, vec![true, false]);
+
+        // Retain As Published forwards RETAIN as set by the publisher
+        let (_, o) = data.native_readv(idx, cursor, 10, true).unwrap();
+        let retains: Vec<bool> = o.iter().map(|((p, _), _)| p.retain).collect();
+        assert_eq!(retains, vec![true, true]);
+    }
+
+    #[test]
+    fn duplicate_qos2_publish_is_recorded_once() {
+        let mut ackslog = AckLog::new();
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::ExactlyOnce;
+        publish.pkid = 1;
+        let pubrec = PubRec {
+            pkid: 1,
+            reason: PubRecReason::Success,
+        };
+
+        assert!(ackslog.pubrec(publish.clone(), pubrec.clone()));
+
+        publish.dup = true;
+        assert!(!ackslog.pubrec(publish, pubrec.clone()));
+        assert_eq!(ackslog.recorded.len(), 1);
+
+        let acks: Vec<_> = ackslog.readv().drain(..).collect();
+        assert_eq!(acks.len(), 2);
+        for ack in acks {
+            assert!(matches!(ack, Ack::PubRec(ack) if ack == pubrec));
         }
     }
 
-    pub fn connack(&mut self, id: ConnectionId, ack: ConnAck, props: Option<ConnAckProperties>) {
-        let ack = Ack::ConnAck(id, ack, props);
-        self.committed.push_back(ack);
+    #[test]
+    fn qos2_handshake_resumes_after_state_import() {
+        let mut ackslog = AckLog::new();
+        for pkid in [1, 2] {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::ExactlyOnce;
+            publish.pkid = pkid;
+            let pubrec = PubRec {
+                pkid,
+                reason: PubRecReason::Success,
+            };
+            ackslog.pubrec(publish, pubrec);
+        }
+
+        // PUBREC of 1 reached the client before the export, PUBREC of 2 didn't
+        ackslog.readv().pop_front();
+        let state = ackslog.export_state();
+        assert_eq!(state.pending_pubrecs, vec![2]);
+        drop(ackslog);
+
+        let mut ackslog = AckLog::new();
+        ackslog.import_state(state.clone());
+        assert_eq!(ackslog.export_state(), state);
+
+        let acks: Vec<_> = ackslog.readv().drain(..).collect();
+        assert!(matches!(&acks[..], [Ack::PubRec(ack)] if ack.pkid == 2));
+
+        // Client releases out of order
+        for pkid in [2, 1] {
+            let pubrel = PubRel {
+                pkid,
+                reason: PubRelReason::Success,
+            };
+            ackslog.pubrel(pubrel);
+            let pubcomp = PubComp {
+                pkid,
+                reason: PubCompReason::Success,
+            };
+            let released = ackslog.pubcomp(pubcomp);
+            assert!(matches!(released, Released::Publish(publish) if publish.pkid == pkid));
+        }
+
+        let pubcomp = PubComp {
+            pkid: 1,
+            reason: PubCompReason::Success,
+        };
+        assert_eq!(ackslog.pubcomp(pubcomp), Released::Duplicate);
     }
 
-    pub fn suback(&mut self, ack: SubAck) {
-        let ack = Ack::SubAck(ack);
-        self.committed.push_back(ack);
+    #[test]
+    fn duplicate_pubrel_is_completed_again() {
+        let mut ackslog = AckLog::new();
+        for pkid in [1, 2] {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::ExactlyOnce;
+            publish.pkid = pkid;
+            let pubrec = PubRec {
+                pkid,
+                reason: PubRecReason::Success,
+            };
+            ackslog.pubrec(publish, pubrec);
+        }
+        ackslog.readv().clear();
+
+        let pubcomp = PubComp {
+            pkid: 1,
+            reason: PubCompReason::Success,
+        };
+        let released = ackslog.pubcomp(pubcomp.clone());
+        assert!(matches!(released, Released::Publish(publish) if publish.pkid == 1));
+
+        // PUBREL sent again after the publish was released
+        assert_eq!(ackslog.pubcomp(pubcomp.clone()), Released::Duplicate);
+        let acks: Vec<_> = ackslog.readv().drain(..).collect();
+        assert_eq!(acks.len(), 2);
+        for ack in acks {
+            assert!(matches!(ack, Ack::PubComp(ack) if ack == pubcomp));
+        }
+
+        // Publish of 2 is still waiting for its PUBREL
+        assert_eq!(ackslog.export_state().recorded.len(), 1);
+        let pubcomp = PubComp {
+            pkid: 3,
+            reason: PubCompReason::Success,
+        };
+        assert_eq!(ackslog.pubcomp(pubcomp), Released::Unknown);
+
+        // Reusing a pkid starts a new handshake
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::ExactlyOnce;
+        publish.pkid = 1;
+        let pubrec = PubRec {
+            pkid: 1,
+            reason: PubRecReason::Success,
+        };
+        assert!(ackslog.pubrec(publish, pubrec));
+        let pubcomp = PubComp {
+            pkid: 1,
+            reason: PubCompReason::Success,
+        };
+        assert!(matches!(ackslog.pubcomp(pubcomp), Released::Publish(_)));
     }
 
-    pub fn puback(&mut self, ack: PubAck) {
-        let ack = Ack::PubAck(ack);
-        self.committed.push_back(ack);
+    #[test]
+    fn retained_messages_over_size_limit_are_refused() {
+        let config = RouterConfig {
+            max_retained_message_size: Some(20),
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+
+        // 4 bytes of overhead + 3 of topic + 13 of payload
+        let publish = Publish::new("a/b", "xxxxxxxxxxxxx", true);
+        let retained = data.insert_to_retained_publishes(publish, None, "a/b".parse().unwrap());
+        assert_eq!(retained, Ok(()));
+
+        let publish = Publish::new("a/b", "yyyyyyyyyyyyyy", true);
+        let retained = data.insert_to_retained_publishes(publish, None, "a/b".parse().unwrap());
+        assert_eq!(retained, Err(RetainError::TooLarge(21, 20)));
+
+        // Refused message leaves the previous one retained
+        let kept = &data.retained_publishes["a/b"];
+        assert_eq!(kept.publish.payload, "xxxxxxxxxxxxx");
     }
 
-    // TODO: Remove this allow once we support QoS::ExactlyOnce
-    #[allow(dead_code)]
-    pub fn pubrec(&mut self, publish: Publish, ack: PubRec) {
-        let ack = Ack::PubRec(ack);
-        self.recorded.push_back(publish);
-        self.committed.push_back(ack);
+    #[test]
+    fn only_matching_retained_topics_are_cleared() {
+        let mut data = DataLog::new(config()).unwrap();
+        for topic in ["tmp/a", "tmp/b/c", "devices/a"] {
+            let publish = Publish::new(topic, "hello", true);
+            data.insert_to_retained_publishes(publish, None, topic.parse().unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(data.clear_retained_matching("tmp/#"), 2);
+        assert_eq!(data.clear_retained_matching("tmp/#"), 0);
+        assert_eq!(data.retained_publishes.len(), 1);
+        assert!(data.retained_publishes.contains_key("devices/a"));
     }
 
-    pub fn pubrel(&mut self, ack: PubRel) {
-        let ack = Ack::PubRel(ack);
-        self.committed.push_back(ack);
+    #[test]
+    fn estimated_memory_grows_by_appended_publishes() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, _) = data.next_native_offset("topic/a");
+        let empty = data.estimated_memory();
+        assert!(empty > 0);
+
+        for _ in 0..10 {
+            let publish = Publish::new(Bytes::from("topic/a"), Bytes::from(vec![1; 100]), false);
+            data.native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+
+        // 4 bytes of framing, 7 of topic and 100 of payload for every publish
+        assert_eq!(data.native.get(idx).unwrap().meter.total_size, 1110);
+        assert_eq!(data.estimated_memory() - empty, 1110);
     }
 
-    pub fn pubcomp(&mut self, ack: PubComp) -> Option<Publish> {
-        let ack = Ack::PubComp(ack);
-        self.committed.push_back(ack);
-        self.recorded.pop_front()
+    #[test]
+    fn filters_with_waiters_beyond_threshold_are_saturated() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (busy, cursor) = data.next_native_offset("busy/+");
+        let (quiet, _) = data.next_native_offset("quiet/+");
+
+        for id in 0..5 {
+            assert!(data.park(id, request("busy/+", busy, cursor)).is_none());
+        }
+        assert!(data.park(5, request("quiet/+", quiet, cursor)).is_none());
+
+        assert_eq!(data.saturated_filters(3), vec![busy]);
+        assert!(data.saturated_filters(5).is_empty());
+
+        // High water is kept when waiters leave before being woken up
+        data.remove_all_waiters(0);
+        data.remove_all_waiters(1);
+        assert_eq!(data.saturated_filters(3), vec![busy]);
+
+        // and reset once they are woken up
+        let publish = Publish::new("busy/a", "hello", false);
+        data.native
+            .get_mut(busy)
+            .unwrap()
+            .append((publish, None).into(), &mut notifications)
+            .unwrap();
+        assert_eq!(notifications.len(), 3);
+        assert!(data.saturated_filters(0).contains(&quiet));
+        assert!(!data.saturated_filters(0).contains(&busy));
     }
 
-    pub fn pingresp(&mut self, ack: PingResp) {
-        let ack = Ack::PingResp(ack);
-        self.committed.push_back(ack);
+    #[test]
+    fn all_waiters_of_connection_are_removed_with_filters() {
+        let mut data = DataLog::new(config()).unwrap();
+        let filters = ["a/+", "b/#", "c/d"];
+        for filter in filters {
+            let (idx, cursor) = data.next_native_offset(filter);
+            assert!(data.park(0, request(filter, idx, cursor)).is_none());
+            assert!(data.park(1, request(filter, idx, cursor)).is_none());
+        }
+
+        let mut removed = data.remove_all_waiters(0);
+        removed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(removed.len(), 3);
+        for ((filter, request), expected) in removed.iter().zip(filters) {
+            assert_eq!(filter, expected);
+            assert_eq!(request.filter, expected);
+        }
+
+        assert!(data.remove_all_waiters(0).is_empty());
+        for filter in filters {
+            let waiters = data.waiters(filter).unwrap();
+            assert_eq!(waiters.waiters().len(), 1);
+        }
     }
 
-    pub fn unsuback(&mut self, ack: UnsubAck) {
-        let ack = Ack::UnsubAck(ack);
-        self.committed.push_back(ack);
+    #[test]
+    fn removing_a_waiter_keeps_the_others_in_order() {
+        let mut data = DataLog::new(config()).unwrap();
+        let (idx, cursor) = data.next_native_offset("a/b");
+        for id in [0, 1, 2, 3] {
+            assert!(data.park(id, request("a/b", idx, cursor)).is_none());
+        }
+
+        assert!(data.remove_waiters_for_id(1, "a/b").is_some());
+        let waiters = data.waiters("a/b").unwrap().waiters();
+        let ids: Vec<_> = waiters.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![0, 2, 3]);
     }
 
-    pub fn readv(&mut self) -> &mut VecDeque<Ack> {
-        &mut self.committed
+    #[test]
+    fn parking_a_parked_connection_replaces_the_stale_request() {
+        let mut data = DataLog::new(config()).unwrap();
+        let (idx, cursor) = data.next_native_offset("a/b");
+        let (other, _) = data.next_native_offset("a/+");
+        assert!(data.park(0, request("a/b", idx, cursor)).is_none());
+        assert!(data.park(0, request("a/+", other, cursor)).is_none());
+
+        let stale = data.park(0, request("a/b", idx, (0, 5))).unwrap();
+        assert_eq!(stale.cursor, cursor);
+        let waiters = data.waiters("a/b").unwrap().waiters();
+        assert_eq!(waiters.len(), 1);
+        assert_eq!(waiters[0].1.cursor, (0, 5));
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::DataLog;
-    use crate::RouterConfig;
+    #[test]
+    fn published_and_retained_topics_are_enumerated() {
+        let config = RouterConfig {
+            match_cache_ttl_ms: Some(1000),
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, _) = data.next_native_offset("sensors/#");
+
+        for topic in ["sensors/a", "sensors/b", "sensors/a"] {
+            data.matches(topic);
+            let publish = Publish::new(topic, "hello", false);
+            data.native[idx]
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+
+        for topic in ["sensors/a", "sensors/c"] {
+            let publish = Publish::new(topic, "hello", true);
+            data.insert_to_retained_publishes(publish, None, topic.parse().unwrap())
+                .unwrap();
+        }
+
+        assert_eq!(data.published_topic_count(), 3);
+
+        // Publishes outlive the cached matches of their topics
+        data.expire_matches(Instant::now() + Duration::from_secs(2));
+        assert!(data.publish_filters.is_empty());
+        assert_eq!(data.published_topic_count(), 3);
+        assert_eq!(data.retained_count(), 2);
+        let mut retained: Vec<&str> = data.retained_topics().map(|t| t.as_str()).collect();
+        retained.sort();
+        assert_eq!(retained, vec!["sensors/a", "sensors/c"]);
+    }
 
     #[test]
-    fn publish_filters_updating_correctly_on_new_topic_subscription() {
+    fn match_cache_hits_and_misses_are_counted() {
+        let mut data = DataLog::new(config()).unwrap();
+        data.next_native_offset("sensors/#");
+        assert_eq!(data.match_cache_stats(), (0, 0));
+
+        for topic in [
+            "sensors/a",
+            "sensors/b",
+            "sensors/a",
+            "sensors/a",
+            "sensors/b",
+        ] {
+            data.matches(topic);
+        }
+        assert_eq!(data.match_cache_stats(), (3, 2));
+
+        // Topics without matching filters aren't cached
+        data.matches("other");
+        data.matches("other");
+        assert_eq!(data.match_cache_stats(), (3, 4));
+    }
+
+    #[test]
+    fn topic_tree_follows_topic_levels() {
+        let mut data = DataLog::new(config()).unwrap();
+        data.next_native_offset("a/#");
+        data.next_native_offset("a/b");
+
+        for topic in ["a/b", "a/c", "a/b/d"] {
+            data.matches(topic);
+        }
+
+        let publish = Publish::new("a/c", "hello", true);
+        data.insert_to_retained_publishes(publish, None, "a/c".parse().unwrap())
+            .unwrap();
+
+        let root = data.topic_tree();
+        assert_eq!(root.children.keys().collect::<Vec<_>>(), vec!["a"]);
+
+        let a = &root.children["a"];
+        assert!(!a.retained);
+        assert_eq!(a.filters, 0);
+        assert_eq!(a.children.keys().collect::<Vec<_>>(), vec!["b", "c"]);
+
+        let b = &a.children["b"];
+        assert!(!b.retained);
+        assert_eq!(b.filters, 2);
+        assert_eq!(b.children.keys().collect::<Vec<_>>(), vec!["d"]);
+
+        let d = &b.children["d"];
+        assert!(!d.retained);
+        assert_eq!(d.filters, 1);
+        assert!(d.children.is_empty());
+
+        let c = &a.children["c"];
+        assert!(c.retained);
+        assert_eq!(c.filters, 1);
+        assert!(c.children.is_empty());
+    }
+
+    #[test]
+    fn config_patch_changing_segments_is_rejected() {
+        let mut data = DataLog::new(config()).unwrap();
+
+        let patch = RouterConfigPatch {
+            max_read_len: Some(10),
+            max_segment_size: Some(2048),
+            ..Default::default()
+        };
+        assert_eq!(
+            data.update_config(patch),
+            Err(ConfigError::Immutable("max_segment_size"))
+        );
+        assert_eq!(data.config.max_read_len, 1024);
+
+        // Unchanged values are accepted
+        let patch = RouterConfigPatch {
+            max_read_len: Some(10),
+            max_segment_size: Some(1024),
+            max_total_inflight_bytes: Some(Some(4096)),
+            ..Default::default()
+        };
+        assert_eq!(data.update_config(patch), Ok(()));
+        assert_eq!(data.config.max_read_len, 10);
+        assert_eq!(data.config.max_total_inflight_bytes, Some(4096));
+    }
+
+    #[test]
+    fn initial_read_len_is_validated_against_max_read_len() {
+        let config = |initial_read_len| RouterConfig {
+            max_read_len: 100,
+            initial_read_len,
+            ..config()
+        };
+
+        assert_eq!(
+            DataLog::new(config(Some(0))).err(),
+            Some(ConfigError::TooSmall("initial_read_len", 1))
+        );
+        assert_eq!(
+            DataLog::new(config(Some(101))).err(),
+            Some(ConfigError::Exceeds("initial_read_len", "max_read_len"))
+        );
+
+        let mut data = DataLog::new(config(Some(100))).unwrap();
+        let patch = RouterConfigPatch {
+            initial_read_len: Some(Some(0)),
+            ..Default::default()
+        };
+        assert_eq!(
+            data.update_config(patch),
+            Err(ConfigError::TooSmall("initial_read_len", 1))
+        );
+
+        // Lowering max_read_len below the current initial_read_len is refused
+        let patch = RouterConfigPatch {
+            max_read_len: Some(50),
+            ..Default::default()
+        };
+        assert_eq!(
+            data.update_config(patch),
+            Err(ConfigError::Exceeds("initial_read_len", "max_read_len"))
+        );
+        assert_eq!(data.config.max_read_len, 100);
+
+        let patch = RouterConfigPatch {
+            max_read_len: Some(50),
+            initial_read_len: Some(Some(10)),
+            ..Default::default()
+        };
+        assert_eq!(data.update_config(patch), Ok(()));
+        assert_eq!(data.config.initial_read_len, Some(10));
+    }
+
+    #[test]
+    fn filters_use_segment_limits_of_their_storage() {
+        let mut data = DataLog::new(RouterConfig {
+            filter_storage: vec![FilterStorage {
+                max_segment_size: 4096,
+                max_segment_count: 2,
+                filters: vec!["devices/#".parse().unwrap()],
+            }],
+            ..config()
+        })
+        .unwrap();
+        let mut notifications = VecDeque::new();
+        let (actions, _) = data.next_native_offset("actions/#");
+        let (devices, _) = data.next_native_offset("devices/#");
+
+        for idx in [actions, devices] {
+            for i in 0..20 {
+                let publish = Publish::new("a/b".to_owned(), format!("{i:0300}"), false);
+                data.native[idx]
+                    .append((publish, None).into(), &mut notifications)
+                    .unwrap();
+            }
+        }
+
+        // ~6KB of publishes fill several 1KB segments but only two 4KB ones
+        assert!(data.native[actions].log.memory_segments_count() > 2);
+        assert_eq!(data.native[devices].log.memory_segments_count(), 2);
+
         let config = RouterConfig {
-            instant_ack: true,
-            max_segment_size: 1024,
-            max_connections: 10,
-            max_segment_count: 10,
-            max_read_len: 1024,
-            initialized_filters: None,
+            filter_storage: vec![FilterStorage {
+                max_segment_size: 100,
+                max_segment_count: 2,
+                filters: vec!["devices/#".parse().unwrap()],
+            }],
+            ..config()
+        };
+        assert_eq!(
+            DataLog::new(config).err(),
+            Some(ConfigError::TooSmall(
+                "filter_storage.max_segment_size",
+                1024
+            ))
+        );
+    }
+
+    #[test]
+    fn reads_from_evicted_offsets_report_the_gap() {
+        let config = RouterConfig {
+            max_segment_count: 2,
+            ..config()
         };
         let mut data = DataLog::new(config).unwrap();
-        data.next_native_offset("topic/a");
-        data.matches("topic/a");
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = data.next_native_offset("hello/world");
+        for _ in 0..20 {
+            let publish = Publish::new("hello/world".to_owned(), "x".repeat(256), false);
+            data.native[idx]
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+        assert_eq!(data.native[idx].meter.messages_dropped, 12);
+
+        let (next, o) = data.native_readv(idx, cursor, 100, false).unwrap();
+        let gap = next.gap(cursor).unwrap();
+        assert_eq!(gap.skipped_from, (0, 0));
+        assert_eq!(gap.resumed_at, (3, 12));
+        assert_eq!(gap.skipped(), 12);
+        assert_eq!(o.len(), 8);
+        assert_eq!(o[0].1, (3, 12));
+
+        let (next, _) = data.native_readv(idx, (3, 12), 100, false).unwrap();
+        assert_eq!(next.gap((3, 12)), None);
+    }
 
-        data.next_native_offset("topic/+");
+    #[test]
+    fn caught_up_cursor_at_evicted_segment_boundary_has_no_gap() {
+        let config = RouterConfig {
+            max_segment_count: 1,
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, mut cursor) = data.next_native_offset("hello/world");
+        for _ in 0..3 {
+            for _ in 0..4 {
+                let publish = Publish::new("hello/world".to_owned(), "x".repeat(256), false);
+                data.native[idx]
+                    .append((publish, None).into(), &mut notifications)
+                    .unwrap();
+            }
 
-        assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 2);
+            let (next, o) = data.native_readv(idx, cursor, 100, false).unwrap();
+            assert_eq!(next.gap(cursor), None);
+            assert_eq!(o.len(), 4);
+            let Position::Done { end, .. } = next else {
+                panic!("read should catch up");
+            };
+            cursor = end;
+        }
+        assert_eq!(data.native[idx].meter.messages_missed, 0);
     }
 
     #[test]
-    fn publish_filters_updating_correctly_on_new_publish() {
+    fn filter_lag_follows_the_fastest_reader() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = data.next_native_offset("hello/world");
+        assert_eq!(data.filter_lag(idx), None);
+
+        data.track_reader(idx, 1, cursor);
+        data.track_reader(idx, 2, cursor);
+        for _ in 0..5 {
+            let publish = Publish::new("hello/world".to_owned(), "x".repeat(10), false);
+            data.native[idx]
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+        assert_eq!(data.filter_lag(idx), Some(5));
+
+        data.track_reader(idx, 2, (0, 3));
+        assert_eq!(data.filter_lag(idx), Some(2));
+
+        data.remove_subscriber(2, "hello/world");
+        assert_eq!(data.filter_lag(idx), Some(5));
+
+        data.remove_subscriber(1, "hello/world");
+        assert_eq!(data.filter_lag(idx), None);
+    }
+
+    #[test]
+    fn max_delivery_lag_follows_the_slowest_reader() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, _) = data.next_native_offset("hello/world");
+        for _ in 0..10 {
+            let publish = Publish::new("hello/world".to_owned(), "x".repeat(10), false);
+            data.native[idx]
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+        assert_eq!(data.max_delivery_lag(idx), None);
+
+        data.track_reader(idx, 1, (0, 4));
+        data.track_reader(idx, 2, (0, 7));
+        data.track_reader(idx, 3, (0, 10));
+        assert_eq!(data.max_delivery_lag(idx), Some(6));
+
+        data.track_reader(idx, 1, (0, 8));
+        assert_eq!(data.max_delivery_lag(idx), Some(3));
+
+        data.remove_subscriber(2, "hello/world");
+        assert_eq!(data.max_delivery_lag(idx), Some(2));
+
+        let (other, _) = data.next_native_offset("goodbye/world");
+        assert_eq!(data.max_delivery_lag(other), None);
+    }
+
+    #[test]
+    fn snapshot_has_next_offset_of_every_filter() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let filters = ["a/b", "a/c", "a/d"];
+        let mut idxs = Vec::new();
+        for (count, filter) in filters.into_iter().enumerate() {
+            let (idx, _) = data.next_native_offset(filter);
+            for i in 0..count * 5 {
+                let publish = Publish::new(filter.to_owned(), format!("{i:0300}"), false);
+                data.native[idx]
+                    .append((publish, None).into(), &mut notifications)
+                    .unwrap();
+            }
+            idxs.push(idx);
+        }
+
+        idxs.push(100);
+        let snapshot = data.snapshot_offsets(&idxs);
+        assert_eq!(snapshot.len(), 3);
+        for idx in &idxs[..3] {
+            assert_eq!(snapshot[idx], data.native[*idx].next_offset());
+        }
+        assert_eq!(snapshot[&idxs[2]], (2, 10));
+    }
+
+    #[test]
+    fn appended_position_points_at_item() {
         let config = RouterConfig {
-            instant_ack: true,
-            max_segment_size: 1024,
-            max_connections: 10,
-            max_segment_count: 10,
-            max_read_len: 1024,
-            initialized_filters: None,
+            compacted_filters: vec!["state/+".parse().unwrap()],
+            ..config()
         };
         let mut data = DataLog::new(config).unwrap();
-        data.next_native_offset("+/+");
+        let mut notifications = VecDeque::new();
+
+        for (filter, topic) in [("sensors/+", "sensors/a"), ("state/+", "state/a")] {
+            let (idx, _) = data.next_native_offset(filter);
+            for i in 0..20 {
+                let payload = format!("{i:0100}");
+                let publish = Publish::new(topic.to_owned(), payload.clone(), false);
+                let (position, _) = data.native[idx]
+                    .append((publish, None).into(), &mut notifications)
+                    .unwrap();
+
+                let appended = data.native[idx].get(position.offset()).unwrap();
+                assert_eq!(appended.publish.payload, payload);
+                assert_eq!(data.native[idx].next_offset().1, position.next_offset().1);
+            }
+        }
+    }
 
-        data.matches("topic/a");
+    #[test]
+    fn ack_latency_is_tracked_only_when_enabled() {
+        let puback = |pkid| PubAck {
+            pkid,
+            reason: PubAckReason::Success,
+        };
 
-        assert_eq!(data.publish_filters.get("topic/a").unwrap().len(), 1);
+        let mut log = AckLog::new();
+        log.puback(puback(1));
+        log.drain();
+        assert_eq!(log.take_ack_latency_stats(), None);
+
+        log.set_track_latency(true);
+        for pkid in 1..=3 {
+            log.puback(puback(pkid));
+        }
+
+        // Acks queued 0, 10 and 20 ms after `start` and drained at 50 ms
+        let start = Instant::now();
+        let enqueued = log.enqueued.as_mut().unwrap();
+        for (i, at) in enqueued.iter_mut().enumerate() {
+            *at = start + Duration::from_millis(10 * i as u64);
+        }
+        assert_eq!(log.take(3, start + Duration::from_millis(50)).len(), 3);
+
+        let stats = log.take_ack_latency_stats().unwrap();
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.min, Duration::from_millis(30));
+        assert_eq!(stats.max, Duration::from_millis(50));
+        assert_eq!(stats.avg(), Duration::from_millis(40));
+
+        // Acks taken off `readv` aren't timed
+        log.puback(puback(4));
+        log.readv().clear();
+        log.puback(puback(5));
+        assert_eq!(log.drain().len(), 1);
+        assert_eq!(log.take_ack_latency_stats().unwrap().count, 1);
+        assert_eq!(log.take_ack_latency_stats().unwrap().count, 0);
+    }
+
+    #[test]
+    fn bounded_drain_keeps_puback_runs_together() {
+        let fill = |log: &mut AckLog| {
+            log.suback(
+                SubAck {
+                    pkid: 1,
+                    return_codes: vec![SubscribeReasonCode::QoS0],
+                },
+                None,
+            );
+            for pkid in 2..5 {
+                log.puback(PubAck {
+                    pkid,
+                    reason: PubAckReason::Success,
+                });
+            }
+            log.pingresp(PingResp);
+        };
+        let pkids = |acks: Vec<Ack>| -> Vec<u16> {
+            acks.iter()
+                .map(|ack| match ack {
+                    Ack::SubAck(ack) => ack.pkid,
+                    Ack::PubAck(ack) => ack.pkid,
+                    _ => 0,
+                })
+                .collect()
+        };
+
+        let mut log = AckLog::new();
+        fill(&mut log);
+        assert_eq!(pkids(log.drain_coalesced(2)), vec![1, 2]);
+        assert_eq!(pkids(log.drain_coalesced(2)), vec![3, 4]);
+        assert_eq!(pkids(log.drain_coalesced(2)), vec![0]);
+        assert!(log.drain_coalesced(2).is_empty());
+
+        let mut log = AckLog::new();
+        log.set_coalesce_pubacks(true);
+        fill(&mut log);
+        assert_eq!(pkids(log.drain_coalesced(2)), vec![1]);
+        // A run longer than the limit is split
+        assert_eq!(pkids(log.drain_coalesced(2)), vec![2, 3]);
+        assert_eq!(pkids(log.drain_coalesced(5)), vec![4, 0]);
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn degenerate_limits_are_rejected() {
+        let config = RouterConfig {
+            max_segment_count: 1,
+            max_read_len: 1,
+            initialized_filters: Some(vec!["a/b".parse().unwrap()]),
+            ..config()
+        };
+        let mut data = DataLog::new(config.clone()).unwrap();
+
+        let cases = [
+            (
+                RouterConfig {
+                    max_segment_size: 0,
+                    ..config.clone()
+                },
+                ConfigError::TooSmall("max_segment_size", 1024),
+            ),
+            (
+                RouterConfig {
+                    max_segment_size: 1023,
+                    ..config.clone()
+                },
+                ConfigError::TooSmall("max_segment_size", 1024),
+            ),
+            (
+                RouterConfig {
+                    max_segment_count: 0,
+                    ..config.clone()
+                },
+                ConfigError::TooSmall("max_segment_count", 1),
+            ),
+            (
+                RouterConfig {
+                    max_read_len: 0,
+                    ..config.clone()
+                },
+                ConfigError::TooSmall("max_read_len", 1),
+            ),
+            (
+                RouterConfig {
+                    max_compacted_keys: Some(0),
+                    ..config.clone()
+                },
+                ConfigError::TooSmall("max_compacted_keys", 1),
+            ),
+            (
+                RouterConfig {
+                    dedup: Some(DedupSettings {
+                        property: "id".to_owned(),
+                        window: 0,
+                        filters: vec!["a/b".parse().unwrap()],
+                    }),
+                    ..config.clone()
+                },
+                ConfigError::TooSmall("dedup.window", 1),
+            ),
+        ];
+
+        for (config, error) in cases {
+            assert_eq!(DataLog::new(config).err(), Some(error));
+        }
+
+        let patch = RouterConfigPatch {
+            max_read_len: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            data.update_config(patch),
+            Err(ConfigError::TooSmall("max_read_len", 1))
+        );
+        assert_eq!(data.config.max_read_len, 1);
+    }
+
+    #[test]
+    fn compacted_filter_keeps_latest_publish_per_topic() {
+        let config = RouterConfig {
+            compacted_filters: vec!["state/+".parse().unwrap()],
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = data.next_native_offset("state/+");
+        assert_eq!(cursor, (0, 0));
+
+        let mut append = |data: &mut DataLog, topic: &'static str, payload: &'static str| {
+            let publish = Publish::new(topic, payload, false);
+            data.native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        };
+
+        for (topic, payload) in [
+            ("state/a", "1"),
+            ("state/b", "1"),
+            ("state/a", "2"),
+            ("state/c", "1"),
+            ("state/b", "2"),
+        ] {
+            append(&mut data, topic, payload);
+        }
+
+        let read = |data: &DataLog, cursor, len| {
+            let (next, o) = data.native_readv(idx, cursor, len, false).unwrap();
+            let o: Vec<_> = o
+                .into_iter()
+                .map(|((p, _), offset)| (p.topic, p.payload, offset))
+                .collect();
+            (next, o)
+        };
+
+        let (next, o) = read(&data, cursor, 10);
+        assert_eq!(
+            o,
+            vec![
+                (Bytes::from("state/a"), Bytes::from("2"), (0, 2)),
+                (Bytes::from("state/c"), Bytes::from("1"), (0, 3)),
+                (Bytes::from("state/b"), Bytes::from("2"), (0, 4)),
+            ]
+        );
+        assert_eq!(
+            next,
+            Position::Done {
+                start: (0, 0),
+                end: (0, 5)
+            }
+        );
+
+        // Partial reads continue after the last returned offset
+        let (next, o) = read(&data, cursor, 2);
+        assert_eq!(o.len(), 2);
+        assert_eq!(
+            next,
+            Position::Next {
+                start: (0, 0),
+                end: (0, 4)
+            }
+        );
+
+        // Caught up readers see overwrites at new offsets
+        append(&mut data, "state/a", "3");
+        let (next, o) = read(&data, (0, 5), 10);
+        assert_eq!(o, vec![(Bytes::from("state/a"), Bytes::from("3"), (0, 5))]);
+        assert_eq!(
+            next,
+            Position::Done {
+                start: (0, 5),
+                end: (0, 6)
+            }
+        );
+        assert_eq!(data.native.get(idx).unwrap().next_offset(), (0, 6));
+
+        // Overwritten entries can't be fetched anymore
+        assert!(data.get_publish(idx, (0, 2)).is_none());
+        let latest = data.get_publish(idx, (0, 5)).unwrap();
+        assert_eq!(latest.publish.payload, Bytes::from("3"));
+    }
+
+    #[test]
+    fn compacted_filter_drops_topics_written_longest_ago() {
+        let config = RouterConfig {
+            compacted_filters: vec!["state/+".parse().unwrap()],
+            max_compacted_keys: Some(2),
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = data.next_native_offset("state/+");
+
+        for (topic, payload) in [
+            ("state/a", "1"),
+            ("state/b", "1"),
+            ("state/a", "2"),
+            ("state/c", "1"),
+        ] {
+            let publish = Publish::new(topic, payload, false);
+            data.native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+
+        // Rewriting state/a kept it over state/b
+        let (_, o) = data.native_readv(idx, cursor, 10, false).unwrap();
+        let o: Vec<_> = o
+            .into_iter()
+            .map(|((p, _), offset)| (p.topic, p.payload, offset))
+            .collect();
+        assert_eq!(
+            o,
+            vec![
+                (Bytes::from("state/a"), Bytes::from("2"), (0, 2)),
+                (Bytes::from("state/c"), Bytes::from("1"), (0, 3)),
+            ]
+        );
+
+        let data = data.native.get(idx).unwrap();
+        assert_eq!(data.meter.messages_dropped, 1);
+        assert_eq!(
+            data.compaction.as_ref().unwrap().total_size,
+            2 * (4 + 7 + 1)
+        );
     }
 
     //     #[test]
@@ -473,4 +2999,153 @@ mod test {
     //             dbg!(v);
     //         }
     //     }
+
+    #[test]
+    fn tail_returns_latest_publishes_across_segments() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, _) = data.next_native_offset("hello/world");
+
+        // Segments fill up after 4 publishes
+        for i in 0..10 {
+            let mut publish = Publish::new("hello/world".to_owned(), "x".repeat(256), false);
+            publish.pkid = i;
+            data.native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+        assert_eq!(data.native[idx].log.len(), 3);
+
+        let tail: Vec<_> = data
+            .tail(idx, 3)
+            .into_iter()
+            .map(|(offset, publish)| (offset, publish.pkid))
+            .collect();
+        assert_eq!(tail, vec![((1, 7), 7), ((2, 8), 8), ((2, 9), 9)]);
+
+        let tail = data.tail(idx, 20);
+        assert_eq!(tail.len(), 10);
+        assert_eq!(tail[0].0, (0, 0));
+        assert!(tail.iter().map(|(_, p)| p.pkid).eq(0..10));
+
+        assert!(data.tail(idx, 0).is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "debug-dump")]
+    fn filter_data_is_dumped_as_json() {
+        let mut data = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = data.next_native_offset("topic/+");
+
+        let properties = super::PublishProperties {
+            user_properties: vec![("k".to_owned(), "v".to_owned())],
+            ..Default::default()
+        };
+        let publishes = [
+            (Publish::new("topic/a", "hello", false), None),
+            (
+                Publish::new(&b"topic/b"[..], &[0, 255][..], false),
+                Some(properties),
+            ),
+            (Publish::new("topic/a", "world", false), None),
+        ];
+        for publish in publishes {
+            data.native[idx]
+                .append(publish.into(), &mut notifications)
+                .unwrap();
+        }
+
+        let dump = data.dump_filter_json(idx, (0, 1), 10);
+        assert_eq!(dump.len(), 2);
+        assert_eq!(dump[0]["offset"], serde_json::json!([0, 1]));
+        assert_eq!(dump[0]["topic"], "topic/b");
+        assert_eq!(dump[0]["qos"], 0);
+        assert_eq!(dump[0]["payload_base64"], "AP8=");
+        assert_eq!(
+            dump[0]["properties"]["user_properties"],
+            serde_json::json!([["k", "v"]])
+        );
+        assert_eq!(dump[1]["payload_base64"], "d29ybGQ=");
+        assert!(dump[1]["properties"].is_null());
+
+        assert_eq!(data.dump_filter_json(idx, cursor, 1).len(), 1);
+        assert!(data.dump_filter_json(idx + 1, cursor, 10).is_empty());
+    }
+
+    #[test]
+    fn debug_dump_reflects_filters_caches_and_waiters() {
+        let config = RouterConfig {
+            compacted_filters: vec!["state/+".parse().unwrap()],
+            ..config()
+        };
+        let mut data = DataLog::new(config).unwrap();
+        let mut notifications = VecDeque::new();
+        let (a, _) = data.next_native_offset("topic/a");
+        let (wildcard, _) = data.next_native_offset("topic/+");
+        let (state, _) = data.next_native_offset("state/+");
+
+        for filter_idx in data.matches("topic/a").unwrap().into_vec() {
+            let publish = Publish::new("topic/a", "hello", false);
+            data.native
+                .get_mut(filter_idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+        data.matches("topic/b");
+        data.insert_to_retained_publishes(
+            Publish::new("topic/b", "retained", true),
+            None,
+            "topic/b".parse().unwrap(),
+        )
+        .unwrap();
+        assert!(data.park(7, request("topic/+", wildcard, (0, 1))).is_none());
+
+        let dump = data.debug_dump();
+        let filters: Vec<_> = dump
+            .filters
+            .iter()
+            .map(|f| {
+                (
+                    f.filter_idx,
+                    f.filter.as_str(),
+                    f.compacted,
+                    f.next_offset,
+                    f.count,
+                )
+            })
+            .collect();
+        assert_eq!(
+            filters,
+            vec![
+                (a, "topic/a", false, (0, 1), 1),
+                (wildcard, "topic/+", false, (0, 1), 1),
+                (state, "state/+", true, (0, 0), 0),
+            ]
+        );
+        assert!(dump.filters[a].waiters.is_empty());
+        assert_eq!(
+            dump.filters[wildcard].waiters,
+            vec![WaiterDump {
+                connection_id: 7,
+                cursor: (0, 1)
+            }]
+        );
+
+        assert_eq!(dump.publish_filters["topic/a"], vec![a, wildcard]);
+        assert_eq!(dump.publish_filters["topic/b"], vec![wildcard]);
+        assert_eq!(dump.retained_topics, ["topic/b"]);
+
+        #[cfg(feature = "debug-dump")]
+        {
+            let json = serde_json::to_value(&dump).unwrap();
+            assert_eq!(
+                json["publish_filters"]["topic/b"],
+                serde_json::json!([wildcard])
+            );
+        }
+    }
 }
@@ -1,4 +1,5 @@
 use std::{
+    cmp::Ordering,
     collections::{HashMap, HashSet, VecDeque},
     fmt,
 };
@@ -12,20 +13,24 @@ use crate::{
         PubAckProperties, PubComp, PubCompProperties, PubRec, PubRecProperties, PubRel,
         PubRelProperties, Publish, PublishProperties, SubAck, SubAckProperties, UnsubAck,
     },
-    ConnectionId, Filter, RouterId, Topic,
+    ConnectionId, Filter, Offset, RouterConfigPatch, RouterId, Topic,
 };
 
 mod alertlog;
 mod connection;
+#[cfg(test)]
+mod fixtures;
 mod graveyard;
 pub mod iobufs;
 mod logs;
 mod routing;
 mod scheduler;
+mod trie;
 mod waiters;
 
 pub use alertlog::Alert;
 pub use connection::Connection;
+pub use logs::{AckLatency, ConfigError};
 pub use routing::Router;
 pub use waiters::Waiters;
 
@@ -33,6 +38,8 @@ pub const MAX_SCHEDULE_ITERATIONS: usize = 100;
 pub const MAX_CHANNEL_CAPACITY: usize = 200;
 
 pub(crate) type FilterIdx = usize;
+/// `[start, end)` range of a commitlog
+pub(crate) type OffsetRange = (Offset, Offset);
 
 #[derive(Debug)]
 // TODO: Fix this
@@ -62,6 +69,17 @@ pub enum Event {
     SendMeters,
     /// Get metrics of a connection or all connections
     PrintStatus(Print),
+    /// Stop accepting publishes and let subscribers drain before shutdown.
+    /// The sender is notified once the router is drained
+    Drain(flume::Sender<()>),
+    /// Change limits of the running router. The sender gets the outcome
+    UpdateConfig(RouterConfigPatch, flume::Sender<Result<(), ConfigError>>),
+    /// Remove a filter without subscribers. The sender gets whether it was
+    /// removed
+    RemoveFilter(String, flume::Sender<bool>),
+    /// Compact the filter logs. The sender gets the number of filters which
+    /// moved to a new index
+    CompactFilters(flume::Sender<usize>),
 }
 
 /// Notification from router to connection
@@ -185,12 +203,92 @@ pub struct DataRequest {
     pub filter_idx: FilterIdx,
     /// Qos of the outgoing data
     pub qos: u8,
+    /// Retain As Published option of the subscription
+    pub preserve_retain: bool,
     /// (segment, offset) tuples per replica (1 native and 2 replicas)
     pub cursor: (u64, u64),
+    /// Number of messages to read at once when `RouterConfig::initial_read_len`
+    /// is set. Adapted to the pace of the subscriber after every read
+    pub read_ahead: u64,
     /// number of messages read from subscription
     pub read_count: usize,
     /// Maximum count of payload buffer per replica
     max_count: usize,
+    /// Order in which publishes appended before the subscription are delivered
+    pub delivery_order: DeliveryOrder,
+    /// `[start, end)` range of the backlog of a `NewestFirst` request which is
+    /// yet to be delivered. Read backwards from `end` whenever the subscriber
+    /// has caught up with publishes appended since the subscription
+    pub backlog: Option<OffsetRange>,
+    /// Deliver a marker on `RouterConfig::live_marker_topic` once the request
+    /// has caught up with its filter. Cleared when the marker is delivered
+    #[serde(default)]
+    pub live_marker: bool,
+}
+
+/// Order of delivery of the publishes a filter already holds when a
+/// subscription is made, e.g. the current state of a compacted filter
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryOrder {
+    #[default]
+    OldestFirst,
+    /// Latest publishes first. Publishes appended after the subscription are
+    /// still delivered in order as they arrive
+    NewestFirst,
+}
+
+/// Outcome of moving the cursor of a [`DataRequest`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorUpdate {
+    Advanced,
+    Unchanged,
+    /// Given cursor was behind the current one and was ignored
+    Regressed,
+}
+
+impl DataRequest {
+    /// Moves the cursor forward to `next`. A cursor behind the current one is
+    /// ignored as that would deliver already delivered data again
+    pub fn update_cursor(&mut self, next: Offset) -> CursorUpdate {
+        match next.cmp(&self.cursor) {
+            Ordering::Greater => {
+                self.cursor = next;
+                CursorUpdate::Advanced
+            }
+            Ordering::Equal => CursorUpdate::Unchanged,
+            Ordering::Less => CursorUpdate::Regressed,
+        }
+    }
+
+    /// Points the request at the index its filter was moved to by
+    /// `DataLog::compact_native`
+    pub fn remap_filter(&mut self, remap: &HashMap<FilterIdx, FilterIdx>) {
+        if let Some(&filter_idx) = remap.get(&self.filter_idx) {
+            self.filter_idx = filter_idx;
+        }
+    }
+
+    /// Number of messages to read next. Always `max` when read ahead isn't
+    /// enabled with `initial`
+    pub fn read_len(&self, initial: Option<u64>, max: u64) -> u64 {
+        match initial {
+            Some(initial) => self.read_ahead.max(initial).min(max),
+            None => max,
+        }
+    }
+
+    /// Adapts read ahead after reading `read` of `requested` messages. A full
+    /// read grows it towards the head of the filter, `lag` messages away, while
+    /// a read which caught up using less than half of it shrinks it
+    pub fn adapt_read_ahead(&mut self, requested: u64, read: u64, lag: u64, caughtup: bool) {
+        self.read_ahead = if read >= requested && !caughtup {
+            requested.saturating_mul(2).max(lag)
+        } else if caughtup && read.saturating_mul(2) < requested {
+            requested / 2
+        } else {
+            requested
+        };
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -272,6 +370,20 @@ pub struct RouterMeter {
     pub total_subscriptions: usize,
     pub total_publishes: usize,
     pub failed_publishes: usize,
+    /// Estimate of resident bytes held by the native logs
+    pub estimated_memory: usize,
+    /// Distinct topics of the publishes held by the logs or retained
+    pub published_topics: usize,
+    pub retained_messages: usize,
+    /// Publish topics routed with cached filter matches so far
+    pub match_cache_hits: u64,
+    /// Publish topics which had to be matched against all the filters so far
+    pub match_cache_misses: u64,
+    /// Time acks spent queued before being written out since the last
+    /// meter, with `track_ack_latency`
+    pub ack_latency: Option<AckLatency>,
+    /// Filters with more parked connections than `saturated_waiters`
+    pub saturated_filters: Vec<String>,
 }
 
 impl RouterMeter {
@@ -304,6 +416,19 @@ pub struct SubscriptionMeter {
     pub sequence: usize,
     pub count: usize,
     pub total_size: usize,
+    /// QoS 0 publishes dropped because all the subscribers were lagging
+    pub shed_count: usize,
+    /// Publishes dropped for carrying an id which was recently seen
+    pub duplicate_count: usize,
+    /// Number of messages the furthest behind subscriber has yet to read
+    pub max_delivery_lag: Option<u64>,
+    /// Segments evicted to stay within `max_segment_count`
+    pub segments_evicted: usize,
+    /// Publishes dropped along with evicted segments
+    pub messages_dropped: usize,
+    /// Publishes subscribers skipped because they were evicted before being
+    /// read, summed over all the subscribers
+    pub messages_missed: usize,
 }
 
 impl SubscriptionMeter {
@@ -330,6 +455,17 @@ impl SubscriptionMeter {
     }
 }
 
+/// Deliveries of a filter to one of its subscribers
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubscriberMeter {
+    /// Publishes read for the subscriber
+    pub count: usize,
+    /// Size of the topics and payloads of those publishes
+    pub total_size: usize,
+    /// Number of messages the subscriber had yet to read after its last read
+    pub lag: u64,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct MeterData {
     pub count: usize,
@@ -346,7 +482,7 @@ pub struct IncomingMeter {
 impl IncomingMeter {
     pub fn register_publish(&mut self, publish: &Publish) -> Result<(), std::str::Utf8Error> {
         let meter = {
-            let topic = std::str::from_utf8(&publish.topic)?.to_string();
+            let topic = Topic::new_unchecked(std::str::from_utf8(&publish.topic)?);
             self.publishes.entry(topic).or_default()
         };
         meter.count += 1;
@@ -366,7 +502,7 @@ impl IncomingMeter {
         self.subscribes.insert(filter)
     }
 
-    pub fn unregister_subscription(&mut self, filter: &Filter) -> bool {
+    pub fn unregister_subscription(&mut self, filter: &str) -> bool {
         self.subscribes.remove(filter)
     }
 
@@ -394,6 +530,8 @@ pub struct ConnectionEvents {
 pub enum Meter {
     Router(usize, RouterMeter),
     Subscription(String, SubscriptionMeter),
+    /// Deliveries of a filter to a client, with `meter_subscribers`
+    Subscriber(String, String, SubscriberMeter),
 }
 
 #[derive(Debug, Clone)]
@@ -405,4 +543,74 @@ pub enum Print {
     Subscriptions,
     Subscription(Filter),
     Waiters(Filter),
+    DataLog,
+    Topics,
+    Offsets,
+    CommitLog(Filter),
+    Publish(Filter, Offset),
+    Tail(Filter, usize),
+    Connections,
+    #[cfg(feature = "debug-dump")]
+    FilterData(Filter),
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fixtures, CursorUpdate, DataRequest};
+
+    fn request(cursor: (u64, u64)) -> DataRequest {
+        fixtures::request("hello/world", 0, cursor)
+    }
+
+    #[test]
+    fn cursor_advances() {
+        let mut request = request((0, 5));
+        assert_eq!(request.update_cursor((0, 8)), CursorUpdate::Advanced);
+        assert_eq!(request.cursor, (0, 8));
+
+        // Moving to next segment is an advance even with a smaller offset
+        assert_eq!(request.update_cursor((1, 0)), CursorUpdate::Advanced);
+        assert_eq!(request.cursor, (1, 0));
+    }
+
+    #[test]
+    fn same_cursor_is_unchanged() {
+        let mut request = request((0, 5));
+        assert_eq!(request.update_cursor((0, 5)), CursorUpdate::Unchanged);
+        assert_eq!(request.cursor, (0, 5));
+    }
+
+    #[test]
+    fn read_ahead_follows_subscriber_pace() {
+        let mut request = request((0, 0));
+        assert_eq!(request.read_len(None, 100), 100);
+        assert_eq!(request.read_len(Some(4), 100), 4);
+
+        // Full reads grow towards the head
+        request.adapt_read_ahead(4, 4, 2, false);
+        assert_eq!(request.read_len(Some(4), 100), 8);
+        request.adapt_read_ahead(8, 8, 50, false);
+        assert_eq!(request.read_len(Some(4), 100), 50);
+        request.adapt_read_ahead(50, 50, 500, false);
+        assert_eq!(request.read_len(Some(4), 100), 100);
+
+        // Sparse reads shrink back, but not below the initial length
+        request.adapt_read_ahead(100, 10, 0, true);
+        assert_eq!(request.read_len(Some(4), 100), 50);
+        request.adapt_read_ahead(50, 30, 0, true);
+        assert_eq!(request.read_len(Some(4), 100), 50);
+        for _ in 0..10 {
+            let len = request.read_len(Some(4), 100);
+            request.adapt_read_ahead(len, 0, 0, true);
+        }
+        assert_eq!(request.read_len(Some(4), 100), 4);
+    }
+
+    #[test]
+    fn regressed_cursor_is_rejected() {
+        let mut request = request((1, 5));
+        assert_eq!(request.update_cursor((1, 2)), CursorUpdate::Regressed);
+        assert_eq!(request.update_cursor((0, 9)), CursorUpdate::Regressed);
+        assert_eq!(request.cursor, (1, 5));
+    }
 }
@@ -1,7 +1,9 @@
 use crate::protocol::{
-    ConnAck, ConnAckProperties, ConnectReturnCode, Disconnect, DisconnectReasonCode, Packet,
-    PingResp, PubAck, PubAckReason, PubComp, PubCompReason, PubRel, PubRelReason, Publish,
-    PublishProperties, QoS, SubAck, SubscribeReasonCode, UnsubAck, UnsubAckReason,
+    validate_topic, validate_topic_chars, ConnAck, ConnAckProperties, ConnectReturnCode,
+    Disconnect, DisconnectReasonCode, MqttVersion, Packet, PingResp, PubAck, PubAckReason, PubComp,
+    PubCompReason, PubRel, PubRelReason, Publish, PublishProperties, QoS, RetainForwardRule,
+    SubAck, SubAckProperties, SubscribeProperties, SubscribeReasonCode, TopicError, UnsubAck,
+    UnsubAckReason,
 };
 use crate::router::alertlog::alert;
 use crate::router::graveyard::SavedState;
@@ -9,12 +11,12 @@ use crate::router::scheduler::{PauseReason, Tracker};
 use crate::router::Forward;
 use crate::segments::Position;
 use crate::*;
-use flume::{bounded, Receiver, RecvError, Sender, TryRecvError};
+use flume::{bounded, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
 use slab::Slab;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::str::Utf8Error;
 use std::thread;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 use thiserror::Error;
 use tracing::{debug, error, info, trace, warn};
 
@@ -22,11 +24,14 @@ use super::alertlog::{Alert, AlertLog};
 use super::connection::BrokerAliases;
 use super::graveyard::Graveyard;
 use super::iobufs::{Incoming, Outgoing};
-use super::logs::{AckLog, DataLog};
+use super::logs::{
+    AckLatency, AckLog, AckLogState, DataLog, DelayedPublish, FilterMatches, PubWithProp,
+    PublishData, Released, RetainError,
+};
 use super::scheduler::{ScheduleReason, Scheduler};
 use super::{
-    packetid, Connection, DataRequest, Event, FilterIdx, Meter, Notification, Print, RouterMeter,
-    ShadowRequest, MAX_CHANNEL_CAPACITY, MAX_SCHEDULE_ITERATIONS,
+    packetid, Connection, CursorUpdate, DataRequest, DeliveryOrder, Event, FilterIdx, Meter,
+    Notification, Print, RouterMeter, ShadowRequest, MAX_CHANNEL_CAPACITY, MAX_SCHEDULE_ITERATIONS,
 };
 
 #[derive(Error, Debug)]
@@ -39,6 +44,8 @@ pub enum RouterError {
     Disconnected,
     #[error("Topic not utf-8")]
     NonUtf8Topic(#[from] Utf8Error),
+    #[error("Invalid characters in topic: {0}")]
+    InvalidTopicChars(#[from] TopicError),
     #[cfg(feature = "validate-tenant-prefix")]
     #[error("Bad Tenant")]
     BadTenant(String, String),
@@ -47,14 +54,30 @@ pub enum RouterError {
     #[error("Unsupported QoS {0:?}")]
     UnsupportedQoS(QoS),
     #[error("Invalid filter prefix {0}")]
-    InvalidFilterPrefix(Filter),
+    InvalidFilterPrefix(String),
+    #[error("Filter {0} has {1} levels, more than {2}")]
+    TooManyFilterLevels(String, usize, usize),
+    #[error("Filter {0} has {1} wildcards, more than {2}")]
+    TooManyWildcards(String, usize, usize),
+    #[error("Filter of {0} bytes is longer than {1}")]
+    FilterTooLong(usize, usize),
     #[error("Invalid client_id {0}")]
     InvalidClientId(String),
+    #[error("Invalid delayed publish topic {0}")]
+    InvalidDelayedTopic(String),
+    #[error("Delay of {0} seconds is longer than {1}")]
+    DelayTooLong(u64, u64),
+    #[error("{0} delayed publishes of the client are already held")]
+    DelayedPublishesFull(usize),
     #[error("Disconnection (Reason: {0:?})")]
     Disconnect(DisconnectReasonCode),
 }
 
 const TOPIC_ALIAS_MAX: u16 = 4096;
+const MAX_DELAYED_PUBLISHES: usize = 1000;
+const MAX_PUBLISH_DELAY_SECS: u64 = 24 * 60 * 60;
+const QOS0_TICK_MS: u64 = 10;
+const SLOW_CONSUMER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 pub struct Router {
     id: RouterId,
@@ -99,10 +122,27 @@ pub struct Router {
     router_meters: RouterMeter,
     /// Buffer for cache exchange of incoming packets
     cache: Option<VecDeque<Packet>>,
+    /// Size of unacknowledged publishes across all the connections
+    inflight_bytes: usize,
+    /// Some connections are paused on `max_total_inflight_bytes`
+    inflight_paused: bool,
+    /// New publishes are rejected while draining
+    draining: bool,
+    /// Set once all the pending data has been delivered after draining began
+    drained: bool,
+    /// Callers waiting for the router to drain
+    drain_waiters: Vec<Sender<()>>,
+    /// Last time unacked publishes were checked for retransmission
+    retransmit_checked: Instant,
+    /// Start of the current `qos0_messages_per_tick` tick
+    ticked: Instant,
+    /// Last time subscribers were checked against `max_consumer_lag`
+    slow_consumers_checked: Instant,
 }
 
 impl Router {
-    pub fn new(router_id: RouterId, config: RouterConfig) -> Router {
+    pub fn new(router_id: RouterId, config: RouterConfig) -> Result<Router, ConfigError> {
+        let datalog = DataLog::new(config.clone())?;
         let (router_tx, router_rx) = bounded(1000);
 
         let meters = Slab::with_capacity(10);
@@ -118,7 +158,7 @@ impl Router {
         };
 
         let max_connections = config.max_connections;
-        Router {
+        Ok(Router {
             id: router_id,
             config: config.clone(),
             graveyard: Graveyard::new(),
@@ -129,7 +169,7 @@ impl Router {
             subscription_map: Default::default(),
             ibufs,
             obufs,
-            datalog: DataLog::new(config.clone()).unwrap(),
+            datalog,
             alertlog: AlertLog::new(config),
             ackslog,
             scheduler: Scheduler::with_capacity(max_connections),
@@ -138,7 +178,15 @@ impl Router {
             router_tx,
             router_meters: router_metrics,
             cache: Some(VecDeque::with_capacity(MAX_CHANNEL_CAPACITY)),
-        }
+            inflight_bytes: 0,
+            inflight_paused: false,
+            draining: false,
+            drained: false,
+            drain_waiters: Vec::new(),
+            retransmit_checked: Instant::now(),
+            ticked: Instant::now(),
+            slow_consumers_checked: Instant::now(),
+        })
     }
 
     /// Gets handle to the router. This is not a public method to ensure that link
@@ -188,11 +236,13 @@ impl Router {
     }
 
     fn run_inner(&mut self) -> Result<(), RouterError> {
-        // Block on incoming events if there are no ready connections for consumption
+        // Block on incoming events if there are no ready connections for consumption,
+        // or timed work which is due
         if self.consume().is_none() {
             // trace!("{}:: {:20} {:20} {:?}", self.id, "", "done-await", self.readyqueue);
-            let (id, data) = self.router_rx.recv()?;
-            self.events(id, data);
+            if let Some((id, data)) = self.recv_until(self.next_timeout())? {
+                self.events(id, data);
+            }
         }
 
         // Try reading more from connections in a non-blocking
@@ -222,10 +272,70 @@ impl Router {
             self.consume();
         }
 
+        if let Some(max_lag) = self.config.max_consumer_lag {
+            self.disconnect_slow_consumers(max_lag, Instant::now());
+        }
+
+        if let Some(interval) = self.config.retransmit_interval_ms {
+            self.retransmit_unacked(Duration::from_millis(interval));
+        }
+
+        self.purge_expired_sessions(Instant::now());
+        self.release_delayed_publishes(Instant::now());
+        self.datalog.expire_matches(Instant::now());
+
+        if self.config.qos0_messages_per_tick.is_some() {
+            self.tick(Instant::now());
+        }
+
+        self.notify_drained();
+
         // self.send_all_alerts();
         Ok(())
     }
 
+    /// Blocks on the next event, up to `deadline` if any. None if the
+    /// deadline passed first
+    fn recv_until(
+        &self,
+        deadline: Option<Instant>,
+    ) -> Result<Option<(ConnectionId, Event)>, RouterError> {
+        let Some(deadline) = deadline else {
+            return Ok(Some(self.router_rx.recv()?));
+        };
+
+        match self.router_rx.recv_deadline(deadline) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RouterError::Disconnected),
+        }
+    }
+
+    /// Earliest time at which timed work of the router is due, so that it
+    /// happens without waiting for events
+    fn next_timeout(&self) -> Option<Instant> {
+        let retransmit = self.config.retransmit_interval_ms.and_then(|ms| {
+            self.retransmit_checked
+                .checked_add(Duration::from_millis(ms))
+        });
+
+        // Paced connections resume on the next tick
+        let tick = match self.config.qos0_messages_per_tick {
+            Some(_) if self.scheduler.has_paced() => self.ticked.checked_add(self.tick_interval()),
+            _ => None,
+        };
+
+        [
+            self.graveyard.next_expiry(),
+            self.datalog.next_delayed_due(),
+            retransmit,
+            tick,
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
     fn events(&mut self, id: ConnectionId, data: Event) {
         let span = tracing::error_span!("[>] incoming", connection_id = id);
         let _guard = span.enter();
@@ -253,14 +363,80 @@ impl Router {
                 self.send_meters();
             }
             Event::PrintStatus(metrics) => print_status(self, metrics),
+            Event::Drain(waiter) => {
+                self.begin_drain();
+                self.drain_waiters.push(waiter);
+            }
+            Event::UpdateConfig(patch, reply) => {
+                let result = self.update_config(patch);
+                let _ = reply.try_send(result);
+            }
+            Event::RemoveFilter(filter, reply) => {
+                let removed = self.remove_filter(&filter);
+                info!(removed, "Removing filter {}", filter);
+                let _ = reply.try_send(removed);
+            }
+            Event::CompactFilters(reply) => {
+                let remap = self.compact_filters();
+                let moved = remap.iter().filter(|(old, new)| old != new).count();
+                info!(moved, "Compacted filter logs");
+                let _ = reply.try_send(moved);
+            }
+        }
+    }
+
+    /// Stops accepting new publishes. Connections which publish after this
+    /// are disconnected with `ServerShuttingDown` while pending acks and data
+    /// are still delivered to the subscribers
+    pub fn begin_drain(&mut self) {
+        info!("Draining router");
+        self.draining = true;
+    }
+
+    /// Applies a config patch. Connections paused on limits which may have
+    /// been raised are resumed so that they check them again
+    fn update_config(&mut self, patch: RouterConfigPatch) -> Result<(), ConfigError> {
+        if let Err(e) = self.datalog.update_config(patch) {
+            error!(error = ?e, "Failed to update router config: {e}");
+            return Err(e);
         }
+
+        self.config = self.datalog.config.clone();
+        self.inflight_paused = false;
+        self.scheduler.reschedule_all(ScheduleReason::InflightFreed);
+        info!("Updated router config");
+        Ok(())
+    }
+
+    /// Lets the callers waiting for a drain know once the router is drained
+    fn notify_drained(&mut self) {
+        if !self.is_drained() {
+            return;
+        }
+
+        if !self.drained {
+            info!("Router drained, safe to shutdown");
+            self.drained = true;
+        }
+
+        for waiter in self.drain_waiters.drain(..) {
+            let _ = waiter.try_send(());
+        }
+    }
+
+    /// Draining has begun, all the acks are delivered, every subscriber
+    /// has caught up with its filters and acked what was sent to it
+    pub fn is_drained(&self) -> bool {
+        self.draining
+            && self.notifications.is_empty()
+            && drained(&self.ackslog, &self.obufs, &self.scheduler, &self.datalog)
     }
 
     fn handle_new_connection(
         &mut self,
         mut connection: Connection,
         incoming: Incoming,
-        outgoing: Outgoing,
+        mut outgoing: Outgoing,
     ) {
         let client_id = outgoing.client_id.clone();
         if let Err(err) = validate_clientid(&client_id) {
@@ -296,10 +472,19 @@ impl Router {
         let saved = self.graveyard.retrieve(&client_id);
         let clean_session = connection.clean;
         let previous_session = saved.is_some();
+        // MQTT 5 only allows resending unacked publishes on reconnect (MQTT-4.4.0-1),
+        // which happens by reading them again from the inflight cursors
+        outgoing.set_retransmit(
+            self.config.retransmit_interval_ms.is_some() && connection.version == MqttVersion::V4,
+        );
+        let mut ackslog = AckLog::new();
+        ackslog.set_coalesce_pubacks(self.config.coalesce_pubacks);
+        ackslog.set_track_latency(self.config.track_ack_latency);
         let tracker = if !clean_session {
             let saved = saved.map_or(SavedState::new(client_id.clone()), |s| s);
             connection.subscriptions = saved.subscriptions;
             connection.events = saved.metrics;
+            ackslog.import_state(saved.acks);
             saved.tracker
         } else {
             // Only retrieve metrics in clean session
@@ -307,7 +492,6 @@ impl Router {
             connection.events = saved.metrics;
             Tracker::new(client_id.clone())
         };
-        let ackslog = AckLog::new();
 
         let time = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(v) => v.as_millis().to_string(),
@@ -330,6 +514,10 @@ impl Router {
 
         assert_eq!(self.ackslog.insert(ackslog), connection_id);
         assert_eq!(self.scheduler.add(tracker), connection_id);
+        for request in self.scheduler.trackers[connection_id].get_data_requests() {
+            self.datalog
+                .track_reader(request.filter_idx, connection_id, request.cursor);
+        }
 
         // Check if there are multiple data requests on same filter.
         debug_assert!(self
@@ -343,8 +531,9 @@ impl Router {
         };
 
         let properties = ConnAckProperties {
-            // TODO: set this to some appropriate value
-            topic_alias_max: Some(TOPIC_ALIAS_MAX),
+            topic_alias_max: Some(topic_alias_max(&self.config)),
+            max_packet_size: self.config.server_max_packet_size,
+            receive_max: self.config.receive_maximum,
             ..Default::default()
         };
 
@@ -413,26 +602,39 @@ impl Router {
             outgoing.handle.try_send(()).ok();
         }
 
-        // Remove connection from router
+        // Remove connection from router. Topic aliases of both sides live on
+        // the connection and are dropped along with it, a saved session or a
+        // reused id never sees them
         let mut connection = self.connections.remove(id);
         let _incoming = self.ibufs.remove(id);
         let outgoing = self.obufs.remove(id);
+        self.release_inflight(outgoing.inflight_bytes());
         let mut tracker = self.scheduler.remove(id);
         self.connection_map.remove(&client_id);
-        self.ackslog.remove(id);
+        let acks = self.ackslog.remove(id);
 
         // Don't remove connection id from readyqueue with index. This will
         // remove wrong connection from readyqueue. Instead just leave disconnected
         // connection in readyqueue and allow 'consume()' method to deal with this
         // self.readyqueue.remove(id);
 
-        let inflight_data_requests = self.datalog.clean(id);
+        let inflight_data_requests: Vec<DataRequest> = self
+            .datalog
+            .remove_all_waiters(id)
+            .into_iter()
+            .map(|(filter, request)| {
+                trace!(cursor = ?request.cursor, "Removed waiter on {}", filter);
+                request
+            })
+            .collect();
         let retransmissions = outgoing.retransmission_map();
 
         // Remove this connection from subscriptions
         for filter in connection.subscriptions.iter() {
+            self.datalog.remove_subscriber(id, filter);
             if let Some(connections) = self.subscription_map.get_mut(filter) {
                 connections.remove(&id);
+                self.publish_subscription_event(filter);
             }
         }
 
@@ -449,8 +651,9 @@ impl Router {
             connection.events.events.pop_front();
         }
 
-        // Save state for persistent sessions
-        if !connection.clean {
+        // Save state for persistent sessions. A zero expiry interval ends the
+        // session along with the connection
+        if !connection.clean && connection.session_expiry_interval != Some(0) {
             // Add inflight data requests back to tracker
             inflight_data_requests
                 .into_iter()
@@ -462,16 +665,116 @@ impl Router {
                 }
             }
 
-            self.graveyard
-                .save(tracker, connection.subscriptions, connection.events);
+            let expires_at = session_expiry(connection.session_expiry_interval);
+            self.graveyard.save(
+                tracker,
+                connection.subscriptions,
+                connection.events,
+                acks.export_state(),
+                expires_at,
+            );
         } else {
             // Only save metrics in clean session
-            self.graveyard
-                .save(Tracker::new(client_id), HashSet::new(), connection.events);
+            self.graveyard.save(
+                Tracker::new(client_id),
+                HashSet::new(),
+                connection.events,
+                AckLogState::default(),
+                None,
+            );
         }
         self.router_meters.total_connections -= 1;
     }
 
+    /// Routes the delayed publishes which are due by `now`
+    fn release_delayed_publishes(&mut self, now: Instant) {
+        let due = self.datalog.take_due_publishes(now);
+        if due.is_empty() {
+            return;
+        }
+
+        for delayed in due {
+            let pkid = delayed.publish.pkid;
+            if let Err(e) = append_publish(
+                delayed.publish,
+                delayed.properties,
+                &delayed.topic,
+                delayed.dynamic_filters,
+                &mut self.datalog,
+                &mut self.notifications,
+                Readers {
+                    scheduler: &self.scheduler,
+                    obufs: &self.obufs,
+                    graveyard: &self.graveyard,
+                },
+            ) {
+                warn!(pkid, reason = ?e, "Failed to append delayed publish");
+            }
+        }
+
+        // Prepare all the consumers which are waiting for new data
+        while let Some((id, request)) = self.notifications.pop_front() {
+            self.scheduler.track(id, request);
+            self.scheduler.reschedule(id, ScheduleReason::FreshData);
+        }
+    }
+
+    /// Drops persistent sessions whose expiry interval has passed since
+    /// their disconnection
+    fn purge_expired_sessions(&mut self, now: Instant) {
+        for client_id in self.graveyard.purge_expired(now) {
+            info!(client_id, "Session expired, purged saved state");
+        }
+    }
+
+    /// Drops the log of `filter` unless a connection or saved session is
+    /// subscribed to it, or has unacked publishes from it. Returns whether it
+    /// was removed
+    pub fn remove_filter(&mut self, filter: &str) -> bool {
+        let Some(filter_idx) = self.datalog.filter_idx(filter) else {
+            return false;
+        };
+
+        let subscribed = self
+            .subscription_map
+            .get(filter)
+            .is_some_and(|connections| !connections.is_empty());
+        let inflight = self
+            .obufs
+            .iter()
+            .any(|(_, outgoing)| outgoing.inflight_cursor(filter_idx).is_some());
+        if subscribed || inflight || self.graveyard.is_subscribed(filter) {
+            return false;
+        }
+
+        self.subscription_map.remove(filter);
+        self.datalog.remove_filter(filter).is_some()
+    }
+
+    /// Compacts the filter logs left fragmented by removed filters and rewrites
+    /// every filter index held by the router. Returns the old to new index of
+    /// every filter
+    pub fn compact_filters(&mut self) -> HashMap<FilterIdx, FilterIdx> {
+        let remap = self.datalog.compact_native();
+
+        for (_, tracker) in self.scheduler.trackers.iter_mut() {
+            for request in tracker.data_requests.iter_mut() {
+                request.remap_filter(&remap);
+            }
+        }
+
+        for (_, request) in self.notifications.iter_mut() {
+            request.remap_filter(&remap);
+        }
+
+        for (_, outgoing) in self.obufs.iter_mut() {
+            outgoing.remap_filters(&remap);
+        }
+
+        self.graveyard.remap_filters(&remap);
+        remap
+    }
+
     /// Handles new incoming data on a topic
     fn handle_device_payload(&mut self, id: ConnectionId) {
         // TODO: Retun errors and move error handling to the caller
@@ -495,18 +798,62 @@ impl Router {
         let mut disconnect = false;
         let mut disconnect_reason: Option<DisconnectReasonCode> = None;
         let mut execute_will = true;
+        // Limits advertised in CONNACK only bind MQTT 5 clients
+        let v5 = self
+            .connections
+            .get(id)
+            .is_some_and(|connection| connection.version == MqttVersion::V5);
 
         // info!("{:15.15}[I] {:20} count = {}", client_id, "packets", packets.len());
 
         for packet in packets.drain(0..) {
             match packet {
-                Packet::Publish(mut publish, properties) => {
+                Packet::Publish(publish, properties) => {
+                    if self.draining {
+                        disconnect = true;
+                        disconnect_reason = Some(DisconnectReasonCode::ServerShuttingDown);
+                        break;
+                    }
+
                     let span = tracing::error_span!("publish", topic = ?publish.topic, pkid = publish.pkid);
                     let _guard = span.enter();
 
                     let qos = publish.qos;
                     let pkid = publish.pkid;
 
+                    if let Some(max) = self.config.server_max_packet_size.filter(|_| v5) {
+                        if publish.len() > max as usize {
+                            error!(
+                                len = publish.len(),
+                                max, "Publish exceeds maximum packet size"
+                            );
+                            disconnect = true;
+                            disconnect_reason = Some(DisconnectReasonCode::PacketTooLarge);
+                            break;
+                        }
+                    }
+
+                    if let Some(max) = self.config.max_topic_length {
+                        if publish.topic.len() > max {
+                            error!(len = publish.topic.len(), max, "Publish topic is too long");
+                            disconnect = true;
+                            disconnect_reason = Some(DisconnectReasonCode::TopicNameInvalid);
+                            break;
+                        }
+                    }
+
+                    if let Some(max) = self.config.receive_maximum.filter(|_| v5) {
+                        // Including this one, if it is to be acked
+                        let unacked = self.ackslog[id].unacked_publishes()
+                            + usize::from(qos != QoS::AtMostOnce);
+                        if unacked > max as usize {
+                            error!(unacked, "Client exceeded receive maximum");
+                            disconnect = true;
+                            disconnect_reason = Some(DisconnectReasonCode::ReceiveMaximumExceeded);
+                            break;
+                        }
+                    }
+
                     // Prepare acks for the above publish
                     // If any of the publish in the batch results in force flush,
                     // set global force flush flag. Force flush is triggered when the
@@ -523,14 +870,7 @@ impl Router {
 
                     match qos {
                         QoS::AtLeastOnce => {
-                            let puback = PubAck {
-                                pkid,
-                                reason: PubAckReason::Success,
-                            };
-
-                            let ackslog = self.ackslog.get_mut(id).unwrap();
-                            ackslog.puback(puback);
-                            force_ack = true;
+                            // Acked once appended, see below
                         }
                         QoS::ExactlyOnce => {
                             error!("QoS::ExactlyOnce is not yet supported");
@@ -553,11 +893,6 @@ impl Router {
 
                     self.router_meters.total_publishes += 1;
 
-                    // Ignore retained messages
-                    if publish.retain {
-                        publish.retain = false;
-                    }
-
                     // Try to append publish to commitlog
                     match append_to_commitlog(
                         id,
@@ -566,12 +901,34 @@ impl Router {
                         &mut self.datalog,
                         &mut self.notifications,
                         &mut self.connections,
+                        Readers {
+                            scheduler: &self.scheduler,
+                            obufs: &self.obufs,
+                            graveyard: &self.graveyard,
+                        },
                     ) {
-                        Ok(_offset) => {
+                        Ok((_offset, topic, retain_refused)) => {
                             // Even if one of the data in the batch is appended to commitlog,
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
                             new_data = true;
+
+                            if qos == QoS::AtLeastOnce {
+                                // Tells MQTT 5 publishers that their retained message
+                                // wasn't stored, or that no one received the publish
+                                let reason = if retain_refused.is_some() {
+                                    PubAckReason::QuotaExceeded
+                                } else if self.has_subscribers(&topic) {
+                                    PubAckReason::Success
+                                } else {
+                                    PubAckReason::NoMatchingSubscribers
+                                };
+                                let puback = PubAck { pkid, reason };
+
+                                let ackslog = self.ackslog.get_mut(id).unwrap();
+                                ackslog.puback(puback);
+                                force_ack = true;
+                            }
                         }
                         Err(e) => {
                             // Disconnect on bad publishes
@@ -581,9 +938,7 @@ impl Router {
                             self.router_meters.failed_publishes += 1;
                             disconnect = true;
 
-                            if let RouterError::Disconnect(code) = e {
-                                disconnect_reason = Some(code)
-                            }
+                            disconnect_reason = publish_failure(&e);
 
                             break;
                         }
@@ -596,9 +951,13 @@ impl Router {
                         );
                     };
                 }
-                Packet::Subscribe(subscribe, _) => {
+                Packet::Subscribe(subscribe, properties) => {
                     let mut return_codes = Vec::new();
+                    let mut denials = Vec::new();
                     let pkid = subscribe.pkid;
+                    let order = delivery_order(properties.as_ref());
+                    let live_marker = self.config.live_marker_topic.is_some()
+                        && wants_live_marker(properties.as_ref());
                     // let len = s.len();
 
                     for f in &subscribe.filters {
@@ -609,20 +968,44 @@ impl Router {
                         info!("Adding subscription on topic {}", f.path);
                         let connection = self.connections.get_mut(id).unwrap();
 
-                        if let Err(e) = validate_subscription(connection, f) {
+                        // Refuse only this filter, the rest of the subscribe still goes through
+                        if let Err(e) = validate_subscription(connection, f, &self.config) {
                             warn!(reason = ?e,"Subscription cannot be validated: {}", e);
 
-                            disconnect = true;
-                            break;
+                            return_codes.push(subscribe_failure(&e));
+                            denials.push(format!("{}: {e}", f.path));
+                            continue;
                         }
 
                         let filter = &f.path;
                         let qos = f.qos;
-
-                        let (idx, cursor) = self.datalog.next_native_offset(filter);
-                        self.prepare_filter(id, cursor, idx, filter.clone(), qos as u8);
-                        self.datalog
-                            .handle_retained_messages(filter, &mut self.notifications);
+                        let resubscribe = connection.subscriptions.contains(filter.as_str());
+
+                        self.prepare_filter(
+                            id,
+                            Filter::new_unchecked(filter.as_str()),
+                            qos as u8,
+                            f.preserve_retain,
+                            order,
+                            live_marker,
+                        );
+                        match (&f.retain_forward_rule, resubscribe) {
+                            (RetainForwardRule::Never, _)
+                            | (RetainForwardRule::OnNewSubscribe, true) => {}
+                            (_, false) => self
+                                .datalog
+                                .handle_retained_messages(filter, &mut self.notifications),
+                            (RetainForwardRule::OnEverySubscribe, true) => {
+                                // Other subscribers of the filter already got the retained
+                                // messages, they are only sent to this one again
+                                let count = self.datalog.resend_retained(
+                                    filter,
+                                    id,
+                                    &mut self.notifications,
+                                );
+                                new_data |= count > 0;
+                            }
+                        }
 
                         let code = match qos {
                             QoS::AtMostOnce => SubscribeReasonCode::QoS0,
@@ -636,15 +1019,22 @@ impl Router {
                     // let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
                     // meter.total_size += len;
 
+                    // Reason String lists why filters were refused
+                    let properties = (!denials.is_empty()).then(|| SubAckProperties {
+                        reason_string: Some(denials.join("; ")),
+                        user_properties: Vec::new(),
+                    });
+
                     let suback = SubAck { pkid, return_codes };
                     let ackslog = self.ackslog.get_mut(id).unwrap();
-                    ackslog.suback(suback);
+                    ackslog.suback(suback, properties);
                     force_ack = true;
                 }
                 Packet::Unsubscribe(unsubscribe, _) => {
                     let connection = self.connections.get_mut(id).unwrap();
                     let pkid = unsubscribe.pkid;
-                    for filter in &unsubscribe.filters {
+                    let mut unsubscribed = Vec::new();
+                    for filter in unsubscribe.filters.iter().map(String::as_str) {
                         let span = tracing::info_span!("unsubscribe", topic = filter, pkid);
                         let _guard = span.enter();
 
@@ -677,11 +1067,23 @@ impl Router {
                             };
                             let ackslog = self.ackslog.get_mut(id).unwrap();
                             ackslog.unsuback(unsuback);
-                            self.scheduler.untrack(id, filter);
-                            self.datalog.remove_waiters_for_id(id, filter);
+                            free_data_request(&mut self.scheduler, &mut self.datalog, id, filter);
+                            self.datalog.remove_subscriber(id, filter);
+                            unsubscribed.push(filter);
                             force_ack = true;
+
+                            if self.config.clear_retained_on_unsubscribe
+                                && connection_ids.is_empty()
+                            {
+                                let count = self.datalog.clear_retained_matching(filter);
+                                debug!(count, "Cleared retained messages on filter {}", filter);
+                            }
                         }
                     }
+
+                    for filter in unsubscribed {
+                        self.publish_subscription_event(filter);
+                    }
                 }
                 Packet::PubAck(puback, _) => {
                     let span = tracing::info_span!("puback", pkid = puback.pkid);
@@ -689,12 +1091,13 @@ impl Router {
 
                     let outgoing = self.obufs.get_mut(id).unwrap();
                     let pkid = puback.pkid;
-                    if outgoing.register_ack(pkid).is_none() {
+                    let Some(size) = outgoing.register_ack(pkid) else {
                         error!(pkid, "Unsolicited/ooo ack received for pkid {}", pkid);
                         disconnect = true;
                         break;
-                    }
+                    };
 
+                    self.release_inflight(size);
                     self.scheduler.reschedule(id, ScheduleReason::IncomingAck);
                 }
                 Packet::PubRec(pubrec, _) => {
@@ -703,11 +1106,13 @@ impl Router {
 
                     let outgoing = self.obufs.get_mut(id).unwrap();
                     let pkid = pubrec.pkid;
-                    if outgoing.register_ack(pkid).is_none() {
+                    let Some(size) = outgoing.register_ack(pkid) else {
                         error!(pkid, "Unsolicited/ooo ack received for pkid {}", pkid);
                         disconnect = true;
                         break;
-                    }
+                    };
+
+                    self.release_inflight(size);
 
                     let ackslog = self.ackslog.get_mut(id).unwrap();
                     let pubrel = PubRel {
@@ -729,8 +1134,13 @@ impl Router {
                     };
 
                     let publish = match ackslog.pubcomp(pubcomp) {
-                        Some(v) => v,
-                        None => {
+                        Released::Publish(v) => v,
+                        // Publish was already appended, only PUBCOMP is sent again
+                        Released::Duplicate => {
+                            force_ack = true;
+                            continue;
+                        }
+                        Released::Unknown => {
                             disconnect = true;
                             break;
                         }
@@ -744,8 +1154,13 @@ impl Router {
                         &mut self.datalog,
                         &mut self.notifications,
                         &mut self.connections,
+                        Readers {
+                            scheduler: &self.scheduler,
+                            obufs: &self.obufs,
+                            graveyard: &self.graveyard,
+                        },
                     ) {
-                        Ok(_offset) => {
+                        Ok(_) => {
                             // Even if one of the data in the batch is appended to commitlog,
                             // set new data. This triggers notifications to wake waiters.
                             // Don't overwrite this flag to false if it is already true.
@@ -813,11 +1228,14 @@ impl Router {
     fn prepare_filter(
         &mut self,
         id: ConnectionId,
-        cursor: Offset,
-        filter_idx: FilterIdx,
-        filter: String,
+        filter: Filter,
         qos: u8,
+        preserve_retain: bool,
+        delivery_order: DeliveryOrder,
+        live_marker: bool,
     ) {
+        let (filter_idx, cursor) = self.datalog.next_native_offset(&filter);
+
         // Add connection id to subscription list
         match self.subscription_map.get_mut(&filter) {
             Some(connections) => {
@@ -834,18 +1252,33 @@ impl Router {
         let connection = self.connections.get_mut(id).unwrap();
 
         if connection.subscriptions.insert(filter.clone()) {
+            // Publishes the filter already holds are read backwards as a backlog
+            // while the cursor follows new ones from the head
+            let head = self.datalog.native[filter_idx].next_offset();
+            let (cursor, backlog) = match delivery_order {
+                DeliveryOrder::NewestFirst if cursor < head => (head, Some((cursor, head))),
+                _ => (cursor, None),
+            };
+
             let request = DataRequest {
                 filter: filter.clone(),
                 filter_idx,
                 qos,
+                preserve_retain,
                 cursor,
+                read_ahead: 0,
                 read_count: 0,
                 max_count: 100,
+                delivery_order,
+                backlog,
+                live_marker,
             };
 
+            self.datalog.track_reader(filter_idx, id, cursor);
             self.scheduler.track(id, request);
             self.scheduler.reschedule(id, ScheduleReason::NewFilter);
-            debug_assert!(self.scheduler.check_tracker_duplicates(id).is_none())
+            debug_assert!(self.scheduler.check_tracker_duplicates(id).is_none());
+            self.publish_subscription_event(&filter);
         }
 
         let meter = &mut self.ibufs.get_mut(id).unwrap().meter;
@@ -876,8 +1309,13 @@ impl Router {
 
         trace!("Consuming requests");
 
-        // We always try to ack when ever a connection is scheduled
-        ack_device_data(ackslog, outgoing);
+        // We always try to ack when ever a connection is scheduled. Acks which
+        // don't fit are written once the link takes the buffer
+        if ack_device_data(ackslog, outgoing, self.config.coalesce_pubacks) {
+            self.scheduler.pause(id, PauseReason::Busy);
+            self.scheduler.trackv(id, requests);
+            return Some(());
+        }
 
         let connection = &mut self.connections[id];
         let broker_topic_aliases = &mut connection.broker_topic_aliases;
@@ -886,6 +1324,15 @@ impl Router {
         // A subscribe will register data request.
         // So a new connection is always scheduled with at least one request
         for _ in 0..MAX_SCHEDULE_ITERATIONS {
+            // Checked before every read, so a single read can overshoot the limit
+            if matches!(self.config.max_total_inflight_bytes, Some(max) if self.inflight_bytes >= max)
+            {
+                trace!("Total inflight bytes limit reached, pausing connection");
+                self.scheduler.pause(id, PauseReason::GlobalInflightFull);
+                self.inflight_paused = true;
+                break;
+            }
+
             let mut request = match requests.pop_front() {
                 // Handle next data or acks request
                 Some(request) => request,
@@ -898,13 +1345,38 @@ impl Router {
                 }
             };
 
-            match forward_device_data(
+            // QoS 0 reads are capped by what's left of the connection's budget
+            // for this tick
+            let tracker = &mut self.scheduler.trackers[id];
+            let budget = match self.config.qos0_messages_per_tick {
+                Some(max) if request.qos == 0 => Some(max.saturating_sub(tracker.qos0_delivered)),
+                _ => None,
+            };
+
+            if budget == Some(0) {
+                trace!("QoS 0 budget of this tick used up, pacing connection");
+                requests.push_front(request);
+                self.scheduler.pause(id, PauseReason::Paced);
+                break;
+            }
+
+            let inflight_bytes = outgoing.inflight_bytes();
+            let read_count = request.read_count;
+            let status = forward_device_data(
+                id,
                 &mut request,
                 datalog,
                 outgoing,
                 alertlog,
                 broker_topic_aliases,
-            ) {
+                budget,
+            );
+            self.inflight_bytes += outgoing.inflight_bytes() - inflight_bytes;
+            if request.qos == 0 {
+                tracker.qos0_delivered += (request.read_count - read_count) as u64;
+            }
+
+            match status {
                 ConsumeStatus::BufferFull => {
                     requests.push_back(request);
                     self.scheduler.pause(id, PauseReason::Busy);
@@ -917,12 +1389,23 @@ impl Router {
                 }
                 ConsumeStatus::FilterCaughtup => {
                     let filter = &request.filter;
-                    trace!(filter, "Filter caughtup {filter}, parking connection");
+                    trace!(%filter, "Filter caughtup {filter}, parking connection");
+
+                    if let Some(topic) = &self.config.live_marker_topic {
+                        if std::mem::take(&mut request.live_marker) {
+                            let marker = live_marker(topic, &request);
+                            outgoing.push_forwards(std::iter::once(marker), 0, request.filter_idx);
+                            outgoing.handle.try_send(()).ok();
+                        }
+                    }
 
                     // When all the data in the log is caught up, current request is
                     // registered in waiters and not added back to the tracker. This
                     // ensures that tracker.next() stops when all the requests are done
-                    datalog.park(id, request);
+                    if let Some(stale) = datalog.park(id, request) {
+                        let filter = &stale.filter;
+                        warn!(%filter, cursor = ?stale.cursor, "Connection already parked on {filter}, replacing stale request");
+                    }
                 }
                 ConsumeStatus::PartialRead => {
                     requests.push_back(request);
@@ -935,6 +1418,78 @@ impl Router {
         Some(())
     }
 
+    /// Ids of all the connected clients
+    pub fn connections(&self) -> Vec<ConnectionId> {
+        self.connections.iter().map(|(id, _)| id).collect()
+    }
+
+    /// Filters every connection is subscribed to, each with the offset it
+    /// reads next, whether its request is tracked for delivery, parked or
+    /// about to be woken up. Built in a single pass and sorted by filter.
+    /// Connections without data requests are left out
+    pub fn connection_subscriptions(&self) -> HashMap<ConnectionId, Vec<(Filter, Offset)>> {
+        let tracked = self.scheduler.trackers.iter().flat_map(|(id, tracker)| {
+            tracker
+                .get_data_requests()
+                .iter()
+                .map(move |request| (id, request))
+        });
+        let parked = self.datalog.native.iter().flat_map(|(_, data)| {
+            data.waiters
+                .waiters()
+                .iter()
+                .map(|(id, request)| (*id, request))
+        });
+        let woken = self
+            .notifications
+            .iter()
+            .map(|(id, request)| (*id, request));
+
+        let mut subscriptions: HashMap<ConnectionId, Vec<(Filter, Offset)>> = HashMap::new();
+        for (id, request) in tracked.chain(parked).chain(woken) {
+            subscriptions
+                .entry(id)
+                .or_default()
+                .push((request.filter.clone(), request.cursor));
+        }
+
+        for filters in subscriptions.values_mut() {
+            filters.sort();
+        }
+
+        subscriptions
+    }
+
+    /// Whether a connection is subscribed to any of the filters matching
+    /// `topic`. Subscriptions of disconnected persistent sessions don't count
+    pub fn has_subscribers(&mut self, topic: &str) -> bool {
+        let Some(filter_idxs) = self.datalog.matches(topic) else {
+            return false;
+        };
+
+        filter_idxs.as_slice().iter().any(|&filter_idx| {
+            let filter = self.datalog.native[filter_idx].filter();
+            self.subscription_map
+                .get(filter)
+                .is_some_and(|connections| !connections.is_empty())
+        })
+    }
+
+    /// Publishes current subscriber count of `filter` on the subscription
+    /// events topic, if enabled
+    fn publish_subscription_event(&mut self, filter: &str) {
+        let Some(topic) = &self.config.subscription_events_topic else {
+            return;
+        };
+
+        let subscribers = self.subscription_map.get(filter).map_or(0, |c| c.len());
+        let payload = serde_json::json!({ "filter": filter, "subscribers": subscribers });
+        let publish = Publish::new(topic.clone(), payload.to_string(), false);
+        if let Err(e) = self.system_publish(publish) {
+            warn!(reason = ?e, "Failed to append subscription event");
+        }
+    }
+
     pub fn handle_last_will(&mut self, id: ConnectionId) {
         let connection = self.connections.get_mut(id).unwrap();
         let will = match connection.last_will.take() {
@@ -960,8 +1515,13 @@ impl Router {
             &mut self.datalog,
             &mut self.notifications,
             &mut self.connections,
+            Readers {
+                scheduler: &self.scheduler,
+                obufs: &self.obufs,
+                graveyard: &self.graveyard,
+            },
         ) {
-            Ok(_offset) => {
+            Ok(_) => {
                 // Prepare all the consumers which are waiting for new data
                 while let Some((id, request)) = self.notifications.pop_front() {
                     self.scheduler.track(id, request);
@@ -979,15 +1539,168 @@ impl Router {
         };
     }
 
+    /// Routes a publish originating from the broker itself, e.g. stats, to
+    /// the subscribers of all the filters matching its topic. RETAIN on the
+    /// publish updates the retained message of the topic, even when no
+    /// filter matches
+    pub fn system_publish(&mut self, publish: Publish) -> Result<Offset, RouterError> {
+        let raw_topic = publish.topic.clone();
+        let topic = std::str::from_utf8(&raw_topic)?;
+        validate_topic(topic)?;
+        validate_topic_chars(topic, self.config.reject_control_chars)?;
+
+        let (offset, ..) = append_publish(
+            publish,
+            None,
+            topic,
+            false,
+            &mut self.datalog,
+            &mut self.notifications,
+            Readers {
+                scheduler: &self.scheduler,
+                obufs: &self.obufs,
+                graveyard: &self.graveyard,
+            },
+        )?;
+
+        // Prepare all the consumers which are waiting for new data
+        while let Some((id, request)) = self.notifications.pop_front() {
+            self.scheduler.track(id, request);
+            self.scheduler.reschedule(id, ScheduleReason::FreshData);
+        }
+
+        Ok(offset)
+    }
+
+    /// Frees up acked or dropped inflight bytes and resumes connections paused
+    /// on the total inflight limit once there is space again, or once the
+    /// limit is removed
+    fn release_inflight(&mut self, size: usize) {
+        self.inflight_bytes -= size;
+        if self.inflight_paused
+            && !matches!(self.config.max_total_inflight_bytes, Some(max) if self.inflight_bytes >= max)
+        {
+            self.inflight_paused = false;
+            self.scheduler.reschedule_all(ScheduleReason::InflightFreed);
+        }
+    }
+
+    /// Disconnect subscribers which have fallen behind the head of any of
+    /// their filters by more than `max_lag` messages. Slow consumers otherwise
+    /// hold back retention of the data they haven't read yet. Subscribers are
+    /// checked at most once every `SLOW_CONSUMER_CHECK_INTERVAL`
+    fn disconnect_slow_consumers(&mut self, max_lag: u64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.slow_consumers_checked);
+        if elapsed < SLOW_CONSUMER_CHECK_INTERVAL {
+            return;
+        }
+
+        self.slow_consumers_checked = now;
+        for id in slow_consumers(&self.scheduler, &self.datalog, max_lag) {
+            warn!(connection_id = id, "Disconnecting slow consumer");
+            self.handle_disconnection(id, true, Some(DisconnectReasonCode::QuotaExceeded));
+        }
+    }
+
+    fn tick_interval(&self) -> Duration {
+        Duration::from_millis(self.config.qos0_tick_ms.unwrap_or(QOS0_TICK_MS))
+    }
+
+    /// Starts a new `qos0_messages_per_tick` tick once the current one is over
+    fn tick(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.ticked) < self.tick_interval() {
+            return;
+        }
+
+        self.ticked = now;
+        self.scheduler.tick();
+    }
+
+    /// Sends publishes again with DUP set to connections which haven't acked them
+    /// within `interval`. Connections are checked at most once every `interval`
+    fn retransmit_unacked(&mut self, interval: Duration) {
+        let now = Instant::now();
+        if now.saturating_duration_since(self.retransmit_checked) < interval {
+            return;
+        }
+
+        self.retransmit_checked = now;
+        for (_, outgoing) in self.obufs.iter_mut() {
+            let due = outgoing.due_for_retransmit(now, interval);
+            if due.is_empty() {
+                continue;
+            }
+
+            debug!(
+                client_id = outgoing.client_id,
+                count = due.len(),
+                "Retransmitting unacked publishes"
+            );
+
+            for forward in due {
+                outgoing.push_notification(Notification::Forward(forward));
+            }
+
+            outgoing.handle.try_send(()).ok();
+        }
+    }
+
     fn send_meters(&mut self) {
         let mut meters = Vec::with_capacity(10);
+        self.router_meters.estimated_memory = self.datalog.estimated_memory();
+        self.router_meters.published_topics = self.datalog.published_topic_count();
+        self.router_meters.retained_messages = self.datalog.retained_count();
+        let (hits, misses) = self.datalog.match_cache_stats();
+        self.router_meters.match_cache_hits = hits;
+        self.router_meters.match_cache_misses = misses;
+        if let Some(threshold) = self.config.saturated_waiters {
+            self.router_meters.saturated_filters = self
+                .datalog
+                .saturated_filters(threshold)
+                .into_iter()
+                .map(|idx| self.datalog.native[idx].filter().to_string())
+                .collect();
+        }
+        if self.config.track_ack_latency {
+            let mut latency = AckLatency::default();
+            for (_, ackslog) in self.ackslog.iter_mut() {
+                if let Some(stats) = ackslog.take_ack_latency_stats() {
+                    latency.merge(&stats);
+                }
+            }
+            self.router_meters.ack_latency = Some(latency);
+        }
         if let Some(router_meter) = self.router_meters.get() {
             meters.push(Meter::Router(self.id, router_meter));
         }
         for f in self.subscription_map.keys() {
-            let filter = f.to_owned();
+            let filter = f.to_string();
+            let lag = self
+                .datalog
+                .filter_idx(f)
+                .and_then(|filter_idx| self.datalog.max_delivery_lag(filter_idx));
+
+            if let Some(meter) = self.datalog.meter(f) {
+                meter.max_delivery_lag = lag;
+            }
+
             if let Some(subscription_meter) = self.datalog.meter(f).and_then(|meter| meter.get()) {
-                meters.push(Meter::Subscription(filter, subscription_meter));
+                meters.push(Meter::Subscription(filter.clone(), subscription_meter));
+            }
+
+            if self.config.meter_subscribers {
+                let Some(filter_idx) = self.datalog.filter_idx(f) else {
+                    continue;
+                };
+
+                for &id in self.subscription_map[f].iter() {
+                    let Some(meter) = self.datalog.subscriber_meter(filter_idx, id) else {
+                        continue;
+                    };
+
+                    let client_id = self.connections[id].client_id.clone();
+                    meters.push(Meter::Subscriber(filter.clone(), client_id, meter));
+                }
             }
         }
 
@@ -1021,7 +1734,8 @@ fn append_to_commitlog(
     datalog: &mut DataLog,
     notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
     connections: &mut Slab<Connection>,
-) -> Result<Offset, RouterError> {
+    readers: Readers,
+) -> Result<(Offset, Topic, Option<RetainError>), RouterError> {
     let connection = connections.get_mut(id).unwrap();
 
     let topic_alias = properties.as_mut().and_then(|p| {
@@ -1030,10 +1744,31 @@ fn append_to_commitlog(
     });
 
     if let Some(alias) = topic_alias {
-        validate_and_set_topic_alias(&mut publish, connection, alias)?;
+        let alias_max = topic_alias_max(&datalog.config);
+        validate_and_set_topic_alias(&mut publish, connection, alias, alias_max)?;
     };
 
-    let topic = std::str::from_utf8(&publish.topic)?;
+    let raw_topic = publish.topic.clone();
+    let topic = std::str::from_utf8(&raw_topic)?;
+    let max_delay = datalog
+        .config
+        .max_publish_delay_secs
+        .unwrap_or(MAX_PUBLISH_DELAY_SECS);
+    let (delay, topic) = match delayed_topic(topic, max_delay)? {
+        Some((delay, topic)) => (Some(delay), topic),
+        None => (None, topic),
+    };
+    let rewritten = rewrite_topic(&datalog.config.topic_rewrites, topic)?;
+    let topic = rewritten.as_deref().unwrap_or(topic);
+    validate_topic_chars(topic, datalog.config.reject_control_chars)?;
+
+    // Wildcards in a topic name are almost always a client bug, they are
+    // only routed as literal characters when explicitly allowed
+    if !datalog.config.allow_publish_wildcards {
+        if let Some(index) = topic.find(['+', '#']) {
+            return Err(TopicError::WildcardInTopic(index).into());
+        }
+    }
 
     // Ensure that only clients associated with a tenant can publish to tenant's topic
     #[cfg(feature = "validate-tenant-prefix")]
@@ -1046,14 +1781,54 @@ fn append_to_commitlog(
         }
     }
 
-    if publish.payload.is_empty() {
-        datalog.remove_from_retained_publishes(topic.to_owned());
-    } else if publish.retain {
-        error!("Unexpected: retain field was not unset");
-        datalog.insert_to_retained_publishes(publish.clone(), properties.clone(), topic.to_owned());
+    // Delayed publishes are routed once they are due, nothing is appended now
+    if let Some(delay) = delay {
+        let max = datalog
+            .config
+            .max_delayed_publishes
+            .unwrap_or(MAX_DELAYED_PUBLISHES);
+        if datalog.delayed_count_of(&connection.client_id) >= max {
+            return Err(RouterError::DelayedPublishesFull(max));
+        }
+
+        let due = Instant::now()
+            .checked_add(delay)
+            .ok_or(RouterError::DelayTooLong(delay.as_secs(), max_delay))?;
+        let topic = Topic::new_unchecked(topic);
+        let delayed = DelayedPublish {
+            publish,
+            properties,
+            topic: topic.clone(),
+            client_id: connection.client_id.clone(),
+            dynamic_filters: connection.dynamic_filters,
+        };
+        datalog.delay_publish(due, delayed);
+        return Ok(((0, 0), topic, None));
     }
 
-    publish.retain = false;
+    let dynamic_filters = connection.dynamic_filters;
+    append_publish(
+        publish,
+        properties,
+        topic,
+        dynamic_filters,
+        datalog,
+        notifications,
+        readers,
+    )
+}
+
+/// Appends a validated publish to the logs of all the filters matching its
+/// topic and updates the retained message of the topic
+fn append_publish(
+    mut publish: Publish,
+    properties: Option<PublishProperties>,
+    topic: &str,
+    dynamic_filters: bool,
+    datalog: &mut DataLog,
+    notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+    readers: Readers,
+) -> Result<(Offset, Topic, Option<RetainError>), RouterError> {
     let pkid = publish.pkid;
 
     let filter_idxs = datalog.matches(topic);
@@ -1061,37 +1836,164 @@ fn append_to_commitlog(
     // Create a dynamic filter if dynamic_filters are enabled for this connection
     let filter_idxs = match filter_idxs {
         Some(v) => v,
-        None if connection.dynamic_filters => {
+        None if dynamic_filters => {
             let (idx, _cursor) = datalog.next_native_offset(topic);
-            vec![idx]
+            FilterMatches::One(idx)
         }
         None => return Err(RouterError::NoMatchingFilters(topic.to_owned())),
     };
 
-    let mut o = (0, 0);
-    for filter_idx in filter_idxs {
-        let datalog = datalog.native.get_mut(filter_idx).unwrap();
-        let publish_data = (publish.clone(), properties.clone());
-        let (offset, filter) = datalog.append(publish_data.into(), notifications);
-        debug!(
+    // Publishes on the same topic share its name rather than each holding
+    // the buffer it was read from. Only names which are routed or retained
+    // are kept in the pool
+    let clears_retained = publish.payload.is_empty() && !datalog.config.retain_empty_payloads;
+    let topic = if !filter_idxs.is_empty() || (publish.retain && !clears_retained) {
+        let (topic, topic_bytes) = datalog.intern_topic(topic);
+        publish.topic = topic_bytes;
+        topic
+    } else {
+        Topic::new_unchecked(topic)
+    };
+
+    // Refused as a whole before anything is retained or appended, filters
+    // can have segments of different sizes
+    let publish_data = PublishData::from((publish.clone(), properties.clone()));
+    let size = publish_data.size();
+    let fits = |&filter_idx: &FilterIdx| datalog.native[filter_idx].fits(size);
+    if !filter_idxs.as_slice().iter().all(fits) {
+        warn!(
             pkid,
-            "Appended to commitlog: {}[{}, {})", filter, offset.0, offset.1,
+            size, "Publish doesn't fit in a segment of its filters"
         );
-
-        o = offset;
+        return Err(RouterError::Disconnect(
+            DisconnectReasonCode::PacketTooLarge,
+        ));
     }
 
-    // error!("{:15.15}[E] {:20} topic = {}", connections[id].client_id, "no-filter", topic);
-    Ok(o)
-}
+    // Only publishes with RETAIN set touch the retained message of their topic
+    let mut retain_refused = None;
+    if publish.retain && clears_retained {
+        datalog.remove_from_retained_publishes(&topic);
+    } else if publish.retain {
+        let retained = datalog.insert_to_retained_publishes(
+            publish.clone(),
+            properties.clone(),
+            topic.clone(),
+        );
+
+        // Publish is still delivered to current subscribers
+        if let Err(e) = retained {
+            warn!(reason = ?e, "Publish not retained");
+            retain_refused = Some(e);
+        }
+    }
+
+    if filter_idxs.is_empty() {
+        let (offset, topic) =
+            route_unmatched(publish, properties, topic, datalog, notifications, readers)?;
+        return Ok((offset, topic, retain_refused));
+    }
+
+    let mut o = (0, 0);
+    let filter_idxs = filter_idxs.as_slice();
+    // Matches are deduplicated as they're built, see `FilterMatches::push`
+    for &filter_idx in filter_idxs.iter() {
+        let shed_lag = datalog.config.shed_qos0_lag;
+        if let Some(max_lag) = shed_lag.filter(|_| publish.qos == QoS::AtMostOnce) {
+            let lag = datalog.shedding_lag(filter_idx);
+            if lag > max_lag {
+                let datalog = datalog.native.get_mut(filter_idx).unwrap();
+                datalog.meter.shed_count += 1;
+                trace!(pkid, lag, "Shedding QoS 0 publish on lagging filter");
+                continue;
+            }
+        }
+
+        // Subscribers are only looked up when the append evicts a segment
+        let floor = match datalog.config.segment_overflow {
+            SegmentOverflow::KeepUnread if datalog.native[filter_idx].is_full() => {
+                readers.floor(notifications, filter_idx)
+            }
+            _ => None,
+        };
+
+        let datalog = datalog.native.get_mut(filter_idx).unwrap();
+        if datalog.is_duplicate(properties.as_ref()) {
+            datalog.meter.duplicate_count += 1;
+            trace!(pkid, "Dropping duplicate publish");
+            continue;
+        }
+
+        // Fits, as checked above
+        let (position, filter) = datalog
+            .append_above(publish_data.clone(), notifications, floor)
+            .map_err(|_| RouterError::Disconnect(DisconnectReasonCode::PacketTooLarge))?;
+        let offset = position.next_offset();
+        debug!(
+            pkid,
+            "Appended to commitlog: {}[{}, {})", filter, offset.0, offset.1,
+        );
+
+        o = offset;
+    }
+
+    // error!("{:15.15}[E] {:20} topic = {}", connections[id].client_id, "no-filter", topic);
+    Ok((o, topic, retain_refused))
+}
+
+/// Handles a publish whose topic no filter matches according to
+/// `no_match_policy`. Its retained message is already stored
+fn route_unmatched(
+    mut publish: Publish,
+    properties: Option<PublishProperties>,
+    topic: Topic,
+    datalog: &mut DataLog,
+    notifications: &mut VecDeque<(ConnectionId, DataRequest)>,
+    readers: Readers,
+) -> Result<(Offset, Topic), RouterError> {
+    let dead_letter = match &datalog.config.no_match_policy {
+        NoMatchPolicy::DeadLetter(dead_letter) if publish.qos != QoS::AtMostOnce => {
+            dead_letter.clone()
+        }
+        _ => {
+            trace!(pkid = publish.pkid, "Dropping publish matching no filter");
+            return Ok(((0, 0), topic));
+        }
+    };
+
+    // Nothing is subscribed to the dead letter topic either
+    if *topic == dead_letter {
+        return Ok(((0, 0), topic));
+    }
+
+    let mut properties = properties.unwrap_or_default();
+    properties
+        .user_properties
+        .push(("original-topic".to_owned(), topic.to_string()));
+    publish.retain = false;
+
+    let (offset, ..) = append_publish(
+        publish,
+        Some(properties),
+        &dead_letter,
+        false,
+        datalog,
+        notifications,
+        readers,
+    )?;
+
+    // Publishers are still told that nobody subscribes to the original topic
+    Ok((offset, topic))
+}
 
 fn validate_and_set_topic_alias(
     publish: &mut Publish,
     connection: &mut Connection,
     alias: u16,
+    alias_max: u16,
 ) -> Result<(), RouterError> {
-    if alias == 0 || alias > TOPIC_ALIAS_MAX {
-        error!("Alias must be greater than 0 and <={TOPIC_ALIAS_MAX}");
+    if alias == 0 || alias > alias_max {
+        error!("Alias must be greater than 0 and <={alias_max}");
         return Err(RouterError::Disconnect(
             DisconnectReasonCode::TopicAliasInvalid,
         ));
@@ -1106,37 +2008,146 @@ fn validate_and_set_topic_alias(
                 ));
             };
         // set the publish topic before further processing
-        publish.topic = alias_topic.to_owned().into();
+        publish.topic = alias_topic.to_string().into();
     } else {
         // if publish topic isn't empty, that means
         // publisher wants to establish new mapping for topic & alias
         let topic = std::str::from_utf8(&publish.topic)?;
-        connection.topic_aliases.insert(alias, topic.to_owned());
+        connection
+            .topic_aliases
+            .insert(alias, Topic::new_unchecked(topic));
         trace!("set alias {alias} for topic {topic}");
     };
 
     Ok(())
 }
 
+/// Deadline of a session disconnected now. `u32::MAX` seconds means the
+/// session never expires
+fn session_expiry(interval: Option<u32>) -> Option<Instant> {
+    let interval = interval.filter(|&interval| interval != u32::MAX)?;
+    Instant::now().checked_add(Duration::from_secs(interval as u64))
+}
+
+/// Highest topic alias clients are allowed to set
+fn topic_alias_max(config: &RouterConfig) -> u16 {
+    config.topic_alias_maximum.unwrap_or(TOPIC_ALIAS_MAX)
+}
+
+/// Connections with at least one data request lagging more than `max_lag`
+/// messages behind the head of its filter
+fn slow_consumers(scheduler: &Scheduler, datalog: &DataLog, max_lag: u64) -> Vec<ConnectionId> {
+    scheduler
+        .trackers
+        .iter()
+        .filter(|(_, tracker)| {
+            tracker
+                .get_data_requests()
+                .iter()
+                .any(|request| datalog.consumer_lag(request) > max_lag)
+        })
+        .map(|(id, _)| id)
+        .collect()
+}
+
+/// Frees the data request of a connection on exactly `filter`, whether it's
+/// tracked for delivery or parked waiting for new data. Requests on other
+/// filters, including overlapping wildcards, are kept
+fn free_data_request(
+    scheduler: &mut Scheduler,
+    datalog: &mut DataLog,
+    id: ConnectionId,
+    filter: &str,
+) -> Option<DataRequest> {
+    let tracked = scheduler.untrack(id, filter);
+    let parked = datalog.remove_waiters_for_id(id, filter);
+    tracked.or(parked)
+}
+
+/// Holders of read positions in the filter logs, whose data
+/// `SegmentOverflow::KeepUnread` keeps
+#[derive(Clone, Copy)]
+struct Readers<'a> {
+    scheduler: &'a Scheduler,
+    obufs: &'a Slab<Outgoing>,
+    graveyard: &'a Graveyard,
+}
+
+impl Readers<'_> {
+    /// Lowest offset of a filter which is yet to be read, whether by a
+    /// subscriber tracked for delivery or about to be woken up, by a saved
+    /// session, or again after a disconnection for unacked publishes. Parked
+    /// subscribers have read everything
+    fn floor(
+        &self,
+        notifications: &VecDeque<(ConnectionId, DataRequest)>,
+        filter_idx: FilterIdx,
+    ) -> Option<Offset> {
+        let requests = self
+            .scheduler
+            .trackers
+            .iter()
+            .flat_map(|(_, tracker)| tracker.get_data_requests())
+            .chain(notifications.iter().map(|(_, request)| request))
+            .chain(self.graveyard.data_requests())
+            .filter(|request| request.filter_idx == filter_idx)
+            .map(|request| request.backlog.map_or(request.cursor, |(start, _)| start));
+        let inflight = self
+            .obufs
+            .iter()
+            .filter_map(|(_, outgoing)| outgoing.inflight_cursor(filter_idx));
+
+        requests.chain(inflight).min()
+    }
+}
+
+/// Acks of all the connections are delivered, no outgoing publish is
+/// waiting for an ack and no data request lags behind the head of its filter
+fn drained(
+    ackslog: &Slab<AckLog>,
+    obufs: &Slab<Outgoing>,
+    scheduler: &Scheduler,
+    datalog: &DataLog,
+) -> bool {
+    ackslog.iter().all(|(_, acks)| acks.is_empty())
+        && obufs.iter().all(|(_, outgoing)| outgoing.inflight() == 0)
+        && scheduler.trackers.iter().all(|(_, tracker)| {
+            tracker
+                .get_data_requests()
+                .iter()
+                .all(|request| datalog.consumer_lag(request) == 0)
+        })
+}
+
 /// Sweep ackslog for all the pending acks.
 /// We write everything to outgoing buf with out worrying about buffer size
-/// because acks most certainly won't cause memory bloat
-fn ack_device_data(ackslog: &mut AckLog, outgoing: &mut Outgoing) -> bool {
+/// because acks most certainly won't cause memory bloat. With PUBACK
+/// coalescing, acks are limited to what fits in the buffer instead and the
+/// connection is unscheduled until the link takes it. Returns true if acks
+/// are left pending
+fn ack_device_data(ackslog: &mut AckLog, outgoing: &mut Outgoing, coalesce: bool) -> bool {
     let span = tracing::info_span!("outgoing_ack", client_id = outgoing.client_id);
     let _guard = span.enter();
 
-    let acks = ackslog.readv();
-    if acks.is_empty() {
+    let mut buffer = outgoing.data_buffer.lock();
+    let acks = if coalesce {
+        // Room for an unschedule notification is kept
+        ackslog.drain_coalesced(MAX_CHANNEL_CAPACITY.saturating_sub(buffer.len() + 1))
+    } else {
+        ackslog.drain()
+    };
+
+    let pending = !ackslog.is_empty();
+    if acks.is_empty() && !pending {
         debug!("No acks pending");
         return false;
     }
 
     let mut count = 0;
-    let mut buffer = outgoing.data_buffer.lock();
 
     // Unlike forwards, we are reading all the pending acks for a given connection.
     // At any given point of time, there can be a max of connection's buffer size
-    for ack in acks.drain(..) {
+    for ack in acks {
         let pkid = packetid(&ack);
         trace!(pkid, "Ack added for pkid {}", pkid);
         let message = Notification::DeviceAck(ack);
@@ -1144,9 +2155,15 @@ fn ack_device_data(ackslog: &mut AckLog, outgoing: &mut Outgoing) -> bool {
         count += 1;
     }
 
+    if pending {
+        debug!("Outgoing channel can't fit all the acks");
+        buffer.push_back(Notification::Unschedule);
+    }
+
+    drop(buffer);
     debug!(acks_count = count, "Acks sent to device");
     outgoing.handle.try_send(()).ok();
-    true
+    pending
 }
 
 enum ConsumeStatus {
@@ -1167,11 +2184,13 @@ enum ConsumeStatus {
 /// 2. `done`: whether the connection was busy or not.
 /// 3. `inflight_full`: whether the inflight requests were completely filled
 fn forward_device_data(
+    id: ConnectionId,
     request: &mut DataRequest,
-    datalog: &DataLog,
+    datalog: &mut DataLog,
     outgoing: &mut Outgoing,
     alertlog: &mut AlertLog,
     broker_topic_aliases: &mut Option<BrokerAliases>,
+    budget: Option<u64>,
 ) -> ConsumeStatus {
     let span = tracing::info_span!("outgoing_publish", client_id = outgoing.client_id);
     let _guard = span.enter();
@@ -1191,39 +2210,96 @@ fn forward_device_data(
 
         len as u64
     } else {
-        datalog.config.max_read_len
+        let config = &datalog.config;
+        let len = request.read_len(config.initial_read_len, config.max_read_len);
+        budget.map_or(len, |budget| len.min(budget))
     };
 
-    let (next, publishes) =
-        match datalog.native_readv(request.filter_idx, request.cursor, inflight_slots) {
-            Ok(v) => v,
-            Err(e) => {
-                error!(error = ?e, "Failed to read from commitlog {}", e);
-                return ConsumeStatus::FilterCaughtup;
-            }
-        };
+    // Retained messages resent on a re-subscription go ahead of the log,
+    // within the same read length and inflight limits
+    let resend = datalog.take_resend(
+        request.filter_idx,
+        id,
+        request.cursor,
+        inflight_slots,
+        request.preserve_retain,
+    );
+    if !resend.is_empty() {
+        request.read_count += resend.len();
+        return forward_publishes(request, resend, false, outgoing, broker_topic_aliases);
+    }
+
+    // Backlog of a newest first subscription is read once the publishes
+    // appended since the subscription are caught up with
+    if let Some(backlog) = request.backlog {
+        if datalog.consumer_lag(request) == 0 {
+            let (remaining, publishes) = datalog.native_readv_rev(
+                request.filter_idx,
+                backlog,
+                inflight_slots,
+                request.preserve_retain,
+            );
 
-    let (start, next, caughtup) = match next {
-        Position::Next { start, end } => (start, end, false),
-        Position::Done { start, end } => (start, end, true),
+            trace!(
+                "Read backlog backwards, remaining = {:?}, read count = {}",
+                remaining,
+                publishes.len()
+            );
+
+            request.backlog = remaining;
+            request.read_count += publishes.len();
+            datalog.meter_delivery(id, request, &publishes);
+            return match (publishes.is_empty(), remaining) {
+                (true, None) => ConsumeStatus::FilterCaughtup,
+                (true, Some(_)) => ConsumeStatus::PartialRead,
+                (false, _) => forward_publishes(
+                    request,
+                    publishes,
+                    remaining.is_none(),
+                    outgoing,
+                    broker_topic_aliases,
+                ),
+            };
+        }
+    }
+
+    let (next, publishes) = match datalog.native_readv(
+        request.filter_idx,
+        request.cursor,
+        inflight_slots,
+        request.preserve_retain,
+    ) {
+        Ok(v) => v,
+        Err(e) => {
+            error!(error = ?e, "Failed to read from commitlog {}", e);
+            return ConsumeStatus::FilterCaughtup;
+        }
     };
 
-    if start != request.cursor {
+    // Publishes evicted before this subscriber read them are skipped
+    if let Some(gap) = next.gap(request.cursor) {
+        let skipped = gap.skipped() as usize;
         let error = format!(
-            "Read cursor start jumped from {:?} to {:?} on {}",
-            request.cursor, start, request.filter
+            "Read cursor start jumped from {:?} to {:?} on {}, skipping {} publishes",
+            gap.skipped_from, gap.resumed_at, request.filter, skipped
         );
 
         warn!(
-            request_cursor = ?request.cursor,
-            start_cursor = ?start,
+            request_cursor = ?gap.skipped_from,
+            start_cursor = ?gap.resumed_at,
             error
         );
 
-        let alert = alert::cursorjump(&outgoing.client_id, &request.filter, 0);
+        datalog.native[request.filter_idx].meter.messages_missed += skipped;
+        let alert = alert::cursorjump(&outgoing.client_id, &request.filter, skipped);
         alertlog.log(alert);
     }
 
+    let (next, caughtup) = match next {
+        Position::Next { end, .. } => (end, false),
+        Position::Done { end, .. } => (end, true),
+    };
+
     trace!(
         "Read from commitlog, cursor = {}[{}, {}), read count = {}",
         request.filter,
@@ -1232,16 +2308,45 @@ fn forward_device_data(
         publishes.len()
     );
 
-    let qos = request.qos;
-    let filter_idx = request.filter_idx;
     request.read_count += publishes.len();
-    request.cursor = next;
+    match request.update_cursor(next) {
+        CursorUpdate::Advanced => datalog.track_reader(request.filter_idx, id, next),
+        CursorUpdate::Unchanged => (),
+        CursorUpdate::Regressed => warn!(
+            request_cursor = ?request.cursor,
+            next_cursor = ?next,
+            "Ignoring read cursor regression on {}",
+            request.filter
+        ),
+    }
     // println!("{:?} {:?} {}", start, next, request.read_count);
 
+    datalog.meter_delivery(id, request, &publishes);
+    if request.qos == 0 && datalog.config.initial_read_len.is_some() {
+        let lag = datalog.consumer_lag(request);
+        request.adapt_read_ahead(inflight_slots, publishes.len() as u64, lag, caughtup);
+    }
+
     if publishes.is_empty() {
         return ConsumeStatus::FilterCaughtup;
     }
 
+    // Live publishes are still pending while the backlog isn't delivered
+    let caughtup = caughtup && request.backlog.is_none();
+    forward_publishes(request, publishes, caughtup, outgoing, broker_topic_aliases)
+}
+
+/// Forwards `publishes` read for `request`. `caughtup` tells whether the
+/// request has read everything there is to read
+fn forward_publishes(
+    request: &DataRequest,
+    publishes: Vec<(PubWithProp, Offset)>,
+    caughtup: bool,
+    outgoing: &mut Outgoing,
+    broker_topic_aliases: &mut Option<BrokerAliases>,
+) -> ConsumeStatus {
+    let qos = request.qos;
+    let filter_idx = request.filter_idx;
     let mut topic_alias = broker_topic_aliases
         .as_ref()
         .and_then(|aliases| aliases.get_alias(&request.filter));
@@ -1392,12 +2497,86 @@ fn print_status(router: &mut Router, metrics: Print) {
             let metrics = router.scheduler.readyqueue.clone();
             println!("{metrics:#?}");
         }
+        Print::DataLog => {
+            let dump = router.datalog.debug_dump();
+            #[cfg(feature = "debug-dump")]
+            match serde_json::to_string_pretty(&dump) {
+                Ok(dump) => println!("{dump}"),
+                Err(e) => error!(error = ?e, "Failed to serialize data log dump"),
+            }
+            #[cfg(not(feature = "debug-dump"))]
+            println!("{dump:#?}");
+        }
+        Print::Topics => {
+            let tree = router.datalog.topic_tree();
+            println!("{tree:#?}");
+        }
+        Print::Offsets => {
+            let filters: Vec<FilterIdx> =
+                router.datalog.native.iter().map(|(idx, _)| idx).collect();
+            let offsets: HashMap<Filter, Offset> = router
+                .datalog
+                .snapshot_offsets(&filters)
+                .into_iter()
+                .map(|(idx, offset)| (router.datalog.native[idx].filter().clone(), offset))
+                .collect();
+
+            println!("{offsets:#?}");
+        }
+        Print::CommitLog(filter) => {
+            let stats = router
+                .datalog
+                .filter_idx(&filter)
+                .and_then(|idx| router.datalog.commitlog_stats(idx));
+            println!("{stats:#?}");
+        }
+        Print::Publish(filter, offset) => {
+            let publish = router
+                .datalog
+                .filter_idx(&filter)
+                .and_then(|idx| router.datalog.get_publish(idx, offset))
+                .map(|data| (data.publish.clone(), data.properties.clone()));
+            println!("{publish:#?}");
+        }
+        Print::Tail(filter, count) => {
+            let publishes = router
+                .datalog
+                .filter_idx(&filter)
+                .map(|idx| router.datalog.tail(idx, count));
+            println!("{publishes:#?}");
+        }
+        Print::Connections => {
+            let mut by_id = router.connection_subscriptions();
+            let subscriptions: HashMap<String, Vec<(Filter, Offset)>> = router
+                .connections()
+                .into_iter()
+                .map(|id| {
+                    let client_id = router.connections[id].client_id.clone();
+                    (client_id, by_id.remove(&id).unwrap_or_default())
+                })
+                .collect();
+
+            println!("{subscriptions:#?}");
+        }
+        #[cfg(feature = "debug-dump")]
+        Print::FilterData(filter) => {
+            // Oldest publishes still in memory, up to a read worth of them
+            let publishes = router.datalog.filter_idx(&filter).map(|idx| {
+                let len = router.config.max_read_len;
+                router.datalog.dump_filter_json(idx, (0, 0), len)
+            });
+            match serde_json::to_string_pretty(&publishes) {
+                Ok(publishes) => println!("{publishes}"),
+                Err(e) => error!(error = ?e, "Failed to serialize filter data"),
+            }
+        }
     };
 }
 
 fn validate_subscription(
     connection: &mut Connection,
     filter: &protocol::Filter,
+    config: &RouterConfig,
 ) -> Result<(), RouterError> {
     trace!(
         "validate subscription = {}, tenant = {:?}",
@@ -1416,13 +2595,145 @@ fn validate_subscription(
         return Err(RouterError::UnsupportedQoS(filter.qos));
     }
 
-    if filter.path.starts_with('$') {
+    let events_topic = config.subscription_events_topic.as_deref();
+    if filter.path.starts_with('$') && events_topic != Some(&filter.path) {
         return Err(RouterError::InvalidFilterPrefix(filter.path.to_owned()));
     }
 
+    validate_topic_chars(&filter.path, config.reject_control_chars)?;
+
+    if let Some(max) = config.max_topic_length {
+        if filter.path.len() > max {
+            return Err(RouterError::FilterTooLong(filter.path.len(), max));
+        }
+    }
+
+    // Filters with many levels and wildcards are costly to match against every topic
+    let levels = filter.path.split('/');
+    if let Some(max) = config.max_filter_levels {
+        let count = levels.clone().count();
+        if count > max {
+            return Err(RouterError::TooManyFilterLevels(
+                filter.path.clone(),
+                count,
+                max,
+            ));
+        }
+    }
+
+    if let Some(max) = config.max_wildcards_per_filter {
+        let count = levels.filter(|level| matches!(*level, "+" | "#")).count();
+        if count > max {
+            return Err(RouterError::TooManyWildcards(
+                filter.path.clone(),
+                count,
+                max,
+            ));
+        }
+    }
+
     Ok(())
 }
 
+/// Delay and topic of a publish to `$delayed/<seconds>/<topic>`. `None` for
+/// all the other topics. Delays are at most `max_delay` seconds
+fn delayed_topic(topic: &str, max_delay: u64) -> Result<Option<(Duration, &str)>, RouterError> {
+    let Some(rest) = topic.strip_prefix("$delayed/") else {
+        return Ok(None);
+    };
+
+    let invalid = || RouterError::InvalidDelayedTopic(topic.to_owned());
+    let (seconds, topic) = rest.split_once('/').ok_or_else(invalid)?;
+    let seconds: u64 = seconds.parse().map_err(|_| invalid())?;
+    if seconds > max_delay {
+        return Err(RouterError::DelayTooLong(seconds, max_delay));
+    }
+
+    validate_topic(topic)?;
+    Ok(Some((Duration::from_secs(seconds), topic)))
+}
+
+/// Topic rewritten by the first of `rules` matching it. Characters and
+/// wildcards of rewritten topics are checked by the caller, the same way as
+/// those of topics which aren't rewritten
+fn rewrite_topic(rules: &[TopicRewrite], topic: &str) -> Result<Option<String>, TopicError> {
+    let Some(rewritten) = rules.iter().find_map(|rule| rule.apply(topic)) else {
+        return Ok(None);
+    };
+
+    if rewritten.is_empty() {
+        return Err(TopicError::Empty);
+    }
+
+    Ok(Some(rewritten))
+}
+
+/// Whether a subscribe asks for a marker once caught up, with a
+/// `live-marker: true` user property
+fn wants_live_marker(properties: Option<&SubscribeProperties>) -> bool {
+    properties.is_some_and(|properties| {
+        properties
+            .user_properties
+            .iter()
+            .any(|(key, value)| key == "live-marker" && value == "true")
+    })
+}
+
+/// Marker telling a subscriber that `request` caught up with its filter and
+/// further publishes are live
+fn live_marker(topic: &str, request: &DataRequest) -> Forward {
+    let publish = Publish::new(topic.to_owned(), request.filter.to_string(), false);
+    Forward {
+        cursor: request.cursor,
+        size: 0,
+        publish,
+        properties: None,
+    }
+}
+
+/// Order requested with a `delivery-order` user property on the subscribe.
+/// Its only recognized value is `newest-first`
+fn delivery_order(properties: Option<&SubscribeProperties>) -> DeliveryOrder {
+    let newest_first = properties.is_some_and(|properties| {
+        properties
+            .user_properties
+            .iter()
+            .any(|(key, value)| key == "delivery-order" && value == "newest-first")
+    });
+
+    match newest_first {
+        true => DeliveryOrder::NewestFirst,
+        false => DeliveryOrder::OldestFirst,
+    }
+}
+
+/// Reason code acknowledging a filter refused by `validate_subscription`
+fn subscribe_failure(error: &RouterError) -> SubscribeReasonCode {
+    match error {
+        RouterError::InvalidFilterPrefix(_) => SubscribeReasonCode::NotAuthorized,
+        RouterError::InvalidTopicChars(_)
+        | RouterError::TooManyFilterLevels(..)
+        | RouterError::TooManyWildcards(..)
+        | RouterError::FilterTooLong(..) => SubscribeReasonCode::TopicFilterInvalid,
+        RouterError::UnsupportedQoS(_) => SubscribeReasonCode::ImplementationSpecific,
+        _ => SubscribeReasonCode::Unspecified,
+    }
+}
+
+/// Reason code of the DISCONNECT sent to a client whose publish failed with
+/// `error`. `None` leaves the reason unspecified
+fn publish_failure(error: &RouterError) -> Option<DisconnectReasonCode> {
+    match error {
+        RouterError::Disconnect(code) => Some(*code),
+        RouterError::InvalidTopicChars(TopicError::WildcardInTopic(_)) => {
+            Some(DisconnectReasonCode::TopicNameInvalid)
+        }
+        RouterError::DelayTooLong(..) => Some(DisconnectReasonCode::ImplementationSpecificError),
+        RouterError::DelayedPublishesFull(_) => Some(DisconnectReasonCode::QuotaExceeded),
+        _ => None,
+    }
+}
+
 fn validate_clientid(client_id: &str) -> Result<(), RouterError> {
     trace!("Validating Client ID = {}", client_id,);
     // Ensure that only client devices of the tenant can
@@ -1433,6 +2744,3026 @@ fn validate_clientid(client_id: &str) -> Result<(), RouterError> {
     Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{v5::V5, Protocol};
+    use crate::protocol::{
+        Filter, PubRec, PubRecReason, RetainForwardRule, Subscribe, Unsubscribe,
+    };
+    use crate::router::connection::Connection;
+    use crate::router::fixtures::{config, request};
+    use crate::router::logs::ConfigError;
+    use crate::router::Ack;
+    use bytes::Bytes;
+    use bytes::BytesMut;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[test]
+    fn consumers_lagging_beyond_limit_are_flagged() {
+        let mut datalog = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = datalog.next_native_offset("hello/world");
+        for _ in 0..10 {
+            let publish = Publish::new("hello/world", "hello", false);
+            datalog
+                .native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+
+        let mut scheduler = Scheduler::with_capacity(10);
+        let mut slow = Tracker::new("slow".to_owned());
+        slow.register_data_request(request("hello/world", idx, cursor));
+        let slow = scheduler.add(slow);
+
+        let mut fast = Tracker::new("fast".to_owned());
+        fast.register_data_request(request("hello/world", idx, (0, 8)));
+        scheduler.add(fast);
+
+        assert_eq!(slow_consumers(&scheduler, &datalog, 5), vec![slow]);
+        assert!(slow_consumers(&scheduler, &datalog, 10).is_empty());
+    }
+
+    #[test]
+    fn drained_after_acks_are_sent_and_consumers_catch_up() {
+        let mut datalog = DataLog::new(config()).unwrap();
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = datalog.next_native_offset("hello/world");
+        for _ in 0..10 {
+            let publish = Publish::new("hello/world", "hello", false);
+            datalog
+                .native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+
+        let mut scheduler = Scheduler::with_capacity(10);
+        let mut tracker = Tracker::new("consumer".to_owned());
+        tracker.register_data_request(request("hello/world", idx, cursor));
+        let id = scheduler.add(tracker);
+
+        let mut ackslog = Slab::new();
+        let mut acks = AckLog::new();
+        acks.puback(PubAck {
+            pkid: 1,
+            reason: PubAckReason::Success,
+        });
+        ackslog.insert(acks);
+
+        let mut obufs = Slab::new();
+        let (mut outgoing, _rx) = Outgoing::new("consumer".to_owned());
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::AtLeastOnce;
+        let forward = Forward {
+            cursor: (0, 0),
+            size: 0,
+            publish,
+            properties: None,
+        };
+        outgoing.push_forwards(std::iter::once(forward), 1, idx);
+        obufs.insert(outgoing);
+        assert!(!drained(&ackslog, &obufs, &scheduler, &datalog));
+
+        ackslog[0].readv().clear();
+        assert!(!drained(&ackslog, &obufs, &scheduler, &datalog));
+
+        let head = datalog.native.get(idx).unwrap().log.next_offset();
+        scheduler.untrack(id, "hello/world");
+        scheduler.track(id, request("hello/world", idx, head));
+        assert!(!drained(&ackslog, &obufs, &scheduler, &datalog));
+
+        // Outgoing QoS 1 publish is still waiting for its ack
+        obufs[0].register_ack(1).unwrap();
+        assert!(drained(&ackslog, &obufs, &scheduler, &datalog));
+    }
+
+    #[test]
+    fn drain_waiters_are_notified_once_inflight_publishes_are_acked() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (id, _tx, _rx) = connect_persistent(&mut router, "consumer", None);
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::AtLeastOnce;
+        let forward = Forward {
+            cursor: (0, 0),
+            size: 0,
+            publish,
+            properties: None,
+        };
+        router.obufs[id].push_forwards(std::iter::once(forward), 1, 0);
+        router.ackslog[id].readv().clear();
+
+        let (waiter, drained) = flume::bounded(1);
+        router.events(0, Event::Drain(waiter));
+        router.notify_drained();
+        assert!(drained.try_recv().is_err());
+
+        router.obufs[id].register_ack(1).unwrap();
+        router.notify_drained();
+        assert!(drained.try_recv().is_ok());
+    }
+
+    /// Registers a new connection and returns its id along with buffers to
+    /// send packets to the router and receive notifications from it
+    #[allow(clippy::type_complexity)]
+    fn connect(
+        router: &mut Router,
+        client_id: &str,
+    ) -> (
+        ConnectionId,
+        Arc<Mutex<VecDeque<Packet>>>,
+        Arc<Mutex<VecDeque<Notification>>>,
+    ) {
+        let connection = Connection::new(
+            None,
+            client_id.to_owned(),
+            true,
+            None,
+            None,
+            false,
+            0,
+            MqttVersion::V5,
+        );
+        register(router, connection)
+    }
+
+    /// Registers a connection which keeps its session for `session_expiry_interval`
+    /// seconds after disconnection
+    #[allow(clippy::type_complexity)]
+    fn connect_persistent(
+        router: &mut Router,
+        client_id: &str,
+        session_expiry_interval: Option<u32>,
+    ) -> (
+        ConnectionId,
+        Arc<Mutex<VecDeque<Packet>>>,
+        Arc<Mutex<VecDeque<Notification>>>,
+    ) {
+        let connection = Connection::new(
+            None,
+            client_id.to_owned(),
+            false,
+            session_expiry_interval,
+            None,
+            false,
+            0,
+            MqttVersion::V5,
+        );
+        register(router, connection)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn register(
+        router: &mut Router,
+        connection: Connection,
+    ) -> (
+        ConnectionId,
+        Arc<Mutex<VecDeque<Packet>>>,
+        Arc<Mutex<VecDeque<Notification>>>,
+    ) {
+        let client_id = connection.client_id.clone();
+        let incoming = Incoming::new(client_id.to_owned());
+        let (outgoing, _) = Outgoing::new(client_id.to_owned());
+        let (tx, rx) = (incoming.buffer(), outgoing.buffer());
+        router.handle_new_connection(connection, incoming, outgoing);
+
+        let id = router.connection_map[&client_id];
+        (id, tx, rx)
+    }
+
+    fn consume_all(router: &mut Router) {
+        for _ in 0..100 {
+            if router.consume().is_none() {
+                return;
+            }
+        }
+    }
+
+    fn forwards(rx: &Mutex<VecDeque<Notification>>) -> Vec<u16> {
+        rx.lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish.pkid),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reads done by a subscriber keeping up with bursts of publishes
+    fn reads_to_keep_up(config: RouterConfig) -> usize {
+        let mut datalog = DataLog::new(config.clone()).unwrap();
+        let mut alertlog = AlertLog::new(config);
+        let (mut outgoing, _) = Outgoing::new("subscriber".to_owned());
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = datalog.next_native_offset("hello/world");
+        let mut request = request("hello/world", idx, cursor);
+
+        let mut reads = 0;
+        for _ in 0..10 {
+            for _ in 0..50 {
+                let publish = Publish::new("hello/world", "hello", false);
+                datalog
+                    .native
+                    .get_mut(idx)
+                    .unwrap()
+                    .append((publish, None).into(), &mut notifications)
+                    .unwrap();
+            }
+
+            loop {
+                reads += 1;
+                let status = forward_device_data(
+                    0,
+                    &mut request,
+                    &mut datalog,
+                    &mut outgoing,
+                    &mut alertlog,
+                    &mut None,
+                    None,
+                );
+                outgoing.buffer().lock().clear();
+                if matches!(status, ConsumeStatus::FilterCaughtup) {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(request.read_count, 500);
+        reads
+    }
+
+    #[test]
+    fn invalid_config_is_returned_by_new() {
+        let config = RouterConfig {
+            max_read_len: 0,
+            ..config()
+        };
+        assert!(matches!(
+            Router::new(0, config),
+            Err(ConfigError::TooSmall("max_read_len", 1))
+        ));
+    }
+
+    #[test]
+    fn read_len_change_applies_to_next_read() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (idx, cursor) = router.datalog.next_native_offset("hello/world");
+        let mut notifications = VecDeque::new();
+        for _ in 0..10 {
+            let publish = Publish::new("hello/world", "hello", false);
+            router
+                .datalog
+                .native
+                .get_mut(idx)
+                .unwrap()
+                .append((publish, None).into(), &mut notifications)
+                .unwrap();
+        }
+
+        let patch = RouterConfigPatch {
+            max_read_len: Some(3),
+            ..Default::default()
+        };
+        let (reply, result) = flume::bounded(1);
+        router.events(0, Event::UpdateConfig(patch, reply));
+        assert_eq!(result.try_recv().unwrap(), Ok(()));
+        assert_eq!(router.config.max_read_len, 3);
+
+        let patch = RouterConfigPatch {
+            max_segment_size: Some(4096),
+            ..Default::default()
+        };
+        let (reply, result) = flume::bounded(1);
+        router.events(0, Event::UpdateConfig(patch, reply));
+        assert_eq!(
+            result.try_recv().unwrap(),
+            Err(ConfigError::Immutable("max_segment_size"))
+        );
+
+        let mut alertlog = AlertLog::new(router.config.clone());
+        let (mut outgoing, _) = Outgoing::new("subscriber".to_owned());
+        let mut request = request("hello/world", idx, cursor);
+        let status = forward_device_data(
+            0,
+            &mut request,
+            &mut router.datalog,
+            &mut outgoing,
+            &mut alertlog,
+            &mut None,
+            None,
+        );
+        assert!(matches!(status, ConsumeStatus::PartialRead));
+        assert_eq!(request.read_count, 3);
+    }
+
+    #[test]
+    fn read_ahead_reduces_reads_of_fast_subscriber() {
+        let adaptive = RouterConfig {
+            max_read_len: 12,
+            initial_read_len: Some(4),
+            ..config()
+        };
+        let mut datalog = DataLog::new(adaptive.clone()).unwrap();
+        let mut alertlog = AlertLog::new(adaptive);
+        let (mut outgoing, _) = Outgoing::new("subscriber".to_owned());
+        let mut notifications = VecDeque::new();
+        let (idx, cursor) = datalog.next_native_offset("hello/world");
+        let mut request = request("hello/world", idx, cursor);
+
+        // Full reads double the read length until it's capped at max_read_len
+        let mut lengths = Vec::new();
+        for count in [8, 8, 20] {
+            for _ in 0..count {
+                let publish = Publish::new("hello/world", "hello", false);
+                datalog
+                    .native
+                    .get_mut(idx)
+                    .unwrap()
+                    .append((publish, None).into(), &mut notifications)
+                    .unwrap();
+            }
+
+            let status = forward_device_data(
+                0,
+                &mut request,
+                &mut datalog,
+                &mut outgoing,
+                &mut alertlog,
+                &mut None,
+                None,
+            );
+            assert!(matches!(status, ConsumeStatus::PartialRead));
+            lengths.push(outgoing.buffer().lock().drain(..).count());
+        }
+        assert_eq!(lengths, vec![4, 8, 12]);
+
+        let fixed = reads_to_keep_up(RouterConfig {
+            max_read_len: 4,
+            ..config()
+        });
+        let adaptive = reads_to_keep_up(RouterConfig {
+            max_read_len: 64,
+            initial_read_len: Some(4),
+            ..config()
+        });
+
+        assert!(
+            adaptive * 4 < fixed,
+            "adaptive = {adaptive}, fixed = {fixed}"
+        );
+    }
+
+    #[test]
+    fn routing_survives_filter_compaction() {
+        let mut router = Router::new(0, config()).unwrap();
+        router.datalog.next_native_offset("unused/a");
+        router.datalog.next_native_offset("unused/b");
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/+".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+
+        let publish = |router: &mut Router, pkid| {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+            consume_all(router);
+        };
+
+        publish(&mut router, 1);
+        assert_eq!(forwards(&sub_rx), vec![1]);
+        assert_eq!(router.datalog.filter_idx("hello/+"), Some(2));
+
+        // Subscriber is parked on the filter while it's moved
+        assert!(!router.remove_filter("hello/+"));
+        assert!(router.remove_filter("unused/a"));
+        assert!(router.remove_filter("unused/b"));
+        let remap = router.compact_filters();
+        assert_eq!(remap, HashMap::from([(2, 0)]));
+        assert_eq!(router.datalog.filter_idx("hello/+"), Some(0));
+        assert_eq!(router.datalog.native.len(), 1);
+
+        publish(&mut router, 2);
+        publish(&mut router, 3);
+        assert_eq!(forwards(&sub_rx), vec![2, 3]);
+        let data = router.datalog.native.get(0).unwrap();
+        assert_eq!(data.meter.count, 3);
+    }
+
+    #[test]
+    fn qos0_backlog_is_paced_over_cycles() {
+        let config = RouterConfig {
+            max_segment_size: 1024 * 1024,
+            qos0_messages_per_tick: Some(100),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+
+        for _ in 0..10_000 {
+            let publish = Publish::new("hello/world", "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+
+        let mut cycles = 0;
+        let mut delivered = 0;
+        loop {
+            consume_all(&mut router);
+            let count = forwards(&sub_rx).len();
+            if count == 0 {
+                break;
+            }
+
+            assert!(count <= 100, "{count} publishes forwarded in one cycle");
+            delivered += count;
+            cycles += 1;
+            router.scheduler.tick();
+        }
+
+        assert_eq!(delivered, 10_000);
+        assert_eq!(cycles, 100);
+    }
+
+    #[test]
+    fn paced_backlog_is_delivered_without_events() {
+        let config = RouterConfig {
+            max_segment_size: 1024 * 1024,
+            qos0_messages_per_tick: Some(100),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        forwards(&sub_rx);
+
+        for _ in 0..1000 {
+            let publish = Publish::new("hello/world", "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+
+        // Nothing else happens on the broker, the router would block on
+        // events if ticks depended on them. Paced connections wake it up at
+        // the end of the tick instead of right away
+        let mut delivered = 0;
+        for _ in 0..20 {
+            router.run_inner().unwrap();
+            if router.scheduler.has_paced() {
+                let deadline = router.next_timeout().unwrap();
+                assert_eq!(deadline, router.ticked + Duration::from_millis(10));
+            }
+
+            delivered += forwards(&sub_rx).len();
+            if delivered == 1000 {
+                break;
+            }
+        }
+
+        assert_eq!(delivered, 1000);
+    }
+
+    #[test]
+    fn newest_first_backlog_is_reversed_before_live_publishes() {
+        let config = RouterConfig {
+            max_read_len: 2,
+            initialized_filters: Some(vec!["hello/+".parse().unwrap()]),
+            compacted_filters: vec!["hello/+".parse().unwrap()],
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let publish = |router: &mut Router, topic: &'static str| {
+            let publish = Publish::new(topic, "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+        };
+
+        let topics = |rx: &Mutex<VecDeque<Notification>>| -> Vec<String> {
+            rx.lock()
+                .drain(..)
+                .filter_map(|notification| match notification {
+                    Notification::Forward(forward) => {
+                        Some(String::from_utf8(forward.publish.topic.to_vec()).unwrap())
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+
+        for topic in ["hello/1", "hello/2", "hello/3", "hello/4", "hello/5"] {
+            publish(&mut router, topic);
+        }
+
+        let filter = Filter {
+            path: "hello/+".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        let properties = SubscribeProperties {
+            id: None,
+            user_properties: vec![("delivery-order".to_owned(), "newest-first".to_owned())],
+        };
+        sub_tx
+            .lock()
+            .push_back(Packet::Subscribe(subscribe, Some(properties)));
+        router.handle_device_payload(subscriber);
+
+        // Publishes appended after the subscription aren't held back by the backlog
+        publish(&mut router, "hello/6");
+        consume_all(&mut router);
+        assert_eq!(
+            topics(&sub_rx),
+            vec!["hello/6", "hello/5", "hello/4", "hello/3", "hello/2", "hello/1"]
+        );
+
+        publish(&mut router, "hello/7");
+        publish(&mut router, "hello/8");
+        consume_all(&mut router);
+        assert_eq!(topics(&sub_rx), vec!["hello/7", "hello/8"]);
+    }
+
+    #[test]
+    fn live_marker_is_delivered_once_after_catching_up() {
+        let config = RouterConfig {
+            live_marker_topic: Some("$SYS/live".to_owned()),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (other, other_tx, other_rx) = connect(&mut router, "other");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let publish = |router: &mut Router, topic: &'static str, retain| {
+            let publish = Publish::new(topic, "hello", retain);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+        };
+        let received = |rx: &Mutex<VecDeque<Notification>>| -> Vec<(String, Bytes)> {
+            rx.lock()
+                .drain(..)
+                .filter_map(|notification| match notification {
+                    Notification::Forward(forward) => {
+                        let topic = String::from_utf8(forward.publish.topic.to_vec()).unwrap();
+                        Some((topic, forward.publish.payload))
+                    }
+                    _ => None,
+                })
+                .collect()
+        };
+        let subscribe = |path: &str, properties| {
+            let filter = Filter {
+                path: path.to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::OnEverySubscribe,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            Packet::Subscribe(subscribe, properties)
+        };
+
+        // Subscriptions without the user property get no marker
+        other_tx.lock().push_back(subscribe("hello/#", None));
+        router.handle_device_payload(other);
+        publish(&mut router, "hello/1", true);
+
+        let properties = SubscribeProperties {
+            id: None,
+            user_properties: vec![("live-marker".to_owned(), "true".to_owned())],
+        };
+        sub_tx
+            .lock()
+            .push_back(subscribe("hello/+", Some(properties)));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+
+        let hello = Bytes::from("hello");
+        assert_eq!(
+            received(&sub_rx),
+            vec![
+                ("hello/1".to_owned(), hello.clone()),
+                ("$SYS/live".to_owned(), Bytes::from("hello/+")),
+            ]
+        );
+        assert_eq!(
+            received(&other_rx),
+            vec![("hello/1".to_owned(), hello.clone())]
+        );
+
+        publish(&mut router, "hello/2", false);
+        consume_all(&mut router);
+        assert_eq!(received(&sub_rx), vec![("hello/2".to_owned(), hello)]);
+    }
+
+    #[test]
+    fn empty_retained_payload_clears_unless_configured() {
+        let run = |retain_empty_payloads| {
+            let config = RouterConfig {
+                retain_empty_payloads,
+                ..config()
+            };
+            let mut router = Router::new(0, config).unwrap();
+            let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+            let mut retained = |payload: &'static str, retain| {
+                let publish = Publish::new("hello/world", payload, retain);
+                pub_tx.lock().push_back(Packet::Publish(publish, None));
+                router.handle_device_payload(publisher);
+                router.datalog.retained_count()
+            };
+
+            assert_eq!(retained("hello", true), 1);
+            // Empty publishes without RETAIN never touch the retained message
+            assert_eq!(retained("", false), 1);
+            retained("", true)
+        };
+
+        assert_eq!(run(false), 0);
+        assert_eq!(run(true), 1);
+    }
+
+    #[test]
+    fn segments_unread_by_subscribers_are_kept() {
+        let run = |segment_overflow| {
+            let config = RouterConfig {
+                max_segment_count: 2,
+                segment_overflow,
+                ..config()
+            };
+            let mut router = Router::new(0, config).unwrap();
+            let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+            let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+            let filter = Filter {
+                path: "hello/world".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+            router.handle_device_payload(subscriber);
+
+            // Subscriber doesn't read while 1 KB segments fill up with 4 publishes each
+            for _ in 0..20 {
+                let publish = Publish::new("hello/world".to_owned(), "x".repeat(256), false);
+                pub_tx.lock().push_back(Packet::Publish(publish, None));
+            }
+            router.handle_device_payload(publisher);
+
+            let idx = router.datalog.filter_idx("hello/world").unwrap();
+            let data = &router.datalog.native[idx];
+            let evicted = (data.meter.segments_evicted, data.meter.messages_dropped);
+            let segments = data.log.memory_segments_count();
+
+            consume_all(&mut router);
+            let missed = router.datalog.native[idx].meter.messages_missed;
+            (evicted, segments, forwards(&sub_rx).len(), missed)
+        };
+
+        let (evicted, segments, delivered, missed) = run(SegmentOverflow::KeepUnread);
+        assert_eq!(evicted, (0, 0));
+        assert_eq!(segments, 5);
+        assert_eq!(delivered, 20);
+        assert_eq!(missed, 0);
+
+        let (evicted, segments, delivered, missed) = run(SegmentOverflow::DropOldest);
+        assert_eq!(evicted, (3, 12));
+        assert_eq!(segments, 2);
+        assert_eq!(delivered, 8);
+        assert_eq!(missed, 12);
+    }
+
+    #[test]
+    fn segments_unread_by_saved_sessions_are_kept() {
+        let config = RouterConfig {
+            max_segment_count: 2,
+            segment_overflow: SegmentOverflow::KeepUnread,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (id, tx, _) = connect_persistent(&mut router, "device", Some(60));
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(id);
+        consume_all(&mut router);
+
+        // Forwarded but never acked, so read again after reconnecting
+        let publish = |pub_tx: &Mutex<VecDeque<Packet>>, count| {
+            for _ in 0..count {
+                let publish = Publish::new("hello/world".to_owned(), "x".repeat(256), false);
+                pub_tx.lock().push_back(Packet::Publish(publish, None));
+            }
+        };
+        publish(&pub_tx, 2);
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+        router.handle_disconnection(id, false, None);
+
+        // 1 KB segments fill up with 4 publishes each
+        publish(&pub_tx, 18);
+        router.handle_device_payload(publisher);
+
+        let idx = router.datalog.filter_idx("hello/world").unwrap();
+        assert_eq!(router.datalog.native[idx].meter.segments_evicted, 0);
+
+        let (_, _, rx) = connect_persistent(&mut router, "device", Some(60));
+        consume_all(&mut router);
+        assert_eq!(forwards(&rx).len(), 20);
+        assert_eq!(router.datalog.native[idx].meter.messages_missed, 0);
+    }
+
+    #[test]
+    fn publishes_on_a_topic_share_its_name() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, _sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+
+        for i in 0..10 {
+            let publish = Publish::new("hello/world".to_owned(), format!("{i}"), true);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+
+        let idx = router.datalog.filter_idx("hello/world").unwrap();
+        let (_, publishes) = router
+            .datalog
+            .native_readv(idx, (0, 0), 100, false)
+            .unwrap();
+        assert_eq!(publishes.len(), 10);
+        let name = publishes[0].0 .0.topic.as_ptr();
+        assert!(publishes.iter().all(|((p, _), _)| p.topic.as_ptr() == name));
+
+        let retained = router.datalog.retained_topics().next().unwrap();
+        let (cached, _) = router
+            .datalog
+            .publish_filters
+            .get_key_value("hello/world")
+            .unwrap();
+        assert_eq!(retained.as_ptr(), cached.as_ptr());
+    }
+
+    #[test]
+    fn only_routed_and_retained_topics_are_pooled() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, _) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+
+        let mut publish = |topic: &str, payload: &str, retain| {
+            let publish = Publish::new(topic.to_owned(), payload.to_owned(), retain);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+            router.datalog.topics.len()
+        };
+
+        assert_eq!(publish("unmatched/1", "x", false), 0);
+        assert_eq!(publish("unmatched/2", "x", false), 0);
+        assert_eq!(publish("hello/world", "x", false), 1);
+        assert_eq!(publish("unmatched/1", "x", true), 2);
+        assert_eq!(publish("unmatched/1", "", true), 1);
+        assert_eq!(publish("unmatched/+", "x", false), 1);
+    }
+
+    #[test]
+    fn published_topics_are_rewritten_before_routing() {
+        let config = RouterConfig {
+            topic_rewrites: vec![TopicRewrite {
+                prefix: "devices/".to_owned(),
+                replacement: "tenant-a/devices/".to_owned(),
+            }],
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = |path: &str| Filter {
+            path: path.to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter("tenant-a/#"), filter("devices/#"), filter("other/#")],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+
+        for topic in ["devices/1", "other/devices/1"] {
+            let publish = Publish::new(topic.to_owned(), "x".to_owned(), false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        let topics: Vec<String> = sub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => {
+                    Some(String::from_utf8(forward.publish.topic.to_vec()).unwrap())
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(topics, vec!["tenant-a/devices/1", "other/devices/1"]);
+    }
+
+    #[test]
+    fn rewritten_topics_follow_the_wildcard_policy() {
+        for allow_publish_wildcards in [false, true] {
+            let config = RouterConfig {
+                topic_rewrites: vec![TopicRewrite {
+                    prefix: "devices/".to_owned(),
+                    replacement: "tenant-a/devices/".to_owned(),
+                }],
+                allow_publish_wildcards,
+                ..config()
+            };
+            let mut router = Router::new(0, config).unwrap();
+            let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+            let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+            let filter = Filter {
+                path: "tenant-a/#".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+            router.handle_device_payload(subscriber);
+
+            let publish = Publish::new("devices/+", "x", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+            consume_all(&mut router);
+
+            let delivered = forwards(&sub_rx).len();
+            assert_eq!(delivered, allow_publish_wildcards as usize);
+            assert_eq!(
+                router.connection_map.contains_key("publisher"),
+                allow_publish_wildcards
+            );
+        }
+    }
+
+    #[test]
+    fn puback_reports_refused_retained_messages() {
+        let config = RouterConfig {
+            max_retained_message_size: Some(64),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (publisher, pub_tx, pub_rx) = connect(&mut router, "publisher");
+
+        for (pkid, size) in [(1, 10), (2, 100)] {
+            let mut publish = Publish::new("hello/world".to_owned(), "x".repeat(size), true);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        let reasons: Vec<(u16, PubAckReason)> = pub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::DeviceAck(Ack::PubAck(puback)) => Some((puback.pkid, puback.reason)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (1, PubAckReason::NoMatchingSubscribers),
+                (2, PubAckReason::QuotaExceeded)
+            ]
+        );
+        assert_eq!(router.datalog.retained_count(), 1);
+    }
+
+    #[test]
+    fn puback_reports_publishes_without_subscribers() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, _sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, pub_rx) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/#".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+
+        for (pkid, topic) in [(1, "hello/world"), (2, "other/world")] {
+            let mut publish = Publish::new(topic, "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        let pubacks: Vec<PubAck> = pub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::DeviceAck(Ack::PubAck(puback)) => Some(puback),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            pubacks,
+            vec![
+                PubAck {
+                    pkid: 1,
+                    reason: PubAckReason::Success
+                },
+                PubAck {
+                    pkid: 2,
+                    reason: PubAckReason::NoMatchingSubscribers
+                },
+            ]
+        );
+        assert!(router.has_subscribers("hello/world"));
+        assert!(!router.has_subscribers("other/world"));
+    }
+
+    #[test]
+    fn subscriptions_are_listed_per_connection() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (first, first_tx, _first_rx) = connect(&mut router, "first");
+        let (second, second_tx, _second_rx) = connect(&mut router, "second");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let subscribe = |paths: &[&str]| {
+            let filters = paths
+                .iter()
+                .map(|path| Filter {
+                    path: path.to_string(),
+                    qos: QoS::AtMostOnce,
+                    nolocal: false,
+                    preserve_retain: false,
+                    retain_forward_rule: RetainForwardRule::Never,
+                })
+                .collect();
+            Packet::Subscribe(Subscribe { pkid: 1, filters }, None)
+        };
+        first_tx.lock().push_back(subscribe(&["a/+", "b/#"]));
+        router.handle_device_payload(first);
+        second_tx.lock().push_back(subscribe(&["b/#", "c/d"]));
+        router.handle_device_payload(second);
+
+        for _ in 0..3 {
+            let publish = Publish::new("b/c", "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        assert_eq!(router.connections(), vec![first, second, publisher]);
+        let filter = |path: &str| path.parse::<crate::Filter>().unwrap();
+        let subscriptions = router.connection_subscriptions();
+        assert_eq!(
+            subscriptions[&first],
+            vec![(filter("a/+"), (0, 0)), (filter("b/#"), (0, 3))]
+        );
+        assert_eq!(
+            subscriptions[&second],
+            vec![(filter("b/#"), (0, 3)), (filter("c/d"), (0, 0))]
+        );
+        assert!(!subscriptions.contains_key(&publisher));
+    }
+
+    #[test]
+    fn persistent_session_resumes_within_expiry() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (id, tx, _) = connect_persistent(&mut router, "device", Some(60));
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(id);
+        consume_all(&mut router);
+        router.handle_disconnection(id, false, None);
+
+        let publish = Publish::new("hello/world", "offline", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        router.purge_expired_sessions(Instant::now());
+
+        let (id, _, rx) = connect_persistent(&mut router, "device", Some(60));
+        consume_all(&mut router);
+
+        let subscriptions = &router.connections[id].subscriptions;
+        assert!(subscriptions.contains("hello/world"));
+        let acks = rx.lock().iter().fold(Vec::new(), |mut acks, n| {
+            if let Notification::DeviceAck(Ack::ConnAck(_, ack, _)) = n {
+                acks.push(ack.session_present);
+            }
+            acks
+        });
+        assert_eq!(acks, vec![true]);
+        assert_eq!(forwards(&rx).len(), 1);
+    }
+
+    #[test]
+    fn persistent_session_resumes_qos2_handshakes() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (id, _, _) = connect_persistent(&mut router, "device", Some(60));
+
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::ExactlyOnce;
+        publish.pkid = 1;
+        let pubrec = PubRec {
+            pkid: 1,
+            reason: PubRecReason::Success,
+        };
+        router.ackslog[id].pubrec(publish, pubrec);
+        router.handle_disconnection(id, false, None);
+
+        let (id, tx, rx) = connect_persistent(&mut router, "device", Some(60));
+        let pubrel = PubRel {
+            pkid: 1,
+            reason: PubRelReason::Success,
+        };
+        tx.lock().push_back(Packet::PubRel(pubrel, None));
+        router.handle_device_payload(id);
+        consume_all(&mut router);
+
+        assert!(router.connections.contains(id));
+        let pubcomps = rx
+            .lock()
+            .iter()
+            .filter(|n| matches!(n, Notification::DeviceAck(Ack::PubComp(_))))
+            .count();
+        assert_eq!(pubcomps, 1);
+    }
+
+    #[test]
+    fn persistent_session_is_purged_after_expiry() {
+        let mut router = Router::new(0, config()).unwrap();
+        let mut ids = Vec::new();
+        for (client_id, expiry) in [
+            ("expiring", Some(1)),
+            ("ending", Some(0)),
+            ("lasting", None),
+        ] {
+            let (id, tx, _) = connect_persistent(&mut router, client_id, expiry);
+            let filter = Filter {
+                path: "hello/world".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            tx.lock().push_back(Packet::Subscribe(subscribe, None));
+            router.handle_device_payload(id);
+            ids.push(id);
+        }
+        consume_all(&mut router);
+        for id in ids {
+            router.handle_disconnection(id, false, None);
+        }
+
+        router.purge_expired_sessions(Instant::now());
+        let ending = router.graveyard.retrieve("ending").unwrap();
+        assert!(ending.subscriptions.is_empty());
+        assert!(ending.tracker.data_requests.is_empty());
+
+        router.purge_expired_sessions(Instant::now() + Duration::from_secs(2));
+        assert!(router.graveyard.retrieve("expiring").is_none());
+        let lasting = router.graveyard.retrieve("lasting").unwrap();
+        assert_eq!(lasting.subscriptions.len(), 1);
+    }
+
+    #[test]
+    fn sessions_expire_without_events() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (id, _, _) = connect_persistent(&mut router, "expiring", Some(1));
+        let disconnected = Instant::now();
+        router.handle_disconnection(id, false, None);
+
+        // Router waits for the expiry instead of blocking on events
+        let deadline = router.next_timeout().unwrap();
+        assert_eq!(router.graveyard.next_expiry(), Some(deadline));
+        assert!(deadline >= disconnected + Duration::from_secs(1));
+        assert!(deadline <= Instant::now() + Duration::from_secs(1));
+
+        router.purge_expired_sessions(deadline - Duration::from_millis(1));
+        assert_eq!(router.graveyard.next_expiry(), Some(deadline));
+
+        router.purge_expired_sessions(deadline);
+        assert_eq!(router.graveyard.next_expiry(), None);
+        assert_eq!(router.next_timeout(), None);
+        assert!(router.graveyard.retrieve("expiring").is_none());
+    }
+
+    #[test]
+    fn deliveries_are_metered_per_subscriber() {
+        let config = RouterConfig {
+            meter_subscribers: true,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (meter_tx, meter_rx) = flume::bounded(10);
+        router.handle_new_meter(meter_tx);
+        let (a, a_tx, _) = connect(&mut router, "a");
+        let (b, b_tx, _) = connect(&mut router, "b");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let subscribe = |path: &str| {
+            let filter = Filter {
+                path: path.to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            Packet::Subscribe(
+                Subscribe {
+                    pkid: 1,
+                    filters: vec![filter],
+                },
+                None,
+            )
+        };
+        a_tx.lock().push_back(subscribe("hello/world"));
+        router.handle_device_payload(a);
+        consume_all(&mut router);
+
+        for _ in 0..2 {
+            let publish = Publish::new("hello/world", "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        // b subscribes after the first publishes and only reads the later ones
+        b_tx.lock().push_back(subscribe("hello/world"));
+        router.handle_device_payload(b);
+        consume_all(&mut router);
+
+        let publish = Publish::new("hello/world", "hello", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        let idx = router.datalog.filter_idx("hello/world").unwrap();
+        let a_meter = router.datalog.subscriber_meter(idx, a).unwrap();
+        let b_meter = router.datalog.subscriber_meter(idx, b).unwrap();
+        assert_eq!((a_meter.count, a_meter.total_size, a_meter.lag), (3, 48, 0));
+        assert_eq!((b_meter.count, b_meter.total_size, b_meter.lag), (1, 16, 0));
+        assert!(router.datalog.subscriber_meter(idx, publisher).is_none());
+
+        router.send_meters();
+        let mut reported: Vec<_> = meter_rx
+            .try_recv()
+            .unwrap()
+            .into_iter()
+            .filter_map(|meter| match meter {
+                Meter::Subscriber(filter, client_id, meter) => {
+                    Some((filter, client_id, meter.count))
+                }
+                _ => None,
+            })
+            .collect();
+        reported.sort();
+        assert_eq!(
+            reported,
+            vec![
+                ("hello/world".to_owned(), "a".to_owned(), 3),
+                ("hello/world".to_owned(), "b".to_owned(), 1),
+            ]
+        );
+
+        router.handle_disconnection(a, false, None);
+        assert!(router.datalog.subscriber_meter(idx, a).is_none());
+    }
+
+    #[test]
+    fn delayed_publishes_are_routed_once_due() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        let publish = Publish::new("$delayed/2/hello/world", "later", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+        router.release_delayed_publishes(Instant::now());
+        consume_all(&mut router);
+        assert!(sub_rx.lock().is_empty());
+
+        router.release_delayed_publishes(Instant::now() + Duration::from_secs(3));
+        consume_all(&mut router);
+        let topics: Vec<_> = sub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish.topic),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(topics, vec![Bytes::from("hello/world")]);
+    }
+
+    /// Subscribes `subscriber` to `a/+/c` and has `publisher` publish to the
+    /// literal topic `a/+/c`. Returns the topics forwarded to the subscriber
+    /// and the reason the publisher was disconnected with, if any
+    fn publish_wildcard_topic(router: &mut Router) -> (Vec<Bytes>, Option<DisconnectReasonCode>) {
+        let (subscriber, sub_tx, sub_rx) = connect(router, "subscriber");
+        let (publisher, pub_tx, pub_rx) = connect(router, "publisher");
+
+        let filter = Filter {
+            path: "a/+/c".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(router);
+        sub_rx.lock().clear();
+
+        let publish = Publish::new("a/+/c", "hello", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(router);
+
+        let topics = sub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish.topic),
+                _ => None,
+            })
+            .collect();
+        let reason = pub_rx
+            .lock()
+            .iter()
+            .find_map(|notification| match notification {
+                Notification::Disconnect(disconnect, _) => Some(disconnect.reason_code),
+                _ => None,
+            });
+        (topics, reason)
+    }
+
+    #[test]
+    fn wildcards_in_published_topics_are_refused_by_default() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (topics, reason) = publish_wildcard_topic(&mut router);
+        assert!(topics.is_empty());
+        assert!(!router.connection_map.contains_key("publisher"));
+        assert_eq!(reason, Some(DisconnectReasonCode::TopicNameInvalid));
+    }
+
+    #[test]
+    fn wildcards_in_published_topics_are_routed_literally_when_allowed() {
+        let config = RouterConfig {
+            allow_publish_wildcards: true,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (topics, reason) = publish_wildcard_topic(&mut router);
+        assert_eq!(topics, vec![Bytes::from("a/+/c")]);
+        assert!(router.connection_map.contains_key("publisher"));
+        assert_eq!(reason, None);
+    }
+
+    #[test]
+    fn delayed_publishes_are_released_without_events() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        let publish = Publish::new("$delayed/1/hello/world", "later", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        // Waits for the publish to be due instead of blocking on events
+        let due = router.next_timeout().unwrap();
+        assert!(due > Instant::now());
+        router.run_inner().unwrap();
+        assert!(Instant::now() >= due);
+        consume_all(&mut router);
+        assert_eq!(router.datalog.delayed_count(), 0);
+        assert_eq!(forwards(&sub_rx).len(), 1);
+    }
+
+    #[test]
+    fn delayed_publishes_are_capped() {
+        let config = RouterConfig {
+            max_delayed_publishes: Some(2),
+            max_publish_delay_secs: Some(60),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+
+        let (id, tx, _) = connect(&mut router, "patient");
+        let publish = Publish::new("$delayed/61/hello/world", "later", false);
+        tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(id);
+        assert!(!router.connection_map.contains_key("patient"));
+
+        let (id, tx, _) = connect(&mut router, "eager");
+        for _ in 0..3 {
+            let publish = Publish::new("$delayed/60/hello/world", "later", false);
+            tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(id);
+        assert!(!router.connection_map.contains_key("eager"));
+        assert_eq!(router.datalog.delayed_count_of("eager"), 2);
+
+        // The cap is per client
+        let (id, tx, _) = connect(&mut router, "other");
+        let publish = Publish::new("$delayed/60/hello/world", "later", false);
+        tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(id);
+        assert!(router.connection_map.contains_key("other"));
+        assert_eq!(router.datalog.delayed_count(), 3);
+
+        // Reconnecting doesn't reset it
+        let (id, tx, _) = connect(&mut router, "eager");
+        let publish = Publish::new("$delayed/60/hello/world", "later", false);
+        tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(id);
+        assert!(!router.connection_map.contains_key("eager"));
+
+        router.release_delayed_publishes(Instant::now() + Duration::from_secs(60));
+        assert_eq!(router.datalog.delayed_count(), 0);
+        assert_eq!(router.datalog.delayed_count_of("eager"), 0);
+    }
+
+    #[test]
+    fn unrepresentable_publish_delay_is_refused() {
+        let config = RouterConfig {
+            max_publish_delay_secs: Some(u64::MAX),
+            ..config()
+        };
+        assert!(matches!(
+            Router::new(0, config),
+            Err(ConfigError::TooLarge("max_publish_delay_secs", _))
+        ));
+    }
+
+    #[test]
+    fn malformed_delayed_topics_are_refused() {
+        assert_eq!(
+            delayed_topic("$delayed/5/a/b", 60).unwrap(),
+            Some((Duration::from_secs(5), "a/b"))
+        );
+        assert_eq!(delayed_topic("a/b", 60).unwrap(), None);
+        for topic in [
+            "$delayed/x/a",
+            "$delayed/5",
+            "$delayed/5/a/+",
+            "$delayed/5/",
+            "$delayed/61/a/b",
+        ] {
+            assert!(delayed_topic(topic, 60).is_err(), "{topic}");
+        }
+    }
+
+    #[test]
+    fn resubscribing_resends_retained_only_to_that_subscriber() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (a, a_tx, a_rx) = connect(&mut router, "a");
+        let (b, b_tx, b_rx) = connect(&mut router, "b");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let subscribe = |retain_forward_rule| {
+            let filter = Filter {
+                path: "hello/#".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            Packet::Subscribe(subscribe, None)
+        };
+        let payloads = |rx: &Mutex<VecDeque<Notification>>| -> Vec<(Bytes, bool)> {
+            rx.lock()
+                .drain(..)
+                .filter_map(|notification| match notification {
+                    Notification::Forward(f) => Some((f.publish.payload, f.publish.retain)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        a_tx.lock().push_back(subscribe(RetainForwardRule::Never));
+        router.handle_device_payload(a);
+        b_tx.lock().push_back(subscribe(RetainForwardRule::Never));
+        router.handle_device_payload(b);
+        consume_all(&mut router);
+
+        let publish = Publish::new("hello/world", "retained", true);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+        payloads(&a_rx);
+        payloads(&b_rx);
+        let cursor = router.connection_subscriptions().remove(&a);
+
+        a_tx.lock()
+            .push_back(subscribe(RetainForwardRule::OnEverySubscribe));
+        router.handle_device_payload(a);
+        consume_all(&mut router);
+        assert_eq!(payloads(&a_rx), vec![(Bytes::from("retained"), true)]);
+        assert!(payloads(&b_rx).is_empty());
+        assert_eq!(router.connection_subscriptions().remove(&a), cursor);
+
+        a_tx.lock()
+            .push_back(subscribe(RetainForwardRule::OnNewSubscribe));
+        router.handle_device_payload(a);
+        consume_all(&mut router);
+        assert!(payloads(&a_rx).is_empty());
+
+        let publish = Publish::new("hello/world", "live", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+        assert_eq!(payloads(&a_rx), vec![(Bytes::from("live"), false)]);
+        assert_eq!(payloads(&b_rx), vec![(Bytes::from("live"), false)]);
+    }
+
+    #[test]
+    fn resent_retained_messages_respect_the_inflight_window() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        for i in 0..150 {
+            let publish = Publish::new(format!("hello/{i}"), "retained".to_owned(), true);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+
+        let subscribe = |retain_forward_rule| {
+            let filter = Filter {
+                path: "hello/#".to_owned(),
+                qos: QoS::AtLeastOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            Packet::Subscribe(subscribe, None)
+        };
+        sub_tx.lock().push_back(subscribe(RetainForwardRule::Never));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_tx
+            .lock()
+            .push_back(subscribe(RetainForwardRule::OnEverySubscribe));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+
+        let pkids = forwards(&sub_rx);
+        assert_eq!(pkids, (1..=100).collect::<Vec<u16>>());
+        assert_eq!(router.obufs[subscriber].inflight(), 100);
+
+        for pkid in pkids {
+            let puback = PubAck {
+                pkid,
+                reason: PubAckReason::Success,
+            };
+            sub_tx.lock().push_back(Packet::PubAck(puback, None));
+        }
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        assert_eq!(forwards(&sub_rx), (1..=50).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn only_mqtt_3_subscribers_get_retransmissions() {
+        let config = RouterConfig {
+            retransmit_interval_ms: Some(1000),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let mut subscribers = Vec::new();
+        for version in [MqttVersion::V4, MqttVersion::V5] {
+            let client_id = format!("subscriber-{version:?}");
+            let connection = Connection::new(None, client_id, true, None, None, false, 0, version);
+            let (id, tx, rx) = register(&mut router, connection);
+
+            let filter = Filter {
+                path: "hello/world".to_owned(),
+                qos: QoS::AtLeastOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            tx.lock().push_back(Packet::Subscribe(subscribe, None));
+            router.handle_device_payload(id);
+            subscribers.push(rx);
+        }
+        consume_all(&mut router);
+
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::AtLeastOnce;
+        publish.pkid = 1;
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+        for rx in subscribers.iter() {
+            assert_eq!(forwards(rx), vec![1]);
+        }
+
+        router.retransmit_unacked(Duration::ZERO);
+        assert_eq!(forwards(&subscribers[0]), vec![1]);
+        assert!(forwards(&subscribers[1]).is_empty());
+    }
+
+    #[test]
+    fn retained_messages_are_never_sent_with_retain_handling_2() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let publish = Publish::new("hello/world", "retained", true);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        let subscribe = || {
+            let filter = Filter {
+                path: "hello/#".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            Packet::Subscribe(subscribe, None)
+        };
+
+        sub_tx.lock().push_back(subscribe());
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        assert!(forwards(&sub_rx).is_empty());
+
+        // Re-subscribing doesn't send them either
+        sub_tx.lock().push_back(subscribe());
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        assert!(forwards(&sub_rx).is_empty());
+    }
+
+    #[test]
+    fn retained_messages_are_sent_on_new_subscription_only_with_retain_handling_1() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let publish = Publish::new("hello/world", "retained", true);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        let subscribe = || {
+            let filter = Filter {
+                path: "hello/#".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::OnNewSubscribe,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter],
+            };
+            Packet::Subscribe(subscribe, None)
+        };
+        let payloads = |rx: &Mutex<VecDeque<Notification>>| -> Vec<Bytes> {
+            rx.lock()
+                .drain(..)
+                .filter_map(|notification| match notification {
+                    Notification::Forward(f) => Some(f.publish.payload),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        sub_tx.lock().push_back(subscribe());
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        assert_eq!(payloads(&sub_rx), vec![Bytes::from("retained")]);
+
+        // The subscription already exists, so retained messages aren't sent again
+        sub_tx.lock().push_back(subscribe());
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        assert!(payloads(&sub_rx).is_empty());
+    }
+
+    #[test]
+    fn system_publishes_reach_subscribers_and_are_optionally_retained() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+
+        let filter = Filter {
+            path: "stats/#".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        let publish = Publish::new("stats/live", "1", false);
+        router.system_publish(publish).unwrap();
+        let publish = Publish::new("stats/total", "2", true);
+        router.system_publish(publish).unwrap();
+        consume_all(&mut router);
+
+        let payloads: Vec<_> = sub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish.payload),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(payloads, vec![Bytes::from("1"), Bytes::from("2")]);
+        let retained: Vec<_> = router.datalog.retained_topics().collect();
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].as_str(), "stats/total");
+
+        let publish = Publish::new("stats/+", "3", false);
+        assert!(router.system_publish(publish).is_err());
+    }
+
+    /// Subscribes `subscriber` to `dead/letters` and publishes `publish` from
+    /// `publisher`. Returns the publishes forwarded to the subscriber
+    fn publish_unmatched(router: &mut Router, publish: Publish) -> Vec<Forward> {
+        let (subscriber, sub_tx, sub_rx) = connect(router, "subscriber");
+        let (publisher, pub_tx, _) = connect(router, "publisher");
+
+        let filter = Filter {
+            path: "dead/letters".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(router);
+        sub_rx.lock().clear();
+
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(router);
+        assert!(router.connection_map.contains_key("publisher"));
+
+        let forwards = sub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward),
+                _ => None,
+            })
+            .collect();
+        forwards
+    }
+
+    fn qos1_publish(topic: &str, retain: bool) -> Publish {
+        let mut publish = Publish::new(topic.to_owned(), "hello".to_owned(), retain);
+        publish.qos = QoS::AtLeastOnce;
+        publish.pkid = 1;
+        publish
+    }
+
+    #[test]
+    fn unmatched_publishes_are_dropped_by_default() {
+        let mut router = Router::new(0, config()).unwrap();
+        let forwards = publish_unmatched(&mut router, qos1_publish("nobody/here", false));
+        assert!(forwards.is_empty());
+        assert!(router.datalog.filter_idx("nobody/here").is_none());
+        assert_eq!(router.datalog.retained_count(), 0);
+    }
+
+    #[test]
+    fn unmatched_publishes_are_still_retained() {
+        let mut router = Router::new(0, config()).unwrap();
+        let forwards = publish_unmatched(&mut router, qos1_publish("nobody/here", true));
+        assert!(forwards.is_empty());
+        let retained: Vec<_> = router.datalog.retained_topics().collect();
+        assert_eq!(retained.len(), 1);
+        assert_eq!(retained[0].as_str(), "nobody/here");
+    }
+
+    #[test]
+    fn unmatched_publishes_are_routed_to_dead_letter_topic() {
+        let dead_letter = |topic: &str| RouterConfig {
+            no_match_policy: NoMatchPolicy::DeadLetter(topic.to_owned()),
+            ..config()
+        };
+        let mut router = Router::new(0, dead_letter("dead/letters")).unwrap();
+        let forwards = publish_unmatched(&mut router, qos1_publish("nobody/here", true));
+        assert_eq!(forwards.len(), 1);
+        assert_eq!(forwards[0].publish.topic, Bytes::from("dead/letters"));
+        assert!(!forwards[0].publish.retain);
+        let user_properties = &forwards[0].properties.as_ref().unwrap().user_properties;
+        assert_eq!(
+            user_properties,
+            &vec![("original-topic".to_owned(), "nobody/here".to_owned())]
+        );
+        assert_eq!(router.datalog.retained_count(), 1);
+
+        // QoS 0 publishes are dropped
+        let (publisher, pub_tx, _) = connect(&mut router, "qos0");
+        let publish = Publish::new("nobody/here", "hello", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        let idx = router.datalog.filter_idx("dead/letters").unwrap();
+        assert_eq!(router.datalog.native[idx].meter.count, 1);
+
+        assert!(matches!(
+            DataLog::new(dead_letter("dead/+")),
+            Err(ConfigError::InvalidTopic("no_match_policy", _))
+        ));
+    }
+
+    #[test]
+    fn publishes_larger_than_a_segment_are_refused() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, pub_rx) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        // 1 KB segments of `config`
+        let publish = Publish::new("hello/world".to_owned(), "x".repeat(1024), true);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        assert!(!router.connection_map.contains_key("publisher"));
+        let reason = pub_rx
+            .lock()
+            .iter()
+            .find_map(|notification| match notification {
+                Notification::Disconnect(disconnect, _) => Some(disconnect.reason_code),
+                _ => None,
+            });
+        assert_eq!(reason, Some(DisconnectReasonCode::PacketTooLarge));
+        assert!(forwards(&sub_rx).is_empty());
+        assert_eq!(router.datalog.retained_count(), 0);
+        let idx = router.datalog.filter_idx("hello/world").unwrap();
+        assert_eq!(router.datalog.native[idx].meter.count, 0);
+    }
+
+    #[test]
+    fn slow_consumers_are_checked_once_per_interval() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (subscriber, sub_tx, _) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+
+        for _ in 0..10 {
+            let publish = Publish::new("hello/world", "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+
+        let checked = router.slow_consumers_checked;
+        router.disconnect_slow_consumers(5, checked + Duration::from_millis(500));
+        assert!(router.connection_map.contains_key("subscriber"));
+
+        router.disconnect_slow_consumers(5, checked + SLOW_CONSUMER_CHECK_INTERVAL);
+        assert!(!router.connection_map.contains_key("subscriber"));
+        assert!(router.connection_map.contains_key("publisher"));
+    }
+
+    #[test]
+    fn qos0_publishes_are_shed_on_lagging_filters() {
+        for (idle_filter_lag, shed) in [
+            (IdleFilterLag::CaughtUp, false),
+            (IdleFilterLag::Hold, true),
+        ] {
+            shed_on_lagging_filter(idle_filter_lag, shed);
+        }
+    }
+
+    fn shed_on_lagging_filter(idle_filter_lag: IdleFilterLag, shed_when_idle: bool) {
+        let config = RouterConfig {
+            shed_qos0_lag: Some(5),
+            idle_filter_lag,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, _) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+
+        // Subscriber is never scheduled again and falls behind
+        let mut publish = |qos, pkid| {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = qos;
+            publish.pkid = pkid;
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+        };
+
+        for _ in 0..10 {
+            publish(QoS::AtMostOnce, 0);
+        }
+        publish(QoS::AtLeastOnce, 1);
+
+        let idx = router.datalog.next_native_offset("hello/world").0;
+        let data = router.datalog.native.get(idx).unwrap();
+        assert_eq!(data.meter.count, 7);
+        assert_eq!(data.meter.shed_count, 4);
+        assert_eq!(data.next_offset(), (0, 7));
+
+        // Once the last subscriber leaves the filter is either caught up or
+        // keeps lagging from where the subscriber left
+        let unsubscribe = Unsubscribe {
+            pkid: 2,
+            filters: vec!["hello/world".to_owned()],
+        };
+        sub_tx
+            .lock()
+            .push_back(Packet::Unsubscribe(unsubscribe, None));
+        router.handle_device_payload(subscriber);
+        assert_eq!(router.datalog.filter_lag(idx), None);
+
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::AtMostOnce;
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+
+        let data = router.datalog.native.get(idx).unwrap();
+        if shed_when_idle {
+            assert_eq!(data.meter.shed_count, 5);
+            assert_eq!(data.next_offset(), (0, 7));
+        } else {
+            assert_eq!(data.meter.shed_count, 4);
+            assert_eq!(data.next_offset(), (0, 8));
+        }
+    }
+
+    #[test]
+    fn topic_aliases_are_dropped_on_disconnect() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let aliased = |topic: &'static str| {
+            let properties = PublishProperties {
+                topic_alias: Some(1),
+                ..Default::default()
+            };
+            Packet::Publish(Publish::new(topic, "hello", false), Some(properties))
+        };
+
+        pub_tx.lock().push_back(aliased("hello/world"));
+        router.handle_device_payload(publisher);
+        assert_eq!(router.connections[publisher].topic_aliases.len(), 1);
+
+        router.handle_disconnection(publisher, false, None);
+        assert!(router.connections.get(publisher).is_none());
+
+        // The next connection reuses the id but not the alias
+        let (reused, pub_tx, _) = connect(&mut router, "reused");
+        assert_eq!(reused, publisher);
+        assert!(router.connections[reused].topic_aliases.is_empty());
+
+        pub_tx.lock().push_back(aliased(""));
+        router.handle_device_payload(reused);
+        assert!(!router.connection_map.contains_key("reused"));
+
+        // Aliases aren't part of a resumed session either
+        let (id, tx, _) = connect_persistent(&mut router, "persistent", Some(60));
+        tx.lock().push_back(aliased("hello/world"));
+        router.handle_device_payload(id);
+        router.handle_disconnection(id, false, None);
+
+        let (id, _, _) = connect_persistent(&mut router, "persistent", Some(60));
+        assert!(router.connections[id].topic_aliases.is_empty());
+    }
+
+    #[test]
+    fn publish_is_appended_once_per_filter() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+        let (idx, _) = router.datalog.next_native_offset("hello/+");
+        router.datalog.matches("hello/world");
+
+        // Route the topic back to a filter it already reaches
+        router
+            .datalog
+            .publish_filters
+            .get_mut("hello/world")
+            .unwrap()
+            .push(idx);
+        assert_eq!(router.datalog.publish_filters["hello/world"].len(), 1);
+
+        let publish = Publish::new("hello/world", "hello", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+
+        let data = router.datalog.native.get(idx).unwrap();
+        assert_eq!(data.meter.count, 1);
+        assert!(router.connection_map.contains_key("publisher"));
+    }
+
+    #[test]
+    fn connack_advertises_configured_limits() {
+        let config = RouterConfig {
+            topic_alias_maximum: Some(10),
+            server_max_packet_size: Some(64),
+            receive_maximum: Some(2),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (_, _, rx) = connect(&mut router, "client");
+        consume_all(&mut router);
+
+        let connack = rx
+            .lock()
+            .drain(..)
+            .find_map(|notification| match notification {
+                Notification::DeviceAck(Ack::ConnAck(_, ack, properties)) => {
+                    Some(Packet::ConnAck(ack, properties))
+                }
+                _ => None,
+            })
+            .unwrap();
+        let mut buffer = BytesMut::new();
+        V5.write(connack, &mut buffer).unwrap();
+
+        // Property identifier followed by its big endian value
+        let contains = |property: &[u8]| buffer.windows(property.len()).any(|w| w == property);
+        assert!(contains(&[0x22, 0, 10]), "topic alias maximum");
+        assert!(contains(&[0x27, 0, 0, 0, 64]), "maximum packet size");
+        assert!(contains(&[0x21, 0, 2]), "receive maximum");
+    }
+
+    #[test]
+    fn advertised_limits_are_enforced() {
+        let config = RouterConfig {
+            topic_alias_maximum: Some(10),
+            server_max_packet_size: Some(64),
+            receive_maximum: Some(2),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        router.datalog.next_native_offset("hello/world");
+
+        let publish = |qos, payload: &str| {
+            let mut publish = Publish::new("hello/world".to_owned(), payload.to_owned(), false);
+            publish.qos = qos;
+            publish.pkid = 1;
+            Packet::Publish(publish, None)
+        };
+
+        let (id, tx, _) = connect(&mut router, "large");
+        tx.lock()
+            .push_back(publish(QoS::AtMostOnce, &"x".repeat(100)));
+        router.handle_device_payload(id);
+        assert!(!router.connection_map.contains_key("large"));
+
+        let (id, tx, _) = connect(&mut router, "eager");
+        for _ in 0..3 {
+            tx.lock().push_back(publish(QoS::AtLeastOnce, "hello"));
+        }
+        router.handle_device_payload(id);
+        assert!(!router.connection_map.contains_key("eager"));
+
+        let (id, tx, _) = connect(&mut router, "aliased");
+        let properties = PublishProperties {
+            topic_alias: Some(11),
+            ..Default::default()
+        };
+        tx.lock().push_back(Packet::Publish(
+            Publish::new("hello/world", "hello", false),
+            Some(properties),
+        ));
+        router.handle_device_payload(id);
+        assert!(!router.connection_map.contains_key("aliased"));
+
+        // Within limits
+        let (id, tx, _) = connect(&mut router, "polite");
+        for _ in 0..2 {
+            tx.lock().push_back(publish(QoS::AtLeastOnce, "hello"));
+        }
+        router.handle_device_payload(id);
+        assert!(router.connection_map.contains_key("polite"));
+    }
+
+    #[test]
+    fn coalesced_acks_wait_for_room_in_outgoing_buffer() {
+        let config = RouterConfig {
+            coalesce_pubacks: true,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (id, tx, rx) = connect(&mut router, "publisher");
+        consume_all(&mut router);
+        rx.lock().clear();
+
+        let count = MAX_CHANNEL_CAPACITY as u16 + 50;
+        for pkid in 1..=count {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(id);
+        consume_all(&mut router);
+
+        let acks = |rx: &Mutex<VecDeque<Notification>>| {
+            let notifications: Vec<_> = rx.lock().drain(..).collect();
+            let unscheduled = matches!(notifications.last(), Some(Notification::Unschedule));
+            let pkids: Vec<u16> = notifications
+                .iter()
+                .filter_map(|notification| match notification {
+                    Notification::DeviceAck(Ack::PubAck(ack)) => Some(ack.pkid),
+                    _ => None,
+                })
+                .collect();
+            (pkids, unscheduled)
+        };
+
+        let (pkids, unscheduled) = acks(&rx);
+        assert_eq!(pkids, (1..MAX_CHANNEL_CAPACITY as u16).collect::<Vec<_>>());
+        assert!(unscheduled);
+
+        // Link took the buffer
+        router.events(id, Event::Ready);
+        consume_all(&mut router);
+        let (pkids, unscheduled) = acks(&rx);
+        assert_eq!(
+            pkids,
+            (MAX_CHANNEL_CAPACITY as u16..=count).collect::<Vec<_>>()
+        );
+        assert!(!unscheduled);
+    }
+
+    #[test]
+    fn saturated_filters_are_reported_in_router_meters() {
+        let config = RouterConfig {
+            saturated_waiters: Some(1),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (meter_tx, meter_rx) = flume::bounded(10);
+        router.handle_new_meter(meter_tx);
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        for client_id in ["a", "b"] {
+            let (id, tx, _) = connect(&mut router, client_id);
+            for path in ["hello/world", "quiet/world"] {
+                let filter = Filter {
+                    path: path.to_owned(),
+                    qos: QoS::AtMostOnce,
+                    nolocal: false,
+                    preserve_retain: false,
+                    retain_forward_rule: RetainForwardRule::Never,
+                };
+                let subscribe = Subscribe {
+                    pkid: 1,
+                    filters: vec![filter],
+                };
+                tx.lock().push_back(Packet::Subscribe(subscribe, None));
+            }
+            router.handle_device_payload(id);
+            consume_all(&mut router);
+        }
+
+        // Both subscribers park on both filters again after reading the publish
+        let publish = Publish::new("hello/world", "hello", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+
+        router.send_meters();
+        let mut saturated = meter_rx
+            .try_recv()
+            .unwrap()
+            .into_iter()
+            .find_map(|meter| match meter {
+                Meter::Router(_, meter) => Some(meter.saturated_filters),
+                _ => None,
+            })
+            .unwrap();
+        saturated.sort();
+        assert_eq!(saturated, vec!["hello/world", "quiet/world"]);
+    }
+
+    #[test]
+    fn match_cache_stats_are_reported_in_router_meters() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (meter_tx, meter_rx) = flume::bounded(10);
+        router.handle_new_meter(meter_tx);
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+        router.datalog.next_native_offset("hello/+");
+
+        for topic in ["hello/world", "hello/world", "hello/there"] {
+            let publish = Publish::new(topic, "hello", false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(publisher);
+
+        router.send_meters();
+        let stats = meter_rx
+            .try_recv()
+            .unwrap()
+            .into_iter()
+            .find_map(|meter| match meter {
+                Meter::Router(_, meter) => Some((meter.match_cache_hits, meter.match_cache_misses)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(stats, (1, 2));
+    }
+
+    #[test]
+    fn ack_latency_is_reported_in_router_meters() {
+        let config = RouterConfig {
+            track_ack_latency: true,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (meter_tx, meter_rx) = flume::bounded(10);
+        router.handle_new_meter(meter_tx);
+        let (id, tx, _) = connect(&mut router, "publisher");
+        consume_all(&mut router);
+
+        for pkid in 1..=3 {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(id);
+        consume_all(&mut router);
+
+        let latency = |router: &mut Router| {
+            router.send_meters();
+            meter_rx
+                .try_recv()
+                .unwrap()
+                .into_iter()
+                .find_map(|meter| match meter {
+                    Meter::Router(_, meter) => meter.ack_latency,
+                    _ => None,
+                })
+                .unwrap()
+        };
+
+        // CONNACK and 3 PUBACKs
+        assert_eq!(latency(&mut router).count, 4);
+
+        let mut publish = Publish::new("hello/world", "hello", false);
+        publish.qos = QoS::AtLeastOnce;
+        publish.pkid = 4;
+        tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(id);
+        consume_all(&mut router);
+        assert_eq!(latency(&mut router).count, 1);
+    }
+
+    #[test]
+    fn receive_maximum_counts_publishes_not_acked_yet() {
+        let config = RouterConfig {
+            receive_maximum: Some(2),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        router.datalog.next_native_offset("hello/world");
+
+        let publish = |pkid| {
+            let mut publish = Publish::new("hello/world", "hello", false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            Packet::Publish(publish, None)
+        };
+
+        let (id, tx, _) = connect(&mut router, "client");
+        router.ackslog[id].readv().clear();
+
+        // Acked before the next batch
+        for pkid in 1..=4 {
+            tx.lock().push_back(publish(pkid));
+            router.handle_device_payload(id);
+            router.ackslog[id].readv().clear();
+        }
+        assert!(router.connection_map.contains_key("client"));
+
+        // PUBACKs of earlier batches still pending
+        for pkid in 5..=7 {
+            tx.lock().push_back(publish(pkid));
+            router.handle_device_payload(id);
+        }
+        assert!(!router.connection_map.contains_key("client"));
+    }
+
+    #[test]
+    fn advertised_limits_are_not_enforced_on_v4_clients() {
+        let config = RouterConfig {
+            server_max_packet_size: Some(64),
+            receive_maximum: Some(2),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        router.datalog.next_native_offset("hello/world");
+
+        let connection = Connection::new(
+            None,
+            "v4".to_owned(),
+            true,
+            None,
+            None,
+            false,
+            0,
+            MqttVersion::V4,
+        );
+        let (id, tx, _) = register(&mut router, connection);
+        for pkid in 1..=3 {
+            let mut publish = Publish::new("hello/world".to_owned(), "x".repeat(100), false);
+            publish.qos = QoS::AtLeastOnce;
+            publish.pkid = pkid;
+            tx.lock().push_back(Packet::Publish(publish, None));
+        }
+        router.handle_device_payload(id);
+        assert!(router.connection_map.contains_key("v4"));
+    }
+
+    #[test]
+    fn duplicate_publishes_are_dropped_within_window() {
+        let config = RouterConfig {
+            dedup: Some(DedupSettings {
+                property: "message-id".to_owned(),
+                window: 2,
+                filters: vec!["hello/world".parse().unwrap()],
+            }),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+        let idx = router.datalog.next_native_offset("hello/world").0;
+
+        let publish = |router: &mut Router, id: &str| {
+            let publish = Publish::new("hello/world", "hello", false);
+            let properties = PublishProperties {
+                user_properties: vec![("message-id".to_owned(), id.to_owned())],
+                ..Default::default()
+            };
+            pub_tx
+                .lock()
+                .push_back(Packet::Publish(publish, Some(properties)));
+            router.handle_device_payload(publisher);
+        };
+
+        publish(&mut router, "a");
+        publish(&mut router, "a");
+        let data = router.datalog.native.get(idx).unwrap();
+        assert_eq!(data.meter.count, 1);
+        assert_eq!(data.meter.duplicate_count, 1);
+
+        // "a" falls out of the window and is accepted again
+        publish(&mut router, "b");
+        publish(&mut router, "c");
+        publish(&mut router, "a");
+        let data = router.datalog.native.get(idx).unwrap();
+        assert_eq!(data.meter.count, 4);
+        assert_eq!(data.meter.duplicate_count, 1);
+        assert_eq!(data.next_offset(), (0, 4));
+    }
+
+    #[test]
+    fn slow_subscriber_of_conflated_filter_gets_latest_publish() {
+        let config = RouterConfig {
+            conflated_filters: vec!["state/+".parse().unwrap()],
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![Filter {
+                path: "state/+".to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            }],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        // Subscriber doesn't get to read until all the updates are in
+        for (topic, payload) in [
+            ("state/a", "1"),
+            ("state/a", "2"),
+            ("state/a", "3"),
+            ("state/a", "4"),
+            ("state/a", "5"),
+            ("state/b", "1"),
+        ] {
+            let publish = Publish::new(topic, payload, false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+        }
+        consume_all(&mut router);
+
+        let received: Vec<_> = sub_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => {
+                    Some((forward.publish.topic, forward.publish.payload))
+                }
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                (Bytes::from("state/a"), Bytes::from("5")),
+                (Bytes::from("state/b"), Bytes::from("1")),
+            ]
+        );
+
+        // Cursor moved past all the updates
+        let publish = Publish::new("state/a", "6", false);
+        pub_tx.lock().push_back(Packet::Publish(publish, None));
+        router.handle_device_payload(publisher);
+        consume_all(&mut router);
+        assert_eq!(forwards(&sub_rx).len(), 1);
+    }
+
+    #[test]
+    fn suback_reports_denied_filters_individually() {
+        let config = RouterConfig {
+            reject_control_chars: true,
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (client, client_tx, client_rx) = connect(&mut router, "client");
+
+        let filter = |path: &str, qos| Filter {
+            path: path.to_owned(),
+            qos,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![
+                filter("hello/+", QoS::AtLeastOnce),
+                filter("$internal/stats", QoS::AtMostOnce),
+                filter("hello/\u{7}", QoS::AtMostOnce),
+                filter("hello/world", QoS::ExactlyOnce),
+                filter("world/#", QoS::AtMostOnce),
+            ],
+        };
+        client_tx
+            .lock()
+            .push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(client);
+        consume_all(&mut router);
+
+        let (suback, properties) = client_rx
+            .lock()
+            .drain(..)
+            .find_map(|notification| match notification {
+                Notification::DeviceAck(Ack::SubAckWithProperties(suback, properties)) => {
+                    Some((suback, properties))
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            suback.return_codes,
+            vec![
+                SubscribeReasonCode::QoS1,
+                SubscribeReasonCode::NotAuthorized,
+                SubscribeReasonCode::TopicFilterInvalid,
+                SubscribeReasonCode::ImplementationSpecific,
+                SubscribeReasonCode::QoS0,
+            ]
+        );
+        let reason = properties.reason_string.unwrap();
+        assert!(reason.starts_with("$internal/stats: "), "{reason}");
+        assert!(reason.contains("hello/world: "), "{reason}");
+
+        // Granted filters are subscribed and the client stays connected
+        let connection = &router.connections[client];
+        let mut subscriptions: Vec<&str> = connection
+            .subscriptions
+            .iter()
+            .map(|f| f.as_str())
+            .collect();
+        subscriptions.sort();
+        assert_eq!(subscriptions, vec!["hello/+", "world/#"]);
+        assert!(router.connection_map.contains_key("client"));
+    }
+
+    #[test]
+    fn topics_and_filters_over_max_length_are_refused() {
+        for version in [MqttVersion::V4, MqttVersion::V5] {
+            let config = RouterConfig {
+                max_topic_length: Some(8),
+                ..config()
+            };
+            let mut router = Router::new(0, config).unwrap();
+            let connection = Connection::new(
+                None,
+                "client".to_owned(),
+                true,
+                None,
+                None,
+                false,
+                0,
+                version,
+            );
+            let (client, client_tx, client_rx) = register(&mut router, connection);
+
+            let filter = |path: &str| Filter {
+                path: path.to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            let subscribe = Subscribe {
+                pkid: 1,
+                filters: vec![filter("a/b/c/de"), filter("a/b/c/def")],
+            };
+            client_tx
+                .lock()
+                .push_back(Packet::Subscribe(subscribe, None));
+            router.handle_device_payload(client);
+            consume_all(&mut router);
+
+            let suback = client_rx
+                .lock()
+                .drain(..)
+                .find_map(|notification| match notification {
+                    Notification::DeviceAck(Ack::SubAck(suback))
+                    | Notification::DeviceAck(Ack::SubAckWithProperties(suback, _)) => Some(suback),
+                    _ => None,
+                })
+                .unwrap();
+            assert_eq!(
+                suback.return_codes,
+                vec![
+                    SubscribeReasonCode::QoS0,
+                    SubscribeReasonCode::TopicFilterInvalid,
+                ],
+                "{version:?}"
+            );
+
+            let publish = Publish::new("a/b/c/de", "hello", false);
+            client_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(client);
+            assert!(router.connection_map.contains_key("client"));
+
+            let publish = Publish::new("a/b/c/def", "hello", false);
+            client_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(client);
+            assert!(!router.connection_map.contains_key("client"), "{version:?}");
+            let reason = client_rx
+                .lock()
+                .iter()
+                .find_map(|notification| match notification {
+                    Notification::Disconnect(disconnect, _) => Some(disconnect.reason_code),
+                    _ => None,
+                });
+            assert_eq!(reason, Some(DisconnectReasonCode::TopicNameInvalid));
+        }
+    }
+
+    #[test]
+    fn filters_over_complexity_limits_are_refused() {
+        let config = RouterConfig {
+            max_filter_levels: Some(3),
+            max_wildcards_per_filter: Some(2),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (client, client_tx, client_rx) = connect(&mut router, "client");
+
+        let filter = |path: &str| Filter {
+            path: path.to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![
+                filter("a/b/c"),
+                filter("a/b/c/d"),
+                filter("+/b/#"),
+                filter("+/+/#"),
+            ],
+        };
+        client_tx
+            .lock()
+            .push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(client);
+        consume_all(&mut router);
+
+        let (suback, properties) = client_rx
+            .lock()
+            .drain(..)
+            .find_map(|notification| match notification {
+                Notification::DeviceAck(Ack::SubAckWithProperties(suback, properties)) => {
+                    Some((suback, properties))
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(
+            suback.return_codes,
+            vec![
+                SubscribeReasonCode::QoS0,
+                SubscribeReasonCode::TopicFilterInvalid,
+                SubscribeReasonCode::QoS0,
+                SubscribeReasonCode::TopicFilterInvalid,
+            ]
+        );
+        let reason = properties.reason_string.unwrap();
+        assert!(reason.contains("4 levels"), "{reason}");
+        assert!(reason.contains("3 wildcards"), "{reason}");
+    }
+
+    #[test]
+    fn subscription_changes_are_published_as_events() {
+        let config = RouterConfig {
+            subscription_events_topic: Some("$SYS/subscriptions".to_owned()),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (dashboard, dashboard_tx, dashboard_rx) = connect(&mut router, "dashboard");
+        let (client, client_tx, _) = connect(&mut router, "client");
+
+        let subscribe = |path: &str| {
+            let filter = Filter {
+                path: path.to_owned(),
+                qos: QoS::AtMostOnce,
+                nolocal: false,
+                preserve_retain: false,
+                retain_forward_rule: RetainForwardRule::Never,
+            };
+            Packet::Subscribe(
+                Subscribe {
+                    pkid: 1,
+                    filters: vec![filter],
+                },
+                None,
+            )
+        };
+
+        dashboard_tx
+            .lock()
+            .push_back(subscribe("$SYS/subscriptions"));
+        router.handle_device_payload(dashboard);
+        consume_all(&mut router);
+        dashboard_rx.lock().clear();
+
+        client_tx.lock().push_back(subscribe("hello/+"));
+        router.handle_device_payload(client);
+        consume_all(&mut router);
+
+        let events: Vec<_> = dashboard_rx
+            .lock()
+            .drain(..)
+            .filter_map(|notification| match notification {
+                Notification::Forward(forward) => Some(forward.publish),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].topic, "$SYS/subscriptions");
+        let event: serde_json::Value = serde_json::from_slice(&events[0].payload).unwrap();
+        assert_eq!(
+            event,
+            serde_json::json!({ "filter": "hello/+", "subscribers": 1 })
+        );
+    }
+
+    #[test]
+    fn unsubscribe_frees_request_of_exactly_one_filter() {
+        let mut router = Router::new(0, config()).unwrap();
+        let (client, client_tx, _) = connect(&mut router, "client");
+
+        let filters = ["a/#", "a/b"].map(|path| Filter {
+            path: path.to_owned(),
+            qos: QoS::AtMostOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        });
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: filters.to_vec(),
+        };
+        client_tx
+            .lock()
+            .push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(client);
+
+        // "a/b" is still tracked while "a/#" is parked
+        consume_all(&mut router);
+        let a_b = router.datalog.remove_waiters_for_id(client, "a/b");
+        router.scheduler.track(client, a_b.unwrap());
+
+        let freed = free_data_request(&mut router.scheduler, &mut router.datalog, client, "a/#");
+        assert_eq!(freed.unwrap().filter, "a/#");
+        assert!(router.datalog.waiters("a/#").unwrap().waiters().is_empty());
+
+        let requests = router.scheduler.trackers[client].get_data_requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].filter, "a/b");
+
+        let freed = free_data_request(&mut router.scheduler, &mut router.datalog, client, "a/b");
+        assert_eq!(freed.unwrap().filter, "a/b");
+        assert!(router.scheduler.trackers[client]
+            .get_data_requests()
+            .is_empty());
+        assert!(
+            free_data_request(&mut router.scheduler, &mut router.datalog, client, "a/b").is_none()
+        );
+    }
+
+    #[test]
+    fn delivery_pauses_on_total_inflight_limit_until_acked() {
+        let config = RouterConfig {
+            max_total_inflight_bytes: Some(500),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        // Every publish is 111 bytes inflight
+        let publish = |router: &mut Router, count| {
+            for _ in 0..count {
+                let publish =
+                    Publish::new(Bytes::from("hello/world"), Bytes::from(vec![0; 100]), false);
+                pub_tx.lock().push_back(Packet::Publish(publish, None));
+            }
+            router.handle_device_payload(publisher);
+            consume_all(router);
+        };
+
+        publish(&mut router, 3);
+        assert_eq!(forwards(&sub_rx), vec![1, 2, 3]);
+
+        // Crosses the limit
+        publish(&mut router, 3);
+        assert_eq!(forwards(&sub_rx), vec![4, 5, 6]);
+        assert_eq!(router.inflight_bytes, 666);
+
+        publish(&mut router, 3);
+        assert!(forwards(&sub_rx).is_empty());
+
+        for pkid in 1..=3 {
+            let puback = PubAck {
+                pkid,
+                reason: PubAckReason::Success,
+            };
+            sub_tx.lock().push_back(Packet::PubAck(puback, None));
+        }
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+
+        assert_eq!(forwards(&sub_rx), vec![7, 8, 9]);
+        assert_eq!(router.inflight_bytes, 666);
+
+        publish(&mut router, 3);
+        assert!(forwards(&sub_rx).is_empty());
+
+        // Connections paused on the limit resume once it is removed
+        router.config.max_total_inflight_bytes = None;
+        let puback = PubAck {
+            pkid: 4,
+            reason: PubAckReason::Success,
+        };
+        sub_tx.lock().push_back(Packet::PubAck(puback, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        assert_eq!(forwards(&sub_rx), vec![10, 11, 12]);
+    }
+
+    #[test]
+    fn raising_total_inflight_limit_resumes_delivery() {
+        let config = RouterConfig {
+            max_total_inflight_bytes: Some(100),
+            ..config()
+        };
+        let mut router = Router::new(0, config).unwrap();
+        let (subscriber, sub_tx, sub_rx) = connect(&mut router, "subscriber");
+        let (publisher, pub_tx, _) = connect(&mut router, "publisher");
+
+        let filter = Filter {
+            path: "hello/world".to_owned(),
+            qos: QoS::AtLeastOnce,
+            nolocal: false,
+            preserve_retain: false,
+            retain_forward_rule: RetainForwardRule::Never,
+        };
+        let subscribe = Subscribe {
+            pkid: 1,
+            filters: vec![filter],
+        };
+        sub_tx.lock().push_back(Packet::Subscribe(subscribe, None));
+        router.handle_device_payload(subscriber);
+        consume_all(&mut router);
+        sub_rx.lock().clear();
+
+        // Every publish is 111 bytes inflight
+        for _ in 0..2 {
+            let publish =
+                Publish::new(Bytes::from("hello/world"), Bytes::from(vec![0; 100]), false);
+            pub_tx.lock().push_back(Packet::Publish(publish, None));
+            router.handle_device_payload(publisher);
+            consume_all(&mut router);
+        }
+        assert_eq!(forwards(&sub_rx), vec![1]);
+
+        let patch = RouterConfigPatch {
+            max_total_inflight_bytes: Some(Some(1000)),
+            ..Default::default()
+        };
+        let (reply, _result) = flume::bounded(1);
+        router.events(0, Event::UpdateConfig(patch, reply));
+        consume_all(&mut router);
+        assert_eq!(forwards(&sub_rx), vec![2]);
+    }
+}
+
 // #[cfg(test)]
 // #[allow(non_snake_case)]
 // mod test {
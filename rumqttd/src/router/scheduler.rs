@@ -5,7 +5,7 @@ use slab::Slab;
 use tracing::trace;
 
 use super::DataRequest;
-use crate::{ConnectionId, Filter};
+use crate::ConnectionId;
 
 pub struct Scheduler {
     /// Subscriptions and matching topics maintained per connection
@@ -49,9 +49,9 @@ impl Scheduler {
         tracker.register_data_request(request);
     }
 
-    pub fn untrack(&mut self, id: ConnectionId, filter: &Filter) {
+    pub fn untrack(&mut self, id: ConnectionId, filter: &str) -> Option<DataRequest> {
         let tracker = self.trackers.get_mut(id).unwrap();
-        tracker.unregister_data_request(filter.clone());
+        tracker.unregister_data_request(filter)
     }
 
     pub fn trackv(&mut self, id: ConnectionId, requests: VecDeque<DataRequest>) {
@@ -67,6 +67,35 @@ impl Scheduler {
         }
     }
 
+    /// Reschedules all the connections which can be readied with `reason`
+    pub fn reschedule_all(&mut self, reason: ScheduleReason) {
+        for (id, tracker) in self.trackers.iter_mut() {
+            if let Some(v) = tracker.try_ready(reason) {
+                trace!(tracker_id = tracker.id, "reschedule {:?} -> Ready", v);
+                self.readyqueue.push_back(id);
+            }
+        }
+    }
+
+    /// Starts a new delivery tick. Resets the QoS 0 budget of all the connections
+    /// and resumes those which were paced in the previous one
+    pub fn tick(&mut self) {
+        for (id, tracker) in self.trackers.iter_mut() {
+            tracker.qos0_delivered = 0;
+            if let Some(v) = tracker.try_ready(ScheduleReason::Tick) {
+                trace!(tracker_id = tracker.id, "reschedule {:?} -> Ready", v);
+                self.readyqueue.push_back(id);
+            }
+        }
+    }
+
+    /// Whether any connection waits for the next tick to resume
+    pub fn has_paced(&self) -> bool {
+        self.trackers
+            .iter()
+            .any(|(_, tracker)| tracker.status == Status::Paused(PauseReason::Paced))
+    }
+
     pub fn pause(&mut self, id: ConnectionId, reason: PauseReason) {
         assert_eq!(self.readyqueue.pop_back(), Some(id));
         let tracker = self.trackers.get_mut(id).unwrap();
@@ -92,6 +121,9 @@ pub struct Tracker {
     pub data_requests: VecDeque<DataRequest>,
     /// State machine
     pub status: Status,
+    /// QoS 0 publishes forwarded during the current delivery tick
+    #[serde(default)]
+    pub qos0_delivered: u64,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -106,14 +138,18 @@ pub enum ScheduleReason {
     NewFilter,
     FreshData,
     IncomingAck,
+    InflightFreed,
     Ready,
+    Tick,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PauseReason {
     Caughtup,
     InflightFull,
+    GlobalInflightFull,
     Busy,
+    Paced,
 }
 
 impl Tracker {
@@ -123,6 +159,7 @@ impl Tracker {
             id: client_id,
             data_requests: requests,
             status: Status::Paused(PauseReason::Busy),
+            qos0_delivered: 0,
         }
     }
 
@@ -164,6 +201,14 @@ impl Tracker {
                 self.status = Status::Ready;
                 Some(previous)
             }
+            ScheduleReason::InflightFreed if previous == PauseReason::GlobalInflightFull => {
+                self.status = Status::Ready;
+                Some(previous)
+            }
+            ScheduleReason::Tick if previous == PauseReason::Paced => {
+                self.status = Status::Ready;
+                Some(previous)
+            }
             ScheduleReason::Ready => {
                 debug_assert!(self.status == Status::Paused(PauseReason::Busy));
                 self.status = Status::Ready;
@@ -189,9 +234,12 @@ impl Tracker {
         self.data_requests.push_back(request);
     }
 
-    pub fn unregister_data_request(&mut self, filter: Filter) {
-        self.data_requests
-            .retain(|data_req| data_req.filter != filter);
+    pub fn unregister_data_request(&mut self, filter: &str) -> Option<DataRequest> {
+        let index = self
+            .data_requests
+            .iter()
+            .position(|data_req| data_req.filter == *filter)?;
+        self.data_requests.remove(index)
     }
 }
 
@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+
+use super::logs::FilterMatches;
+use super::FilterIdx;
+
+/// Filters indexed by their levels so that matching a topic walks only the
+/// branches its levels lead to instead of every filter. Follows the rules of
+/// `protocol::matches`
+#[derive(Debug, Default)]
+pub struct FilterTrie {
+    root: Node,
+}
+
+#[derive(Debug, Default)]
+struct Node {
+    /// Filter ending at this level
+    filter_idx: Option<FilterIdx>,
+    children: HashMap<Box<str>, Node>,
+}
+
+impl Node {
+    fn is_empty(&self) -> bool {
+        self.filter_idx.is_none() && self.children.is_empty()
+    }
+}
+
+impl FilterTrie {
+    pub fn new() -> FilterTrie {
+        FilterTrie::default()
+    }
+
+    pub fn insert(&mut self, filter: &str, filter_idx: FilterIdx) {
+        let mut node = &mut self.root;
+        for level in filter.split('/') {
+            node = node.children.entry(level.into()).or_default();
+        }
+
+        node.filter_idx = Some(filter_idx);
+    }
+
+    /// Removes a filter and the branches only it was using
+    pub fn remove(&mut self, filter: &str) -> Option<FilterIdx> {
+        fn remove(node: &mut Node, levels: &[&str]) -> Option<FilterIdx> {
+            let (level, rest) = match levels.split_first() {
+                Some(v) => v,
+                None => return node.filter_idx.take(),
+            };
+
+            let child = node.children.get_mut(*level)?;
+            let filter_idx = remove(child, rest);
+            if child.is_empty() {
+                node.children.remove(*level);
+            }
+
+            filter_idx
+        }
+
+        let levels: Vec<&str> = filter.split('/').collect();
+        remove(&mut self.root, &levels)
+    }
+
+    /// Indexes of the filters matching `topic`
+    pub fn matches(&self, topic: &str) -> FilterMatches {
+        self.matches_visiting(topic).0
+    }
+
+    /// Indexes of the filters matching `topic` and the number of nodes
+    /// visited to find them
+    fn matches_visiting(&self, topic: &str) -> (FilterMatches, usize) {
+        let mut matches = FilterMatches::Zero;
+        let levels: Vec<&str> = topic.split('/').collect();
+
+        // Wildcards at the first level don't match topics starting with '$'
+        let wildcards = !topic.starts_with('$');
+        let visited = collect(&self.root, &levels, wildcards, &mut matches);
+        (matches, visited)
+    }
+
+    /// Rewrites filter indexes moved by `DataLog::compact_native`
+    pub fn remap_filters(&mut self, remap: &HashMap<FilterIdx, FilterIdx>) {
+        let mut nodes = vec![&mut self.root];
+        while let Some(node) = nodes.pop() {
+            if let Some(filter_idx) = node.filter_idx.as_mut() {
+                *filter_idx = remap[filter_idx];
+            }
+
+            nodes.extend(node.children.values_mut());
+        }
+    }
+}
+
+/// Pushes the filters under `node` matching `levels` and returns the number
+/// of nodes visited
+fn collect(node: &Node, levels: &[&str], wildcards: bool, matches: &mut FilterMatches) -> usize {
+    let mut visited = 1;
+
+    // "#" matches the parent level too, a/# matches a
+    if wildcards {
+        if let Some(idx) = node.children.get("#").and_then(|n| n.filter_idx) {
+            matches.push(idx);
+        }
+    }
+
+    let (level, rest) = match levels.split_first() {
+        Some(v) => v,
+        None => {
+            if let Some(idx) = node.filter_idx {
+                matches.push(idx);
+            }
+            return visited;
+        }
+    };
+
+    if *level == "#" {
+        return visited;
+    }
+
+    if wildcards {
+        if let Some(child) = node.children.get("+") {
+            visited += collect(child, rest, true, matches);
+        }
+    }
+
+    if *level != "+" {
+        if let Some(child) = node.children.get(*level) {
+            visited += collect(child, rest, true, matches);
+        }
+    }
+
+    visited
+}
+
+#[cfg(test)]
+mod test {
+    use super::FilterTrie;
+    use crate::protocol::matches;
+
+    const FILTERS: &[&str] = &[
+        "#",
+        "+",
+        "+/+",
+        "+/#",
+        "a",
+        "a/#",
+        "a/+",
+        "a/b",
+        "a/b/#",
+        "a/+/c",
+        "a/b/c",
+        "+/b/c",
+        "$SYS/#",
+        "$SYS/+",
+        "$SYS/broker",
+        "/",
+        "/+",
+        "+/",
+        "",
+    ];
+
+    const TOPICS: &[&str] = &[
+        "a",
+        "a/b",
+        "a/b/c",
+        "a/b/c/d",
+        "x/b/c",
+        "b",
+        "$SYS",
+        "$SYS/broker",
+        "$SYS/broker/load",
+        "/",
+        "/a",
+        "a/",
+        "",
+        "a/+",
+    ];
+
+    #[test]
+    fn trie_matches_like_protocol_matches() {
+        let mut trie = FilterTrie::new();
+        for (idx, filter) in FILTERS.iter().enumerate() {
+            trie.insert(filter, idx);
+        }
+
+        for topic in TOPICS {
+            let mut got = trie.matches(topic).into_vec();
+            got.sort_unstable();
+            let expected: Vec<usize> = (0..FILTERS.len())
+                .filter(|&idx| matches(topic, FILTERS[idx]))
+                .collect();
+            assert_eq!(got, expected, "topic = {topic}");
+        }
+    }
+
+    #[test]
+    fn removed_filters_no_longer_match() {
+        let mut trie = FilterTrie::new();
+        trie.insert("a/b/#", 0);
+        trie.insert("a/b", 1);
+
+        assert_eq!(trie.remove("a/b/#"), Some(0));
+        assert_eq!(trie.remove("a/b/#"), None);
+        assert_eq!(trie.matches("a/b").into_vec(), vec![1]);
+        assert!(trie.matches("a/b/c").is_empty());
+
+        assert_eq!(trie.remove("a/b"), Some(1));
+        assert!(trie.root.is_empty());
+    }
+
+    #[test]
+    fn trie_visits_a_few_nodes_of_10k_filters() {
+        let filters: Vec<String> = (0..10_000)
+            .map(|i| match i % 4 {
+                0 => format!("devices/{i}/events"),
+                1 => format!("devices/{i}/+"),
+                2 => format!("devices/+/config/{i}"),
+                _ => format!("sites/{}/devices/{i}/#", i % 100),
+            })
+            .collect();
+        let topics: Vec<String> = (0..100)
+            .map(|i| format!("devices/{}/events", i * 7))
+            .collect();
+
+        let mut trie = FilterTrie::new();
+        for (idx, filter) in filters.iter().enumerate() {
+            trie.insert(filter, idx);
+        }
+
+        for topic in topics.iter() {
+            let linear = filters.iter().filter(|f| matches(topic, f)).count();
+            let (found, visited) = trie.matches_visiting(topic);
+            assert_eq!(found.len(), linear);
+
+            // Root, devices, the device and +, and events below one of them,
+            // where a linear scan compares the topic with all 10k filters
+            assert!(visited <= 5, "{topic} visited {visited} nodes");
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::ConnectionId;
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 
 /// Waiters are connections which are waiting to be notified. They have
 /// made a data request but router didn't respond because of the connection
@@ -8,12 +8,18 @@ use std::collections::VecDeque;
 pub struct Waiters<T> {
     /// Waiters on new topics
     current: VecDeque<(ConnectionId, T)>,
+    /// Connections in `current`, each of which is parked at most once
+    parked: HashSet<ConnectionId>,
+    /// Most waiters parked at once since they were last woken up
+    high_water: usize,
 }
 
 impl<T> Waiters<T> {
     pub fn with_capacity(max_connections: usize) -> Waiters<T> {
         Waiters {
             current: VecDeque::with_capacity(max_connections),
+            parked: HashSet::with_capacity(max_connections),
+            high_water: 0,
         }
     }
 
@@ -22,10 +28,22 @@ impl<T> Waiters<T> {
         &self.current
     }
 
-    /// Pushes a request to current wait queue
-    pub fn register(&mut self, id: ConnectionId, request: T) {
-        let request = (id, request);
-        self.current.push_back(request);
+    /// Pushes a request to current wait queue. When the connection is already
+    /// waiting, its request is replaced in place and the stale one returned
+    pub fn register(&mut self, id: ConnectionId, request: T) -> Option<T> {
+        if !self.parked.insert(id) {
+            let (_, parked) = self.current.iter_mut().find(|(waiter, _)| *waiter == id)?;
+            return Some(std::mem::replace(parked, request));
+        }
+
+        self.current.push_back((id, request));
+        self.high_water = self.high_water.max(self.current.len());
+        None
+    }
+
+    /// Most waiters parked at once since they were last woken up
+    pub fn high_water(&self) -> usize {
+        self.high_water
     }
 
     /// Swaps next wait queue with current wait queue
@@ -35,22 +53,23 @@ impl<T> Waiters<T> {
         }
 
         let next = VecDeque::new();
+        self.high_water = 0;
+        self.parked.clear();
         Some(std::mem::replace(&mut self.current, next))
     }
 
     /// Remove a connection from waiters
-    pub fn remove(&mut self, id: ConnectionId) -> Vec<T> {
-        let mut requests = Vec::new();
-
-        while let Some(index) = self.current.iter().position(|x| x.0 == id) {
-            let request = self.current.swap_remove_back(index).map(|v| v.1).unwrap();
-            requests.push(request)
+    pub fn remove(&mut self, id: ConnectionId) -> Option<T> {
+        if !self.parked.remove(&id) {
+            return None;
         }
 
-        requests
+        let index = self.current.iter().position(|(waiter, _)| *waiter == id)?;
+        self.current.remove(index).map(|(_, request)| request)
     }
 
-    pub fn get_mut(&mut self) -> &mut VecDeque<(ConnectionId, T)> {
-        &mut self.current
+    /// Parked requests, which can be updated in place
+    pub fn requests_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.current.iter_mut().map(|(_, request)| request)
     }
 }
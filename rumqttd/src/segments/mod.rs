@@ -1,4 +1,5 @@
 use crate::Offset;
+use std::time::Duration;
 use std::usize;
 use std::{collections::VecDeque, io};
 
@@ -14,8 +15,94 @@ pub enum Position {
     Done { start: (u64, u64), end: (u64, u64) },
 }
 
+impl Position {
+    /// Items evicted from under a read from `cursor`. Reads from offsets which
+    /// are no longer in the log jump to the oldest item left instead. A cursor
+    /// at the end of an evicted segment moves to the next segment without
+    /// skipping anything, so only the absolute offset tells a gap apart
+    pub fn gap(&self, cursor: Offset) -> Option<ReadGap> {
+        let (Position::Next { start, .. } | Position::Done { start, .. }) = *self;
+        (start.1 > cursor.1).then_some(ReadGap {
+            skipped_from: cursor,
+            resumed_at: start,
+        })
+    }
+}
+
+/// Stretch of evicted items skipped over by a read. See [`Position::gap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadGap {
+    /// Offset the read was asked to start at
+    pub skipped_from: Offset,
+    /// Offset of the oldest item left, where the read started instead
+    pub resumed_at: Offset,
+}
+
+impl ReadGap {
+    /// Number of items skipped
+    pub fn skipped(&self) -> u64 {
+        self.resumed_at.1.saturating_sub(self.skipped_from.1)
+    }
+}
+
+/// How full the segments of a [`CommitLog`] are. See [`CommitLog::stats`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommitLogStats {
+    pub segment_count: usize,
+    /// Bytes in each segment over `max_segment_size`, oldest segment first.
+    /// The item which fills a segment can take it slightly above 1
+    pub fill_ratios: Vec<f64>,
+    pub avg_fill_ratio: f64,
+    /// Time since the oldest segment in memory became the active one
+    pub oldest_segment_age: Duration,
+    /// Bytes in all the segments
+    pub size: u64,
+}
+
+/// Location of an item appended to a [`CommitLog`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AppendPosition {
+    /// Id of the segment holding the item
+    pub segment: u64,
+    /// Absolute offset of the item
+    pub offset: u64,
+}
+
+impl AppendPosition {
+    /// Offset of the item, as accepted by [`CommitLog::get`]
+    #[cfg(test)]
+    pub fn offset(&self) -> Offset {
+        (self.segment, self.offset)
+    }
+
+    /// Cursor right after the item, where a read for fresh data starts
+    pub fn next_offset(&self) -> Offset {
+        (self.segment, self.offset + 1)
+    }
+}
+
+/// Segments evicted by an append and the number of items they held
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Eviction {
+    pub segments: usize,
+    pub items: u64,
+}
+
+/// Item refused by the appends of a [`CommitLog`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum AppendError {
+    #[error("Item of {size} bytes doesn't fit in a segment of {max} bytes")]
+    TooLarge { size: usize, max: usize },
+}
+
 pub trait Storage {
     fn size(&self) -> usize;
+
+    /// Bytes allocated for the item, which can be more than `size`. Types
+    /// that don't expose their allocation report `size`
+    fn capacity(&self) -> usize {
+        self.size()
+    }
 }
 
 /// There are 3 limits which are enforced:
@@ -124,6 +211,34 @@ where
         size
     }
 
+    /// Fill ratio of every segment in memory, for judging whether
+    /// `max_segment_size` suits the traffic
+    pub fn stats(&self) -> CommitLogStats {
+        let max = self.max_segment_size as f64;
+        let fill_ratios: Vec<f64> = self
+            .segments
+            .iter()
+            .map(|segment| segment.size() as f64 / max)
+            .collect();
+
+        CommitLogStats {
+            segment_count: self.segments.len(),
+            avg_fill_ratio: fill_ratios.iter().sum::<f64>() / fill_ratios.len() as f64,
+            fill_ratios,
+            oldest_segment_age: self.segments.front().unwrap().created.elapsed(),
+            size: self.size(),
+        }
+    }
+
+    /// Estimate of resident bytes of all the segments in memory. Unlike `size`,
+    /// this accounts for allocated capacity and per item bookkeeping
+    pub fn memory_usage(&self) -> usize {
+        let segments = self.segments.capacity() * std::mem::size_of::<Segment<T>>();
+        self.segments
+            .iter()
+            .fold(segments, |usage, segment| usage + segment.memory_usage())
+    }
+
     /// Number of segments
     #[allow(dead_code)]
     #[inline]
@@ -149,32 +264,103 @@ where
     }
 
     /// Append a new [`T`] to the active segment.
+    #[allow(dead_code)]
+    #[inline]
+    pub fn append(&mut self, message: T) -> Result<AppendPosition, AppendError> {
+        let size = message.size();
+        self.append_sized(message, size)
+    }
+
+    /// Append a new [`T`] whose `Storage::size` was already computed by the
+    /// caller, so that it's computed only once per item
     #[inline]
-    pub fn append(&mut self, message: T) -> (u64, u64) {
-        self.apply_retention();
+    pub fn append_sized(&mut self, message: T, size: usize) -> Result<AppendPosition, AppendError> {
+        Ok(self.append_bounded(message, size, None)?.0)
+    }
+
+    /// Whether an item of `size` bytes can be appended. Items larger than
+    /// `max_segment_size` are refused
+    pub fn fits(&self, size: usize) -> bool {
+        size <= self.max_segment_size
+    }
+
+    /// Whether the next append starts a new segment while the log is holding
+    /// `max_mem_segments` already, which evicts the oldest segment
+    pub fn is_full(&self) -> bool {
+        self.active_segment().size() >= self.max_segment_size as u64
+            && self.memory_segments_count() >= self.max_mem_segments
+    }
+
+    /// Append a new [`T`] of given size. Once the active segment is full, the
+    /// oldest segments are evicted to stay within `max_mem_segments`, except
+    /// for those holding items at or after `floor`. The log grows beyond the
+    /// limit while they are kept. Items which don't fit in a segment on their
+    /// own are refused and leave the log as it was
+    pub fn append_bounded(
+        &mut self,
+        message: T,
+        size: usize,
+        floor: Option<Offset>,
+    ) -> Result<(AppendPosition, Eviction), AppendError> {
+        if !self.fits(size) {
+            return Err(AppendError::TooLarge {
+                size,
+                max: self.max_segment_size,
+            });
+        }
+
+        let eviction = self.apply_retention(floor);
         let active_segment = self.active_segment_mut();
-        active_segment.push(message);
-        let absolute_offset = self.active_segment().next_offset();
-        (self.tail, absolute_offset)
+        active_segment.push(message, size);
+        let position = AppendPosition {
+            segment: self.tail,
+            offset: self.active_segment().next_offset() - 1,
+        };
+
+        Ok((position, eviction))
     }
 
-    fn apply_retention(&mut self) {
+    fn apply_retention(&mut self, floor: Option<Offset>) -> Eviction {
         if self.active_segment().size() >= self.max_segment_size as u64 {
-            // Read absolute_offset before applying memory retention, incase there is only 1
-            // segment allowed
-            let absolute_offset = self.active_segment().next_offset();
-            // If active segment is full and segments are full, apply retention policy
-            if self.memory_segments_count() >= self.max_mem_segments {
-                self.segments.pop_front();
-                self.head += 1;
+            return self.next_segment(floor);
+        }
+
+        Eviction::default()
+    }
+
+    /// Starts a new active segment, evicting the oldest segments beyond
+    /// `max_mem_segments` which don't hold items at or after `floor`
+    fn next_segment(&mut self, floor: Option<Offset>) -> Eviction {
+        let mut eviction = Eviction::default();
+
+        // Read absolute_offset before applying memory retention, incase there is only 1
+        // segment allowed
+        let absolute_offset = self.active_segment().next_offset();
+        // If active segment is full and segments are full, apply retention policy
+        while self.memory_segments_count() >= self.max_mem_segments {
+            // Offset right after the last item of the oldest segment
+            let end = match self.segments.get(1) {
+                Some(next) => next.absolute_offset,
+                None => absolute_offset,
+            };
+
+            if matches!(floor, Some(floor) if floor.1 < end) {
+                break;
             }
 
-            // Pushing a new segment into segments and updating tail automatically changes active
-            // segment to new empty one.
-            self.segments
-                .push_back(Segment::with_offset(absolute_offset));
-            self.tail += 1;
+            let segment = self.segments.pop_front().unwrap();
+            self.head += 1;
+            eviction.segments += 1;
+            eviction.items += segment.data.len() as u64;
         }
+
+        // Pushing a new segment into segments and updating tail automatically changes active
+        // segment to new empty one.
+        self.segments
+            .push_back(Segment::with_offset(absolute_offset));
+        self.tail += 1;
+
+        eviction
     }
 
     #[inline]
@@ -182,6 +368,95 @@ where
         self.active_segment().last()
     }
 
+    /// Item at given offset, `None` if it was dropped by retention or hasn't
+    /// been appended yet
+    pub fn get(&self, offset: Offset) -> Option<&T> {
+        if offset.0 < self.head || offset.0 > self.tail {
+            return None;
+        }
+
+        let segment = &self.segments[(offset.0 - self.head) as usize];
+        let idx = offset.1.checked_sub(segment.absolute_offset)?;
+        segment.data.get(idx as usize)
+    }
+
+    /// Last `n` items along with their offsets in the order they were appended.
+    /// Fewer items are returned if the log doesn't hold as many
+    pub fn tail(&self, n: usize) -> Vec<(T, Offset)> {
+        let mut o = Vec::new();
+        'segments: for (i, segment) in self.segments.iter().enumerate().rev() {
+            let segment_id = self.head + i as u64;
+            for (idx, item) in segment.data.iter().enumerate().rev() {
+                if o.len() == n {
+                    break 'segments;
+                }
+
+                let offset = (segment_id, segment.absolute_offset + idx as u64);
+                o.push((item.clone(), offset));
+            }
+        }
+
+        o.reverse();
+        o
+    }
+
+    /// Items from `cursor` onwards along with their offsets, without cloning
+    /// them. Cursors before the head of the log start at the head
+    pub fn iter_from(&self, cursor: Offset) -> impl Iterator<Item = (&T, Offset)> + '_ {
+        let cursor = if cursor.0 < self.head {
+            (self.head, self.segments.front().unwrap().absolute_offset)
+        } else {
+            cursor
+        };
+
+        let skip = (cursor.0 - self.head) as usize;
+        self.segments
+            .iter()
+            .enumerate()
+            .skip(skip)
+            .flat_map(move |(i, segment)| {
+                let segment_id = self.head + i as u64;
+                let skip = match segment_id == cursor.0 {
+                    true => cursor.1.saturating_sub(segment.absolute_offset),
+                    false => 0,
+                };
+
+                segment
+                    .data
+                    .iter()
+                    .enumerate()
+                    .skip(skip as usize)
+                    .map(move |(idx, item)| {
+                        (item, (segment_id, segment.absolute_offset + idx as u64))
+                    })
+            })
+    }
+
+    /// Reads up to `len` items in `[start, end)` backwards, newest first. Items
+    /// dropped by retention are skipped
+    pub fn readv_rev(&self, start: Offset, end: Offset, len: u64, out: &mut Vec<(T, Offset)>) {
+        let mut remaining = len;
+        for (i, segment) in self.segments.iter().enumerate().rev() {
+            let segment_id = self.head + i as u64;
+            let from = start.1.saturating_sub(segment.absolute_offset) as usize;
+            let to =
+                (end.1.saturating_sub(segment.absolute_offset) as usize).min(segment.data.len());
+            for idx in (from..to).rev() {
+                if remaining == 0 {
+                    return;
+                }
+
+                let offset = (segment_id, segment.absolute_offset + idx as u64);
+                out.push((segment.data[idx].clone(), offset));
+                remaining -= 1;
+            }
+
+            if segment.absolute_offset <= start.1 {
+                return;
+            }
+        }
+    }
+
     /// Read `len` Ts at once. More efficient that reading 1 at a time. Returns
     /// the next offset to read data from. The Position::start returned need not
     /// be a valid index if the start given is not valid either.
@@ -299,6 +574,152 @@ mod tests {
         assert_eq!(out.0, expected);
     }
 
+    #[test]
+    fn stats_report_fill_of_each_segment() {
+        let mut log: CommitLog<Bytes> = CommitLog::new(10 * 1024, 10).unwrap();
+        let stats = log.stats();
+        assert_eq!(stats.segment_count, 1);
+        assert_eq!(stats.fill_ratios, vec![0.0]);
+
+        // 2 full segments of 10 1 KB items and one with 5 of them
+        for i in 0..25 {
+            log.append(random_payload(i, 1024)).unwrap();
+        }
+
+        let stats = log.stats();
+        assert_eq!(stats.segment_count, 3);
+        assert_eq!(stats.fill_ratios, vec![1.0, 1.0, 0.5]);
+        assert!((stats.avg_fill_ratio - 2.5 / 3.0).abs() < 1e-9);
+        assert_eq!(stats.size, 25 * 1024);
+        assert!(stats.oldest_segment_age < Duration::from_secs(60));
+    }
+
+    #[test]
+    fn items_larger_than_a_segment_are_refused() {
+        let mut log: CommitLog<Bytes> = CommitLog::new(1024, 2).unwrap();
+        log.append(random_payload(0, 1024)).unwrap();
+        let next = log.next_offset();
+
+        assert_eq!(
+            log.append(random_payload(1, 1025)),
+            Err(AppendError::TooLarge {
+                size: 1025,
+                max: 1024
+            })
+        );
+        assert_eq!(log.next_offset(), next);
+        assert_eq!(log.memory_segments_count(), 1);
+
+        // Log carries on as if the item was never appended
+        let position = log.append(random_payload(2, 1024)).unwrap();
+        assert_eq!(position.offset(), (1, 1));
+        let mut out = Vec::new();
+        log.readv((0, 0), 10, &mut out).unwrap();
+        assert_eq!(out.len(), 2);
+        verify(0, 1024, out[0].clone());
+        verify(2, 1024, out[1].clone());
+    }
+
+    #[test]
+    fn memory_usage_includes_unused_capacity() {
+        let mut log: CommitLog<Vec<u8>> = CommitLog::new(10 * 1024, 10).unwrap();
+        let empty = log.memory_usage();
+        assert!(empty > 0);
+
+        for i in 0..5 {
+            let mut item = Vec::with_capacity(1000);
+            item.extend_from_slice(&[i; 100]);
+            log.append(item).unwrap();
+        }
+
+        // Items fit in the preallocated segment, only their buffers are added
+        assert_eq!(log.size(), 500);
+        assert_eq!(log.memory_usage() - empty, 5000);
+    }
+
+    /// Item which counts how many times its size was computed
+    #[derive(Clone)]
+    struct Counted(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl Storage for Counted {
+        fn size(&self) -> usize {
+            self.0.set(self.0.get() + 1);
+            256
+        }
+
+        fn capacity(&self) -> usize {
+            256
+        }
+    }
+
+    #[test]
+    fn size_is_computed_once_per_item() {
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut log: CommitLog<Counted> = CommitLog::new(1024, 2).unwrap();
+        for _ in 0..10 {
+            log.append(Counted(calls.clone())).unwrap();
+        }
+        assert_eq!(calls.get(), 10);
+
+        // Rotation, reads and accounting use the stored sizes
+        assert_eq!(log.len(), 2);
+        assert_eq!(log.size(), 6 * 256);
+        let mut out = Vec::new();
+        log.readv((1, 4), 10, &mut out).unwrap();
+        assert_eq!(out.len(), 6);
+        log.memory_usage();
+        assert_eq!(calls.get(), 10);
+    }
+
+    #[test]
+    fn items_are_fetched_by_offset() {
+        // 1 KB segments fit 4 items, only 2 segments are kept in memory
+        let mut log: CommitLog<Bytes> = CommitLog::new(1024, 2).unwrap();
+        let offsets: Vec<Offset> = (0..12)
+            .map(|i| log.append(random_payload(i, 256)).unwrap().offset())
+            .collect();
+
+        assert_eq!(log._head_and_tail(), (1, 2));
+        for i in [4, 7, 8, 11] {
+            verify(i, 256, (log.get(offsets[i]).unwrap().clone(), offsets[i]));
+        }
+
+        // Dropped by retention
+        assert_eq!(offsets[3], (0, 3));
+        assert!(log.get(offsets[3]).is_none());
+
+        // Offset belonging to another segment and offset not appended yet
+        assert!(log.get((1, 9)).is_none());
+        assert!(log.get((2, 12)).is_none());
+        assert!(log.get((3, 12)).is_none());
+    }
+
+    #[test]
+    fn reverse_reads_cross_segments_newest_first() {
+        // 1 KB segments fit 4 items, only 2 segments are kept in memory
+        let mut log: CommitLog<Bytes> = CommitLog::new(1024, 2).unwrap();
+        for i in 0..12 {
+            log.append(random_payload(i, 256)).unwrap();
+        }
+
+        let mut out = Vec::new();
+        log.readv_rev((1, 5), (2, 10), 3, &mut out);
+        let offsets: Vec<Offset> = out.iter().map(|(_, offset)| *offset).collect();
+        assert_eq!(offsets, vec![(2, 9), (2, 8), (1, 7)]);
+        verify(9, 256, out[0].clone());
+
+        // Stops at the start of the range and at items dropped by retention
+        out.clear();
+        log.readv_rev((1, 5), (1, 7), 10, &mut out);
+        let offsets: Vec<Offset> = out.iter().map(|(_, offset)| *offset).collect();
+        assert_eq!(offsets, vec![(1, 6), (1, 5)]);
+
+        out.clear();
+        log.readv_rev((0, 0), (1, 6), 10, &mut out);
+        let offsets: Vec<Offset> = out.iter().map(|(_, offset)| *offset).collect();
+        assert_eq!(offsets, vec![(1, 5), (1, 4)]);
+    }
+
     #[test]
     fn reading_at_invalid_cursor_returns_none() {
         // 1 as active only
@@ -332,8 +753,8 @@ mod tests {
 
         // Fill the active segment
         for i in 0..100 {
-            let offset = log.append(random_payload(i as u8, packet_size));
-            assert_eq!(offset, (0, i as u64 + 1))
+            let position = log.append(random_payload(i as u8, packet_size)).unwrap();
+            assert_eq!(position.next_offset(), (0, i as u64 + 1))
         }
         assert_eq!(log.size(), max_segment_size as u64);
         assert_eq!(log.head, 0);
@@ -341,22 +762,22 @@ mod tests {
         assert_eq!(log.len(), 1);
 
         // Append more data to trigger new segment creation
-        log.append(random_payload(100, packet_size));
+        log.append(random_payload(100, packet_size)).unwrap();
         assert_eq!(log.head, 0);
         assert_eq!(log.tail, 1);
         assert_eq!(log.len(), 2);
 
         // Fill the rest of new active segment
         for (i, v) in (101..200).enumerate() {
-            let offset = log.append(random_payload(v, packet_size));
-            assert_eq!(offset, (1, i as u64 + 102))
+            let position = log.append(random_payload(v, packet_size)).unwrap();
+            assert_eq!(position.next_offset(), (1, i as u64 + 102))
         }
         assert_eq!(log.head, 0);
         assert_eq!(log.tail, 1);
         assert_eq!(log.len(), 2);
 
         // Append more data to trigger new segment creation and retention policy
-        log.append(random_payload(200, packet_size));
+        log.append(random_payload(200, packet_size)).unwrap();
         assert_eq!(log.head, 1);
         assert_eq!(log.tail, 2);
         assert_eq!(log.len(), 2);
@@ -370,7 +791,7 @@ mod tests {
         let mut log = CommitLog::new(max_segment_size, 1).unwrap();
 
         for i in 0..10 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
 
         assert_eq!(log.active_segment().len(), 10);
@@ -475,7 +896,7 @@ mod tests {
             }
         );
         for i in 10..20 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
         let next = log.readv((0, 10), 20, &mut out).unwrap();
         assert_eq!(out.len(), 10);
@@ -500,7 +921,7 @@ mod tests {
 
         // Fill active segment
         for i in 0..100 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
         assert_eq!(log.head, 0);
         assert_eq!(log.tail, 0);
@@ -522,7 +943,7 @@ mod tests {
         // Fill with data worth 2 more segments. Active segment will change
         let mut out = Vec::new();
         for i in 0..200 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
         assert_eq!(log.head, 0);
         assert_eq!(log.tail, 2);
@@ -565,7 +986,7 @@ mod tests {
 
         // Fill active segment + 3 more memory segments
         for i in 0..40 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
 
         // One big jump
@@ -659,7 +1080,7 @@ mod tests {
 
         // Fill all 10 in memory segments
         for i in 0..100 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
         assert_eq!(log.head, 0);
         assert_eq!(log.tail, 9);
@@ -676,7 +1097,7 @@ mod tests {
 
         // Fill 10 more inmemory segment pushing previous into retention policy
         for i in 0..100 {
-            log.append(random_payload(i, packet_size));
+            log.append(random_payload(i, packet_size)).unwrap();
         }
         assert_eq!(log.head, 10);
         assert_eq!(log.tail, 19);
@@ -2,16 +2,21 @@ use crate::{Cursor, Offset};
 
 use super::Storage;
 use std::io;
+use std::time::Instant;
 
 pub(crate) struct Segment<T> {
     /// Holds the actual segment.
     pub(crate) data: Vec<T>,
     total_size: u64,
+    /// Bytes allocated for all the items, see `Storage::capacity`
+    total_capacity: u64,
     /// The absolute offset at which the `inner` starts at. All reads will return the absolute
     /// offset as the offset of the cursor.
     ///
     /// **NOTE**: this offset is re-generated on each run of the commit log.
     pub(crate) absolute_offset: u64,
+    /// When the segment became the active one
+    pub(crate) created: Instant,
 }
 
 pub(crate) enum SegmentPosition {
@@ -32,6 +37,8 @@ where
             data: Vec::with_capacity(1024),
             absolute_offset,
             total_size: 0,
+            total_capacity: 0,
+            created: Instant::now(),
         }
     }
     pub(crate) fn new() -> Self {
@@ -39,6 +46,8 @@ where
             data: Vec::with_capacity(1024),
             absolute_offset: 0,
             total_size: 0,
+            total_capacity: 0,
+            created: Instant::now(),
         }
     }
 
@@ -47,10 +56,11 @@ where
         self.absolute_offset + self.len()
     }
 
-    /// Push a new `T` in the segment.
+    /// Push a new `T` of given size, as computed by `Storage::size`, in the segment.
     #[inline]
-    pub(crate) fn push(&mut self, inner_type: T) {
-        self.total_size += inner_type.size() as u64;
+    pub(crate) fn push(&mut self, inner_type: T, size: usize) {
+        self.total_size += size as u64;
+        self.total_capacity += inner_type.capacity() as u64;
         self.data.push(inner_type);
     }
 
@@ -108,6 +118,13 @@ where
         self.total_size
     }
 
+    /// Estimate of bytes held by the segment, counting unused capacity of
+    /// the backing `Vec` along with the allocation of every item.
+    #[inline]
+    pub(crate) fn memory_usage(&self) -> usize {
+        self.data.capacity() * std::mem::size_of::<T>() + self.total_capacity as usize
+    }
+
     #[inline]
     pub fn last(&self) -> Option<T> {
         self.data.last().cloned()
@@ -123,7 +140,7 @@ mod tests {
     fn segment_works_for_bytes() {
         let mut mem_segment: Segment<Bytes> = Segment::new();
         let test_byte = Bytes::from_static(b"test1");
-        mem_segment.push(test_byte.clone());
+        mem_segment.push(test_byte.clone(), test_byte.len());
         assert_eq!(mem_segment.len(), 1);
         assert_eq!(mem_segment.last().unwrap(), test_byte);
     }
@@ -131,15 +148,15 @@ mod tests {
     #[test]
     fn readv_works_for_bytes() {
         let mut segment: Segment<Bytes> = Segment::new();
-        segment.push(Bytes::from_static(b"test1"));
-        segment.push(Bytes::from_static(b"test2"));
-        segment.push(Bytes::from_static(b"test3"));
-        segment.push(Bytes::from_static(b"test4"));
-        segment.push(Bytes::from_static(b"test5"));
-        segment.push(Bytes::from_static(b"test6"));
-        segment.push(Bytes::from_static(b"test7"));
-        segment.push(Bytes::from_static(b"test8"));
-        segment.push(Bytes::from_static(b"test9"));
+        segment.push(Bytes::from_static(b"test1"), 5);
+        segment.push(Bytes::from_static(b"test2"), 5);
+        segment.push(Bytes::from_static(b"test3"), 5);
+        segment.push(Bytes::from_static(b"test4"), 5);
+        segment.push(Bytes::from_static(b"test5"), 5);
+        segment.push(Bytes::from_static(b"test6"), 5);
+        segment.push(Bytes::from_static(b"test7"), 5);
+        segment.push(Bytes::from_static(b"test8"), 5);
+        segment.push(Bytes::from_static(b"test9"), 5);
         assert_eq!(segment.len(), 9);
 
         let mut out: Vec<(Bytes, Offset)> = Vec::new();
@@ -156,15 +173,15 @@ mod tests {
     #[test]
     fn readv_works_for_vec_of_u8() {
         let mut segment: Segment<Vec<u8>> = Segment::new();
-        segment.push(vec![1u8]);
-        segment.push(vec![2u8]);
-        segment.push(vec![3u8]);
-        segment.push(vec![4u8]);
-        segment.push(vec![5u8]);
-        segment.push(vec![6u8]);
-        segment.push(vec![7u8]);
-        segment.push(vec![8u8]);
-        segment.push(vec![9u8]);
+        segment.push(vec![1u8], 1);
+        segment.push(vec![2u8], 1);
+        segment.push(vec![3u8], 1);
+        segment.push(vec![4u8], 1);
+        segment.push(vec![5u8], 1);
+        segment.push(vec![6u8], 1);
+        segment.push(vec![7u8], 1);
+        segment.push(vec![8u8], 1);
+        segment.push(vec![9u8], 1);
         assert_eq!(segment.len(), 9);
 
         let mut out: Vec<(Vec<u8>, Offset)> = Vec::new();
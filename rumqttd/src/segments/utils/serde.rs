@@ -20,4 +20,8 @@ impl Storage for Vec<u8> {
         // For bytes len returns number of bytes in the given `Bytes`
         self.len()
     }
+
+    fn capacity(&self) -> usize {
+        Vec::capacity(self)
+    }
 }
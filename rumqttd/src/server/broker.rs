@@ -9,7 +9,7 @@ use crate::protocol::v4::V4;
 use crate::protocol::v5::V5;
 #[cfg(feature = "websockets")]
 use crate::protocol::ws::Ws;
-use crate::protocol::Protocol;
+use crate::protocol::{MqttVersion, Protocol};
 #[cfg(any(feature = "use-rustls", feature = "use-native-tls"))]
 use crate::server::tls::{self, TLSAcceptor};
 use crate::{meters, ConnectionSettings, Meter};
@@ -28,7 +28,7 @@ use std::{io, thread};
 use crate::link::console;
 use crate::link::local::{self, Link, LinkRx, LinkTx};
 use crate::router::{Disconnection, Event, Router};
-use crate::{Config, ConnectionId, ServerSettings};
+use crate::{Config, ConfigError, ConnectionId, RouterConfigPatch, ServerSettings};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::time::error::Elapsed;
 use tokio::{task, time};
@@ -51,6 +51,17 @@ pub enum Error {
     Accept(String),
     #[error("Remote error = {0}")]
     Remote(#[from] remote::Error),
+    #[error("Router config error = {0}")]
+    Config(#[from] ConfigError),
+}
+
+/// Failure of an administrative request to the running router
+#[derive(Debug, thiserror::Error)]
+pub enum AdminError {
+    #[error("Router isn't running")]
+    RouterStopped,
+    #[error("Router config error = {0}")]
+    Config(#[from] ConfigError),
 }
 
 pub struct Broker {
@@ -59,10 +70,10 @@ pub struct Broker {
 }
 
 impl Broker {
-    pub fn new(config: Config) -> Broker {
+    pub fn new(config: Config) -> Result<Broker, ConfigError> {
         let config = Arc::new(config);
         let router_config = config.router.clone();
-        let router: Router = Router::new(config.id, router_config);
+        let router: Router = Router::new(config.id, router_config)?;
 
         // Setup cluster if cluster settings are configured
         match config.cluster.clone() {
@@ -76,11 +87,11 @@ impl Broker {
                 // Start router first and then cluster in the background
                 let router_tx = router.spawn();
                 // cluster.spawn();
-                Broker { config, router_tx }
+                Ok(Broker { config, router_tx })
             }
             None => {
                 let router_tx = router.spawn();
-                Broker { config, router_tx }
+                Ok(Broker { config, router_tx })
             }
         }
     }
@@ -136,6 +147,48 @@ impl Broker {
         Ok(link)
     }
 
+    /// Asks the router to stop accepting publishes so that subscribers can
+    /// drain pending data before the broker is shut down. The returned
+    /// receiver gets a message once everything is delivered and acked
+    pub fn drain(&self) -> flume::Receiver<()> {
+        let (tx, rx) = flume::bounded(1);
+        if let Err(e) = self.router_tx.send((0, Event::Drain(tx))) {
+            error!(error=?e, "Failed to start draining router");
+        }
+
+        rx
+    }
+
+    /// Changes limits of the running router. See [`RouterConfigPatch`]
+    pub fn update_router_config(&self, patch: RouterConfigPatch) -> Result<(), AdminError> {
+        let (tx, rx) = flume::bounded(1);
+        self.router_tx
+            .send((0, Event::UpdateConfig(patch, tx)))
+            .map_err(|_| AdminError::RouterStopped)?;
+        rx.recv().map_err(|_| AdminError::RouterStopped)??;
+        Ok(())
+    }
+
+    /// Drops the log of a filter nobody is subscribed to, including saved
+    /// sessions. Returns whether the filter was removed
+    pub fn remove_filter(&self, filter: &str) -> Result<bool, AdminError> {
+        let (tx, rx) = flume::bounded(1);
+        self.router_tx
+            .send((0, Event::RemoveFilter(filter.to_owned(), tx)))
+            .map_err(|_| AdminError::RouterStopped)?;
+        rx.recv().map_err(|_| AdminError::RouterStopped)
+    }
+
+    /// Compacts the filter logs of the router, which leave gaps behind when
+    /// filters are removed. Returns the number of filters which moved
+    pub fn compact_filters(&self) -> Result<usize, AdminError> {
+        let (tx, rx) = flume::bounded(1);
+        self.router_tx
+            .send((0, Event::CompactFilters(tx)))
+            .map_err(|_| AdminError::RouterStopped)?;
+        rx.recv().map_err(|_| AdminError::RouterStopped)
+    }
+
     pub fn link(&self, client_id: &str) -> Result<(LinkTx, LinkRx), local::LinkError> {
         // Register this connection with the router. Router replies with ack which if ok will
         // start the link. Router can sometimes reject the connection (ex max connection limit)
@@ -145,8 +198,10 @@ impl Broker {
             self.router_tx.clone(),
             true,
             None,
+            None,
             false,
             None,
+            MqttVersion::V4,
         )?;
         Ok((link_tx, link_rx))
     }
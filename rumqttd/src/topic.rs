@@ -0,0 +1,310 @@
+use std::borrow::Borrow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::{validate_filter, validate_topic, TopicError};
+
+/// Topic name of a publish. Parsing checks that it is neither empty nor
+/// contains wildcards. Clones share the same string
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Topic(Arc<str>);
+
+/// Topic filter of a subscription. Parsing checks that `+` and `#` occupy whole
+/// levels and that `#` is only used as the last level
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Filter(String);
+
+impl Topic {
+    /// Wraps a topic which was already validated, e.g. one read off a publish
+    /// accepted by the router
+    pub(crate) fn new_unchecked(topic: impl Into<Arc<str>>) -> Topic {
+        Topic(topic.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Filter {
+    /// Wraps a filter which was already validated, e.g. one of a subscription
+    /// accepted by the router
+    pub(crate) fn new_unchecked(filter: impl Into<String>) -> Filter {
+        Filter(filter.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Topic {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        validate_topic(s)?;
+        Ok(Topic(s.into()))
+    }
+}
+
+impl FromStr for Filter {
+    type Err = TopicError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        validate_filter(s)?;
+        Ok(Filter(s.to_owned()))
+    }
+}
+
+impl TryFrom<String> for Topic {
+    type Error = TopicError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        validate_topic(&s)?;
+        Ok(Topic(s.into()))
+    }
+}
+
+impl TryFrom<String> for Filter {
+    type Error = TopicError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        validate_filter(&s)?;
+        Ok(Filter(s))
+    }
+}
+
+impl From<Topic> for String {
+    fn from(topic: Topic) -> String {
+        topic.0.to_string()
+    }
+}
+
+impl From<Filter> for String {
+    fn from(filter: Filter) -> String {
+        filter.0
+    }
+}
+
+impl Deref for Topic {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Deref for Filter {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Topic {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Filter {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Topic {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Filter {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Topic {
+    fn eq(&self, other: &str) -> bool {
+        &*self.0 == other
+    }
+}
+
+impl PartialEq<str> for Filter {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Topic {
+    fn eq(&self, other: &&str) -> bool {
+        &*self.0 == *other
+    }
+}
+
+impl PartialEq<&str> for Filter {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Topics handed out to the logs, so that every map and publish refering to
+/// a topic shares one copy of it instead of holding its own
+#[derive(Debug, Default)]
+pub(crate) struct TopicPool {
+    /// Topic along with the same name as publishes carry it. `Bytes` can't
+    /// be built on top of the `Arc<str>`, hence the second copy per topic
+    topics: HashMap<Topic, Bytes>,
+}
+
+impl TopicPool {
+    /// Shared copies of an already validated `topic`, allocated on first use
+    pub fn intern(&mut self, topic: &str) -> (Topic, Bytes) {
+        if let Some((topic, bytes)) = self.topics.get_key_value(topic) {
+            return (topic.clone(), bytes.clone());
+        }
+
+        let interned = Topic::new_unchecked(topic);
+        let bytes = Bytes::copy_from_slice(topic.as_bytes());
+        self.topics.insert(interned.clone(), bytes.clone());
+        (interned, bytes)
+    }
+
+    /// Forgets topics which aren't held outside of the pool anymore.
+    /// Publishes still in the logs keep their own reference
+    pub fn prune(&mut self) {
+        self.topics
+            .retain(|topic, _| Arc::strong_count(&topic.0) > 1);
+    }
+
+    /// Forgets `topic` if it isn't held outside of the pool anymore. See
+    /// `prune`
+    pub fn release(&mut self, topic: &str) {
+        let unused = self
+            .topics
+            .get_key_value(topic)
+            .is_some_and(|(topic, _)| Arc::strong_count(&topic.0) == 1);
+        if unused {
+            self.topics.remove(topic);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.topics.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Filter, Topic, TopicPool};
+    use crate::protocol::TopicError;
+
+    #[test]
+    fn valid_filters_are_parsed() {
+        for filter in [
+            "sport",
+            "sport/tennis/player1",
+            "+",
+            "#",
+            "sport/+/player1",
+            "sport/#",
+            "+/+",
+            "/finance",
+            "$SYS/#",
+        ] {
+            let parsed: Filter = filter.parse().unwrap();
+            assert_eq!(&*parsed, filter);
+        }
+    }
+
+    #[test]
+    fn invalid_filters_are_rejected() {
+        let cases = [
+            ("", TopicError::Empty),
+            ("sport+", TopicError::PartialWildcard(0)),
+            ("sport/tennis#", TopicError::PartialWildcard(1)),
+            ("sport/++", TopicError::PartialWildcard(1)),
+            ("sport/#/ranking", TopicError::MultiLevelWildcardNotLast(1)),
+            ("#/", TopicError::MultiLevelWildcardNotLast(0)),
+            ("sport/\0", TopicError::NullCharacter(6)),
+        ];
+
+        for (filter, error) in cases {
+            assert_eq!(filter.parse::<Filter>(), Err(error), "{filter:?}");
+        }
+    }
+
+    #[test]
+    fn topics_are_validated() {
+        for topic in ["sport", "sport/tennis/player1", "/", "$SYS/uptime"] {
+            assert_eq!(&*topic.parse::<Topic>().unwrap(), topic);
+        }
+
+        assert_eq!("".parse::<Topic>(), Err(TopicError::Empty));
+        assert_eq!(
+            "sport/+/player1".parse::<Topic>(),
+            Err(TopicError::WildcardInTopic(6))
+        );
+        assert_eq!(
+            "sport/#".parse::<Topic>(),
+            Err(TopicError::WildcardInTopic(6))
+        );
+    }
+
+    #[test]
+    fn invalid_filters_fail_to_deserialize() {
+        let filters: Vec<Filter> = serde_json::from_str(r#"["a/+", "a/#"]"#).unwrap();
+        let expected: Vec<Filter> = vec!["a/+".parse().unwrap(), "a/#".parse().unwrap()];
+        assert_eq!(filters, expected);
+
+        let error = serde_json::from_str::<Vec<Filter>>(r#"["a/b#"]"#).unwrap_err();
+        assert!(error.to_string().contains("level 1"));
+    }
+
+    #[test]
+    fn interned_topics_share_their_allocation() {
+        let mut pool = TopicPool::default();
+        let (topic, bytes) = pool.intern("a/b");
+        let (again, again_bytes) = pool.intern("a/b");
+        assert!(std::sync::Arc::ptr_eq(&topic.0, &again.0));
+        assert_eq!(bytes.as_ptr(), again_bytes.as_ptr());
+        assert_eq!(&bytes[..], b"a/b");
+
+        pool.intern("a/c");
+        assert_eq!(pool.len(), 2);
+
+        drop((again, again_bytes));
+        pool.prune();
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool.intern("a/b").0, topic);
+
+        pool.release("a/b");
+        assert_eq!(pool.len(), 1);
+        drop(topic);
+        pool.release("a/b");
+        assert_eq!(pool.len(), 0);
+    }
+}